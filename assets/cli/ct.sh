@@ -70,6 +70,22 @@ api() {
     printf '%s\n' "$out"
 }
 
+# download PATH FILE — save a response body to FILE as is, for what a shell
+# variable can't hold. On failure the server's message goes to stderr and
+# FILE is removed.
+download() {
+    local path="$1" file="$2" status
+    status="$(curl -sS "$API_BASE$path" -o "$file" -w '%{http_code}' \
+        -H "Authorization: Bearer $CT_TOKEN")" || die "could not reach $API_BASE"
+
+    if [ "$status" -ge 400 ]; then
+        cat "$file" >&2
+        printf '\n' >&2
+        rm -f "$file"
+        exit 1
+    fi
+}
+
 # Pull one string field out of a flat JSON object without needing jq.
 json_field() {
     local key="$1"
@@ -81,11 +97,71 @@ json_number() {
     sed -n "s/.*\"$key\"[[:space:]]*:[[:space:]]*\([0-9-]*\).*/\1/p" | head -n 1
 }
 
+# follow_logs BUNDLE JOB_ID — print a job's output as it is written, from the
+# server-sent event stream at .../logs?follow=1. Returns once the job ends,
+# zero only if it succeeded, so `run --wait` can be used in a script.
+follow_logs() {
+    local line event="" status=""
+
+    while IFS= read -r line; do
+        case "$line" in
+            "event: "*) event="${line#event: }" ;;
+            "data: "*)
+                if [ "$event" = "status" ]; then
+                    status="${line#data: }"
+                else
+                    printf '%s\n' "${line#data: }"
+                fi
+                ;;
+            "") event="" ;;
+            :*) ;;
+            # Anything else is not part of the stream — an error body.
+            *) printf '%s\n' "$line" >&2 ;;
+        esac
+    done < <(curl -sSN "$API_BASE/api/shell/$1/jobs/$2/logs?follow=1" \
+        -H 'Accept: text/event-stream' \
+        -H "Authorization: Bearer $CT_TOKEN")
+
+    [ -n "$status" ] || die "the log stream ended before the job did"
+
+    local state
+    state="$(printf '%s' "$status" | json_field status)"
+    printf '\njob %s: %s\n' "$2" "$state" >&2
+    [ "$state" = "success" ]
+}
+
 # JSON-escape a string so a password with quotes or backslashes survives.
 json_escape() {
     printf '%s' "$1" | sed -e 's/\\/\\\\/g' -e 's/"/\\"/g'
 }
 
+# vars_json [[--secret] KEY=VALUE ...] — sets VARS_JSON and SECRET_JSON to the
+# members of a request's "vars" object and "secret" array.
+vars_json() {
+    local pair key value is_secret
+    VARS_JSON="" SECRET_JSON=""
+    while [ $# -gt 0 ]; do
+        is_secret=""
+        if [ "$1" = "--secret" ]; then
+            is_secret=1; shift
+            [ $# -gt 0 ] || die "--secret needs a KEY=VALUE after it"
+        fi
+        pair="$1"; shift
+        case "$pair" in
+            *=*) ;;
+            *) die "variables must be KEY=VALUE, got: $pair" ;;
+        esac
+        key="${pair%%=*}"
+        value="${pair#*=}"
+        [ -n "$VARS_JSON" ] && VARS_JSON="$VARS_JSON,"
+        VARS_JSON="$VARS_JSON\"$(json_escape "$key")\":\"$(json_escape "$value")\""
+        if [ -n "$is_secret" ]; then
+            [ -n "$SECRET_JSON" ] && SECRET_JSON="$SECRET_JSON,"
+            SECRET_JSON="$SECRET_JSON\"$(json_escape "$key")\""
+        fi
+    done
+}
+
 # ── commands ────────────────────────────────────────────────────────────────
 
 cmd_login() {
@@ -141,35 +217,176 @@ cmd_shell() {
             api GET "/api/shell/$1/describe/$2"
             ;;
         run)
-            [ $# -ge 2 ] || die "usage: ct shell run <bundle> <target> [KEY=VALUE ...]"
-            local bundle="$1" target="$2"; shift 2
-            local vars="" pair key value
+            local wait="" queue=false query="" keep_going=false profile=""
+            while [ $# -gt 0 ]; do
+                case "$1" in
+                    --wait)     wait=1; shift ;;
+                    --queue)    queue=true; shift ;;
+                    --dry-run)  query="?dry_run=1"; shift ;;
+                    --continue) keep_going=true; shift ;;
+                    --profile)
+                        [ $# -ge 2 ] || die "--profile needs a name"
+                        profile="$2"; shift 2 ;;
+                    *) break ;;
+                esac
+            done
+            [ $# -ge 2 ] || die "usage: ct shell run [--wait] [--queue] [--dry-run] [--continue] [--profile <name>] <bundle> <target> [<target> ...] [[--secret] KEY=VALUE ...]"
+            local bundle="$1"; shift
+            # Targets up to the first variable; more than one is a pipeline.
+            local targets=()
+            while [ $# -gt 0 ]; do
+                case "$1" in
+                    --secret|*=*) break ;;
+                    *) targets+=("$1"); shift ;;
+                esac
+            done
+            [ ${#targets[@]} -ge 1 ] || die "no target given"
+            vars_json "$@"
+            local response id body
+            body="\"vars\":{$VARS_JSON},\"secret\":[$SECRET_JSON],\"queue\":$queue"
+            [ -n "$profile" ] && body="$body,\"profile\":\"$(json_escape "$profile")\""
+            if [ ${#targets[@]} -eq 1 ]; then
+                response="$(api POST "/api/shell/$bundle/run/${targets[0]}$query" "{$body}")"
+            else
+                local list="" target
+                for target in "${targets[@]}"; do
+                    list="${list:+$list,}\"$(json_escape "$target")\""
+                done
+                response="$(api POST "/api/shell/$bundle/pipeline$query" "{\"targets\":[$list],\"continue_on_failure\":$keep_going,$body}")"
+            fi
+            if [ -z "$wait" ]; then
+                printf '%s\n' "$response"
+                return
+            fi
+            id="$(printf '%s' "$response" | json_field uuid)"
+            [ -n "$id" ] || die "no job id in the response"
+            printf 'job %s started\n\n' "$id" >&2
+            follow_logs "$bundle" "$id"
+            ;;
+        jobs)
+            [ $# -ge 1 ] || die "usage: ct shell jobs <bundle> [status=S target=T user_id=U kind=K from=MS to=MS limit=N offset=N]"
+            local bundle="$1"; shift
+            local query="" pair
             for pair in "$@"; do
                 case "$pair" in
-                    *=*) ;;
-                    *) die "variables must be KEY=VALUE, got: $pair" ;;
+                    status=*|target=*|user_id=*|kind=*|from=*|to=*|limit=*|offset=*) ;;
+                    *) die "unknown filter: $pair" ;;
                 esac
-                key="${pair%%=*}"
-                value="${pair#*=}"
-                [ -n "$vars" ] && vars="$vars,"
-                vars="$vars\"$(json_escape "$key")\":\"$(json_escape "$value")\""
+                query="${query:+$query&}$pair"
             done
-            api POST "/api/shell/$bundle/run/$target" "{\"vars\":{$vars}}"
+            api GET "/api/shell/$bundle/jobs${query:+?$query}"
             ;;
         job)
             [ $# -ge 2 ] || die "usage: ct shell job <bundle> <job-id>"
             api GET "/api/shell/$1/jobs/$2"
             ;;
         logs)
-            [ $# -ge 2 ] || die "usage: ct shell logs <bundle> <job-id>"
+            if [ "${1:-}" = "-f" ]; then
+                shift
+                [ $# -ge 2 ] || die "usage: ct shell logs -f <bundle> <job-id>"
+                follow_logs "$1" "$2"
+                return
+            fi
+            [ $# -ge 2 ] || die "usage: ct shell logs [-f] <bundle> <job-id>"
             api GET "/api/shell/$1/jobs/$2/logs"
             ;;
+        artifacts)
+            [ $# -ge 2 ] || die "usage: ct shell artifacts <bundle> <job-id> [<file> [-o <path>]]"
+            if [ $# -eq 2 ]; then
+                api GET "/api/shell/$1/jobs/$2/artifacts"
+                return
+            fi
+            local out="$3"
+            if [ "${4:-}" = "-o" ]; then
+                [ $# -ge 5 ] || die "-o needs a path"
+                out="$5"
+            fi
+            download "/api/shell/$1/jobs/$2/artifacts/$3" "$out"
+            printf 'saved %s\n' "$out" >&2
+            ;;
+        cancel)
+            [ $# -ge 2 ] || die "usage: ct shell cancel <bundle> <job-id>"
+            api POST "/api/shell/$1/jobs/$2/cancel"
+            ;;
+        approve)
+            [ $# -ge 2 ] || die "usage: ct shell approve <bundle> <job-id>"
+            api POST "/api/shell/$1/jobs/$2/approve"
+            ;;
+        reject)
+            [ $# -ge 2 ] || die "usage: ct shell reject <bundle> <job-id>"
+            api POST "/api/shell/$1/jobs/$2/reject"
+            ;;
+        schedules)
+            [ $# -ge 1 ] || die "usage: ct shell schedules <bundle>"
+            api GET "/api/shell/$1/schedules"
+            ;;
+        schedule)
+            [ $# -ge 3 ] || die "usage: ct shell schedule <bundle> <target> '<cron>' [[--secret] KEY=VALUE ...]"
+            local bundle="$1" target="$2" cron="$3"; shift 3
+            vars_json "$@"
+            api POST "/api/shell/$bundle/schedules" "{\"target\":\"$(json_escape "$target")\",\"cron\":\"$(json_escape "$cron")\",\"vars\":{$VARS_JSON},\"secret\":[$SECRET_JSON]}"
+            ;;
+        unschedule)
+            [ $# -ge 2 ] || die "usage: ct shell unschedule <bundle> <schedule-id>"
+            api DELETE "/api/shell/$1/schedules/$2"
+            ;;
+        profiles)
+            [ $# -ge 1 ] || die "usage: ct shell profiles <bundle>"
+            api GET "/api/shell/$1/profiles"
+            ;;
+        profile)
+            [ $# -ge 2 ] || die "usage: ct shell profile <bundle> <name> [[--secret] KEY=VALUE ...] [--unset KEY ...]"
+            local bundle="$1" name="$2"; shift 2
+            local pairs=() unset=""
+            while [ $# -gt 0 ]; do
+                case "$1" in
+                    --unset)
+                        [ $# -ge 2 ] || die "--unset needs a KEY after it"
+                        unset="${unset:+$unset,}\"$(json_escape "$2")\""
+                        shift 2 ;;
+                    *) pairs+=("$1"); shift ;;
+                esac
+            done
+            vars_json ${pairs[@]+"${pairs[@]}"}
+            api PUT "/api/shell/$bundle/profiles/$name" "{\"vars\":{$VARS_JSON},\"secret\":[$SECRET_JSON],\"unset\":[$unset]}"
+            ;;
+        unprofile)
+            [ $# -ge 2 ] || die "usage: ct shell unprofile <bundle> <name>"
+            api DELETE "/api/shell/$1/profiles/$2"
+            ;;
+        hooks)
+            [ $# -ge 1 ] || die "usage: ct shell hooks <bundle>"
+            api GET "/api/shell/$1/hooks"
+            ;;
+        hook)
+            [ $# -ge 2 ] || die "usage: ct shell hook <bundle> <target> [[--secret] KEY=VALUE ...]"
+            local bundle="$1" target="$2"; shift 2
+            vars_json "$@"
+            api POST "/api/shell/$bundle/hooks" "{\"target\":\"$(json_escape "$target")\",\"vars\":{$VARS_JSON},\"secret\":[$SECRET_JSON]}"
+            printf '\nThe "secret" above is the signing key; it is not shown again.\n' >&2
+            ;;
+        unhook)
+            [ $# -ge 2 ] || die "usage: ct shell unhook <bundle> <hook-id>"
+            api DELETE "/api/shell/$1/hooks/$2"
+            ;;
         *)
             die "unknown subcommand: ${sub:-<none>} (try: ct help)"
             ;;
     esac
 }
 
+cmd_audit() {
+    local query="" pair
+    for pair in "$@"; do
+        case "$pair" in
+            action=*|actor=*|subject=*|outcome=*|ip=*|from=*|to=*|limit=*|offset=*) ;;
+            *) die "unknown filter: $pair" ;;
+        esac
+        query="${query:+$query&}$pair"
+    done
+    api GET "/api/audit${query:+?$query}"
+}
+
 cmd_upgrade() {
     curl -fsSL "$API_BASE/install.sh" | bash
 }
@@ -186,8 +403,55 @@ ct — terminal client for sabbirhassan.com
   ct shell targets <bundle>                its steps, in run order
   ct shell describe <bundle> <target>      variables that target needs
   ct shell run <bundle> <target> [K=V ...] start it; prints a job id
+  ct shell run <bundle> <t1> <t2> ... [K=V ...]
+                                           several targets in order, as one
+                                           job with a status per step
+      --wait                               then follow its output to the end
+      --continue                           go on past a step that fails
+                                           rather than skipping the rest
+      --profile <name>                     fill in variables from a stored
+                                           profile; K=V given here still win
+      --queue                              if another run holds the bundle,
+                                           wait for it rather than refusing
+      --secret K=V                         a value kept out of the log and
+                                           out of any response
+      --dry-run                            print the plan, change nothing;
+                                           vars.env is left as it is
+  ct shell jobs <bundle> [filter=V ...]    past runs, newest first; filters:
+                                           status target user_id kind from
+                                           to limit offset
   ct shell job <bundle> <job-id>           status of a run
   ct shell logs <bundle> <job-id>          its output
+      -f                                   keep following until the job ends
+  ct shell artifacts <bundle> <job-id>     files it left in $ARTIFACT_DIR
+  ct shell artifacts <bundle> <job-id> <file>
+                                           download one, to ./<file>
+      -o <path>                            or to here
+  ct shell cancel <bundle> <job-id>        stop a run, and whatever it started
+  ct shell approve <bundle> <job-id>       let a run waiting for a second
+                                           administrator start (administrators)
+  ct shell reject <bundle> <job-id>        turn it down
+  ct shell schedules <bundle>              its timetabled runs (administrators)
+  ct shell schedule <bundle> <target> '<cron>' [K=V ...]
+                                           run it on a five-field UTC cron
+                                           expression, e.g. '30 2 * * *'
+  ct shell unschedule <bundle> <id>        remove one
+  ct shell profiles <bundle>               its variable profiles (administrators)
+  ct shell profile <bundle> <name> [K=V ...]
+                                           create one, or set variables in it;
+                                           --secret K=V is stored encrypted,
+                                           --unset K removes one
+  ct shell unprofile <bundle> <name>       delete one
+  ct shell hooks <bundle>                  its webhooks (administrators)
+  ct shell hook <bundle> <target> [K=V ...]
+                                           a webhook URL for the target; prints
+                                           its signing key, once
+  ct shell unhook <bundle> <id>            revoke one
+
+  ct audit [filter=V ...]                  privileged actions, newest first
+                                           (administrators); filters: action
+                                           (shell.* for a prefix) actor subject
+                                           outcome ip from to limit offset
 
   ct upgrade                               reinstall the latest client
   ct help                                  this
@@ -202,6 +466,7 @@ case "${1:-help}" in
     logout)  shift; cmd_logout "$@" ;;
     whoami)  shift; cmd_whoami "$@" ;;
     shell)   shift; cmd_shell "$@" ;;
+    audit)   shift; cmd_audit "$@" ;;
     upgrade) shift; cmd_upgrade "$@" ;;
     help|-h|--help) cmd_help ;;
     *) printf 'unknown command: %s\n\n' "$1" >&2; cmd_help >&2; exit 1 ;;
@@ -155,12 +155,20 @@ function ComingSoonTab({ label, title, body }) {
 
 /* ── Documentation tab ───────────────────────────────────────────────── */
 
-// POST /documentation (Fetcher prefixes /api itself) JSON-encodes the zip as
-// a plain byte array (see RequestBody.file: Vec<u8> in
-// src/handler/documentation/create.rs), which runs ~4-5x larger than the raw
-// file once stringified. The server accepts up to 64MB of JSON for this
-// route, so keep some margin under that.
-const MAX_ZIP_BYTES = 12 * 1024 * 1024;
+// POST /documentation (Fetcher prefixes /api itself) takes the zip as a
+// multipart file part, and stops reading past MAX_UPLOAD_BYTES in
+// src/handler/documentation/create.rs. Checked here too so an oversized zip
+// is refused before it is sent.
+const MAX_ZIP_BYTES = 64 * 1024 * 1024;
+
+// What src/utils/archive.rs unpacks, for doc sites and shell bundles alike.
+// The server goes by the file's first bytes; the name is only checked here.
+const ARCHIVE_ACCEPT = ".zip,.tar,.tar.gz,.tgz";
+
+function isArchive(name) {
+  const lower = name.toLowerCase();
+  return ARCHIVE_ACCEPT.split(",").some((ext) => lower.endsWith(ext));
+}
 
 function Switch({ on, onToggle, disabled, label }) {
   return (
@@ -213,10 +221,10 @@ function CreateDocumentation({ onCreated, onCancel }) {
     const next = {};
     if (!form.title.trim()) next.title = "Enter a title";
     if (!form.description.trim()) next.description = "Enter a description";
-    if (!file) next.file = "Choose a zip file";
-    else if (!file.name.toLowerCase().endsWith(".zip")) next.file = "Must be a .zip file";
+    if (!file) next.file = "Choose an archive";
+    else if (!isArchive(file.name)) next.file = "Must be a .zip, .tar or .tar.gz file";
     else if (file.size > MAX_ZIP_BYTES) {
-      next.file = "Zip is too large (max " + Math.floor(MAX_ZIP_BYTES / (1024 * 1024)) + "MB)";
+      next.file = "Archive is too large (max " + Math.floor(MAX_ZIP_BYTES / (1024 * 1024)) + "MB)";
     }
     setErrors(next);
     setFormError(null);
@@ -224,29 +232,20 @@ function CreateDocumentation({ onCreated, onCancel }) {
 
     setStatus("pending");
 
-    let bytes;
-    try {
-      const buffer = await file.arrayBuffer();
-      bytes = Array.from(new Uint8Array(buffer));
-    } catch (err) {
-      setStatus("idle");
-      setFormError("Could not read that file. Try picking it again.");
-      return;
-    }
-
     const tags = form.tags
       .split(",")
       .map((t) => t.trim())
       .filter(Boolean);
 
-    const result = await Fetcher.post({
+    const formData = new FormData();
+    formData.append("name", form.title.trim());
+    formData.append("description", form.description.trim());
+    formData.append("tags", tags.join(","));
+    formData.append("file", file, file.name);
+
+    const result = await Fetcher.upload({
       endpoint: "/documentation",
-      body: {
-        name: form.title.trim(),
-        description: form.description.trim(),
-        tags,
-        file: bytes,
-      },
+      formData,
       showError: false,
     });
 
@@ -339,12 +338,12 @@ function CreateDocumentation({ onCreated, onCancel }) {
 
         <div>
           <label htmlFor="doc-zip" className="meta mb-2 block text-muted-2">
-            Zip file
+            Archive
           </label>
           <input
             id="doc-zip"
             type="file"
-            accept=".zip"
+            accept={ARCHIVE_ACCEPT}
             onChange={onFile}
             disabled={pending}
             aria-invalid={!!errors.file}
@@ -1349,9 +1348,9 @@ function YoutubeTab() {
 
 /* ── Shell tab ───────────────────────────────────────────────────────── */
 
-// Bundles are a handful of scripts; the JSON-encoded byte array runs several
-// times the raw size, and routes/shell.rs caps the body at 32MB.
-const MAX_BUNDLE_BYTES = 6 * 1024 * 1024;
+// Bundles are a handful of scripts; MAX_UPLOAD_BYTES in
+// src/handler/shell/create.rs stops reading the upload past 16MB.
+const MAX_BUNDLE_BYTES = 16 * 1024 * 1024;
 
 // Mirrors is_valid_bundle in src/handler/shell.rs. The name is the directory
 // on disk *and* the URL segment (/api/shell/{name}/run/...), which is why it
@@ -1360,7 +1359,7 @@ const BUNDLE_NAME = /^[a-z0-9_-]+$/;
 
 function CreateShellBundle({ onCreated, onCancel }) {
   const { useState } = React;
-  const [form, setForm] = useState({ name: "", description: "" });
+  const [form, setForm] = useState({ name: "", description: "", signature: "" });
   const [file, setFile] = useState(null);
   const [errors, setErrors] = useState({});
   const [formError, setFormError] = useState(null);
@@ -1391,10 +1390,10 @@ function CreateShellBundle({ onCreated, onCancel }) {
       next.name = "Lowercase letters, digits, hyphens and underscores only";
     }
     if (!form.description.trim()) next.description = "Enter a description";
-    if (!file) next.file = "Choose a zip file";
-    else if (!file.name.toLowerCase().endsWith(".zip")) next.file = "Must be a .zip file";
+    if (!file) next.file = "Choose an archive";
+    else if (!isArchive(file.name)) next.file = "Must be a .zip, .tar or .tar.gz file";
     else if (file.size > MAX_BUNDLE_BYTES) {
-      next.file = "Zip is too large (max " + Math.floor(MAX_BUNDLE_BYTES / (1024 * 1024)) + "MB)";
+      next.file = "Archive is too large (max " + Math.floor(MAX_BUNDLE_BYTES / (1024 * 1024)) + "MB)";
     }
     setErrors(next);
     setFormError(null);
@@ -1402,19 +1401,15 @@ function CreateShellBundle({ onCreated, onCancel }) {
 
     setStatus("pending");
 
-    let bytes;
-    try {
-      const buffer = await file.arrayBuffer();
-      bytes = Array.from(new Uint8Array(buffer));
-    } catch (err) {
-      setStatus("idle");
-      setFormError("Could not read that file. Try picking it again.");
-      return;
-    }
+    const formData = new FormData();
+    formData.append("name", name);
+    formData.append("description", form.description.trim());
+    if (form.signature.trim()) formData.append("signature", form.signature.trim());
+    formData.append("file", file, file.name);
 
-    const result = await Fetcher.post({
+    const result = await Fetcher.upload({
       endpoint: "/shell",
-      body: { name, description: form.description.trim(), file: bytes },
+      formData,
       showError: false,
     });
 
@@ -1493,12 +1488,12 @@ function CreateShellBundle({ onCreated, onCancel }) {
 
         <div>
           <label htmlFor="bundle-zip" className="meta mb-2 block text-muted-2">
-            Zip
+            Archive
           </label>
           <input
             id="bundle-zip"
             type="file"
-            accept=".zip,application/zip"
+            accept={ARCHIVE_ACCEPT}
             onChange={onFile}
             disabled={pending}
             className="meta block text-muted-2 file:mr-4 file:rounded-sm file:border-0 file:bg-ink file:px-4 file:py-2.5 file:text-[13px] file:font-semibold file:text-white file:transition-colors file:duration-300 hover:file:bg-vermilion"
@@ -1506,6 +1501,21 @@ function CreateShellBundle({ onCreated, onCancel }) {
           {errors.file && <p className="meta mt-2 text-vermilion">{errors.file}</p>}
         </div>
 
+        <div>
+          <label htmlFor="bundle-signature" className="meta mb-2 block text-muted-2">
+            Signature
+          </label>
+          <input
+            id="bundle-signature"
+            type="text"
+            value={form.signature}
+            onChange={set("signature")}
+            disabled={pending}
+            placeholder="Optional — Ed25519 over the archive, base64 or hex"
+            className={FIELD}
+          />
+        </div>
+
         {formError && (
           <p
             role="alert"
@@ -1 +1,232 @@
-/* DEFINE ALL OF YOUR CRON ROUTINES HERE */
+/*
+ * Cron expressions, for anything that runs on a timetable — shell bundle
+ * schedules (handler/shell/schedules.rs) are the first. The loop that drives
+ * them is in main.rs.
+ *
+ * The usual five fields, in UTC:
+ *
+ *   minute  hour  day-of-month  month  day-of-week
+ *   0-59    0-23  1-31          1-12   0-7 (0 and 7 are Sunday)
+ *
+ * each `*`, a value, a range `a-b`, either of the last two followed by `/n`
+ * for every nth, or a comma list of those. Months and weekdays also take
+ * their three-letter English names.
+ * @hourly, @daily (@midnight), @weekly, @monthly and @yearly (@annually)
+ * stand for the obvious expressions.
+ *
+ * As in Vixie cron, when day-of-month and day-of-week are both restricted a
+ * day matching either one counts.
+ */
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+
+/// How far ahead `next_after` looks before deciding an expression never
+/// fires — "0 0 30 2 *" is valid syntax and matches nothing.
+const HORIZON_DAYS: i64 = 366 * 5;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    pub fn parse(expr: &str) -> Result<Schedule, String> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "a cron expression has five fields (minute hour day month weekday), got {}",
+                fields.len()
+            ));
+        }
+
+        let mut weekdays = field(fields[4], 0, 7, &WEEKDAYS, 0).map_err(|e| format!("weekday: {}", e))?;
+        // 7 is Sunday too.
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Schedule {
+            minutes: field(fields[0], 0, 59, &[], 0).map_err(|e| format!("minute: {}", e))?,
+            hours: field(fields[1], 0, 23, &[], 0).map_err(|e| format!("hour: {}", e))?,
+            days: field(fields[2], 1, 31, &[], 0).map_err(|e| format!("day: {}", e))?,
+            months: field(fields[3], 1, 12, &MONTHS, 1).map_err(|e| format!("month: {}", e))?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    /// The first whole minute strictly after `after` that the expression
+    /// matches, or None if there isn't one within HORIZON_DAYS.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = after + Duration::days(HORIZON_DAYS);
+
+        while t <= limit {
+            if !has(self.months, t.month()) {
+                let (year, month) = if t.month() == 12 { (t.year() + 1, 1) } else { (t.year(), t.month() + 1) };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?.and_utc();
+                continue;
+            }
+            if !self.day_matches(&t) {
+                t = (t.date_naive() + Duration::days(1)).and_hms_opt(0, 0, 0)?.and_utc();
+                continue;
+            }
+            if !has(self.hours, t.hour()) {
+                t = t.with_minute(0)? + Duration::hours(1);
+                continue;
+            }
+            if !has(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+                continue;
+            }
+            return Some(t);
+        }
+        None
+    }
+
+    fn day_matches(&self, t: &DateTime<Utc>) -> bool {
+        let day = has(self.days, t.day());
+        let weekday = has(self.weekdays, t.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+fn has(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+/// One field as a bitmask of the values it allows. `names[i]` stands for
+/// `offset + i`.
+fn field(spec: &str, min: u32, max: u32, names: &[&str], offset: u32) -> Result<u64, String> {
+    let mut mask = 0u64;
+
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| format!("bad step in {}", part))?;
+                if step == 0 {
+                    return Err(format!("step of zero in {}", part));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (value(a, names, offset)?, value(b, names, offset)?)
+        } else {
+            let v = value(range, names, offset)?;
+            // "5/15" means from 5 to the end, every 15.
+            (v, if part.contains('/') { max } else { v })
+        };
+
+        if from < min || to > max || from > to {
+            return Err(format!("{} is outside {}-{}", part, min, max));
+        }
+
+        let mut v = from;
+        while v <= to {
+            mask |= 1 << v;
+            v += step;
+        }
+    }
+    Ok(mask)
+}
+
+fn value(text: &str, names: &[&str], offset: u32) -> Result<u32, String> {
+    if let Ok(v) = text.parse::<u32>() {
+        return Ok(v);
+    }
+    let lower = text.to_ascii_lowercase();
+    names
+        .iter()
+        .position(|n| *n == lower)
+        .map(|i| i as u32 + offset)
+        .ok_or_else(|| format!("{} is not a number", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn nightly_fires_once_a_day_at_its_minute() {
+        let s = Schedule::parse("30 2 * * *").unwrap();
+        assert_eq!(s.next_after(at(2025, 1, 1, 0, 0)), Some(at(2025, 1, 1, 2, 30)));
+        assert_eq!(s.next_after(at(2025, 1, 1, 2, 30)), Some(at(2025, 1, 2, 2, 30)));
+        // Year end.
+        assert_eq!(s.next_after(at(2025, 12, 31, 3, 0)), Some(at(2026, 1, 1, 2, 30)));
+    }
+
+    #[test]
+    fn steps_ranges_lists_and_names() {
+        let s = Schedule::parse("*/15 9-17 * * mon-fri").unwrap();
+        // 2025-01-04 is a Saturday.
+        assert_eq!(s.next_after(at(2025, 1, 4, 10, 0)), Some(at(2025, 1, 6, 9, 0)));
+        assert_eq!(s.next_after(at(2025, 1, 6, 9, 0)), Some(at(2025, 1, 6, 9, 15)));
+
+        let s = Schedule::parse("0 0 1,15 jan,jul *").unwrap();
+        assert_eq!(s.next_after(at(2025, 1, 2, 0, 0)), Some(at(2025, 1, 15, 0, 0)));
+        assert_eq!(s.next_after(at(2025, 1, 15, 0, 0)), Some(at(2025, 7, 1, 0, 0)));
+    }
+
+    #[test]
+    fn day_and_weekday_together_match_either() {
+        // The 13th, or any Friday.
+        let s = Schedule::parse("0 0 13 * 5").unwrap();
+        // 2025-01-03 is a Friday.
+        assert_eq!(s.next_after(at(2025, 1, 1, 0, 0)), Some(at(2025, 1, 3, 0, 0)));
+        assert_eq!(s.next_after(at(2025, 1, 11, 0, 0)), Some(at(2025, 1, 13, 0, 0)));
+    }
+
+    #[test]
+    fn sunday_is_zero_or_seven() {
+        assert_eq!(Schedule::parse("0 0 * * 7"), Schedule::parse("0 0 * * 0"));
+        assert_eq!(Schedule::parse("@weekly"), Schedule::parse("0 0 * * sun"));
+    }
+
+    #[test]
+    fn nonsense_is_refused() {
+        for bad in ["", "* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "*/0 * * * *", "5-1 * * * *", "x * * * *"] {
+            assert!(Schedule::parse(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn an_impossible_date_never_fires() {
+        let s = Schedule::parse("0 0 30 2 *").unwrap();
+        assert_eq!(s.next_after(at(2025, 1, 1, 0, 0)), None);
+    }
+}
@@ -0,0 +1,7 @@
+/*
+ * The audit trail, for administrators (middleware/audit.rs writes it).
+ *
+ *   GET /api/audit   newest first, filtered — `ct audit`
+ */
+pub mod list;
+pub use list as List;
@@ -0,0 +1,145 @@
+/*
+ * GET /api/audit — audit events, newest first. Filters, all optional:
+ *
+ *   action                       exact, or a prefix when it ends in `*`
+ *                                (`shell.*`)
+ *   actor, subject, outcome, ip  exact match
+ *   from, to                     `at` bounds, epoch millis, inclusive
+ *   limit, offset                50 by default, at most 500 per page
+ *
+ * Administrator-only, with either credential, like the cross-bundle job list.
+ */
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Auth::{require_access, require_cli, AccessRequirement};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditEvent;
+use crate::utils::response::Response;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Params {
+    pub action: Option<String>,
+    pub actor: Option<String>,
+    pub subject: Option<String>,
+    pub outcome: Option<String>,
+    pub ip: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+}
+
+pub async fn task(req: HttpRequest, query: web::Query<Params>) -> Result<HttpResponse, Error> {
+    if require_access(&req, AccessRequirement::Role(AccountRole::Administrator)).is_err() {
+        require_cli(&req, AccessRequirement::Role(AccountRole::Administrator)).await?;
+    }
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let offset = query.offset.unwrap_or(0);
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<AuditEvent>("audit_event");
+
+    let cursor = collection
+        .find(filter(&query))
+        .sort(doc! { "at": -1 })
+        .skip(offset)
+        .limit(limit)
+        .await;
+
+    let cursor = match cursor {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let events: Vec<AuditEvent> = match cursor.try_collect().await {
+        Ok(v) => v,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(events))
+}
+
+fn filter(query: &Params) -> Document {
+    let mut filter = doc! {};
+
+    if let Some(action) = non_empty(&query.action) {
+        match action.strip_suffix('*') {
+            // Escaped, so a prefix is only ever a prefix.
+            Some(prefix) => filter.insert(
+                "action",
+                doc! { "$regex": format!("^{}", regex::escape(prefix)) },
+            ),
+            None => filter.insert("action", action),
+        };
+    }
+    for (key, value) in [
+        ("actor", &query.actor),
+        ("subject", &query.subject),
+        ("outcome", &query.outcome),
+        ("ip", &query.ip),
+    ] {
+        if let Some(value) = non_empty(value) {
+            filter.insert(key, value);
+        }
+    }
+
+    let mut at = doc! {};
+    if let Some(from) = query.from {
+        at.insert("$gte", from);
+    }
+    if let Some(to) = query.to {
+        at.insert("$lte", to);
+    }
+    if !at.is_empty() {
+        filter.insert("at", at);
+    }
+
+    filter
+}
+
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_trailing_star_is_an_escaped_prefix() {
+        let query = Params { action: Some("shell.*".to_string()), ..Params::default() };
+        assert_eq!(filter(&query), doc! { "action": { "$regex": "^shell\\." } });
+
+        let query = Params { action: Some("shell.run".to_string()), ..Params::default() };
+        assert_eq!(filter(&query), doc! { "action": "shell.run" });
+    }
+
+    #[test]
+    fn filters_only_what_was_asked_for() {
+        assert_eq!(filter(&Params::default()), doc! {});
+
+        let query = Params {
+            outcome: Some("denied".to_string()),
+            actor: Some(" ".to_string()),
+            from: Some(10),
+            ..Params::default()
+        };
+        assert_eq!(filter(&query), doc! { "outcome": "denied", "at": { "$gte": 10_i64 } });
+    }
+}
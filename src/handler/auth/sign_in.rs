@@ -6,10 +6,13 @@ use actix_session::Session;
 use crate::{builtins::jwt, Model::Account};
 use crate::Integrations::Smtp;
 use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::User;
+use crate::Model::Audit::AuditOutcome;
 use crate::utils::response::Response;
 use serde::{ Serialize, Deserialize };
 use mongodb::{ClientSession, Database};
-use actix_web::{ web, Error, HttpResponse};
+use actix_web::{ web, Error, HttpRequest, HttpResponse};
 use super::{auth_access_cookie, auth_refresh_cookie};
 
 //in minutes
@@ -35,7 +38,7 @@ struct AuthPayload {
     role: Account::AccountRole,
 }
 
-pub async fn task(form_data: web::Json<PostData>, actix_session: Session) -> Result<HttpResponse, Error> {
+pub async fn task(req: HttpRequest, form_data: web::Json<PostData>, actix_session: Session) -> Result<HttpResponse, Error> {
     let email_or_username = form_data.email_or_username.trim().to_string().to_lowercase();
     if email_or_username.len() == 0 {
         return Ok(Response::bad_request("Email/Username is required"));
@@ -61,6 +64,8 @@ pub async fn task(form_data: web::Json<PostData>, actix_session: Session) -> Res
     ).await {
         Ok(res) => res,
         Err(error) => {
+            let detail = Some(error.status().to_string());
+            Audit::record(&req, None, "auth.sign_in", &email_or_username, AuditOutcome::Failed, detail).await;
             return Ok(error);
         }
     };
@@ -138,6 +143,13 @@ pub async fn task(form_data: web::Json<PostData>, actix_session: Session) -> Res
     let access_cookie = auth_access_cookie(access_token.clone());
     let refresh_cookie = auth_refresh_cookie(refresh_token.clone());
 
+    let user = User {
+        user_id: account_core.uuid.clone(),
+        role: account_core.role.clone(),
+        token_label: None,
+    };
+    Audit::record(&req, Some(&user), "auth.sign_in", &email_or_username, AuditOutcome::Success, None).await;
+
     let data = AuthPayload {
         access_token,
         refresh_token,
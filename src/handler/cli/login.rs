@@ -16,11 +16,13 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
 
 use crate::BuiltIns::mongo::MongoDB;
-use crate::Middleware::Auth::hash_token;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{hash_token, User};
 use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
 use crate::Model::CliToken::CliToken;
 use crate::utils::response::Response;
 
@@ -47,7 +49,7 @@ struct Payload {
     expires_at: i64,
 }
 
-pub async fn task(form_data: web::Json<PostData>) -> Result<HttpResponse, Error> {
+pub async fn task(req: HttpRequest, form_data: web::Json<PostData>) -> Result<HttpResponse, Error> {
     let email_or_username = form_data.email_or_username.trim().to_lowercase();
     if email_or_username.is_empty() {
         return Ok(Response::bad_request("Email/Username is required"));
@@ -88,7 +90,11 @@ pub async fn task(form_data: web::Json<PostData>) -> Result<HttpResponse, Error>
     .await
     {
         Ok(account) => account,
-        Err(error) => return Ok(error),
+        Err(error) => {
+            let detail = Some(error.status().to_string());
+            Audit::record(&req, None, "cli.login", &email_or_username, AuditOutcome::Failed, detail).await;
+            return Ok(error);
+        }
     };
 
     if let Err(error) = session.commit_transaction().await {
@@ -109,7 +115,7 @@ pub async fn task(form_data: web::Json<PostData>) -> Result<HttpResponse, Error>
         user_id: account.uuid.clone(),
         role: account.role.clone(),
         token_hash: hash_token(&token),
-        label,
+        label: label.clone(),
         created_at: now,
         expires_at,
         last_used_at: None,
@@ -122,6 +128,13 @@ pub async fn task(form_data: web::Json<PostData>) -> Result<HttpResponse, Error>
         return Ok(Response::internal_server_error(&error.to_string()));
     }
 
+    let user = User {
+        user_id: account.uuid.clone(),
+        role: account.role.clone(),
+        token_label: Some(label),
+    };
+    Audit::record(&req, Some(&user), "cli.login", &email_or_username, AuditOutcome::Success, None).await;
+
     Ok(HttpResponse::Ok().content_type("application/json").json(Payload {
         token,
         user_id: account.uuid,
@@ -5,7 +5,9 @@ use actix_web::{Error, HttpRequest, HttpResponse};
 use serde_json::json;
 
 use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
 use crate::Middleware::Auth::{hash_token, require_cli, AccessRequirement};
+use crate::Model::Audit::AuditOutcome;
 use crate::Model::CliToken::CliToken;
 use crate::utils::response::Response;
 
@@ -17,7 +19,8 @@ use crate::utils::response::Response;
 pub async fn task(req: HttpRequest) -> Result<HttpResponse, Error> {
     // Going through the gate first means an already-invalid token gets the
     // same clear message it would anywhere else.
-    require_cli(&req, AccessRequirement::AnyToken).await?;
+    let gate = require_cli(&req, AccessRequirement::AnyToken).await;
+    let user = Audit::checked(&req, "cli.logout", "", gate).await?;
 
     let token = req
         .headers()
@@ -42,6 +45,8 @@ pub async fn task(req: HttpRequest) -> Result<HttpResponse, Error> {
         return Ok(Response::internal_server_error(&error.to_string()));
     }
 
+    Audit::record(&req, Some(&user), "cli.logout", "", AuditOutcome::Success, None).await;
+
     Ok(HttpResponse::Ok()
         .content_type("application/json")
         .json(json!({ "revoked": true })))
@@ -1,3 +1,11 @@
+/*
+ * Upload a documentation site: a mkdocs build as a zip, .tar or .tar.gz.
+ *
+ * multipart/form-data — `name`, `description`, `tags` (comma-separated) and a
+ * `file` part — with the archive streamed to a temporary file rather than
+ * held in memory (utils/upload.rs). `task_json` still takes the old JSON
+ * body, the archive as an array of bytes, and marks its answer deprecated.
+ */
 use std::fs;
 use uuid::Uuid;
 use chrono::Utc;
@@ -5,13 +13,19 @@ use mongodb::bson::doc;
 use crate::{Model, DOCS_ROOT};
 use std::path::Path;
 use crate::BuiltIns::mongo::MongoDB;
-use crate::utils::{mkdocs, response::Response};
+use crate::utils::{archive, mkdocs, response::Response, upload};
 use serde::{ Serialize, Deserialize };
 use crate::Model::Account::AccountRole;
-use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement, User};
+use crate::Model::Audit::AuditOutcome;
+use actix_multipart::Multipart;
 use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
 
 
+/// The archive as sent; mkdocs::unpack holds what is inside it to its own limits.
+const MAX_UPLOAD_BYTES: u64 = 64 * 1024 * 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestBody {
     name: String,
@@ -27,27 +41,81 @@ struct ResponseBody {
 }
 
 
-pub async fn task(
-    req: HttpRequest,
-    form_data: web::Json<RequestBody>,
-) -> Result<HttpResponse, Error> {
+pub async fn task(req: HttpRequest, payload: Multipart) -> Result<HttpResponse, Error> {
     // Same middleware every authenticated API route uses (src/middleware/auth.rs),
     // reading the access_token cookie the dashboard sets at sign-in. Without this,
     // anyone could create documentation entries — there is no other access control.
-    require_access(&req, AccessRequirement::Role(AccountRole::Administrator))?;
+    // Checked before the body is read.
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "documentation.create", "", gate).await?;
+
+    let upload = match upload::receive(payload, "file", MAX_UPLOAD_BYTES).await {
+        Ok(upload) => upload,
+        Err(res) => return Ok(res),
+    };
+    let file = match &upload.file {
+        Some(file) if file.size > 0 => file,
+        _ => return Ok(Response::bad_request("An archive (.zip, .tar or .tar.gz) is required")),
+    };
+
+    let tags = upload
+        .field("tags")
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    create(
+        &req,
+        &user,
+        upload.field("name"),
+        upload.field("description"),
+        tags,
+        archive::Source::File(&file.path),
+    )
+    .await
+}
 
-    let doc_name = form_data.name.trim().to_string();
-    let description = form_data.description.trim().to_string();
+/// The JSON body, deprecated in favour of `task`.
+pub async fn task_json(
+    req: HttpRequest,
+    form_data: web::Json<RequestBody>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "documentation.create", "", gate).await?;
+    log::warn!("documentation.create: deprecated JSON upload from {}", user.user_id);
 
+    if form_data.file.is_empty() {
+        return Ok(upload::deprecated(Response::bad_request("An archive (.zip, .tar or .tar.gz) is required")));
+    }
+
+    let res = create(
+        &req,
+        &user,
+        form_data.name.trim().to_string(),
+        form_data.description.trim().to_string(),
+        form_data.tags.clone(),
+        archive::Source::Bytes(&form_data.file),
+    )
+    .await?;
+    Ok(upload::deprecated(res))
+}
+
+async fn create(
+    req: &HttpRequest,
+    user: &User,
+    doc_name: String,
+    description: String,
+    tags: Vec<String>,
+    source: archive::Source<'_>,
+) -> Result<HttpResponse, Error> {
     if doc_name.is_empty() {
         return Ok(Response::bad_request("Title is required"));
     }
     if description.is_empty() {
         return Ok(Response::bad_request("Description is required"));
     }
-    if form_data.file.is_empty() {
-        return Ok(Response::bad_request("Zip file is required"));
-    }
 
     /* DATABASE ACID SESSION INIT */
     let (db, mut session) = MongoDB.connect_acid().await;
@@ -83,7 +151,7 @@ pub async fn task(
         uuid: doc_id.clone(),
         name: doc_name.clone(),
         description: description.clone(),
-        tags: form_data.tags.clone(),
+        tags,
         featured: false,
         view_count: 0,
         created_at: now,
@@ -106,7 +174,7 @@ pub async fn task(
     // giving the root an index.html when the build has none.
     let target_dir = Path::new(DOCS_ROOT).join(&doc_id);
 
-    if let Err(error) = mkdocs::unpack(&form_data.file, &target_dir, &doc_id) {
+    if let Err(error) = mkdocs::unpack(source, &target_dir, &doc_id) {
         log::error!("{}", error);
         // Nothing should be left half-unpacked under a uuid the database is
         // about to forget about.
@@ -122,6 +190,8 @@ pub async fn task(
         return Ok(Response::internal_server_error(&error.to_string()));
     }
 
+    Audit::record(req, Some(user), "documentation.create", &doc_id, AuditOutcome::Success, Some(doc_name.clone())).await;
+
     let res = ResponseBody {
         uuid: doc_id.clone(),
         name: doc_name.clone(),
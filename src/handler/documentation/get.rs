@@ -142,7 +142,7 @@ fn content_type(path: &Path) -> &'static str {
 mod tests {
     use super::*;
     use crate::routes;
-    use crate::utils::mkdocs;
+    use crate::utils::{archive, mkdocs};
     use actix_web::{test, App};
 
     const UUID: &str = "0198f2b1-serve-7000-8000-000000000000";
@@ -158,7 +158,7 @@ mod tests {
         let dir = Path::new(DOCS_ROOT).join(UUID);
         let _ = fs::remove_dir_all(&dir);
         let bytes = fs::read(zip).unwrap();
-        mkdocs::unpack(&bytes, &dir, UUID).unwrap();
+        mkdocs::unpack(archive::Source::Bytes(&bytes), &dir, UUID).unwrap();
         Some(dir)
     }
 
@@ -6,7 +6,9 @@ use crate::Model::Project::Project;
 use crate::Model::Account::AccountRole;
 use crate::BuiltIns::mongo::MongoDB;
 use crate::builtins::sqlite;
+use crate::Middleware::Audit;
 use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Model::Audit::AuditOutcome;
 use crate::utils::response::Response;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 
@@ -66,7 +68,8 @@ pub async fn task(
     req: HttpRequest,
     form_data: web::Json<RequestBody>,
 ) -> Result<HttpResponse, Error> {
-    require_access(&req, AccessRequirement::Role(AccountRole::Administrator))?;
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "project.create", "", gate).await?;
 
     let title = form_data.title.trim().to_string();
     let subtitle = form_data.subtitle.trim().to_string();
@@ -151,6 +154,8 @@ pub async fn task(
         return Ok(Response::internal_server_error(&error.to_string()));
     }
 
+    Audit::record(&req, Some(&user), "project.create", &uuid, AuditOutcome::Success, Some(title.clone())).await;
+
     Ok(HttpResponse::Ok().content_type("application/json").json(ResponseBody { uuid, title }))
 }
 
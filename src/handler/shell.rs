@@ -26,39 +26,120 @@
  * in as an administrator or not. See src/middleware/auth.rs for what that does
  * and does not prove.
  *
- * Uploading and listing bundles (shell/create.rs, shell/list.rs) are ordinary
- * dashboard operations and stay on require_access.
+ * Uploading, versioning, listing and deleting bundles (shell/create.rs,
+ * shell/update.rs, shell/version.rs, shell/list.rs, shell/delete.rs,
+ * shell/restore.rs) are ordinary dashboard operations and stay on
+ * require_access.
  *
  *   GET  /api/shell/{name}/targets            the step list, in run order
  *   GET  /api/shell/{name}/describe/{target}  variables a target needs,
- *                                             without running anything
- *   POST /api/shell/{name}/run/{target}       { vars: { KEY: "value" } }
- *                                             -> 202 { id, ... }
+ *                                             without running anything, and
+ *                                             which each profile covers
+ *   POST /api/shell/{name}/run/{target}       { vars: { KEY: "value" },
+ *                                               profile, queue: false }
+ *                                             -> 202 { uuid, ... }, or 409
+ *                                             while another job holds the
+ *                                             bundle's lock (shell/lock.rs);
+ *                                             ?dry_run=1 for the plan only.
+ *                                             A step marked for approval
+ *                                             waits `pending_approval` for a
+ *                                             second administrator
+ *                                             (shell/approval.rs)
+ *   /api/shell/{name}/profiles                named variable sets a run can
+ *                                             take as `profile: "prod"`
+ *                                             (shell/profiles.rs)
+ *   POST /api/shell/{name}/pipeline           { targets: [...],
+ *                                               continue_on_failure: false,
+ *                                               vars, queue } — several
+ *                                             targets in order as one job,
+ *                                             with a status per step
+ *                                             (shell/pipeline.rs)
+ *   GET  /api/shell/{name}/jobs               run history (shell/jobs.rs)
  *   GET  /api/shell/{name}/jobs/{id}          status
- *   GET  /api/shell/{name}/jobs/{id}/logs     combined output, text/plain
+ *   GET  /api/shell/{name}/jobs/{id}/logs     combined output, text/plain;
+ *                                             ?follow=1 streams it as
+ *                                             server-sent events until the
+ *                                             job ends (shell/follow.rs)
+ *   GET  /api/shell/{name}/jobs/{id}/artifacts[/{file}]
+ *                                             what it left in $ARTIFACT_DIR
+ *                                             (shell/artifacts.rs)
+ *   POST /api/shell/{name}/jobs/{id}/cancel   stop it (shell/cancel.rs)
+ *   POST /api/shell/{name}/jobs/{id}/approve  let a pending run start, or
+ *   POST /api/shell/{name}/jobs/{id}/reject   not
+ *   /api/shell/{name}/schedules               runs on a timetable
+ *                                             (shell/schedules.rs)
+ *   /api/shell/{name}/hooks                   runs from a signed webhook
+ *                                             (shell/hooks.rs)
  *
  * main.sh reports anything it still needs as a "MISSING_VARS:a,b,c" line and
  * exits before touching the system, which is what surfaces as the
  * failed_missing_vars status below.
+ *
+ * A dry run (`?dry_run=1`, job kind `dry_run`) starts main.sh with DRY_RUN=1
+ * in its environment. The contract for a bundle is that under DRY_RUN=1 it
+ * prints what each step would do — packages it would install, files it would
+ * write, services it would restart — and changes nothing: no package manager,
+ * no writes outside /tmp, no service or user changes. The run's variables are
+ * passed as environment variables and vars.env is neither written nor
+ * required, so a bundle should prefer a variable already in its environment
+ * to the one in the file. Its plan is the job log.
+ *
+ * Nothing on this side can tell whether main.sh honours DRY_RUN=1, so a dry
+ * run is admitted like any other: it takes the bundle's lock, runs as the
+ * bundle's account, and waits for a second administrator if it reaches a
+ * step marked for approval.
+ *
+ * Ordinary accounts reach a bundle through `public_run` or a grant naming
+ * them, or a group they are in, and the targets they may run
+ * (shell/grants.rs).
+ *
+ * A job runs as its bundle's `run_as` account — an unprivileged default
+ * unless an administrator has opted the bundle into root (shell/run_as.rs).
+ *
+ * A job can leave files for its caller in $ARTIFACT_DIR, which are kept and
+ * served once it ends (shell/artifacts.rs).
+ *
+ * When a job ends, whoever its bundle names is emailed or sent a webhook
+ * (shell/notify.rs).
+ *
+ * No job starts from files that differ from what was uploaded: each is
+ * hashed against the record first (shell/integrity.rs). An upload can also be
+ * signed, and a server can insist that it is (shell/signing.rs).
+ *
+ * Jobs are recorded in the `shell_job` collection (Model::Shell::ShellJob),
+ * not in memory, so a deploy or a crash doesn't turn every past run into a
+ * 404 while its log still sits in LOG_DIR. A job the previous process was
+ * still watching when it died can't be finished by this one; see
+ * `reconcile_interrupted`, which main.rs runs at startup. Its main.sh may
+ * well have outlived it, in a process group of its own, so that group is
+ * stopped first.
  */
 use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::sync::{Mutex, OnceLock};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
-use mongodb::bson::doc;
-use serde::{Deserialize, Serialize};
+use futures::future::join_all;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, to_bson};
+use serde::Deserialize;
 use uuid::Uuid;
 
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 
 use crate::BuiltIns::mongo::MongoDB;
-use crate::Middleware::Auth::{require_cli, AccessRequirement};
-use crate::Model::Shell::ShellBundle;
-use crate::Model::Account::AccountRole;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, require_cli, AccessRequirement, User};
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellBundle, ShellJob, ShellJobStep, ShellLimits};
+use crate::Model::Account::{AccountCore, AccountRole};
 use crate::utils::response::Response;
 
 pub mod create;
@@ -70,46 +151,177 @@ pub use list as List;
 pub mod toggle_public;
 pub use toggle_public as TogglePublic;
 
+pub mod follow;
+
+pub mod cancel;
+pub use cancel as Cancel;
+
+pub mod jobs;
+pub use jobs as Jobs;
+
+pub mod lock;
+use lock::Admission;
+
+pub mod limits;
+pub use limits as Limits;
+
+pub mod update;
+pub use update as Update;
+
+pub mod version;
+pub use version as Version;
+
+pub mod delete;
+pub use delete as Delete;
+
+pub mod restore;
+pub use restore as Restore;
+
+pub mod trash;
+pub use trash as Trash;
+
+pub mod manifest;
+
+pub mod redact;
+use redact::Redactor;
+
+pub mod schedules;
+pub use schedules as Schedules;
+
+pub mod hooks;
+pub use hooks as Hooks;
+
+pub mod run_as;
+pub use run_as as RunAs;
+
+pub mod grants;
+pub use grants as Grants;
+
+pub mod approval;
+pub use approval as Approval;
+
+pub mod notify;
+pub use notify as Notify;
+
+pub mod retention;
+pub use retention as Retention;
+
+pub mod integrity;
+
+pub mod signing;
+pub use signing as Signing;
+
+pub mod pipeline;
+pub use pipeline as Pipeline;
+
+pub mod artifacts;
+pub use artifacts as Artifacts;
+
+pub mod profiles;
+pub use profiles as Profiles;
+
 /// Where uploaded bundles are unpacked, relative to the process working
 /// directory. Created on demand — nothing is checked in here, so on a fresh
 /// deploy it stays empty until the first upload.
 const SHELL_ROOT: &str = "./shell";
 /// Job logs, outside the bundle tree so a run doesn't dirty the working copy.
 const LOG_DIR: &str = "./logs/shell";
+/// Between the SIGTERM that stops a job — a cancel, a limit — and the
+/// SIGKILL for whatever ignored it. Long enough for a well-behaved step to
+/// clean up after itself, short enough that a wrong target doesn't get to do
+/// much more.
+const KILL_GRACE: Duration = Duration::from_secs(10);
+/// How long the log copiers (shell/redact.rs) get to drain the pipes once
+/// bash has exited and its group has been stopped. Only a process that left
+/// the group — setsid — can hold them open past that.
+const COPY_GRACE: Duration = Duration::from_secs(5);
+/// How long `main.sh --list` or `--describe` gets. They only print, so one
+/// that hasn't answered by then is stuck, not busy.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RunBody {
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    /// Wait for the bundle's lock instead of answering 409 when another job
+    /// holds it.
+    #[serde(default)]
+    pub queue: bool,
+    /// Names of variables to treat as secret on this run, on top of any the
+    /// bundle's manifest marks: written 0600, and redacted from the log.
+    #[serde(default)]
+    pub secret: Vec<String>,
+    /// A stored profile whose variables fill in what `vars` leaves out
+    /// (shell/profiles.rs).
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+/// What a job runs: one target, or several in order (shell/pipeline.rs).
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub targets: Vec<String>,
+    /// Recorded with a step per target, even if there is only one.
+    pub pipeline: bool,
+    pub continue_on_failure: bool,
+    /// Under DRY_RUN=1, recorded as kind `dry_run`.
+    pub dry_run: bool,
+}
+
+impl Plan {
+    pub fn single(target: &str) -> Self {
+        Plan {
+            targets: vec![target.to_string()],
+            pipeline: false,
+            continue_on_failure: false,
+            dry_run: false,
+        }
+    }
+
+    pub fn pipeline(targets: Vec<String>, continue_on_failure: bool) -> Self {
+        Plan {
+            targets,
+            pipeline: true,
+            continue_on_failure,
+            dry_run: false,
+        }
+    }
+
+    /// What the job records as its `target`.
+    pub fn target(&self) -> String {
+        self.targets.join(" ")
+    }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Job {
-    pub id: String,
-    /// Which bundle under SHELL_ROOT this ran, so one bundle's job ids can't
-    /// be used to read another's logs.
-    pub bundle: String,
-    pub target: String,
-    /// running | success | failed | failed_missing_vars
-    pub status: String,
-    pub started_at: String,
-    pub finished_at: Option<String>,
-    pub exit_code: Option<i32>,
-    pub missing_vars: Option<Vec<String>>,
+    /// What the job records as its `kind`.
+    pub fn kind(&self) -> &'static str {
+        if self.dry_run {
+            "dry_run"
+        } else {
+            "run"
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
-pub struct RunBody {
+pub struct RunQuery {
+    /// `?dry_run=1`: run the target under DRY_RUN=1 and keep vars.env as it is.
     #[serde(default)]
-    pub vars: HashMap<String, String>,
+    pub dry_run: Option<String>,
 }
 
-/// In-memory, exactly like the node version — a restart forgets past runs. The
-/// logs on disk outlive it.
-fn jobs() -> &'static Mutex<HashMap<String, Job>> {
-    static JOBS: OnceLock<Mutex<HashMap<String, Job>>> = OnceLock::new();
-    JOBS.get_or_init(|| Mutex::new(HashMap::new()))
+#[derive(Debug, Deserialize)]
+pub struct LogsQuery {
+    /// `?follow=1`: keep the connection open and stream lines as they are
+    /// written, rather than answering with what is there now.
+    #[serde(default)]
+    pub follow: Option<String>,
 }
 
 /* ── routes ── */
 
 pub async fn targets(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
     let bundle = path.into_inner();
-    if let Err(res) = authorize(&req, &bundle).await {
+    if let Err(res) = authorize(&req, &bundle, None).await {
         return Ok(res);
     }
 
@@ -118,7 +330,11 @@ pub async fn targets(req: HttpRequest, path: web::Path<String>) -> Result<HttpRe
         Err(res) => return Ok(res),
     };
 
-    let targets = match list_targets(&dir) {
+    let targets = match run_as::for_bundle(&bundle).await {
+        Ok(account) => list_targets(&dir, &account),
+        Err(error) => Err(error),
+    };
+    let targets = match targets {
         Ok(targets) => targets,
         Err(error) => return Ok(Response::internal_server_error(&error)),
     };
@@ -133,7 +349,7 @@ pub async fn describe(
     path: web::Path<(String, String)>,
 ) -> Result<HttpResponse, Error> {
     let (bundle, target) = path.into_inner();
-    if let Err(res) = authorize(&req, &bundle).await {
+    if let Err(res) = authorize(&req, &bundle, Some(&target)).await {
         return Ok(res);
     }
 
@@ -145,33 +361,67 @@ pub async fn describe(
         return Ok(Response::bad_request("Invalid target name"));
     }
 
+    // A bundle that declares its variables in bundle.json is described from
+    // that, types and all, without running anything.
+    match manifest::for_bundle(&bundle).await {
+        Ok(Some(declared)) => {
+            let mut schema = manifest::schema(&declared, &target);
+            let defaulted: Vec<&str> = schema
+                .schema
+                .iter()
+                .filter(|v| v.default.is_some())
+                .map(|v| v.name.as_str())
+                .collect();
+            schema.profiles = match profiles::coverage(&bundle, &schema.vars, &defaulted).await {
+                Ok(coverage) => coverage,
+                Err(res) => return Ok(res),
+            };
+            return Ok(HttpResponse::Ok().content_type("application/json").json(schema));
+        }
+        Ok(None) => {}
+        Err(res) => return Ok(res),
+    }
+
+    let account = match run_as::for_bundle(&bundle).await {
+        Ok(account) => account,
+        Err(error) => return Ok(Response::internal_server_error(&error)),
+    };
+
     // main.sh answers non-zero for an unknown target, so this is a 400 rather
     // than a 500 — the caller asked about something that doesn't exist.
-    let out = match run_sync(&dir, &["--describe", &target]) {
+    let out = match run_sync(&dir, &["--describe", &target], &account) {
         Ok(out) => out,
         Err(error) => return Ok(Response::bad_request(&error)),
     };
 
-    let vars: Vec<String> = out
-        .lines()
-        .map(|l| l.trim().to_string())
-        .filter(|l| !l.is_empty())
-        .collect();
+    let vars: Vec<&str> = out.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    let coverage = match profiles::coverage(&bundle, &vars, &[]).await {
+        Ok(coverage) => coverage,
+        Err(res) => return Ok(res),
+    };
 
     Ok(HttpResponse::Ok()
         .content_type("application/json")
-        .json(serde_json::json!({ "vars": vars })))
+        .json(serde_json::json!({ "vars": vars, "profiles": coverage })))
 }
 
 pub async fn run(
     req: HttpRequest,
     path: web::Path<(String, String)>,
+    query: web::Query<RunQuery>,
     body: Option<web::Json<RunBody>>,
 ) -> Result<HttpResponse, Error> {
     let (bundle, target) = path.into_inner();
-    if let Err(res) = authorize(&req, &bundle).await {
-        return Ok(res);
-    }
+    let action = if is_set(&query.dry_run) { "shell.dry_run" } else { "shell.run" };
+    let subject = format!("{}/{}", bundle, target);
+    let user = match authorize(&req, &bundle, Some(&target)).await {
+        Ok(user) => user,
+        Err(res) => {
+            let detail = Some(res.status().to_string());
+            Audit::record(&req, None, action, &subject, AuditOutcome::Denied, detail).await;
+            return Ok(res);
+        }
+    };
 
     let dir = match bundle_dir(&bundle) {
         Ok(dir) => dir,
@@ -180,23 +430,28 @@ pub async fn run(
     if !is_valid_target(&target) {
         return Ok(Response::bad_request("Invalid target name"));
     }
-
-    if let Some(body) = &body {
-        if !body.vars.is_empty() {
-            if let Err(error) = write_vars(&bundle, &body.vars) {
-                return Ok(Response::bad_request(&error));
-            }
+    let files = match integrity::check(&bundle, &dir).await {
+        Ok(files) => files,
+        Err(res) => {
+            let detail = Some(format!("integrity: {}", res.status()));
+            Audit::record(&req, Some(&user), action, &subject, AuditOutcome::Failed, detail).await;
+            return Ok(res);
         }
+    };
+
+    let mut body = body.map(|body| body.into_inner()).unwrap_or_default();
+    if let Err(res) = profiles::apply(&bundle, &mut body).await {
+        return Ok(res);
     }
 
-    let job = match start_job(&bundle, &dir, &target) {
-        Ok(job) => job,
-        Err(error) => return Ok(Response::internal_server_error(&error)),
-    };
+    // Checked now, though only written once the lock is held: a bad name
+    // should be a 400 to this caller, not a failed job later.
+    if let Err(res) = prepare_vars(&bundle, &target, &mut body.vars, &mut body.secret).await {
+        return Ok(res);
+    }
 
-    Ok(HttpResponse::Accepted()
-        .content_type("application/json")
-        .json(job))
+    let plan = Plan::single(&target);
+    Ok(submit(&req, &user, &bundle, files, &plan, body, is_set(&query.dry_run)).await)
 }
 
 pub async fn job(
@@ -204,32 +459,49 @@ pub async fn job(
     path: web::Path<(String, String)>,
 ) -> Result<HttpResponse, Error> {
     let (bundle, id) = path.into_inner();
-    if let Err(res) = authorize(&req, &bundle).await {
+    if let Err(res) = authorize(&req, &bundle, None).await {
         return Ok(res);
     }
 
-    match lookup(&bundle, &id) {
-        Some(job) => Ok(HttpResponse::Ok().content_type("application/json").json(job)),
-        None => Ok(Response::not_found("No such job")),
+    match lookup(&bundle, &id).await {
+        Ok(Some(job)) => Ok(HttpResponse::Ok().content_type("application/json").json(job)),
+        Ok(None) => Ok(Response::not_found("No such job")),
+        Err(res) => Ok(res),
     }
 }
 
 pub async fn job_logs(
     req: HttpRequest,
     path: web::Path<(String, String)>,
+    query: web::Query<LogsQuery>,
 ) -> Result<HttpResponse, Error> {
     let (bundle, id) = path.into_inner();
-    if let Err(res) = authorize(&req, &bundle).await {
+    if let Err(res) = authorize(&req, &bundle, None).await {
         return Ok(res);
     }
 
-    if lookup(&bundle, &id).is_none() {
-        return Ok(Response::not_found("No such job"));
+    let job = match lookup(&bundle, &id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return Ok(Response::not_found("No such job")),
+        Err(res) => return Ok(res),
+    };
+
+    // Ahead of the follow branch too, so a follower is told the same thing
+    // rather than handed an empty stream and a status event.
+    if let Some(at) = job.log_pruned_at {
+        return Ok(HttpResponse::Gone().content_type("application/json").json(Response {
+            message: format!("This job's log was pruned at {} (epoch ms)", at),
+        }));
     }
 
-    // The id came out of the registry, so it is a uuid this process minted —
-    // it can't reach outside LOG_DIR.
-    let body = fs::read_to_string(log_path(&id)).unwrap_or_default();
+    if is_set(&query.follow) {
+        return Ok(follow::response(&job));
+    }
+
+    // Built from the uuid of a record this server wrote rather than from the
+    // stored log_path, so it can't reach outside LOG_DIR whatever the
+    // collection holds. Compressed or not (shell/retention.rs).
+    let body = retention::read(&job.uuid).unwrap_or_default();
     Ok(HttpResponse::Ok()
         .content_type("text/plain; charset=utf-8")
         .body(body))
@@ -237,6 +509,75 @@ pub async fn job_logs(
 
 /* ── internals ── */
 
+/// Start, queue or hold a run whose caller, bundle and variables have all
+/// been checked, and answer for it. Shared by `run` and shell/pipeline.rs.
+async fn submit(
+    req: &HttpRequest,
+    user: &User,
+    bundle: &str,
+    files: integrity::Checked,
+    plan: &Plan,
+    body: RunBody,
+    dry_run: bool,
+) -> HttpResponse {
+    let action = if dry_run { "shell.dry_run" } else { "shell.run" };
+    let subject = format!("{}/{}", bundle, plan.target());
+    let RunBody { vars, queue, secret, .. } = body;
+
+    // A dry run is admitted like any other: nothing here can tell whether
+    // main.sh honours DRY_RUN=1, so it gets the same lock and approval.
+    let plan = Plan { dry_run, ..plan.clone() };
+    let admission = lock::admit(bundle, files, &plan, user, vars, secret, queue).await;
+    let (outcome, detail) = match &admission {
+        Ok(Admission::Started(job)) | Ok(Admission::Queued(job)) | Ok(Admission::Pending(job)) => {
+            (AuditOutcome::Success, job.uuid.clone())
+        }
+        Ok(Admission::Busy(holder)) => (AuditOutcome::Failed, format!("busy with {}", holder)),
+        Err(error) => (AuditOutcome::Failed, error.clone()),
+    };
+    Audit::record(req, Some(user), action, &subject, outcome, Some(detail)).await;
+
+    match admission {
+        Ok(Admission::Started(job)) | Ok(Admission::Queued(job)) | Ok(Admission::Pending(job)) => HttpResponse::Accepted()
+            .content_type("application/json")
+            .json(job),
+        Ok(Admission::Busy(holder)) => HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(serde_json::json!({
+                "message": "Another job is running on this bundle; pass queue: true to wait for it",
+                "job": holder,
+            })),
+        Err(error) => Response::internal_server_error(&error),
+    }
+}
+
+/// Check a run's variables — names, and values against the bundle's manifest
+/// if it has one — fill in the manifest's defaults, and settle which are
+/// secret. Shared by `run` and the scheduler (shell/schedules.rs).
+async fn prepare_vars(
+    bundle: &str,
+    target: &str,
+    vars: &mut HashMap<String, String>,
+    secret: &mut Vec<String>,
+) -> Result<(), HttpResponse> {
+    for (key, value) in vars.iter() {
+        check_var(key, value).map_err(|error| Response::bad_request(&error))?;
+    }
+    for key in secret.iter() {
+        check_var(key, "").map_err(|error| Response::bad_request(&error))?;
+    }
+
+    if let Some(declared) = manifest::for_bundle(bundle).await? {
+        let existing: Vec<String> = read_vars(bundle).into_keys().collect();
+        manifest::validate(&declared, target, vars, &existing)
+            .map_err(|error| Response::bad_request(&error))?;
+        secret.extend(manifest::secrets(&declared));
+    }
+    secret.sort();
+    secret.dedup();
+    Ok(())
+}
+
 /// SHELL_ROOT, created if it isn't there yet, canonicalized so callers can
 /// compare paths against it.
 pub fn shell_root() -> Result<PathBuf, String> {
@@ -246,9 +587,10 @@ pub fn shell_root() -> Result<PathBuf, String> {
         .map_err(|e| format!("{}: {}", SHELL_ROOT, e))
 }
 
-/// The target names a bundle exposes, read from its own `main.sh --list`.
-pub fn list_targets(dir: &Path) -> Result<Vec<String>, String> {
-    let out = run_sync(dir, &["--list"])?;
+/// The target names a bundle exposes, read from its own `main.sh --list` run
+/// as `account`.
+pub fn list_targets(dir: &Path, account: &run_as::Account) -> Result<Vec<String>, String> {
+    let out = run_sync(dir, &["--list"], account)?;
     // Drop the "Available targets, in run order:" header main.sh prints first.
     Ok(out
         .lines()
@@ -259,13 +601,15 @@ pub fn list_targets(dir: &Path) -> Result<Vec<String>, String> {
 }
 
 /// The gate every execution route runs: a valid CLI token, then permission to
-/// run *this* bundle.
+/// use *this* bundle — to run `target`, or with None, to see its targets and
+/// jobs. Hands back who that token belongs to, for the job record.
 ///
 /// An Administrator may run anything. An ordinary User may run only a bundle
-/// someone has marked `public_run` from the dashboard — uploading a bundle does
-/// not expose it. Running a target executes root scripts on the host, so the
-/// decision to open one up is per bundle and explicit.
-async fn authorize(req: &HttpRequest, bundle: &str) -> Result<(), HttpResponse> {
+/// someone has marked `public_run` from the dashboard, or a target a grant
+/// covers (shell/grants.rs) — uploading a bundle does not expose it. Running a
+/// target executes root scripts on the host, so the decision to open one up is
+/// explicit.
+async fn authorize(req: &HttpRequest, bundle: &str, target: Option<&str>) -> Result<User, HttpResponse> {
     let user = match require_cli(
         req,
         AccessRequirement::AnyOf(vec![AccountRole::Administrator, AccountRole::User]),
@@ -277,7 +621,7 @@ async fn authorize(req: &HttpRequest, bundle: &str) -> Result<(), HttpResponse>
     };
 
     if user.role == AccountRole::Administrator {
-        return Ok(());
+        return Ok(user);
     }
 
     let db = MongoDB.connect();
@@ -288,10 +632,15 @@ async fn authorize(req: &HttpRequest, bundle: &str) -> Result<(), HttpResponse>
         .await;
 
     match found {
-        Ok(Some(record)) if record.public_run => Ok(()),
-        Ok(Some(_)) => Err(Response::forbidden(
-            "This bundle is not open to ordinary accounts",
-        )),
+        Ok(Some(record)) => match grants::permits(&record, &user, target).await? {
+            true => Ok(user),
+            false if target.is_some() && grants::permits(&record, &user, None).await? => Err(
+                Response::forbidden("No grant of yours covers this target"),
+            ),
+            false => Err(Response::forbidden(
+                "This bundle is not open to ordinary accounts",
+            )),
+        },
         Ok(None) => Err(Response::not_found("No such shell bundle")),
         Err(error) => {
             log::error!("{:?}", error);
@@ -300,6 +649,34 @@ async fn authorize(req: &HttpRequest, bundle: &str) -> Result<(), HttpResponse>
     }
 }
 
+/// The gate for the administrator-only routes that take either credential:
+/// the dashboard's session cookie, or failing that a CLI token.
+async fn admin(req: &HttpRequest) -> Result<User, Error> {
+    match require_access(req, AccessRequirement::Role(AccountRole::Administrator)) {
+        Ok(user) => Ok(user),
+        Err(_) => require_cli(req, AccessRequirement::Role(AccountRole::Administrator)).await,
+    }
+}
+
+/// Whether the account `user_id` is still an administrator, and not
+/// suspended. A schedule or a hook runs as whoever made it, long after they
+/// did; this is asked each time it starts one.
+async fn is_admin(user_id: &str) -> Result<bool, String> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<AccountCore>("account_core");
+
+    match collection.find_one(doc! { "uuid": user_id }).await {
+        Ok(Some(account)) => {
+            Ok(account.role == AccountRole::Administrator && account.suspended_at.is_none())
+        }
+        Ok(None) => Ok(false),
+        Err(error) => {
+            log::error!("{:?}", error);
+            Err(error.to_string())
+        }
+    }
+}
+
 /// Resolve a bundle name to its directory, or the response explaining why not.
 ///
 /// The name is validated, then the resolved path is canonicalized and checked
@@ -350,20 +727,65 @@ fn vars_file(bundle: &str) -> PathBuf {
     Path::new("/etc").join(bundle).join("vars.env")
 }
 
+/// What a bundle's vars.env holds now, from earlier runs.
+fn read_vars(bundle: &str) -> HashMap<String, String> {
+    parse_vars(&fs::read_to_string(vars_file(bundle)).unwrap_or_default())
+}
+
+/// The reverse of `env_line`.
+fn parse_vars(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            (key.trim().to_string(), unescape(value))
+        })
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                out.push(next);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
 /// A job, but only if it belongs to this bundle.
-fn lookup(bundle: &str, id: &str) -> Option<Job> {
-    jobs()
-        .lock()
-        .ok()?
-        .get(id)
-        .filter(|job| job.bundle == bundle)
-        .cloned()
+async fn lookup(bundle: &str, id: &str) -> Result<Option<ShellJob>, HttpResponse> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    collection
+        .find_one(doc! { "uuid": id, "bundle": bundle })
+        .await
+        .map_err(|error| {
+            log::error!("{:?}", error);
+            Response::internal_server_error(&error.to_string())
+        })
 }
 
 fn log_path(id: &str) -> PathBuf {
     Path::new(LOG_DIR).join(format!("{}.log", id))
 }
 
+/// A query-string switch: `?follow=1`, `?follow=true`.
+pub fn is_set(flag: &Option<String>) -> bool {
+    matches!(flag.as_deref(), Some("1") | Some("true") | Some("yes"))
+}
+
 /// "--full", "sshd-config", "certbot-onwards". The value is passed to bash as
 /// its own argv entry so there is no shell to inject into, but keeping the
 /// vocabulary tight means an unknown target fails here with a clear message
@@ -381,26 +803,83 @@ fn is_valid_target(target: &str) -> bool {
 }
 
 /// Read-only routes run the script straight through and capture its output;
-/// they don't touch the system, so they don't need the job machinery.
-fn run_sync(dir: &Path, args: &[&str]) -> Result<String, String> {
-    let output = Command::new("bash")
+/// they don't touch the system, so they don't need the job machinery. They do
+/// need a bound: whoever is waiting is an HTTP request, or an upload. And
+/// they are the bundle's code all the same, so they run as `account`, as its
+/// jobs do (shell/run_as.rs).
+fn run_sync(dir: &Path, args: &[&str], account: &run_as::Account) -> Result<String, String> {
+    run_as::check(account)?;
+
+    let mut command = Command::new("bash");
+    command
         .arg("main.sh")
         .args(args)
         .current_dir(dir)
         .stdin(Stdio::null())
-        .output()
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    run_as::apply(&mut command, account);
+    let mut child = command
+        .spawn()
         .map_err(|e| format!("could not run main.sh: {}", e))?;
+    let pid = child.id() as i32;
+
+    // Read as it is written, or a script with more to say than a pipe holds
+    // would block on it and never exit.
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() < SYNC_TIMEOUT => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Ok(None) => {
+                signal_group(pid, libc::SIGKILL);
+                let _ = child.wait();
+                return Err(format!(
+                    "main.sh {} didn't finish within {}s",
+                    args.join(" "),
+                    SYNC_TIMEOUT.as_secs()
+                ));
+            }
+            Err(e) => {
+                signal_group(pid, libc::SIGKILL);
+                let _ = child.wait();
+                return Err(format!("could not run main.sh: {}", e));
+            }
+        }
+    };
+    // Whatever it left in the background would hold the pipes open.
+    signal_group(pid, libc::SIGKILL);
+
+    let collect = |reader: Option<JoinHandle<Vec<u8>>>| {
+        reader.and_then(|reader| reader.join().ok()).unwrap_or_default()
+    };
+    let (stdout, stderr) = (collect(stdout), collect(stderr));
 
-    if !output.status.success() {
-        let err = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if !status.success() {
+        let err = String::from_utf8_lossy(&stderr).trim().to_string();
         return Err(if err.is_empty() {
-            format!("main.sh exited with {}", output.status)
+            format!("main.sh exited with {}", status)
         } else {
             err
         });
     }
 
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    Ok(String::from_utf8_lossy(&stdout).to_string())
+}
+
+/// Read `from` to the end on a thread of its own.
+fn drain(mut from: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut out = Vec::new();
+        let _ = from.read_to_end(&mut out);
+        out
+    })
 }
 
 /// Write KEY="value" lines into the shared vars file, replacing any existing
@@ -427,7 +906,10 @@ fn check_var(key: &str, value: &str) -> Result<(), String> {
     Ok(())
 }
 
-fn write_vars(bundle: &str, vars: &HashMap<String, String>) -> Result<(), String> {
+/// Merge `vars` into the bundle's vars.env. With `secret`, the file is
+/// narrowed to 0600 first — and stays that way, since it now holds the value
+/// whatever is written to it later.
+fn write_vars(bundle: &str, vars: &HashMap<String, String>, secret: bool) -> Result<(), String> {
     // Validate the whole batch first: a bad key shouldn't leave /etc/vps-setup
     // created and half the values written.
     for (key, value) in vars {
@@ -440,8 +922,31 @@ fn write_vars(bundle: &str, vars: &HashMap<String, String>) -> Result<(), String
         fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
     }
 
-    let mut lines: Vec<String> = fs::read_to_string(path)
-        .unwrap_or_default()
+    // O_NOFOLLOW: this runs as root, and a vars.env that is a link would
+    // have it rewrite wherever the link points. Opened once, and everything
+    // after — the read, the chmod, the truncate — goes through this handle.
+    let mut options = fs::OpenOptions::new();
+    options
+        .read(true)
+        .write(true)
+        .create(true)
+        .custom_flags(libc::O_NOFOLLOW);
+    if secret {
+        // For a file that doesn't exist yet; one that does is narrowed below,
+        // before anything is written to it.
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if secret {
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+
+    let mut existing = String::new();
+    let _ = file.read_to_string(&mut existing);
+    let mut lines: Vec<String> = existing
         .lines()
         .filter(|l| !l.trim().is_empty())
         .map(|l| l.to_string())
@@ -450,89 +955,530 @@ fn write_vars(bundle: &str, vars: &HashMap<String, String>) -> Result<(), String
     for (key, value) in vars {
         let prefix = format!("{}=", key);
         lines.retain(|l| !l.starts_with(&prefix));
-        lines.push(format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\"")));
+        lines.push(env_line(key, value));
     }
 
     let mut body = lines.join("\n");
     body.push('\n');
-    fs::write(path, body).map_err(|e| format!("{}: {}", path.display(), e))
+
+    file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(body.as_bytes()))
+        .map_err(|e| format!("{}: {}", path.display(), e))
 }
 
-fn start_job(bundle: &str, dir: &Path, target: &str) -> Result<Job, String> {
-    fs::create_dir_all(LOG_DIR).map_err(|e| format!("{}: {}", LOG_DIR, e))?;
+fn env_line(key: &str, value: &str) -> String {
+    format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\""))
+}
 
+/// Write a job's record. Nothing runs yet: a `running` job is launched
+/// straight after by lock::admit, a `queued` one when its lock frees.
+async fn record_job(
+    bundle: &str,
+    plan: &Plan,
+    user: &User,
+    var_keys: Vec<String>,
+    secret_keys: Vec<String>,
+    status: &str,
+) -> Result<ShellJob, String> {
     let id = Uuid::now_v7().to_string();
-    let path = log_path(&id);
-    let log = fs::File::create(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
-    let log_err = log.try_clone().map_err(|e| e.to_string())?;
 
-    let job = Job {
-        id: id.clone(),
+    let job = ShellJob {
+        uuid: id.clone(),
         bundle: bundle.to_string(),
-        target: target.to_string(),
-        status: "running".to_string(),
-        started_at: Utc::now().to_rfc3339(),
-        finished_at: None,
+        target: plan.target(),
+        user_id: user.user_id.clone(),
+        token_label: user.token_label.clone().unwrap_or_default(),
+        var_keys,
+        secret_keys,
+        kind: plan.kind().to_string(),
+        status: status.to_string(),
         exit_code: None,
         missing_vars: None,
+        log_path: log_path(&id).display().to_string(),
+        run_as: None,
+        pid: None,
+        cancelled_by: None,
+        cancelled_at: None,
+        approval_expires_at: None,
+        approved_by: None,
+        approved_at: None,
+        rejected_by: None,
+        rejected_at: None,
+        started_at: Utc::now().timestamp_millis(),
+        finished_at: None,
+        steps: if plan.pipeline { pipeline::steps(&plan.targets) } else { Vec::new() },
+        continue_on_failure: plan.continue_on_failure,
+        artifacts: Vec::new(),
+        log_pruned_at: None,
     };
 
-    // stdin is null, which is what puts main.sh in its non-interactive mode:
-    // rather than blocking on a prompt it reports MISSING_VARS and stops.
-    let child = Command::new("bash")
-        .arg("main.sh")
-        .arg(target)
-        .current_dir(dir)
-        .stdin(Stdio::null())
-        .stdout(Stdio::from(log))
-        .stderr(Stdio::from(log_err))
-        .spawn();
-
-    let mut child = match child {
-        Ok(c) => c,
-        Err(e) => {
-            let _ = fs::write(&path, format!("Failed to spawn: {}\n", e));
-            return Err(format!("could not start main.sh: {}", e));
+    // Recorded before the spawn: a crash between the two then leaves a job
+    // the startup reconcile marks interrupted, rather than a root process
+    // nothing has a record of.
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+    if let Err(error) = collection.insert_one(job.clone()).await {
+        log::error!("{:?}", error);
+        return Err(error.to_string());
+    }
+
+    Ok(job)
+}
+
+/// Spawn main.sh for a recorded job and watch it until it exits — or, for a
+/// pipeline, each of its targets in turn (shell/pipeline.rs).
+///
+/// Only ever called holding the job's lock (see shell/lock.rs), which is why
+/// the vars are written here rather than when the request came in: a queued
+/// run must not rewrite /etc/<bundle>/vars.env under the job still using it.
+async fn launch(
+    mut job: ShellJob,
+    files: &integrity::Checked,
+    vars: &HashMap<String, String>,
+) -> Result<ShellJob, String> {
+    let id = job.uuid.clone();
+
+    fs::create_dir_all(LOG_DIR).map_err(|e| format!("{}: {}", LOG_DIR, e))?;
+    let path = log_path(&id);
+    // Emptied, then written through an append handle, so the server's own
+    // notes (append_log) and each step of a pipeline go after what is there
+    // rather than over it.
+    let log = fs::File::create(&path)
+        .and_then(|_| fs::OpenOptions::new().append(true).open(&path))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let dry_run = job.kind == "dry_run";
+
+    // Hashed before the lock was taken; a queued job may start long after
+    // that, and anything touched since is enough to stop it.
+    if let Err(error) = integrity::unchanged(files) {
+        let _ = fs::write(&path, format!("Can't run this bundle: {}\n", error));
+        finish_job(&id, "failed", None, None).await;
+        return Err(error);
+    }
+
+    let account = run_as::for_bundle(&job.bundle)
+        .await
+        .and_then(|account| run_as::check(&account).map(|_| account));
+    let account = match account {
+        Ok(account) => account,
+        Err(error) => {
+            let _ = fs::write(&path, format!("Can't run this bundle: {}\n", error));
+            finish_job(&id, "failed", None, None).await;
+            return Err(error);
         }
     };
 
-    if let Ok(mut map) = jobs().lock() {
-        map.insert(id.clone(), job.clone());
+    if !vars.is_empty() && !dry_run {
+        let secret = vars.keys().any(|key| job.secret_keys.contains(key));
+        if let Err(error) = write_vars(&job.bundle, vars, secret) {
+            let _ = fs::write(&path, format!("Failed to write variables: {}\n", error));
+            finish_job(&id, "failed", None, None).await;
+            return Err(error);
+        }
     }
+    // A dry run gets its variables in the environment, and leaves /etc as
+    // it found it.
+    if !dry_run {
+        if let Err(error) = run_as::share(&vars_file(&job.bundle), &account) {
+            let _ = fs::write(&path, format!("Failed to share variables: {}\n", error));
+            finish_job(&id, "failed", None, None).await;
+            return Err(error);
+        }
+    }
+    let artifact_dir = match artifacts::prepare(&id, &account) {
+        Ok(scratch) => scratch,
+        Err(error) => {
+            let _ = fs::write(&path, format!("Failed to make the artifact directory: {}\n", error));
+            finish_job(&id, "failed", None, None).await;
+            return Err(error);
+        }
+    };
 
-    // A setup run takes minutes; waiting on it in a worker thread would block
-    // the runtime, so it is watched on a thread of its own and the registry
-    // updated when it exits.
-    let watch_id = id.clone();
-    std::thread::spawn(move || {
-        let status = child.wait();
-        let log_text = fs::read_to_string(log_path(&watch_id)).unwrap_or_default();
+    // A secret this run doesn't set may still be in vars.env from an earlier
+    // one, and the script can print it just the same.
+    let stored = read_vars(&job.bundle);
+    let redactor = Redactor::new(
+        job.secret_keys
+            .iter()
+            .filter_map(|key| vars.get(key).or_else(|| stored.get(key)).cloned()),
+    );
+
+    let run = Prepared {
+        id: id.clone(),
+        dir: files.dir.clone(),
+        limits: limits::for_bundle(&job.bundle).await,
+        started: Instant::now(),
+        account,
+        redactor,
+        artifact_dir,
+        env: if dry_run { Some(vars.clone()) } else { None },
+        log,
+    };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+    let started_at = Utc::now().timestamp_millis();
+
+    // A pipeline spawns its first step from the watcher, like every other;
+    // until it has, there is no pid for a cancel to signal.
+    let first = if job.steps.is_empty() {
+        match run.spawn(&job.target) {
+            Ok(first) => Some(first),
+            Err(error) => {
+                let _ = fs::write(&path, format!("Failed to spawn: {}\n", error));
+                artifacts::discard(&id);
+                finish_job(&id, "failed", None, None).await;
+                return Err(format!("could not start main.sh: {}", error));
+            }
+        }
+    } else {
+        None
+    };
+    let pid = first.as_ref().map(|first| first.pid);
+
+    // A queued job's started_at was when it was asked for; from here on it
+    // is when it actually began.
+    let _ = collection
+        .update_one(
+            doc! { "uuid": &id },
+            doc! { "$set": {
+                "status": "running",
+                "pid": pid,
+                "run_as": &run.account.name,
+                "started_at": started_at,
+            } },
+        )
+        .await;
+    job.status = "running".to_string();
+    job.run_as = Some(run.account.name.clone());
+    job.pid = pid;
+    job.started_at = started_at;
+
+    // A setup run takes minutes, so it is watched from a task of its own —
+    // polled rather than waited on, so the limits can be enforced as it goes
+    // — and the record updated when it exits.
+    let watched = job.clone();
+    tokio::spawn(async move {
+        let (state, code, missing) = match first {
+            Some(first) => run.wait(first, 0).await.0,
+            None => pipeline::run_steps(&run, watched.steps, watched.continue_on_failure).await,
+        };
+        artifacts::collect(&watched.uuid, &run.artifact_dir).await;
+
+        finish_job(&watched.uuid, state, code, missing).await;
+        lock::release(watched.bundle, watched.uuid).await;
+    });
+
+    Ok(job)
+}
+
+/// What every target a job runs is run with, settled once by `launch`.
+struct Prepared {
+    id: String,
+    dir: PathBuf,
+    limits: ShellLimits,
+    /// What the wall-clock limit counts from: the job as a whole, so a
+    /// pipeline's steps share one timeout rather than getting one each.
+    started: Instant,
+    account: run_as::Account,
+    redactor: Redactor,
+    /// $ARTIFACT_DIR, collected from when the job ends (shell/artifacts.rs).
+    artifact_dir: artifacts::Scratch,
+    /// A dry run's variables, which go in the environment instead of vars.env.
+    env: Option<HashMap<String, String>>,
+    log: fs::File,
+}
+
+/// main.sh running one target, and the threads copying its output into the
+/// log when it has to be redacted on the way.
+struct Spawned {
+    child: Child,
+    pid: i32,
+    copiers: Vec<JoinHandle<()>>,
+}
+
+/// How a target ended: a job status, the exit code, and what main.sh said it
+/// was missing.
+type Outcome = (&'static str, Option<i32>, Option<Vec<String>>);
+
+impl Prepared {
+    fn spawn(&self, target: &str) -> Result<Spawned, String> {
+        let log = self.log.try_clone().map_err(|e| e.to_string())?;
+        let (stdout, stderr, redacted_log) = if self.redactor.is_empty() {
+            let log_err = log.try_clone().map_err(|e| e.to_string())?;
+            (Stdio::from(log), Stdio::from(log_err), None)
+        } else {
+            (Stdio::piped(), Stdio::piped(), Some(log))
+        };
+
+        // stdin is null, which is what puts main.sh in its non-interactive
+        // mode: rather than blocking on a prompt it reports MISSING_VARS and
+        // stops.
+        //
+        // process_group(0) makes bash the leader of a group of its own, so a
+        // cancel can signal the whole tree — the apt or curl a step is waiting
+        // on as well as the script — without touching this server.
+        let mut command = Command::new("bash");
+        command
+            .arg("main.sh")
+            .arg(target)
+            .current_dir(&self.dir)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr)
+            .env("ARTIFACT_DIR", &self.artifact_dir.path)
+            .process_group(0);
+        if let Some(vars) = &self.env {
+            command.env("DRY_RUN", "1").envs(vars);
+        }
+        limits::apply(&mut command, &self.limits);
+        run_as::apply(&mut command, &self.account);
+        let mut child = command.spawn().map_err(|e| e.to_string())?;
+
+        let mut copiers = Vec::new();
+        if let Some(log) = redacted_log {
+            let log = Arc::new(Mutex::new(log));
+            if let Some(out) = child.stdout.take() {
+                copiers.push(redact::copy(out, log.clone(), self.redactor.clone()));
+            }
+            if let Some(err) = child.stderr.take() {
+                copiers.push(redact::copy(err, log, self.redactor.clone()));
+            }
+        }
+
+        let pid = child.id() as i32;
+        Ok(Spawned { child, pid, copiers })
+    }
+
+    /// Wait for a spawned target under the bundle's limits. Only what it
+    /// wrote from `log_start` on is searched for MISSING_VARS. Says too which
+    /// of the job's own limits ended it, if one did.
+    async fn wait(&self, spawned: Spawned, log_start: u64) -> (Outcome, Option<limits::Breach>) {
+        let Spawned { mut child, pid, copiers } = spawned;
+        let (status, breach) = limits::watch(&mut child, &self.id, pid, &self.limits, self.started).await;
+        // The run is over when bash is. Anything main.sh left going in the
+        // background (`daemon &`) would otherwise hold the pipes open, and the
+        // job — and its lock — with them.
+        stop_group(pid).await;
+
+        // The pipes close once the last process holding them exits; until
+        // the copiers have drained them the log isn't complete. Bounded, for
+        // the process that escaped the group: its copier is left to finish
+        // on its own.
+        let drained = tokio::task::spawn_blocking(move || {
+            for copier in copiers {
+                let _ = copier.join();
+            }
+        });
+        if tokio::time::timeout(COPY_GRACE, drained).await.is_err() {
+            append_log(
+                &self.id,
+                "A process started by this run is still holding its output open; \
+                 the log may be incomplete.",
+            );
+        }
+        let log_text = fs::read(log_path(&self.id))
+            .map(|bytes| String::from_utf8_lossy(bytes.get(log_start as usize..).unwrap_or_default()).into_owned())
+            .unwrap_or_default();
 
         let (state, code, missing) = match status {
             Ok(status) if status.success() => ("success", status.code(), None),
+            Ok(status) if limits::hit_cpu_limit(&status, &self.limits) => {
+                append_log(&self.id, "A command ran past the CPU time limit.");
+                ("limit_exceeded", status.code(), None)
+            }
             Ok(status) => match missing_vars(&log_text) {
                 Some(vars) => ("failed_missing_vars", status.code(), Some(vars)),
                 None => ("failed", status.code(), None),
             },
             Err(e) => {
-                if let Ok(mut f) = fs::OpenOptions::new().append(true).open(log_path(&watch_id)) {
-                    let _ = writeln!(f, "\nFailed while waiting: {}", e);
-                }
+                append_log(&self.id, &format!("Failed while waiting: {}", e));
                 ("failed", None, None)
             }
         };
 
-        if let Ok(mut map) = jobs().lock() {
-            if let Some(entry) = map.get_mut(&watch_id) {
-                entry.status = state.to_string();
-                entry.exit_code = code;
-                entry.missing_vars = missing;
-                entry.finished_at = Some(Utc::now().to_rfc3339());
+        // A cancel or a limit already said why this stopped; going by the
+        // exit code alone it would read as an ordinary failure.
+        let (state, breach) = if was_cancelled(&self.id).await {
+            ("cancelled", None)
+        } else if let Some(breach) = breach {
+            (breach.status(), Some(breach))
+        } else {
+            (state, None)
+        };
+
+        ((state, code, missing), breach)
+    }
+
+    /// How far the log has got: where whatever is written next begins.
+    fn log_len(&self) -> u64 {
+        fs::metadata(log_path(&self.id)).map(|m| m.len()).unwrap_or(0)
+    }
+}
+
+/// Record how a job ended, and have whoever the bundle names told
+/// (shell/notify.rs). A failure here is logged rather than returned: the
+/// process has already exited, and there is no caller left to tell.
+async fn finish_job(id: &str, state: &str, code: Option<i32>, missing: Option<Vec<String>>) {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": id },
+            doc! { "$set": {
+                "status": state,
+                "exit_code": code,
+                "missing_vars": missing,
+                "finished_at": Utc::now().timestamp_millis(),
+            } },
+        )
+        .await;
+
+    if let Err(error) = result {
+        log::error!("shell job {}: {:?}", id, error);
+    }
+
+    tokio::spawn(notify::finished(id.to_string()));
+}
+
+async fn was_cancelled(id: &str) -> bool {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    matches!(
+        collection.find_one(doc! { "uuid": id }).await,
+        Ok(Some(job)) if job.cancelled_at.is_some()
+    )
+}
+
+/// Send `signal` to every process in the group `pgid` leads. False if there
+/// is no such group any more — everything in it has already exited.
+fn signal_group(pgid: i32, signal: i32) -> bool {
+    // A non-positive pgid would address this server's own group, or every
+    // process it may signal; a job's pid is never either.
+    if pgid <= 1 {
+        return false;
+    }
+    // SAFETY: kill(2) takes plain integers and touches no memory of ours.
+    unsafe { libc::kill(-pgid, signal) == 0 }
+}
+
+/// Stop whatever is left in a finished run's process group: SIGTERM, then
+/// SIGKILL for anything still there after KILL_GRACE. Returns at once when
+/// bash left nothing behind.
+async fn stop_group(pgid: i32) {
+    if !signal_group(pgid, libc::SIGTERM) {
+        return;
+    }
+
+    let started = Instant::now();
+    while started.elapsed() < KILL_GRACE {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // Signal 0 only asks whether the group still has a member.
+        if !signal_group(pgid, 0) {
+            return;
+        }
+    }
+    signal_group(pgid, libc::SIGKILL);
+}
+
+/// Add a line of the server's own to a job's log, set apart from the script's
+/// output by a blank line.
+fn append_log(id: &str, note: &str) {
+    if let Ok(mut f) = fs::OpenOptions::new().append(true).open(log_path(id)) {
+        let _ = writeln!(f, "\n{}", note);
+    }
+}
+
+/// Mark every job still `running` or `queued` as `interrupted`.
+///
+/// Called once from main.rs before the server starts listening. Whatever was
+/// watching those jobs died with the previous process, so nothing will ever
+/// record how they ended; leaving them `running` would have `ct shell job`
+/// report a run in progress forever. The queue and the runs waiting for
+/// approval were in memory too, so none of those is ever going to start.
+///
+/// A running job's process group is stopped before it is marked: left alone,
+/// main.sh would go on changing the machine while its bundle counts as idle,
+/// and a new run could start beside it.
+pub async fn reconcile_interrupted() {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    let cursor = collection
+        .find(doc! { "status": { "$in": ["running", "queued", "pending_approval"] } })
+        .await;
+    let cursor = match cursor {
+        Ok(cursor) => cursor,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return;
+        }
+    };
+
+    let stale: Vec<ShellJob> = match cursor.try_collect().await {
+        Ok(v) => v,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return;
+        }
+    };
+
+    // All at once: each may take KILL_GRACE, and nothing listens until
+    // they are done.
+    let stops = stale
+        .iter()
+        .filter(|job| job.status == "running")
+        .filter_map(|job| job.pid)
+        .map(stop_group);
+    join_all(stops).await;
+
+    for job in &stale {
+        append_log(&job.uuid, "Server restarted while this job was running; marked interrupted.");
+
+        // A pipeline's step that was running went with it; the ones after
+        // it never will.
+        let steps: Vec<ShellJobStep> = job
+            .steps
+            .iter()
+            .cloned()
+            .map(|mut step| {
+                match step.status.as_str() {
+                    "running" => step.status = "interrupted".to_string(),
+                    "pending" => step.status = "skipped".to_string(),
+                    _ => {}
+                }
+                step
+            })
+            .collect();
+
+        let mut set = doc! {
+            "status": "interrupted",
+            "finished_at": Utc::now().timestamp_millis(),
+        };
+        if let Ok(steps) = to_bson(&steps) {
+            set.insert("steps", steps);
+        }
+
+        let result = collection
+            .update_one(doc! { "uuid": &job.uuid, "status": &job.status }, doc! { "$set": set })
+            .await;
+
+        match result {
+            Ok(r) if r.modified_count > 0 && job.status == "running" => {
+                tokio::spawn(notify::finished(job.uuid.clone()));
             }
+            Ok(_) => {}
+            Err(error) => log::error!("shell job {}: {:?}", job.uuid, error),
         }
-    });
+    }
 
-    Ok(job)
+    if !stale.is_empty() {
+        log::warn!("Marked {} shell job(s) interrupted", stale.len());
+    }
 }
 
 /// main.sh prints "MISSING_VARS:a,b,c" when a non-interactive run can't collect
@@ -621,17 +1567,32 @@ mod tests {
     fn write_vars_rejects_what_would_corrupt_the_env_file() {
         let mut vars = HashMap::new();
         vars.insert("2bad".to_string(), "x".to_string());
-        assert!(write_vars("vps-setup", &vars).is_err(), "a name starting with a digit");
+        assert!(write_vars("vps-setup", &vars, false).is_err(), "a name starting with a digit");
 
         let mut vars = HashMap::new();
         vars.insert("has space".to_string(), "x".to_string());
-        assert!(write_vars("vps-setup", &vars).is_err(), "a name with a space");
+        assert!(write_vars("vps-setup", &vars, false).is_err(), "a name with a space");
 
         // The node version escaped quotes but not newlines, which let a value
         // append arbitrary extra assignments to the file.
         let mut vars = HashMap::new();
         vars.insert("ok_name".to_string(), "a\"b\nnew_username=root".to_string());
-        assert!(write_vars("vps-setup", &vars).is_err(), "a value containing a newline");
+        assert!(write_vars("vps-setup", &vars, false).is_err(), "a value containing a newline");
+    }
+
+    #[test]
+    fn vars_env_reads_back_what_was_written() {
+        let text = [
+            env_line("PLAIN", "value"),
+            env_line("QUOTED", "say \"hi\" \\o/"),
+            "not a var line".to_string(),
+        ]
+        .join("\n");
+
+        let vars = parse_vars(&text);
+        assert_eq!(vars.get("PLAIN").map(String::as_str), Some("value"));
+        assert_eq!(vars.get("QUOTED").map(String::as_str), Some("say \"hi\" \\o/"));
+        assert_eq!(vars.len(), 2);
     }
 
     #[test]
@@ -671,8 +1632,11 @@ mod route_tests {
         let paths = [
             "/api/shell/vps-setup/targets",
             "/api/shell/vps-setup/describe/ufw",
+            "/api/shell/jobs",
+            "/api/shell/vps-setup/jobs",
             "/api/shell/vps-setup/jobs/whatever",
             "/api/shell/vps-setup/jobs/whatever/logs",
+            "/api/shell/vps-setup/jobs/whatever/logs?follow=1",
         ];
 
         for path in paths {
@@ -681,14 +1645,41 @@ mod route_tests {
             assert_eq!(res.status(), 401, "{} was reachable with no credential", path);
         }
 
+        for uri in ["/api/shell/vps-setup/run/--full", "/api/shell/vps-setup/run/--full?dry_run=1"] {
+            let res =
+                test::call_service(&app, test::TestRequest::post().uri(uri).to_request()).await;
+            assert_eq!(res.status(), 401, "{} was reachable with no credential", uri);
+        }
+
         let res = test::call_service(
             &app,
             test::TestRequest::post()
-                .uri("/api/shell/vps-setup/run/--full")
+                .uri("/api/shell/vps-setup/jobs/whatever/cancel")
                 .to_request(),
         )
         .await;
-        assert_eq!(res.status(), 401, "run was reachable with no credential");
+        assert_eq!(res.status(), 401, "cancel was reachable with no credential");
+    }
+
+    /// Deleting and restoring go through the dashboard session; with none
+    /// they stop at the gate, before any lookup.
+    #[actix_web::test]
+    async fn trash_routes_refuse_callers_with_no_session() {
+        let app = test::init_service(App::new().configure(routes::shell::router)).await;
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::delete().uri("/api/shell/some-uuid").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), 401, "delete was reachable with no session");
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::post().uri("/api/shell/some-uuid/restore").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), 401, "restore was reachable with no session");
     }
 
     /// Fetch metadata is set by the browser itself and page script cannot strip
@@ -0,0 +1,479 @@
+/*
+ * Two-person approval for a bundle's sensitive steps.
+ *
+ *   PATCH /api/shell/{uuid}/approval            { targets: ["sshd-config"] }
+ *                                               administrator, dashboard
+ *   POST  /api/shell/{name}/jobs/{id}/approve   a second administrator, with
+ *   POST  /api/shell/{name}/jobs/{id}/reject    a CLI token
+ *
+ * A run that would reach a listed step — the step itself, --full, or a
+ * <step>-onwards starting at or before it — doesn't start when asked. A
+ * pipeline with any such step waits as a whole. lock::admit records it
+ * `pending_approval` and emails the other administrators, and main.sh is only
+ * spawned once one of them approves. The account that asked can't approve its
+ * own run, administrator or not. Any administrator may reject it instead, and
+ * whoever asked may withdraw it with an ordinary cancel. Undecided after
+ * SHELL_APPROVAL_TIMEOUT_SECS (an hour if unset), it ends `approval_expired`.
+ * Each outcome is on the job record and in the audit log.
+ *
+ * Approving starts a root run, so it takes a CLI token and not the session
+ * cookie, the same as `run` (see the top of shell.rs); rejecting goes with it.
+ *
+ * This holds however the run was asked for — `run`, a pipeline, a schedule,
+ * a hook — and for a dry run too: that it changes nothing is up to main.sh
+ * honouring DRY_RUN=1, which this server can't check. An approved run takes
+ * the bundle's lock like any other, queueing behind a job that holds it.
+ *
+ * Like the queue (shell/lock.rs), a pending run's variables stay in memory
+ * rather than in shell_job, since they may be passwords. A restart loses
+ * them, and `reconcile_interrupted` marks the job interrupted.
+ */
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, to_bson};
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Integrations::Smtp;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, require_cli, AccessRequirement, User};
+use crate::Model::Account::{AccountCore, AccountRole};
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellBundle, ShellJob};
+use crate::utils::response::Response;
+
+use super::{integrity, lock};
+use super::{log_path, lookup, record_job, Plan, LOG_DIR};
+
+/// How long a run waits for a decision when SHELL_APPROVAL_TIMEOUT_SECS isn't
+/// set.
+const DEFAULT_TIMEOUT_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    uuid: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestBody {
+    #[serde(default)]
+    targets: Vec<String>,
+}
+
+struct Pending {
+    job: ShellJob,
+    dir: PathBuf,
+    vars: HashMap<String, String>,
+}
+
+/// Job id -> the run waiting on it. Whoever takes an entry out decides the
+/// job — approve, reject, withdraw or expire — so two of them racing can't
+/// both act on it.
+fn pending() -> &'static Mutex<HashMap<String, Pending>> {
+    static PENDING: OnceLock<Mutex<HashMap<String, Pending>>> = OnceLock::new();
+    PENDING.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn take(id: &str) -> Option<Pending> {
+    pending().lock().unwrap_or_else(|e| e.into_inner()).remove(id)
+}
+
+/// Where a waiting run would start from, leaving it waiting.
+fn dir_of(id: &str) -> Option<PathBuf> {
+    pending().lock().unwrap_or_else(|e| e.into_inner()).get(id).map(|p| p.dir.clone())
+}
+
+fn timeout() -> Duration {
+    let secs = env::var("SHELL_APPROVAL_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+    form_data: web::Json<RequestBody>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.approval", &path.uuid, gate).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let bundle = match collection.find_one(doc! { "uuid": &path.uuid, "deleted_at": null }).await {
+        Ok(Some(bundle)) => bundle,
+        Ok(None) => return Ok(Response::not_found("Bundle not found")),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let mut targets: Vec<String> = form_data.targets.iter().map(|t| t.trim().to_string()).collect();
+    targets.sort();
+    targets.dedup();
+    // Steps only: --full and -onwards are covered by whichever steps they
+    // reach.
+    if let Some(unknown) = targets.iter().find(|t| !bundle.targets.contains(t)) {
+        return Ok(Response::bad_request(&format!("{} has no step {}", bundle.name, unknown)));
+    }
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &bundle.uuid },
+            doc! { "$set": { "requires_approval": &targets } },
+        )
+        .await;
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let detail = Some(targets.join(","));
+    Audit::record(&req, Some(&user), "shell.approval", &bundle.name, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(
+        Response { message: "Updated".to_string() }
+    ))
+}
+
+pub async fn approve(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (bundle, id) = path.into_inner();
+    let subject = format!("{}/{}", bundle, id);
+    let gate = require_cli(&req, AccessRequirement::Role(AccountRole::Administrator)).await;
+    let user = Audit::checked(&req, "shell.approve", &subject, gate).await?;
+
+    let job = match lookup(&bundle, &id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return Ok(Response::not_found("No such job")),
+        Err(res) => return Ok(res),
+    };
+    if job.status != "pending_approval" {
+        return Ok(Response::bad_request("That job is not waiting for approval"));
+    }
+    if job.user_id == user.user_id {
+        Audit::record(&req, Some(&user), "shell.approve", &subject, AuditOutcome::Denied, None).await;
+        return Ok(Response::forbidden(
+            "A run has to be approved by an administrator other than the one who asked for it",
+        ));
+    }
+
+    // Checked while it still waits, so that files changed on the host can
+    // be put right and the run approved then.
+    let dir = match dir_of(&id) {
+        Some(dir) => dir,
+        None => return Ok(Response::bad_request("That job is no longer waiting for approval")),
+    };
+    let files = match integrity::check(&bundle, &dir).await {
+        Ok(files) => files,
+        Err(res) => {
+            let detail = Some(format!("integrity: {}", res.status()));
+            Audit::record(&req, Some(&user), "shell.approve", &subject, AuditOutcome::Failed, detail).await;
+            return Ok(res);
+        }
+    };
+
+    // Expired or decided a moment ago.
+    let held = match take(&id) {
+        Some(held) => held,
+        None => return Ok(Response::bad_request("That job is no longer waiting for approval")),
+    };
+
+    let now = Utc::now().timestamp_millis();
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+    let result = collection
+        .update_one(
+            doc! { "uuid": &id, "status": "pending_approval" },
+            doc! { "$set": { "approved_by": &user.user_id, "approved_at": now } },
+        )
+        .await;
+    if let Err(error) = result {
+        log::error!("shell job {}: {:?}", id, error);
+    }
+
+    let mut job = held.job;
+    job.approved_by = Some(user.user_id.clone());
+    job.approved_at = Some(now);
+
+    match lock::admit_approved(job, files, held.vars).await {
+        Ok(job) => {
+            Audit::record(&req, Some(&user), "shell.approve", &subject, AuditOutcome::Success, None).await;
+            Ok(HttpResponse::Accepted().content_type("application/json").json(job))
+        }
+        Err(error) => {
+            Audit::record(&req, Some(&user), "shell.approve", &subject, AuditOutcome::Failed, Some(error.clone())).await;
+            Ok(Response::internal_server_error(&error))
+        }
+    }
+}
+
+pub async fn reject(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (bundle, id) = path.into_inner();
+    let subject = format!("{}/{}", bundle, id);
+    let gate = require_cli(&req, AccessRequirement::Role(AccountRole::Administrator)).await;
+    let user = Audit::checked(&req, "shell.reject", &subject, gate).await?;
+
+    match lookup(&bundle, &id).await {
+        Ok(Some(job)) if job.status == "pending_approval" => {}
+        Ok(Some(_)) => return Ok(Response::bad_request("That job is not waiting for approval")),
+        Ok(None) => return Ok(Response::not_found("No such job")),
+        Err(res) => return Ok(res),
+    }
+    if take(&id).is_none() {
+        return Ok(Response::bad_request("That job is no longer waiting for approval"));
+    }
+
+    let now = Utc::now().timestamp_millis();
+    let note = format!("Rejected by {}.", user.user_id);
+    close(&id, "rejected", doc! { "rejected_by": &user.user_id, "rejected_at": now }, &note).await;
+    Audit::record(&req, Some(&user), "shell.reject", &subject, AuditOutcome::Success, None).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(
+        Response { message: "Rejected".to_string() }
+    ))
+}
+
+/// Whether running any of `targets` on `bundle` needs a second
+/// administrator. A bundle with no record has nothing marked, so never does.
+pub async fn required(bundle: &str, targets: &[String]) -> Result<bool, String> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    match collection.find_one(doc! { "name": bundle, "deleted_at": null }).await {
+        Ok(Some(record)) => Ok(targets
+            .iter()
+            .any(|target| reaches(&record.requires_approval, &record.targets, target))),
+        Ok(None) => Ok(false),
+        Err(error) => {
+            log::error!("{:?}", error);
+            Err(error.to_string())
+        }
+    }
+}
+
+/// Record a run as waiting for approval, keep what it needs to start later,
+/// and tell the other administrators. Called by lock::admit in place of
+/// starting it.
+pub async fn hold(
+    bundle: &str,
+    dir: PathBuf,
+    plan: &Plan,
+    user: &User,
+    vars: HashMap<String, String>,
+    secret_keys: Vec<String>,
+) -> Result<ShellJob, String> {
+    let mut var_keys: Vec<String> = vars.keys().cloned().collect();
+    var_keys.sort();
+
+    let mut job = record_job(bundle, plan, user, var_keys, secret_keys, "pending_approval").await?;
+
+    let wait = timeout();
+    let expires_at = job.started_at + wait.as_millis() as i64;
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+    let result = collection
+        .update_one(
+            doc! { "uuid": &job.uuid },
+            doc! { "$set": { "approval_expires_at": expires_at } },
+        )
+        .await;
+    if let Err(error) = result {
+        log::error!("shell job {}: {:?}", job.uuid, error);
+    }
+    job.approval_expires_at = Some(expires_at);
+
+    pending().lock().unwrap_or_else(|e| e.into_inner()).insert(
+        job.uuid.clone(),
+        Pending { job: job.clone(), dir, vars },
+    );
+
+    let requester = User {
+        user_id: user.user_id.clone(),
+        role: user.role.clone(),
+        token_label: user.token_label.clone(),
+    };
+    let waiting = job.clone();
+    tokio::spawn(async move {
+        notify(&waiting).await;
+        tokio::time::sleep(wait).await;
+        expire(&waiting, &requester).await;
+    });
+
+    Ok(job)
+}
+
+/// Take a pending run back and record it cancelled. False if it wasn't
+/// waiting — it was decided in the meantime.
+pub async fn withdraw(id: &str, cancelled_by: &str) -> bool {
+    if take(id).is_none() {
+        return false;
+    }
+    let now = Utc::now().timestamp_millis();
+    let note = format!("Withdrawn by {}.", cancelled_by);
+    close(id, "cancelled", doc! { "cancelled_by": cancelled_by, "cancelled_at": now }, &note).await;
+    true
+}
+
+async fn expire(job: &ShellJob, requester: &User) {
+    if take(&job.uuid).is_none() {
+        return;
+    }
+    close(&job.uuid, "approval_expired", doc! {}, "Nobody approved this run in time.").await;
+
+    let subject = format!("{}/{}", job.bundle, job.uuid);
+    let detail = Some("approval timed out".to_string());
+    Audit::record_system(requester, "shell.approve", &subject, AuditOutcome::Failed, detail).await;
+}
+
+/// End a pending job with `status`, setting `fields` alongside. It never
+/// started, so `note` is the whole of its log.
+///
+/// Nothing is written to the log while a job waits: `launch` creates it
+/// afresh, and a `ct shell run --wait` already tailing the old one would
+/// lose its place.
+async fn close(id: &str, status: &str, mut fields: mongodb::bson::Document, note: &str) {
+    let _ = fs::create_dir_all(LOG_DIR);
+    let _ = fs::write(log_path(id), format!("{}\n", note));
+
+    fields.insert("status", status);
+    fields.insert("finished_at", Utc::now().timestamp_millis());
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+    let result = collection
+        .update_one(doc! { "uuid": id, "status": "pending_approval" }, doc! { "$set": fields })
+        .await;
+    if let Err(error) = result {
+        log::error!("shell job {}: {:?}", id, error);
+    }
+}
+
+/// Email every administrator but the one who asked. Without SMTP configured
+/// the run still waits; someone has to notice it in the dashboard instead.
+async fn notify(job: &ShellJob) {
+    if env::var("SMTP_EMAIL").is_err() || env::var("SMTP_PROJECT_NAME").is_err() {
+        log::warn!("shell job {}: SMTP isn't configured; no approval emails sent", job.uuid);
+        return;
+    }
+
+    let role = match to_bson(&AccountRole::Administrator) {
+        Ok(role) => role,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return;
+        }
+    };
+    let db = MongoDB.connect();
+    let collection = db.collection::<AccountCore>("account_core");
+    let admins: Result<Vec<AccountCore>, _> = match collection.find(doc! { "role": role }).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(error) => Err(error),
+    };
+    let admins = match admins {
+        Ok(admins) => admins,
+        Err(error) => {
+            log::error!("shell job {}: {:?}", job.uuid, error);
+            return;
+        }
+    };
+
+    let requester = admins
+        .iter()
+        .find(|a| a.uuid == job.user_id)
+        .map(|a| a.email_address.clone())
+        .unwrap_or_else(|| job.user_id.clone());
+    let expires_at = when(job.approval_expires_at.unwrap_or_default());
+
+    for admin in admins.into_iter().filter(|a| a.uuid != job.user_id) {
+        let message = Smtp::shell_approval_request_template(
+            &admin.email_address,
+            &requester,
+            &job.bundle,
+            &job.target,
+            &job.uuid,
+            &expires_at,
+        );
+        let id = job.uuid.clone();
+        // lettre's SmtpTransport blocks.
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = Smtp::send_email(message) {
+                log::error!("shell job {}: approval email to {}: {}", id, admin.email_address, error);
+            }
+        });
+    }
+}
+
+fn when(millis: i64) -> String {
+    match Utc.timestamp_millis_opt(millis).single() {
+        Some(at) => at.format("%Y-%m-%d %H:%M UTC").to_string(),
+        None => millis.to_string(),
+    }
+}
+
+/// Whether running `target` reaches any of the `marked` steps, given the
+/// bundle's steps in run order. An -onwards whose start isn't in the list is
+/// taken to reach them all, rather than none.
+fn reaches(marked: &[String], order: &[String], target: &str) -> bool {
+    if marked.is_empty() {
+        return false;
+    }
+    if target == "--full" || marked.iter().any(|m| m == target) {
+        return true;
+    }
+    match target.strip_suffix("-onwards") {
+        Some(step) => match order.iter().position(|t| t == step) {
+            Some(start) => order[start..].iter().any(|t| marked.contains(t)),
+            None => true,
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn a_run_needs_approval_when_it_reaches_a_marked_step() {
+        let order = names(&["base", "ufw", "sshd-config", "certbot"]);
+        let marked = names(&["sshd-config"]);
+
+        assert!(reaches(&marked, &order, "sshd-config"));
+        assert!(reaches(&marked, &order, "--full"));
+        assert!(reaches(&marked, &order, "ufw-onwards"));
+        assert!(reaches(&marked, &order, "sshd-config-onwards"));
+
+        assert!(!reaches(&marked, &order, "ufw"));
+        assert!(!reaches(&marked, &order, "certbot-onwards"));
+        assert!(!reaches(&[], &order, "--full"));
+    }
+
+    #[test]
+    fn an_unknown_starting_step_errs_towards_approval() {
+        let order = names(&["base", "sshd-config"]);
+        assert!(reaches(&names(&["sshd-config"]), &order, "renamed-onwards"));
+    }
+}
@@ -0,0 +1,510 @@
+/*
+ * Files a job leaves behind for whoever ran it.
+ *
+ *   GET /api/shell/{name}/jobs/{id}/artifacts          what it kept
+ *   GET /api/shell/{name}/jobs/{id}/artifacts/{file}   one of them
+ *
+ * main.sh is started with ARTIFACT_DIR set to an empty directory of the job's
+ * own, owned by the account it runs as; every step of a pipeline shares the
+ * one. It is made inside a directory of the server's, 0711, so the job can
+ * reach its own but not rename or replace it. Whatever a target writes there
+ * — the public half of a key it generated, a config dump, a report — is
+ * collected when the job ends: copied to ARTIFACT_ROOT/<job>/ and listed on
+ * the job's `artifacts` with its size and SHA-256. The directory itself is
+ * then removed.
+ *
+ * Only regular files at the top of it are kept, under names that are a
+ * single safe path segment. Within
+ *
+ *   SHELL_ARTIFACT_MAX_FILES   files per job (32)
+ *   SHELL_ARTIFACT_MAX_MB      the size of any one file (10)
+ *   SHELL_ARTIFACT_TOTAL_MB    all of a job's files together (50)
+ *
+ * — "0" turns a limit off — and anything past them is left out with a note in
+ * the job log saying so.
+ *
+ * The job's account owns the directory and the server reads from it as root.
+ * So the directory is opened without following a symlink and refused unless
+ * it is the one `prepare` made; each file is opened relative to it, again
+ * without following a symlink, and refused if it has another name elsewhere:
+ * a target can't hand back /etc/shadow by linking to it. Artifacts aren't
+ * redacted the way the log is; a secret has no business in one. They go when
+ * the retention sweep prunes the job's log (retention.rs).
+ */
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::{chown, DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use mongodb::bson::{doc, to_bson};
+use sha2::{Digest, Sha256};
+
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Model::Shell::{ShellArtifact, ShellJob};
+use crate::utils::response::Response;
+
+use super::retention::limit;
+use super::run_as::Account;
+use super::{append_log, authorize, lookup};
+
+/// Where collected artifacts are kept, one directory per job.
+const ARTIFACT_ROOT: &str = "./artifacts/shell";
+const DEFAULT_MAX_FILES: u64 = 32;
+const DEFAULT_MAX_MB: u64 = 10;
+const DEFAULT_TOTAL_MB: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Caps {
+    max_files: Option<usize>,
+    max_file_bytes: Option<u64>,
+    max_total_bytes: Option<u64>,
+}
+
+fn caps() -> Caps {
+    Caps {
+        max_files: limit("SHELL_ARTIFACT_MAX_FILES", DEFAULT_MAX_FILES).map(|n| n as usize),
+        max_file_bytes: limit("SHELL_ARTIFACT_MAX_MB", DEFAULT_MAX_MB).map(|mb| mb * 1024 * 1024),
+        max_total_bytes: limit("SHELL_ARTIFACT_TOTAL_MB", DEFAULT_TOTAL_MB).map(|mb| mb * 1024 * 1024),
+    }
+}
+
+pub async fn list(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (bundle, id) = path.into_inner();
+    if let Err(res) = authorize(&req, &bundle, None).await {
+        return Ok(res);
+    }
+
+    match lookup(&bundle, &id).await {
+        Ok(Some(job)) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(serde_json::json!({ "artifacts": job.artifacts }))),
+        Ok(None) => Ok(Response::not_found("No such job")),
+        Err(res) => Ok(res),
+    }
+}
+
+pub async fn download(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (bundle, id, file) = path.into_inner();
+    if let Err(res) = authorize(&req, &bundle, None).await {
+        return Ok(res);
+    }
+
+    let job = match lookup(&bundle, &id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return Ok(Response::not_found("No such job")),
+        Err(res) => return Ok(res),
+    };
+    if let Some(at) = job.log_pruned_at {
+        return Ok(HttpResponse::Gone().content_type("application/json").json(Response {
+            message: format!("This job's artifacts were pruned at {} (epoch ms)", at),
+        }));
+    }
+
+    // Only a name the job record lists, so what is joined onto the path was
+    // checked when it was collected, not just now.
+    let artifact = match job.artifacts.iter().find(|a| a.name == file) {
+        Some(artifact) => artifact,
+        None => return Ok(Response::not_found("No such artifact")),
+    };
+    let body = match fs::read(stored(&job.uuid).join(&artifact.name)) {
+        Ok(body) => body,
+        Err(error) => {
+            log::error!("shell job {}: {}: {}", job.uuid, artifact.name, error);
+            return Ok(Response::not_found("That artifact is no longer on disk"));
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(artifact.name.clone())],
+        })
+        .body(body))
+}
+
+/// Where a job's collected artifacts are.
+pub fn stored(id: &str) -> PathBuf {
+    Path::new(ARTIFACT_ROOT).join(id)
+}
+
+/// A job's ARTIFACT_DIR as `prepare` made it, so `collect` can tell it is
+/// still that directory and not something put in its place.
+#[derive(Debug, Clone)]
+pub struct Scratch {
+    pub path: PathBuf,
+    dev: u64,
+    ino: u64,
+    uid: u32,
+}
+
+/// Where every job's ARTIFACT_DIR is made while it runs.
+fn scratch_root() -> PathBuf {
+    std::env::temp_dir().join("shell-artifacts")
+}
+
+/// The ARTIFACT_DIR a job is started with, while it runs.
+fn scratch(id: &str) -> PathBuf {
+    scratch_root().join(id)
+}
+
+/// Make sure `scratch_root` is a directory of this server's own and not a
+/// link, and leave it 0711: an account can reach its job's directory, but not
+/// list, rename or replace what is in it.
+fn checked_root() -> Result<PathBuf, String> {
+    let root = scratch_root();
+    match fs::DirBuilder::new().mode(0o711).create(&root) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(error) => return Err(format!("{}: {}", root.display(), error)),
+    }
+
+    let meta = fs::symlink_metadata(&root).map_err(|e| format!("{}: {}", root.display(), e))?;
+    // SAFETY: geteuid(2) can't fail and touches no memory.
+    if !meta.is_dir() || meta.uid() != unsafe { libc::geteuid() } {
+        return Err(format!("{}: not a directory of this server's own", root.display()));
+    }
+    fs::set_permissions(&root, fs::Permissions::from_mode(0o711))
+        .map_err(|e| format!("{}: {}", root.display(), e))?;
+    Ok(root)
+}
+
+/// What `collect` will check the directory at `path` against.
+fn identify(path: &Path) -> Result<Scratch, String> {
+    let meta = fs::symlink_metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Scratch { path: path.to_path_buf(), dev: meta.dev(), ino: meta.ino(), uid: meta.uid() })
+}
+
+/// Make a job's empty ARTIFACT_DIR, writable by the account it runs as and
+/// nobody else.
+pub fn prepare(id: &str, account: &Account) -> Result<Scratch, String> {
+    let dir = checked_root()?.join(id);
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+    // SAFETY: geteuid(2) can't fail and touches no memory.
+    if account.uid != 0 && unsafe { libc::geteuid() } == 0 {
+        chown(&dir, Some(account.uid), Some(account.gid))
+            .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    identify(&dir)
+}
+
+/// Remove a job's ARTIFACT_DIR without keeping anything: the job never ran.
+pub fn discard(id: &str) {
+    let _ = fs::remove_dir_all(scratch(id));
+}
+
+/// Open a job's ARTIFACT_DIR, if it is still the one `prepare` made. None if
+/// it is gone.
+fn open(scratch: &Scratch) -> Result<Option<fs::File>, String> {
+    let dir = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(&scratch.path)
+    {
+        Ok(dir) => dir,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(format!("{}: {}", scratch.path.display(), error)),
+    };
+    let meta = dir.metadata().map_err(|e| e.to_string())?;
+    if (meta.dev(), meta.ino(), meta.uid()) != (scratch.dev, scratch.ino, scratch.uid) {
+        return Err(format!("{}: not the directory the job was given", scratch.path.display()));
+    }
+    Ok(Some(dir))
+}
+
+/// Keep what a finished job left in its ARTIFACT_DIR, and list it on the job
+/// record.
+pub async fn collect(id: &str, scratch: &Scratch) {
+    let job_id = id.to_string();
+    let scratch = scratch.clone();
+    let gathered = tokio::task::spawn_blocking(move || {
+        let result = match open(&scratch) {
+            Ok(Some(dir)) => gather(&dir, &stored(&job_id), &caps()),
+            // Nothing there to keep.
+            Ok(None) => Ok((Vec::new(), Vec::new())),
+            Err(error) => Err(error),
+        };
+        let _ = fs::remove_dir_all(&scratch.path);
+        result
+    })
+    .await;
+
+    let (kept, notes) = match gathered {
+        Ok(Ok(gathered)) => gathered,
+        Ok(Err(error)) => (Vec::new(), vec![format!("Couldn't collect artifacts: {}", error)]),
+        Err(error) => (Vec::new(), vec![format!("Couldn't collect artifacts: {}", error)]),
+    };
+    for note in &notes {
+        append_log(id, note);
+    }
+    if kept.is_empty() {
+        return;
+    }
+
+    let artifacts = match to_bson(&kept) {
+        Ok(artifacts) => artifacts,
+        Err(error) => {
+            log::error!("shell job {}: {:?}", id, error);
+            return;
+        }
+    };
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+    let result = collection
+        .update_one(doc! { "uuid": id }, doc! { "$set": { "artifacts": artifacts } })
+        .await;
+    if let Err(error) = result {
+        log::error!("shell job {}: {:?}", id, error);
+    }
+}
+
+/// Copy what the directory `work` holds into `store` within `caps`. Returns
+/// what was kept, by name, and a note for each thing that wasn't.
+fn gather(work: &fs::File, store: &Path, caps: &Caps) -> Result<(Vec<ShellArtifact>, Vec<String>), String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut notes: Vec<String> = Vec::new();
+
+    for name in entries(work)? {
+        if is_valid_name(&name) {
+            names.push(name);
+        } else {
+            notes.push(format!("Artifact {:?} left out: not a plain file name.", name));
+        }
+    }
+    names.sort();
+
+    let mut kept: Vec<ShellArtifact> = Vec::new();
+    let mut total: u64 = 0;
+    for name in names {
+        if caps.max_files.map(|max| kept.len() >= max).unwrap_or(false) {
+            notes.push(format!("Artifact {} left out: a job keeps at most {} files.", name, kept.len()));
+            continue;
+        }
+        let room = match (caps.max_file_bytes, caps.max_total_bytes) {
+            (Some(file), Some(all)) => Some(file.min(all.saturating_sub(total))),
+            (Some(file), None) => Some(file),
+            (None, Some(all)) => Some(all.saturating_sub(total)),
+            (None, None) => None,
+        };
+
+        match copy(work, &name, &store.join(&name), room) {
+            Ok(Some((size, sha256))) => {
+                total += size;
+                kept.push(ShellArtifact { name, size, sha256 });
+            }
+            Ok(None) => notes.push(format!("Artifact {} left out: over the size limit.", name)),
+            Err(error) => notes.push(format!("Artifact {} left out: {}.", name, error)),
+        }
+    }
+    Ok((kept, notes))
+}
+
+/// The names in the directory `dir`, read through that handle rather than
+/// its path.
+fn entries(dir: &fs::File) -> Result<Vec<String>, String> {
+    // SAFETY: the duplicate fd is handed to fdopendir, which owns it from
+    // then on and closedir releases it; each entry is read before the next
+    // readdir call can overwrite it.
+    unsafe {
+        let fd = libc::fcntl(dir.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+        let stream = libc::fdopendir(fd);
+        if stream.is_null() {
+            let error = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(error.to_string());
+        }
+        // The duplicate shares its offset with `dir`; start from the top.
+        libc::rewinddir(stream);
+
+        let mut names = Vec::new();
+        loop {
+            let entry = libc::readdir(stream);
+            if entry.is_null() {
+                break;
+            }
+            let name = CStr::from_ptr((*entry).d_name.as_ptr()).to_string_lossy().into_owned();
+            if name != "." && name != ".." {
+                names.push(name);
+            }
+        }
+        libc::closedir(stream);
+        Ok(names)
+    }
+}
+
+/// Copy the file `name` out of a job's ARTIFACT_DIR, and hash it on the way.
+/// None if it is larger than `room`.
+fn copy(dir: &fs::File, name: &str, to: &Path, room: Option<u64>) -> Result<Option<(u64, String)>, String> {
+    let name = CString::new(name).map_err(|e| e.to_string())?;
+    let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC;
+    // SAFETY: an open directory and a NUL-terminated name; the fd returned,
+    // if any, is owned by the File made from it and nothing else.
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) };
+    if fd < 0 {
+        let error = io::Error::last_os_error();
+        return Err(match error.raw_os_error() {
+            Some(libc::ELOOP) => "a symlink".to_string(),
+            _ => error.to_string(),
+        });
+    }
+    // SAFETY: see above.
+    let file = unsafe { fs::File::from_raw_fd(fd) };
+    let meta = file.metadata().map_err(|e| e.to_string())?;
+    if !meta.is_file() {
+        return Err("not a regular file".to_string());
+    }
+    if meta.nlink() > 1 {
+        return Err("it has another name elsewhere".to_string());
+    }
+    if room.map(|room| meta.len() > room).unwrap_or(false) {
+        return Ok(None);
+    }
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+    }
+    let mut out = fs::File::create(to).map_err(|e| e.to_string())?;
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    let mut buf = [0u8; 64 * 1024];
+
+    // Held to `room` as it is read too: the job may still be writing it.
+    let mut reader = file.take(room.map(|room| room + 1).unwrap_or(u64::MAX));
+    loop {
+        let n = reader.read(&mut buf).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        size += n as u64;
+        if room.map(|room| size > room).unwrap_or(false) {
+            drop(out);
+            let _ = fs::remove_file(to);
+            return Ok(None);
+        }
+        hasher.update(&buf[..n]);
+        out.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+    }
+    Ok(Some((size, hex::encode(hasher.finalize()))))
+}
+
+/// A single path segment a browser will save as is: no separators, nothing
+/// hidden.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn scratch_pair(tag: &str) -> (PathBuf, PathBuf) {
+        let base = std::env::temp_dir().join(format!("shell-artifacts-test-{}-{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let work = base.join("work");
+        fs::create_dir_all(&work).unwrap();
+        (work, base.join("store"))
+    }
+
+    fn opened(work: &Path) -> fs::File {
+        open(&identify(work).unwrap()).unwrap().unwrap()
+    }
+
+    fn uncapped() -> Caps {
+        Caps { max_files: None, max_file_bytes: None, max_total_bytes: None }
+    }
+
+    #[test]
+    fn plain_files_are_kept_and_the_rest_noted() {
+        let (work, store) = scratch_pair("plain");
+        fs::write(work.join("id_ed25519.pub"), "ssh-ed25519 AAAA").unwrap();
+        fs::write(work.join(".hidden"), "x").unwrap();
+        fs::create_dir(work.join("nested")).unwrap();
+        symlink("/etc/passwd", work.join("passwd")).unwrap();
+        fs::write(work.join("report.txt"), "ok").unwrap();
+        fs::hard_link(work.join("report.txt"), work.join("report-copy.txt")).unwrap();
+
+        let (kept, notes) = gather(&opened(&work), &store, &uncapped()).unwrap();
+        let names: Vec<&str> = kept.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["id_ed25519.pub"]);
+        assert_eq!(kept[0].size, 16);
+        assert_eq!(kept[0].sha256, hex::encode(Sha256::digest(b"ssh-ed25519 AAAA")));
+        assert_eq!(fs::read(store.join("id_ed25519.pub")).unwrap(), b"ssh-ed25519 AAAA");
+
+        // .hidden, nested, the symlink and both names of the hard link.
+        assert_eq!(notes.len(), 5);
+        assert!(notes.iter().any(|n| n.contains("passwd") && n.contains("symlink")));
+        assert!(!store.join("passwd").exists());
+        let _ = fs::remove_dir_all(work.parent().unwrap());
+    }
+
+    #[test]
+    fn the_limits_are_applied_in_name_order() {
+        let (work, store) = scratch_pair("caps");
+        fs::write(work.join("a.txt"), [0u8; 6]).unwrap();
+        fs::write(work.join("b.txt"), [0u8; 20]).unwrap();
+        fs::write(work.join("c.txt"), [0u8; 6]).unwrap();
+        fs::write(work.join("d.txt"), [0u8; 1]).unwrap();
+
+        let caps = Caps { max_files: Some(2), max_file_bytes: Some(10), max_total_bytes: Some(12) };
+        let (kept, notes) = gather(&opened(&work), &store, &caps).unwrap();
+        let names: Vec<&str> = kept.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["a.txt", "c.txt"]);
+        assert!(notes[0].contains("b.txt") && notes[0].contains("size"));
+        assert!(notes[1].contains("d.txt") && notes[1].contains("at most 2"));
+        assert!(!store.join("b.txt").exists());
+        let _ = fs::remove_dir_all(work.parent().unwrap());
+    }
+
+    #[test]
+    fn a_directory_put_in_its_place_is_refused() {
+        let (work, _) = scratch_pair("swap");
+        let given = identify(&work).unwrap();
+        let moved = work.with_file_name("moved");
+        fs::rename(&work, &moved).unwrap();
+
+        symlink(&moved, &work).unwrap();
+        assert!(open(&given).is_err());
+
+        fs::remove_file(&work).unwrap();
+        fs::create_dir(&work).unwrap();
+        assert!(open(&given).unwrap_err().contains("not the directory"));
+
+        fs::remove_dir(&work).unwrap();
+        assert!(open(&given).unwrap().is_none());
+        let _ = fs::remove_dir_all(work.parent().unwrap());
+    }
+
+    #[test]
+    fn names_are_one_plain_segment() {
+        assert!(is_valid_name("report-2024.json"));
+        assert!(!is_valid_name("../etc"));
+        assert!(!is_valid_name(".env"));
+        assert!(!is_valid_name("with space"));
+        assert!(!is_valid_name(""));
+    }
+}
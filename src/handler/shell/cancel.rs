@@ -0,0 +1,162 @@
+/*
+ * `POST /api/shell/{name}/jobs/{id}/cancel` — stop a run that is still going.
+ *
+ * SIGTERM goes to the job's whole process group (`launch` puts bash at the
+ * head of one), so the apt or curl a step is blocked on is asked to stop too,
+ * not just the script around it. Anything still alive after KILL_GRACE gets
+ * SIGKILL — if the job hasn't ended in the meantime, so the group can't be
+ * one that has since taken over its pgid.
+ *
+ * The record is stamped with who cancelled *before* the signal is sent. The
+ * watcher in `launch` reads that stamp when the process exits, which is what
+ * makes the final status `cancelled` rather than a signal-killed `failed`.
+ *
+ * A job still `queued` behind its bundle's lock has no process yet; it is
+ * simply taken out of the queue (shell/lock.rs).
+ */
+use chrono::Utc;
+use mongodb::bson::doc;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::ShellJob;
+use crate::utils::response::Response;
+
+use super::{append_log, approval, authorize, lock, lookup, signal_group, KILL_GRACE};
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (bundle, id) = path.into_inner();
+    let subject = format!("{}/{}", bundle, id);
+    let user = match authorize(&req, &bundle, None).await {
+        Ok(user) => user,
+        Err(res) => {
+            let detail = Some(res.status().to_string());
+            Audit::record(&req, None, "shell.cancel", &subject, AuditOutcome::Denied, detail).await;
+            return Ok(res);
+        }
+    };
+
+    let job = match lookup(&bundle, &id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return Ok(Response::not_found("No such job")),
+        Err(res) => return Ok(res),
+    };
+
+    // Being allowed to run a bundle isn't being allowed to stop somebody
+    // else's run of it.
+    if user.role != AccountRole::Administrator && job.user_id != user.user_id {
+        Audit::record(&req, Some(&user), "shell.cancel", &subject, AuditOutcome::Denied, None).await;
+        return Ok(Response::forbidden("Only an administrator can cancel another account's job"));
+    }
+
+    if job.status == "pending_approval" {
+        if approval::withdraw(&job.uuid, &user.user_id).await {
+            let detail = Some("withdrawn".to_string());
+            Audit::record(&req, Some(&user), "shell.cancel", &subject, AuditOutcome::Success, detail).await;
+            return Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .json(Response { message: "Cancelled".to_string() }));
+        }
+        return Ok(Response::bad_request("That job has just been approved or rejected; try again"));
+    }
+
+    if job.status == "queued" {
+        if lock::dequeue(&job.uuid, &user.user_id).await {
+            let detail = Some("dequeued".to_string());
+            Audit::record(&req, Some(&user), "shell.cancel", &subject, AuditOutcome::Success, detail).await;
+            return Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .json(Response { message: "Cancelled".to_string() }));
+        }
+        return Ok(Response::bad_request("That job has just started; try again"));
+    }
+
+    if job.status != "running" {
+        return Ok(Response::bad_request("That job is not running"));
+    }
+
+    // No pid: a pipeline between two steps, which is stamped and starts no
+    // more (shell/pipeline.rs); or main.sh not spawned yet — a window of a few
+    // milliseconds between record_job and launch, with nothing in it to
+    // signal.
+    let pgid = match job.pid {
+        Some(pid) => Some(pid),
+        None if !job.steps.is_empty() => None,
+        None => return Ok(Response::bad_request("That job has not started yet; try again")),
+    };
+
+    let now = Utc::now().timestamp_millis();
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    // Filtered on status and on not having been cancelled already, so two
+    // cancels racing each other stamp the record once; and on the pid read
+    // above, so a pipeline that has moved on to another step since isn't
+    // taken to be in the one that ended.
+    let result = collection
+        .update_one(
+            doc! { "uuid": &job.uuid, "status": "running", "cancelled_at": null, "pid": pgid },
+            doc! { "$set": { "cancelled_by": &user.user_id, "cancelled_at": now } },
+        )
+        .await;
+
+    match result {
+        Ok(r) if r.matched_count == 0 => {
+            return Ok(Response::bad_request(
+                "That job is already finished, being cancelled or on to its next step; try again",
+            ));
+        }
+        Ok(_) => {}
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    }
+
+    let by = match &user.token_label {
+        Some(label) => format!("{} ({})", user.user_id, label),
+        None => user.user_id.clone(),
+    };
+    Audit::record(&req, Some(&user), "shell.cancel", &subject, AuditOutcome::Success, None).await;
+
+    let pgid = match pgid {
+        Some(pgid) => pgid,
+        None => {
+            append_log(&job.uuid, &format!("Cancelled by {}; no further steps will start.", by));
+            return Ok(HttpResponse::Accepted()
+                .content_type("application/json")
+                .json(Response { message: "Cancelling".to_string() }));
+        }
+    };
+    append_log(&job.uuid, &format!("Cancelled by {}; sending SIGTERM.", by));
+    signal_group(pgid, libc::SIGTERM);
+
+    let job_id = job.uuid.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(KILL_GRACE).await;
+        // Only while the job is still going in the group it was cancelled
+        // in. Once it has ended the pgid is free, and may have been taken by
+        // something that has nothing to do with it.
+        let current = collection.find_one(doc! { "uuid": &job_id }).await;
+        let same = matches!(
+            current,
+            Ok(Some(job)) if job.finished_at.is_none() && job.pid == Some(pgid)
+        );
+        // Signal 0 delivers nothing; it only asks whether the group exists.
+        if same && signal_group(pgid, 0) {
+            append_log(&job_id, "Still running after the grace period; sending SIGKILL.");
+            signal_group(pgid, libc::SIGKILL);
+        }
+    });
+
+    Ok(HttpResponse::Accepted()
+        .content_type("application/json")
+        .json(Response { message: "Cancelling".to_string() }))
+}
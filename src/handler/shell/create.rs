@@ -1,9 +1,12 @@
 /*
  * Upload a shell bundle from the dashboard.
  *
- * The zip goes over the wire as a JSON array of bytes, matching how
- * handler/documentation/create.rs takes its doc-site zip — the dashboard uses
- * one upload path for both.
+ * The archive — a zip, .tar or .tar.gz (utils/archive.rs) — comes as
+ * multipart/form-data: `name`, `description` and a `file` part, streamed to
+ * a temporary file and unpacked from there (utils/upload.rs), the same as
+ * handler/documentation/create.rs takes a doc site. The older JSON body,
+ * with the archive as an array of bytes, is still accepted by `task_json`
+ * and answered with a Deprecation header.
  *
  * What lands on disk here is scripts this server will later run as root, so
  * the gate is the same Administrator session every other route in this module
@@ -16,38 +19,102 @@ use mongodb::bson::doc;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use actix_multipart::Multipart;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 
 use crate::BuiltIns::mongo::MongoDB;
-use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement, User};
 use crate::Model::Account::AccountRole;
-use crate::Model::Shell::ShellBundle;
-use crate::utils::{archive, response::Response};
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellBundle, ShellBundleVersion, ShellLimits, ShellNotify};
+use crate::utils::{archive, response::Response, upload};
 
-use super::{is_valid_bundle, list_targets, shell_root};
+use super::version::{activate, version_dir, versions_dir};
+use super::{integrity, is_valid_bundle, list_targets, manifest, run_as, shell_root, signing};
 
-/// A bundle is a handful of scripts.
-const LIMITS: archive::Limits = archive::Limits {
+/// A bundle is a handful of scripts. Shared with update.rs.
+pub const LIMITS: archive::Limits = archive::Limits {
     max_entries: 500,
     max_total_bytes: 16 * 1024 * 1024,
+    // A bundle may link one script to another; nothing outside it.
+    symlinks: archive::Symlinks::Inside,
 };
 
+/// The archive as sent. Its contents are held to LIMITS once unpacking
+/// starts; this stops reading the upload itself. Shared with update.rs.
+pub const MAX_UPLOAD_BYTES: u64 = 16 * 1024 * 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestBody {
     name: String,
     description: String,
     file: Vec<u8>,
+    /// Detached Ed25519 signature over `file` (signing.rs).
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+pub async fn task(req: HttpRequest, payload: Multipart) -> Result<HttpResponse, Error> {
+    // Checked before a byte of the body is read.
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.upload", "", gate).await?;
+
+    let upload = match upload::receive(payload, "file", MAX_UPLOAD_BYTES).await {
+        Ok(upload) => upload,
+        Err(res) => return Ok(res),
+    };
+    let file = match &upload.file {
+        Some(file) if file.size > 0 => file,
+        _ => return Ok(Response::bad_request("An archive (.zip, .tar or .tar.gz) is required")),
+    };
+
+    create(
+        &req,
+        &user,
+        upload.field("name").to_lowercase(),
+        upload.field("description"),
+        archive::Source::File(&file.path),
+        upload.fields.get("signature").map(String::as_str),
+    )
+    .await
 }
 
-pub async fn task(
+/// The JSON body, deprecated in favour of `task`.
+pub async fn task_json(
     req: HttpRequest,
     form_data: web::Json<RequestBody>,
 ) -> Result<HttpResponse, Error> {
-    require_access(&req, AccessRequirement::Role(AccountRole::Administrator))?;
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.upload", "", gate).await?;
+    log::warn!("shell.upload: deprecated JSON upload from {}", user.user_id);
+
+    if form_data.file.is_empty() {
+        return Ok(upload::deprecated(Response::bad_request(
+            "An archive (.zip, .tar or .tar.gz) is required",
+        )));
+    }
 
-    let name = form_data.name.trim().to_lowercase();
-    let description = form_data.description.trim().to_string();
+    let res = create(
+        &req,
+        &user,
+        form_data.name.trim().to_lowercase(),
+        form_data.description.trim().to_string(),
+        archive::Source::Bytes(&form_data.file),
+        form_data.signature.as_deref(),
+    )
+    .await?;
+    Ok(upload::deprecated(res))
+}
 
+async fn create(
+    req: &HttpRequest,
+    user: &User,
+    name: String,
+    description: String,
+    source: archive::Source<'_>,
+    signature: Option<&str>,
+) -> Result<HttpResponse, Error> {
     if name.is_empty() {
         return Ok(Response::bad_request("Name is required"));
     }
@@ -59,9 +126,6 @@ pub async fn task(
     if description.is_empty() {
         return Ok(Response::bad_request("Description is required"));
     }
-    if form_data.file.is_empty() {
-        return Ok(Response::bad_request("Zip file is required"));
-    }
 
     let root = match shell_root() {
         Ok(root) => root,
@@ -70,7 +134,10 @@ pub async fn task(
             return Ok(Response::internal_server_error(&error));
         }
     };
-    let target_dir = root.join(&name);
+    // Every upload is a numbered version; SHELL_ROOT/<name> is a link to the
+    // active one (version.rs). This is version 1.
+    let link = root.join(&name);
+    let target_dir = version_dir(&root, &name, 1);
 
     let db = MongoDB.connect();
     let collection = db.collection::<ShellBundle>("shell_bundle");
@@ -91,51 +158,111 @@ pub async fn task(
         }
     }
 
-    if target_dir.exists() {
+    if fs::symlink_metadata(&link).is_ok() || versions_dir(&root, &name).exists() {
         return Ok(Response::bad_request(
             "A directory with that name is already on disk",
         ));
     }
 
-    if let Err(error) = archive::unzip(&form_data.file, &target_dir, &LIMITS) {
-        let _ = fs::remove_dir_all(&target_dir);
+    // Before anything is unpacked: a bad signature leaves nothing on disk.
+    let signed_by = match signing::check(&source, signature).await {
+        Ok(signed_by) => signed_by,
+        Err(res) => return Ok(res),
+    };
+
+    if let Err(error) = archive::extract(source, &target_dir, &LIMITS) {
+        let _ = fs::remove_dir_all(versions_dir(&root, &name));
         return Ok(Response::bad_request(&error));
     }
 
     // Without a main.sh the run routes have nothing to call, so this is not a
     // bundle — say so now rather than 404ing later from /targets.
     if !target_dir.join("main.sh").is_file() {
-        let _ = fs::remove_dir_all(&target_dir);
+        let _ = fs::remove_dir_all(versions_dir(&root, &name));
         return Ok(Response::bad_request(
             "The archive has no main.sh at its root",
         ));
     }
 
+    // Optional, but a bundle.json that is there and wrong is refused here,
+    // not discovered on the first run.
+    let manifest = match manifest::load(&target_dir) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            let _ = fs::remove_dir_all(versions_dir(&root, &name));
+            return Ok(Response::bad_request(&error));
+        }
+    };
+
+    // Hashed before --list runs, so nothing that run leaves behind is taken
+    // for part of the upload.
+    let files = match integrity::hash_tree(&target_dir) {
+        Ok(files) => files,
+        Err(error) => {
+            log::error!("{}", error);
+            let _ = fs::remove_dir_all(versions_dir(&root, &name));
+            return Ok(Response::internal_server_error(&error));
+        }
+    };
+
     // Read the targets once, here, so the dashboard doesn't shell out on every
-    // page load. A bundle whose --list fails still uploads: it may need root
-    // for something this process doesn't have, and that is a run-time problem.
-    let targets = list_targets(&target_dir).unwrap_or_default();
+    // page load. It runs as the default account, as a new bundle's jobs will
+    // (shell/run_as.rs). A bundle whose --list fails still uploads: it may
+    // need something that account doesn't have, and that is a run-time
+    // problem.
+    let targets = run_as::resolve(None)
+        .and_then(|account| list_targets(&target_dir, &account))
+        .unwrap_or_default();
 
+    if let Err(error) = activate(&root, &name, 1) {
+        log::error!("{}", error);
+        let _ = fs::remove_dir_all(versions_dir(&root, &name));
+        return Ok(Response::internal_server_error(&error));
+    }
+
+    let created_at = Utc::now().timestamp_millis();
     let bundle = ShellBundle {
         uuid: Uuid::now_v7().to_string(),
         name: name.clone(),
         description,
-        targets,
+        targets: targets.clone(),
+        version: 1,
+        versions: vec![ShellBundleVersion {
+            version: 1,
+            targets,
+            manifest: manifest.clone(),
+            files: files.clone(),
+            signed_by: signed_by.clone(),
+            created_at,
+            created_by: user.user_id.clone(),
+        }],
+        manifest,
+        files,
+        signed_by,
         // Opt-in from the dashboard, never on upload.
         public_run: false,
-        created_at: Utc::now().timestamp_millis(),
+        grants: Vec::new(),
+        requires_approval: Vec::new(),
+        limits: ShellLimits::default(),
+        notify: ShellNotify::default(),
+        created_at,
         created_by: "admin".to_string(),
         deleted_at: None,
+        run_as: None,
         deleted_by: None,
     };
 
     if let Err(error) = collection.insert_one(bundle.clone()).await {
         log::error!("{:?}", error);
         // Nothing should be left on disk under a name the database doesn't know.
-        let _ = fs::remove_dir_all(&target_dir);
+        let _ = fs::remove_file(&link);
+        let _ = fs::remove_dir_all(versions_dir(&root, &name));
         return Ok(Response::internal_server_error(&error.to_string()));
     }
 
+    let detail = bundle.signed_by.as_ref().map(|key| format!("signed by {}", key));
+    Audit::record(req, Some(user), "shell.upload", &name, AuditOutcome::Success, detail).await;
+
     Ok(HttpResponse::Ok()
         .content_type("application/json")
         .json(bundle))
@@ -0,0 +1,122 @@
+/*
+ * `DELETE /api/shell/{uuid}` — move a bundle to the trash (shell/trash.rs).
+ *
+ * Administrator-only, from the dashboard. The record is kept, stamped with
+ * deleted_at/deleted_by, so the bundle can be restored (shell/restore.rs)
+ * until it is purged. Refused while a job on it is queued, running or
+ * awaiting approval, the same as swapping its version. Its schedules, hooks
+ * and profiles go with it (trash::park).
+ */
+use std::fs;
+
+use chrono::Utc;
+use mongodb::bson::doc;
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::ShellBundle;
+use crate::utils::response::Response;
+
+use super::{lock, shell_root};
+use super::trash::{park, trash_dir, trash_root, unpark};
+use super::version::{activate, adopt_unversioned, busy, has_active_jobs, versions_dir};
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    uuid: String,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.delete", &path.uuid, gate).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let bundle = match collection.find_one(doc! { "uuid": &path.uuid, "deleted_at": null }).await {
+        Ok(Some(bundle)) => bundle,
+        Ok(None) => return Ok(Response::not_found("Bundle not found")),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let _paused = lock::pause().await;
+    match has_active_jobs(&bundle.name).await {
+        Ok(false) => {}
+        Ok(true) => return Ok(busy()),
+        Err(res) => return Ok(res),
+    }
+
+    let (root, trash) = match (shell_root(), trash_root()) {
+        (Ok(root), Ok(trash)) => (root, trash),
+        (Err(error), _) | (_, Err(error)) => {
+            log::error!("{}", error);
+            return Ok(Response::internal_server_error(&error));
+        }
+    };
+
+    if let Err(error) = adopt_unversioned(&root, &bundle.name) {
+        log::error!("{}", error);
+        return Ok(Response::internal_server_error(&error));
+    }
+
+    // First, so that nothing set up for this bundle can start a run on the
+    // next one to take its name.
+    if let Err(error) = park(&bundle.name, &bundle.uuid).await {
+        log::error!("shell bundle {}: {}", bundle.uuid, error);
+        return Ok(Response::internal_server_error(&error));
+    }
+
+    let versions = versions_dir(&root, &bundle.name);
+    let dest = trash_dir(&trash, &bundle.uuid);
+    let link = root.join(&bundle.name);
+
+    // A bundle whose files are already gone can still be deleted; there is
+    // just nothing to move.
+    let moved = versions.exists();
+    if moved {
+        let _ = fs::remove_dir_all(&dest);
+        if let Err(error) = fs::rename(&versions, &dest) {
+            log::error!("{}: {}", versions.display(), error);
+            let _ = unpark(&bundle.uuid, &bundle.name).await;
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    }
+    let _ = fs::remove_file(&link);
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &bundle.uuid, "deleted_at": null },
+            doc! { "$set": {
+                "deleted_at": Utc::now().timestamp_millis(),
+                "deleted_by": &user.user_id,
+            } },
+        )
+        .await;
+
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        if moved && fs::rename(&dest, &versions).is_ok() {
+            let _ = activate(&root, &bundle.name, bundle.version);
+        }
+        let _ = unpark(&bundle.uuid, &bundle.name).await;
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    Audit::record(&req, Some(&user), "shell.delete", &bundle.name, AuditOutcome::Success, None).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(
+        Response { message: "Moved to the trash".to_string() }
+    ))
+}
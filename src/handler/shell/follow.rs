@@ -0,0 +1,227 @@
+/*
+ * `GET /api/shell/{name}/jobs/{id}/logs?follow=1` — the job log as a
+ * server-sent event stream, for `ct shell logs -f` and `ct shell run --wait`.
+ *
+ * The log file is tailed rather than the child's pipes read: the file is
+ * already the one place a job's output goes (`launch` points stdout and
+ * stderr at it), so a follower that connects late, or reconnects, starts from
+ * byte zero and sees exactly what the plain route would have returned.
+ *
+ *   event: log      one line of output per event, as it is written
+ *   event: status   { uuid, status, exit_code }, once, after the last line —
+ *                   then the stream ends. Flat on purpose: a pipeline's
+ *                   record has a status per step as well, and a client
+ *                   without a JSON parser would have to tell them apart.
+ *                   The full record is at .../jobs/{id}.
+ *
+ * A job counts as over once its record has a finished_at, not when the file
+ * stops growing: a quiet apt step can go a minute without printing anything.
+ */
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use futures::stream;
+use mongodb::bson::doc;
+use mongodb::Collection;
+
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Model::Shell::ShellJob;
+
+use super::{log_path, retention};
+
+/// How often the file and the record are checked when nothing new has arrived.
+const POLL: Duration = Duration::from_millis(500);
+/// A comment line every so often keeps proxies from closing a connection
+/// that is only waiting on a slow step.
+const KEEP_ALIVE_POLLS: u32 = 30;
+
+struct Tail {
+    id: String,
+    collection: Collection<ShellJob>,
+    offset: u64,
+    /// Bytes after the last newline read so far — held back until the line
+    /// is complete, so an event never carries half a line.
+    partial: Vec<u8>,
+    idle: u32,
+    done: bool,
+}
+
+pub fn response(job: &ShellJob) -> HttpResponse {
+    let tail = Tail {
+        id: job.uuid.clone(),
+        collection: MongoDB.connect().collection::<ShellJob>("shell_job"),
+        offset: 0,
+        partial: Vec::new(),
+        idle: 0,
+        done: false,
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // nginx buffers proxied responses by default, which would hold every
+        // line back until the job ended.
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream::unfold(tail, next))
+}
+
+async fn next(mut tail: Tail) -> Option<(Result<Bytes, Error>, Tail)> {
+    if tail.done {
+        return None;
+    }
+
+    loop {
+        let lines = read_lines(&mut tail);
+        if !lines.is_empty() {
+            tail.idle = 0;
+            return Some((Ok(Bytes::from(log_events(&lines))), tail));
+        }
+
+        let record = match tail.collection.find_one(doc! { "uuid": &tail.id }).await {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                tail.done = true;
+                return None;
+            }
+            Err(error) => {
+                log::error!("{:?}", error);
+                tail.done = true;
+                return None;
+            }
+        };
+
+        if record.finished_at.is_some() {
+            // The record is written after the child exits, so anything that
+            // landed in the file between the last read and now is the tail end
+            // of the output — send it before the status.
+            let mut lines = read_lines(&mut tail);
+            if !tail.partial.is_empty() {
+                lines.push(String::from_utf8_lossy(&tail.partial).to_string());
+                tail.partial.clear();
+            }
+
+            let mut body = log_events(&lines);
+            body.push_str(&status_event(&record));
+            tail.done = true;
+            return Some((Ok(Bytes::from(body)), tail));
+        }
+
+        tokio::time::sleep(POLL).await;
+        tail.idle += 1;
+        if tail.idle >= KEEP_ALIVE_POLLS {
+            tail.idle = 0;
+            return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), tail));
+        }
+    }
+}
+
+/// Whatever complete lines have been appended since the last call.
+fn read_lines(tail: &mut Tail) -> Vec<String> {
+    let mut file = match fs::File::open(log_path(&tail.id)) {
+        Ok(file) => file,
+        // Long finished, and compressed since (shell/retention.rs): read it
+        // all at once.
+        Err(_) => {
+            let bytes = retention::read_bytes(&tail.id).unwrap_or_default();
+            let rest = bytes.get(tail.offset as usize..).unwrap_or_default();
+            tail.offset += rest.len() as u64;
+            tail.partial.extend_from_slice(rest);
+            return split_lines(&mut tail.partial);
+        }
+    };
+    if file.seek(SeekFrom::Start(tail.offset)).is_err() {
+        return Vec::new();
+    }
+
+    let mut buf = Vec::new();
+    let read = match file.read_to_end(&mut buf) {
+        Ok(read) => read,
+        Err(_) => return Vec::new(),
+    };
+    tail.offset += read as u64;
+    tail.partial.extend_from_slice(&buf);
+
+    split_lines(&mut tail.partial)
+}
+
+/// Take every newline-terminated line off the front of `buf`, leaving any
+/// unterminated remainder in place.
+fn split_lines(buf: &mut Vec<u8>) -> Vec<String> {
+    let end = match buf.iter().rposition(|b| *b == b'\n') {
+        Some(i) => i + 1,
+        None => return Vec::new(),
+    };
+
+    let complete: Vec<u8> = buf.drain(..end).collect();
+    String::from_utf8_lossy(&complete)
+        .split('\n')
+        .map(|l| l.trim_end_matches('\r').to_string())
+        .collect::<Vec<_>>()
+        .split_last()
+        .map(|(_, rest)| rest.to_vec())
+        .unwrap_or_default()
+}
+
+fn status_event(job: &ShellJob) -> String {
+    let data = serde_json::json!({
+        "uuid": job.uuid,
+        "status": job.status,
+        "exit_code": job.exit_code,
+    });
+    format!("event: status\ndata: {}\n\n", data)
+}
+
+fn log_events(lines: &[String]) -> String {
+    lines
+        .iter()
+        .map(|line| format!("event: log\ndata: {}\n\n", line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_complete_lines_are_taken() {
+        let mut buf = b"one\ntwo\r\nthr".to_vec();
+        assert_eq!(split_lines(&mut buf), vec!["one".to_string(), "two".to_string()]);
+        assert_eq!(buf, b"thr".to_vec());
+
+        buf.extend_from_slice(b"ee\n\n");
+        assert_eq!(split_lines(&mut buf), vec!["three".to_string(), String::new()]);
+        assert!(buf.is_empty());
+
+        let mut buf = b"no newline yet".to_vec();
+        assert!(split_lines(&mut buf).is_empty());
+        assert_eq!(buf, b"no newline yet".to_vec());
+    }
+
+    #[test]
+    fn the_status_event_carries_the_job_s_own_status() {
+        let job: ShellJob = serde_json::from_value(serde_json::json!({
+            "uuid": "j", "bundle": "vps-setup", "target": "ufw nginx", "user_id": "u", "token_label": "",
+            "kind": "run", "status": "failed", "exit_code": 1, "log_path": "", "started_at": 0,
+            "steps": [
+                { "target": "ufw", "status": "failed", "exit_code": 1 },
+                { "target": "nginx", "status": "success", "exit_code": 0 },
+            ],
+        }))
+        .unwrap();
+
+        assert_eq!(
+            status_event(&job),
+            "event: status\ndata: {\"uuid\":\"j\",\"status\":\"failed\",\"exit_code\":1}\n\n"
+        );
+    }
+
+    #[test]
+    fn every_line_is_its_own_event() {
+        let body = log_events(&["a".to_string(), String::new()]);
+        assert_eq!(body, "event: log\ndata: a\n\nevent: log\ndata: \n\n");
+    }
+}
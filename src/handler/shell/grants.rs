@@ -0,0 +1,434 @@
+/*
+ * Who besides an administrator may run a bundle.
+ *
+ *   POST   /api/shell/{uuid}/grants            { user_id | group, targets,
+ *                                                expires_at } — add one
+ *   DELETE /api/shell/{uuid}/grants/{grant}    remove it
+ *
+ *   GET    /api/shell/groups                   the groups
+ *   PUT    /api/shell/groups/{group}           { members: [user_id, ...] } —
+ *                                              create it, or replace its
+ *                                              members
+ *   DELETE /api/shell/groups/{group}
+ *
+ * All administrator-only, from the dashboard. A bundle's grants come back
+ * with it from the bundle list.
+ *
+ * `public_run` still opens a bundle to every ordinary account at once. A grant
+ * is the narrower alternative: one account, or the members of one group; all
+ * targets, or only those listed; until `expires_at`, or for good. `authorize`
+ * in handler/shell.rs asks `permits`. Running a target needs a grant that
+ * covers it; seeing the bundle's targets and jobs needs a grant of any kind.
+ *
+ * Expired grants, and grants to a group that has since been deleted, simply
+ * stop matching — they stay on the record until someone removes them, as a
+ * trace of who had access.
+ */
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, to_bson};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement, User};
+use crate::Model::Account::{AccountCore, AccountRole};
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellBundle, ShellGrant, ShellGroup};
+use crate::utils::response::Response;
+
+use super::is_valid_target;
+
+#[derive(Debug, Deserialize)]
+pub struct BundlePath {
+    uuid: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrantPath {
+    uuid: String,
+    grant: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupPath {
+    group: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrantBody {
+    user_id: Option<String>,
+    group: Option<String>,
+    #[serde(default)]
+    targets: Vec<String>,
+    expires_at: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupBody {
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+pub async fn add(
+    req: HttpRequest,
+    path: web::Path<BundlePath>,
+    form_data: web::Json<GrantBody>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.grant.add", &path.uuid, gate).await?;
+    let body = form_data.into_inner();
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let bundle = match collection.find_one(doc! { "uuid": &path.uuid, "deleted_at": null }).await {
+        Ok(Some(bundle)) => bundle,
+        Ok(None) => return Ok(Response::not_found("Bundle not found")),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let user_id = body.user_id.as_deref().map(str::trim).filter(|v| !v.is_empty());
+    let group = body.group.as_deref().map(str::trim).filter(|v| !v.is_empty());
+    match (user_id, group) {
+        (Some(user_id), None) => match accounts_exist(&[user_id.to_string()]).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(Response::not_found("No such account")),
+            Err(res) => return Ok(res),
+        },
+        (None, Some(group)) => match find_group(group).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(Response::not_found("No such group")),
+            Err(res) => return Ok(res),
+        },
+        _ => return Ok(Response::bad_request("Give either a user_id or a group, not both")),
+    }
+
+    let mut targets: Vec<String> = body.targets.iter().map(|t| t.trim().to_string()).collect();
+    targets.sort();
+    targets.dedup();
+    for target in &targets {
+        if !is_known_target(&bundle.targets, target) {
+            return Ok(Response::bad_request(&format!("{} has no target {}", bundle.name, target)));
+        }
+    }
+
+    let now = Utc::now().timestamp_millis();
+    if body.expires_at.map(|at| at <= now).unwrap_or(false) {
+        return Ok(Response::bad_request("expires_at is already past"));
+    }
+
+    let grant = ShellGrant {
+        uuid: Uuid::now_v7().to_string(),
+        user_id: user_id.map(str::to_string),
+        group: group.map(str::to_string),
+        targets,
+        expires_at: body.expires_at,
+        created_at: now,
+        created_by: user.user_id.clone(),
+    };
+
+    let bson = match to_bson(&grant) {
+        Ok(b) => b,
+        Err(error) => return Ok(Response::internal_server_error(&error.to_string())),
+    };
+    let result = collection
+        .update_one(doc! { "uuid": &bundle.uuid }, doc! { "$push": { "grants": bson } })
+        .await;
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let detail = Some(format!("{} to {}", grant.uuid, user_id.or(group).unwrap_or_default()));
+    Audit::record(&req, Some(&user), "shell.grant.add", &bundle.name, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(grant))
+}
+
+pub async fn remove(req: HttpRequest, path: web::Path<GrantPath>) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.grant.remove", &path.uuid, gate).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &path.uuid, "grants.uuid": &path.grant },
+            doc! { "$pull": { "grants": { "uuid": &path.grant } } },
+        )
+        .await;
+
+    match result {
+        Ok(r) if r.matched_count == 0 => Ok(Response::not_found("No such grant")),
+        Ok(_) => {
+            let detail = Some(path.grant.clone());
+            Audit::record(&req, Some(&user), "shell.grant.remove", &path.uuid, AuditOutcome::Success, detail).await;
+            Ok(HttpResponse::Ok().content_type("application/json").json(
+                Response { message: "Removed".to_string() }
+            ))
+        }
+        Err(error) => {
+            log::error!("{:?}", error);
+            Ok(Response::internal_server_error(&error.to_string()))
+        }
+    }
+}
+
+pub async fn groups(req: HttpRequest) -> Result<HttpResponse, Error> {
+    require_access(&req, AccessRequirement::Role(AccountRole::Administrator))?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellGroup>("shell_group");
+
+    let cursor = match collection.find(doc! {}).sort(doc! { "name": 1 }).await {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let groups: Vec<ShellGroup> = match cursor.try_collect().await {
+        Ok(v) => v,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(groups))
+}
+
+pub async fn set_group(
+    req: HttpRequest,
+    path: web::Path<GroupPath>,
+    form_data: web::Json<GroupBody>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.group.set", &path.group, gate).await?;
+
+    if !is_valid_group(&path.group) {
+        return Ok(Response::bad_request("Invalid group name"));
+    }
+
+    let mut members: Vec<String> = form_data.members.iter().map(|m| m.trim().to_string()).collect();
+    members.sort();
+    members.dedup();
+    match accounts_exist(&members).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(Response::not_found("Not every member is an account")),
+        Err(res) => return Ok(res),
+    }
+
+    let existing = match find_group(&path.group).await {
+        Ok(existing) => existing,
+        Err(res) => return Ok(res),
+    };
+    let group = ShellGroup {
+        name: path.group.clone(),
+        members,
+        created_at: existing.as_ref().map(|g| g.created_at).unwrap_or_else(|| Utc::now().timestamp_millis()),
+        created_by: existing.map(|g| g.created_by).unwrap_or_else(|| user.user_id.clone()),
+    };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellGroup>("shell_group");
+    let result = collection
+        .replace_one(doc! { "name": &group.name }, &group)
+        .upsert(true)
+        .await;
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let detail = Some(group.members.join(","));
+    Audit::record(&req, Some(&user), "shell.group.set", &group.name, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(group))
+}
+
+pub async fn delete_group(req: HttpRequest, path: web::Path<GroupPath>) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.group.delete", &path.group, gate).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellGroup>("shell_group");
+
+    match collection.delete_one(doc! { "name": &path.group }).await {
+        Ok(r) if r.deleted_count == 0 => Ok(Response::not_found("No such group")),
+        Ok(_) => {
+            Audit::record(&req, Some(&user), "shell.group.delete", &path.group, AuditOutcome::Success, None).await;
+            Ok(HttpResponse::Ok().content_type("application/json").json(
+                Response { message: "Deleted".to_string() }
+            ))
+        }
+        Err(error) => {
+            log::error!("{:?}", error);
+            Ok(Response::internal_server_error(&error.to_string()))
+        }
+    }
+}
+
+/// Whether `user`, not an administrator, may use `bundle`: run `target`, or
+/// with None, see the bundle at all.
+pub async fn permits(
+    bundle: &ShellBundle,
+    user: &User,
+    target: Option<&str>,
+) -> Result<bool, HttpResponse> {
+    if bundle.public_run {
+        return Ok(true);
+    }
+
+    // Only looked up when a group grant could make a difference.
+    let groups = if bundle.grants.iter().any(|g| g.group.is_some()) {
+        groups_of(&user.user_id).await?
+    } else {
+        Vec::new()
+    };
+
+    let now = Utc::now().timestamp_millis();
+    Ok(bundle
+        .grants
+        .iter()
+        .any(|grant| allows(grant, &user.user_id, &groups, target, now)))
+}
+
+fn allows(grant: &ShellGrant, user_id: &str, groups: &[String], target: Option<&str>, now: i64) -> bool {
+    let holder = match (&grant.user_id, &grant.group) {
+        (Some(id), _) => id == user_id,
+        (None, Some(group)) => groups.contains(group),
+        (None, None) => false,
+    };
+    let live = grant.expires_at.map(|at| at > now).unwrap_or(true);
+    let covers = match target {
+        Some(target) => grant.targets.is_empty() || grant.targets.iter().any(|t| t == target),
+        None => true,
+    };
+    holder && live && covers
+}
+
+/// A target as `run` would take it: one of the bundle's steps, `--full`, or
+/// `<step>-onwards`.
+fn is_known_target(targets: &[String], target: &str) -> bool {
+    if !is_valid_target(target) {
+        return false;
+    }
+    target == "--full"
+        || targets.iter().any(|t| t == target)
+        || target
+            .strip_suffix("-onwards")
+            .map(|step| targets.iter().any(|t| t == step))
+            .unwrap_or(false)
+}
+
+fn is_valid_group(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+async fn find_group(name: &str) -> Result<Option<ShellGroup>, HttpResponse> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellGroup>("shell_group");
+    collection.find_one(doc! { "name": name }).await.map_err(|error| {
+        log::error!("{:?}", error);
+        Response::internal_server_error(&error.to_string())
+    })
+}
+
+async fn groups_of(user_id: &str) -> Result<Vec<String>, HttpResponse> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellGroup>("shell_group");
+
+    let groups: Result<Vec<ShellGroup>, _> = match collection.find(doc! { "members": user_id }).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(error) => Err(error),
+    };
+    groups.map(|g| g.into_iter().map(|g| g.name).collect()).map_err(|error| {
+        log::error!("{:?}", error);
+        Response::internal_server_error(&error.to_string())
+    })
+}
+
+async fn accounts_exist(ids: &[String]) -> Result<bool, HttpResponse> {
+    if ids.is_empty() {
+        return Ok(true);
+    }
+    let db = MongoDB.connect();
+    let collection = db.collection::<AccountCore>("account_core");
+    collection
+        .count_documents(doc! { "uuid": { "$in": ids } })
+        .await
+        .map(|n| n as usize == ids.len())
+        .map_err(|error| {
+            log::error!("{:?}", error);
+            Response::internal_server_error(&error.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(user_id: Option<&str>, group: Option<&str>, targets: &[&str], expires_at: Option<i64>) -> ShellGrant {
+        ShellGrant {
+            uuid: "g".to_string(),
+            user_id: user_id.map(str::to_string),
+            group: group.map(str::to_string),
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            expires_at,
+            created_at: 0,
+            created_by: "admin".to_string(),
+        }
+    }
+
+    #[test]
+    fn a_grant_covers_its_holder_and_targets_only() {
+        let g = grant(Some("alice"), None, &["certbot"], None);
+        assert!(allows(&g, "alice", &[], Some("certbot"), 0));
+        assert!(!allows(&g, "alice", &[], Some("--full"), 0));
+        assert!(!allows(&g, "bob", &[], Some("certbot"), 0));
+        // Seeing the bundle needs a grant of any kind.
+        assert!(allows(&g, "alice", &[], None, 0));
+
+        let every = grant(Some("alice"), None, &[], None);
+        assert!(allows(&every, "alice", &[], Some("--full"), 0));
+    }
+
+    #[test]
+    fn group_grants_go_by_membership() {
+        let g = grant(None, Some("ops"), &[], None);
+        assert!(allows(&g, "bob", &["ops".to_string()], Some("ufw"), 0));
+        assert!(!allows(&g, "bob", &["dev".to_string()], Some("ufw"), 0));
+    }
+
+    #[test]
+    fn an_expired_grant_allows_nothing() {
+        let g = grant(Some("alice"), None, &[], Some(100));
+        assert!(allows(&g, "alice", &[], None, 99));
+        assert!(!allows(&g, "alice", &[], None, 100));
+    }
+
+    #[test]
+    fn granted_targets_must_exist() {
+        let targets = vec!["ufw".to_string(), "certbot".to_string()];
+        for good in ["ufw", "--full", "ufw-onwards"] {
+            assert!(is_known_target(&targets, good), "{}", good);
+        }
+        for bad in ["nginx", "nginx-onwards", "UFW", ""] {
+            assert!(!is_known_target(&targets, bad), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,540 @@
+/*
+ * Webhooks: start a bundle target from a git push or a CI job, with no `ct`
+ * session on the other end.
+ *
+ *   GET    /api/shell/{name}/hooks        the bundle's hooks
+ *   POST   /api/shell/{name}/hooks        { target, vars, secret } — make one
+ *   DELETE /api/shell/{name}/hooks/{id}   revoke it
+ *   POST   /api/shell/hooks/{id}          the hook itself
+ *
+ * The first three are administrator-only, with either credential — except
+ * making a hook, which sets up runs and so, like making a schedule, takes a
+ * CLI token and not the session cookie. A hook is bound to one target and a
+ * fixed set of variables when it is made; a delivery can't choose either, and
+ * its body is only signed, never read.
+ *
+ * The hook URL deliberately takes no session and no CLI token — whatever
+ * calls it has neither. It is authenticated by a per-hook key instead, handed
+ * out once when the hook is made:
+ *
+ *   X-Shell-Timestamp: <unix seconds>
+ *   X-Shell-Signature: sha256=<hex HMAC-SHA256(key, "<timestamp>.<body>")>
+ *
+ * A timestamp more than WINDOW_SECS away from the server's clock is refused,
+ * so a captured request can't be replayed later; inside the window, a
+ * signature a hook has already accepted is refused too, so it can't be
+ * replayed sooner either. From a shell:
+ *
+ *   ts=$(date +%s)
+ *   sig=$(printf '%s.%s' "$ts" "$body" | openssl dgst -sha256 -hmac "$key" -r | cut -d' ' -f1)
+ *   curl -X POST -H "X-Shell-Timestamp: $ts" -H "X-Shell-Signature: sha256=$sig" \
+ *        --data "$body" https://.../api/shell/hooks/<id>
+ *
+ * A secret variable's value is sealed, as a schedule's is.
+ *
+ * A delivery is started like a schedule firing — through the bundle's lock
+ * with `queue: true`, the job's token_label naming the hook — so two pushes
+ * in quick succession deploy one after the other. Like a schedule's, the job
+ * runs as the administrator who made the hook, and a hook whose maker no
+ * longer is one is revoked rather than delivered.
+ */
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use rand::Rng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_cli, AccessRequirement, User};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::ShellHook;
+use crate::utils::response::Response;
+
+use super::lock::{self, Admission};
+use super::redact::MASK;
+use super::{admin, bundle_dir, integrity, is_admin, is_valid_target, prepare_vars, profiles, Plan};
+
+/// How far a delivery's timestamp may be from the server's clock, either way.
+const WINDOW_SECS: i64 = 5 * 60;
+const KEY_BYTES: usize = 32;
+
+#[derive(Debug, Deserialize)]
+pub struct RequestBody {
+    target: String,
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    #[serde(default)]
+    secret: Vec<String>,
+}
+
+pub async fn task(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+    admin(&req).await?;
+    let bundle = path.into_inner();
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellHook>("shell_hook");
+
+    let cursor = match collection
+        .find(doc! { "bundle": &bundle })
+        .sort(doc! { "created_at": -1 })
+        .await
+    {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let hooks: Vec<ShellHook> = match cursor.try_collect().await {
+        Ok(v) => v,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let hooks: Vec<ShellHook> = hooks.into_iter().map(masked).collect();
+    Ok(HttpResponse::Ok().content_type("application/json").json(hooks))
+}
+
+pub async fn create(
+    req: HttpRequest,
+    path: web::Path<String>,
+    form_data: web::Json<RequestBody>,
+) -> Result<HttpResponse, Error> {
+    let bundle = path.into_inner();
+    let gate = require_cli(&req, AccessRequirement::Role(AccountRole::Administrator)).await;
+    let user = Audit::checked(&req, "shell.hook.create", &bundle, gate).await?;
+    let body = form_data.into_inner();
+
+    if let Err(res) = bundle_dir(&bundle) {
+        return Ok(res);
+    }
+    if !is_valid_target(&body.target) {
+        return Ok(Response::bad_request("Invalid target name"));
+    }
+
+    // As with a schedule: checked now, defaults left to each delivery.
+    let mut vars = body.vars;
+    let mut secret = body.secret;
+    if let Err(res) = prepare_vars(&bundle, &body.target, &mut vars.clone(), &mut secret).await {
+        return Ok(res);
+    }
+
+    let uuid = Uuid::now_v7().to_string();
+    let secrets = if vars.keys().any(|k| secret.contains(k)) {
+        let sealed = profiles::key()
+            .and_then(|key| profiles::seal_secrets(&key, &scope(&uuid), &mut vars, &secret));
+        match sealed {
+            Ok(secrets) => secrets,
+            Err(error) => return Ok(Response::bad_request(&error)),
+        }
+    } else {
+        HashMap::new()
+    };
+
+    let mut rng = rand::rng();
+    let mut key = [0u8; KEY_BYTES];
+    rng.fill(&mut key);
+
+    let hook = ShellHook {
+        uuid,
+        bundle,
+        target: body.target,
+        vars,
+        secret_keys: secret,
+        secrets,
+        secret: hex::encode(key),
+        created_at: Utc::now().timestamp_millis(),
+        created_by: user.user_id.clone(),
+        revoked_at: None,
+        last_delivery_at: None,
+        last_job: None,
+    };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellHook>("shell_hook");
+    if let Err(error) = collection.insert_one(hook.clone()).await {
+        log::error!("{:?}", error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let subject = format!("{}/{}", hook.bundle, hook.target);
+    let detail = Some(hook.uuid.clone());
+    Audit::record(&req, Some(&user), "shell.hook.create", &subject, AuditOutcome::Success, detail).await;
+
+    // The one response that carries the key.
+    let key = hook.secret.clone();
+    Ok(HttpResponse::Ok().content_type("application/json").json(ShellHook {
+        secret: key,
+        ..masked(hook)
+    }))
+}
+
+pub async fn revoke(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (bundle, id) = path.into_inner();
+    let user = Audit::checked(&req, "shell.hook.revoke", &bundle, admin(&req).await).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellHook>("shell_hook");
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &id, "bundle": &bundle, "revoked_at": null },
+            doc! { "$set": { "revoked_at": Utc::now().timestamp_millis() } },
+        )
+        .await;
+
+    match result {
+        Ok(result) if result.matched_count == 0 => Ok(Response::not_found("No such hook")),
+        Ok(_) => {
+            Audit::record(&req, Some(&user), "shell.hook.revoke", &bundle, AuditOutcome::Success, Some(id)).await;
+            Ok(HttpResponse::Ok().content_type("application/json").json(
+                Response { message: "Revoked".to_string() }
+            ))
+        }
+        Err(error) => {
+            log::error!("{:?}", error);
+            Ok(Response::internal_server_error(&error.to_string()))
+        }
+    }
+}
+
+/// POST /api/shell/hooks/{id}
+pub async fn deliver(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let id = path.into_inner();
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellHook>("shell_hook");
+
+    let hook = match collection.find_one(doc! { "uuid": &id, "revoked_at": null }).await {
+        Ok(Some(hook)) => hook,
+        Ok(None) => return Ok(Response::not_found("No such hook")),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.trim().to_string())
+    };
+    let now = Utc::now().timestamp();
+    let signature = header("X-Shell-Signature");
+    let checked = verify(
+        &hook.secret,
+        header("X-Shell-Timestamp").as_deref(),
+        signature.as_deref(),
+        &body,
+        now,
+    )
+    .and_then(|sent| {
+        let mut seen = seen().lock().unwrap_or_else(|e| e.into_inner());
+        let signature = signature.clone().unwrap_or_default();
+        if remember(&mut seen, (hook.uuid.clone(), signature), sent, now) {
+            Ok(())
+        } else {
+            Err("This delivery has already been accepted")
+        }
+    });
+    if let Err(error) = checked {
+        log::warn!("hook {}: {}", hook.uuid, error);
+        let detail = Some(format!("{}: {}", hook.uuid, error));
+        Audit::record(&req, None, "shell.run", &hook.bundle, AuditOutcome::Denied, detail).await;
+        return Ok(Response::unauthorized(error));
+    }
+
+    let dir = match bundle_dir(&hook.bundle) {
+        Ok(dir) => dir,
+        Err(res) => return Ok(res),
+    };
+
+    let mut vars = hook.vars.clone();
+    if !hook.secrets.is_empty() {
+        let opened = profiles::key()
+            .and_then(|key| profiles::open_secrets(&key, &scope(&hook.uuid), &hook.secrets));
+        match opened {
+            Ok(opened) => vars.extend(opened),
+            Err(error) => {
+                log::error!("hook {}: {}", hook.uuid, error);
+                return Ok(Response::internal_server_error(&error));
+            }
+        }
+    }
+    let mut secret = hook.secret_keys.clone();
+    if let Err(res) = prepare_vars(&hook.bundle, &hook.target, &mut vars, &mut secret).await {
+        return Ok(res);
+    }
+
+    let user = User {
+        user_id: hook.created_by.clone(),
+        role: AccountRole::Administrator,
+        token_label: Some(format!("hook {}", hook.uuid)),
+    };
+
+    let subject = format!("{}/{}", hook.bundle, hook.target);
+
+    // Runs as whoever made it, so only while they could still make it.
+    match is_admin(&hook.created_by).await {
+        Ok(true) => {}
+        Ok(false) => {
+            log::warn!(
+                "hook {}: {} is no longer an administrator; revoked",
+                hook.uuid,
+                hook.created_by
+            );
+            let _ = collection
+                .update_one(
+                    doc! { "uuid": &hook.uuid, "revoked_at": null },
+                    doc! { "$set": { "revoked_at": Utc::now().timestamp_millis() } },
+                )
+                .await;
+            let detail = Some(format!("hook {}: its owner is no longer an administrator", hook.uuid));
+            Audit::record(&req, None, "shell.run", &subject, AuditOutcome::Denied, detail).await;
+            return Ok(Response::not_found("No such hook"));
+        }
+        Err(error) => return Ok(Response::internal_server_error(&error)),
+    }
+
+    let files = match integrity::check(&hook.bundle, &dir).await {
+        Ok(files) => files,
+        Err(res) => {
+            let detail = Some(format!("integrity: {}", res.status()));
+            Audit::record(&req, Some(&user), "shell.run", &subject, AuditOutcome::Failed, detail).await;
+            return Ok(res);
+        }
+    };
+
+    let job = match lock::admit(&hook.bundle, files, &Plan::single(&hook.target), &user, vars, secret, true).await {
+        Ok(Admission::Started(job)) | Ok(Admission::Queued(job)) | Ok(Admission::Pending(job)) => {
+            Audit::record(&req, Some(&user), "shell.run", &subject, AuditOutcome::Success, Some(job.uuid.clone())).await;
+            job
+        }
+        Ok(Admission::Busy(holder)) => return Ok(HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(serde_json::json!({
+                "message": "Another job is running on this bundle",
+                "job": holder,
+            }))),
+        Err(error) => return Ok(Response::internal_server_error(&error)),
+    };
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &hook.uuid },
+            doc! { "$set": { "last_delivery_at": Utc::now().timestamp_millis(), "last_job": &job.uuid } },
+        )
+        .await;
+    if let Err(error) = result {
+        log::error!("hook {}: {:?}", hook.uuid, error);
+    }
+
+    Ok(HttpResponse::Accepted().content_type("application/json").json(job))
+}
+
+/// A hook as it goes out in a response: no key, secret values masked.
+fn masked(mut hook: ShellHook) -> ShellHook {
+    hook.secret = String::new();
+    for value in hook.secrets.values_mut() {
+        *value = MASK.to_string();
+    }
+    hook
+}
+
+/// What a hook's secrets are sealed under (profiles::seal_secrets).
+fn scope(uuid: &str) -> String {
+    format!("hook:{}", uuid)
+}
+
+/// Signatures each hook has accepted, keyed by (hook, signature), with the
+/// time past which the timestamp check would refuse them anyway. In memory:
+/// a restart forgets them, which leaves at most WINDOW_SECS in which one
+/// could be delivered a second time.
+fn seen() -> &'static Mutex<HashMap<(String, String), i64>> {
+    static SEEN: OnceLock<Mutex<HashMap<(String, String), i64>>> = OnceLock::new();
+    SEEN.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Note a verified delivery sent at `sent`. False if it has been seen before.
+/// Entries the window has closed on are dropped on the way.
+fn remember(seen: &mut HashMap<(String, String), i64>, key: (String, String), sent: i64, now: i64) -> bool {
+    seen.retain(|_, until| *until >= now);
+    match seen.entry(key) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(entry) => {
+            entry.insert(sent + WINDOW_SECS);
+            true
+        }
+    }
+}
+
+/// Check a delivery's signature headers against the hook's key, and return
+/// the timestamp it was signed with. `now` is in unix seconds.
+fn verify(
+    key: &str,
+    timestamp: Option<&str>,
+    signature: Option<&str>,
+    body: &[u8],
+    now: i64,
+) -> Result<i64, &'static str> {
+    let timestamp = timestamp.ok_or("Missing X-Shell-Timestamp")?;
+    let signature = signature.ok_or("Missing X-Shell-Signature")?;
+
+    let sent: i64 = timestamp.parse().map_err(|_| "X-Shell-Timestamp is not a number")?;
+    if (now - sent).abs() > WINDOW_SECS {
+        return Err("X-Shell-Timestamp is outside the allowed window");
+    }
+
+    let given = signature
+        .strip_prefix("sha256=")
+        .and_then(|h| hex::decode(h).ok())
+        .ok_or("X-Shell-Signature must be sha256=<hex>")?;
+
+    let mut message = Vec::with_capacity(timestamp.len() + 1 + body.len());
+    message.extend_from_slice(timestamp.as_bytes());
+    message.push(b'.');
+    message.extend_from_slice(body);
+
+    if !same(&hmac_sha256(key.as_bytes(), &message), &given) {
+        return Err("Bad signature");
+    }
+    Ok(sent)
+}
+
+/// HMAC-SHA256 (RFC 2104), on the sha2 crate the CLI tokens already hash with.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// Compare without stopping at the first difference, so the time taken says
+/// nothing about how much of a forged signature was right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(key: &str, timestamp: &str, body: &[u8]) -> String {
+        let message = [timestamp.as_bytes(), b".", body].concat();
+        format!("sha256={}", hex::encode(hmac_sha256(key.as_bytes(), &message)))
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        // Test case 2.
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // Test case 6: a key longer than the block is hashed first.
+        assert_eq!(
+            hex::encode(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn a_signed_delivery_inside_the_window_passes() {
+        let body = br#"{"ref":"refs/heads/main"}"#;
+        let signature = sign("k3y", "1000", body);
+        assert_eq!(verify("k3y", Some("1000"), Some(&signature), body, 1000 + WINDOW_SECS), Ok(1000));
+    }
+
+    #[test]
+    fn a_delivery_is_accepted_once() {
+        let mut seen = HashMap::new();
+        let key = |hook: &str| (hook.to_string(), "sha256=ab".to_string());
+
+        assert!(remember(&mut seen, key("h1"), 1000, 1000));
+        assert!(!remember(&mut seen, key("h1"), 1000, 1000 + WINDOW_SECS));
+        // The same signature on another hook is another delivery.
+        assert!(remember(&mut seen, key("h2"), 1000, 1001));
+
+        // Forgotten once verify would refuse it anyway.
+        remember(&mut seen, key("h3"), 2000, 1001 + WINDOW_SECS);
+        assert!(!seen.contains_key(&key("h1")));
+    }
+
+    #[test]
+    fn anything_else_is_refused() {
+        let body = b"payload";
+        let signature = sign("k3y", "1000", body);
+
+        // Replayed too late, or stamped too far ahead.
+        assert!(verify("k3y", Some("1000"), Some(&signature), body, 1001 + WINDOW_SECS).is_err());
+        assert!(verify("k3y", Some("1000"), Some(&signature), body, 999 - WINDOW_SECS).is_err());
+        // Another key, another body, another timestamp under the same signature.
+        assert!(verify("other", Some("1000"), Some(&signature), body, 1000).is_err());
+        assert!(verify("k3y", Some("1000"), Some(&signature), b"payloaD", 1000).is_err());
+        assert!(verify("k3y", Some("1001"), Some(&signature), body, 1000).is_err());
+        // Missing or malformed headers.
+        assert!(verify("k3y", None, Some(&signature), body, 1000).is_err());
+        assert!(verify("k3y", Some("1000"), None, body, 1000).is_err());
+        assert!(verify("k3y", Some("1000"), Some(&signature[7..]), body, 1000).is_err());
+    }
+
+    #[test]
+    fn the_key_never_leaves_but_once() {
+        let hook = ShellHook {
+            uuid: "h".to_string(),
+            bundle: "site".to_string(),
+            target: "deploy".to_string(),
+            vars: HashMap::from([("BRANCH".to_string(), "main".to_string())]),
+            secret_keys: vec!["DEPLOY_KEY".to_string()],
+            secrets: HashMap::from([("DEPLOY_KEY".to_string(), "c2VhbGVk".to_string())]),
+            secret: "abcd".to_string(),
+            created_at: 0,
+            created_by: "admin".to_string(),
+            revoked_at: None,
+            last_delivery_at: None,
+            last_job: None,
+        };
+
+        let out = serde_json::to_value(masked(hook)).unwrap();
+        assert!(out.get("secret").is_none());
+        assert_eq!(out["vars"]["BRANCH"], "main");
+        assert_eq!(out["secrets"]["DEPLOY_KEY"], MASK);
+    }
+}
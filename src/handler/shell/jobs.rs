@@ -0,0 +1,179 @@
+/*
+ * Job history, newest first.
+ *
+ *   GET /api/shell/{name}/jobs   one bundle's runs — `ct shell jobs`
+ *   GET /api/shell/jobs          every bundle's, for the dashboard
+ *
+ * Both take the same filters, all optional:
+ *
+ *   status, target, user_id,     exact match
+ *   kind (run | dry_run)
+ *   from, to                     started_at bounds, epoch millis, inclusive
+ *   limit, offset                20 by default, at most 100 per page
+ *
+ * plus `bundle` on the cross-bundle view. The per-bundle route sits behind the
+ * same `authorize` as running one; an ordinary account sees only the runs it
+ * started itself, since another user's target and variable names are theirs.
+ */
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Auth::{require_access, require_cli, AccessRequirement};
+use crate::Model::Account::AccountRole;
+use crate::Model::Shell::ShellJob;
+use crate::utils::response::Response;
+
+use super::authorize;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Params {
+    pub bundle: Option<String>,
+    pub status: Option<String>,
+    pub target: Option<String>,
+    pub user_id: Option<String>,
+    pub kind: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<Params>,
+) -> Result<HttpResponse, Error> {
+    let bundle = path.into_inner();
+    let user = match authorize(&req, &bundle, None).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res),
+    };
+
+    let mut filter = filter(&query);
+    filter.insert("bundle", &bundle);
+    if user.role != AccountRole::Administrator {
+        filter.insert("user_id", &user.user_id);
+    }
+
+    Ok(find(filter, &query).await)
+}
+
+/// Administrator-only, and like the bundle list it takes either credential:
+/// the dashboard reads it with its session, `ct` with a token.
+pub async fn all(req: HttpRequest, query: web::Query<Params>) -> Result<HttpResponse, Error> {
+    if require_access(&req, AccessRequirement::Role(AccountRole::Administrator)).is_err() {
+        require_cli(&req, AccessRequirement::Role(AccountRole::Administrator)).await?;
+    }
+
+    let mut filter = filter(&query);
+    if let Some(bundle) = non_empty(&query.bundle) {
+        filter.insert("bundle", bundle);
+    }
+
+    Ok(find(filter, &query).await)
+}
+
+async fn find(filter: Document, query: &Params) -> HttpResponse {
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let offset = query.offset.unwrap_or(0);
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    let cursor = collection
+        .find(filter)
+        .sort(doc! { "started_at": -1 })
+        .skip(offset)
+        .limit(limit)
+        .await;
+
+    let cursor = match cursor {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Response::internal_server_error(&error.to_string());
+        }
+    };
+
+    let jobs: Vec<ShellJob> = match cursor.try_collect().await {
+        Ok(v) => v,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Response::internal_server_error(&error.to_string());
+        }
+    };
+
+    HttpResponse::Ok().content_type("application/json").json(jobs)
+}
+
+/// The filters both routes share. Values are matched exactly, never as a
+/// pattern, so nothing here needs escaping.
+fn filter(query: &Params) -> Document {
+    let mut filter = doc! {};
+
+    if let Some(status) = non_empty(&query.status) {
+        filter.insert("status", status);
+    }
+    if let Some(target) = non_empty(&query.target) {
+        filter.insert("target", target);
+    }
+    if let Some(user_id) = non_empty(&query.user_id) {
+        filter.insert("user_id", user_id);
+    }
+    if let Some(kind) = non_empty(&query.kind) {
+        filter.insert("kind", kind);
+    }
+
+    let mut started = doc! {};
+    if let Some(from) = query.from {
+        started.insert("$gte", from);
+    }
+    if let Some(to) = query.to {
+        started.insert("$lte", to);
+    }
+    if !started.is_empty() {
+        filter.insert("started_at", started);
+    }
+
+    filter
+}
+
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_only_what_was_asked_for() {
+        assert_eq!(filter(&Params::default()), doc! {});
+
+        let query = Params {
+            status: Some("failed".to_string()),
+            target: Some("  ".to_string()),
+            from: Some(100),
+            to: Some(200),
+            ..Params::default()
+        };
+        assert_eq!(
+            filter(&query),
+            doc! { "status": "failed", "started_at": { "$gte": 100_i64, "$lte": 200_i64 } }
+        );
+    }
+
+    #[test]
+    fn an_open_ended_range_has_one_bound() {
+        let query = Params { to: Some(5), ..Params::default() };
+        assert_eq!(filter(&query), doc! { "started_at": { "$lte": 5_i64 } });
+    }
+}
@@ -0,0 +1,314 @@
+/*
+ * Per-bundle bounds on a run: a wall-clock timeout, and optionally CPU time,
+ * open files and output size (Model::Shell::ShellLimits).
+ *
+ *   PATCH /api/shell/{uuid}/limits   administrator, from the dashboard
+ *
+ * CPU time and open files are rlimits, set on the bash process between fork
+ * and exec and inherited by everything it starts. The wall clock and the
+ * output size are enforced by `watch`, which is how `launch`'s watcher waits
+ * on the child. Both are on the job, not the process: a pipeline's steps
+ * share one timeout, counted from when the job began. Past either, the job's
+ * process group gets SIGTERM, then SIGKILL after KILL_GRACE, and the job ends
+ * `timed_out` or `limit_exceeded` with a note in its log saying which.
+ *
+ * RLIMIT_CPU is per process, so it is the command that overran — apt, say —
+ * that the kernel kills with SIGXCPU, not bash. It counts as
+ * `limit_exceeded` when bash either died of that signal itself or passed the
+ * command's 128+SIGXCPU status on as its own, which `set -e` does.
+ */
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use mongodb::bson::{doc, to_bson};
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellBundle, ShellLimits};
+use crate::utils::response::Response;
+
+use super::{append_log, log_path, signal_group, KILL_GRACE};
+
+/// What a bundle gets when nobody has set a timeout for it. A full vps-setup
+/// run is well under this; a run still going after it is stuck.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60 * 60;
+/// A day. Past that a "timeout" isn't bounding anything.
+const MAX_TIMEOUT_SECS: u64 = 24 * 60 * 60;
+/// How often the running child is checked against the limits.
+const WATCH_POLL: Duration = Duration::from_millis(500);
+/// Seconds between the CPU soft limit's SIGXCPU and the hard limit's SIGKILL.
+const CPU_HARD_MARGIN: u64 = 5;
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    uuid: String,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+    form_data: web::Json<ShellLimits>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.limits", &path.uuid, gate).await?;
+
+    let limits = form_data.into_inner();
+    if let Err(error) = validate(&limits) {
+        return Ok(Response::bad_request(&error));
+    }
+
+    let limits = match to_bson(&limits) {
+        Ok(limits) => limits,
+        Err(error) => return Ok(Response::bad_request(&error.to_string())),
+    };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &path.uuid, "deleted_at": null },
+            doc! { "$set": { "limits": limits } },
+        )
+        .await;
+
+    let update_result = match result {
+        Ok(r) => r,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    if update_result.matched_count == 0 {
+        return Ok(Response::not_found("Bundle not found"));
+    }
+
+    Audit::record(&req, Some(&user), "shell.limits", &path.uuid, AuditOutcome::Success, None).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(
+        Response { message: "Updated".to_string() }
+    ))
+}
+
+fn validate(limits: &ShellLimits) -> Result<(), String> {
+    let fields = [
+        ("timeout_secs", limits.timeout_secs),
+        ("cpu_secs", limits.cpu_secs),
+        ("max_open_files", limits.max_open_files),
+        ("max_output_bytes", limits.max_output_bytes),
+    ];
+    for (name, value) in fields {
+        if value == Some(0) {
+            return Err(format!("{} must be above zero, or left out for no limit", name));
+        }
+    }
+
+    if limits.timeout_secs.unwrap_or(0) > MAX_TIMEOUT_SECS {
+        return Err(format!("timeout_secs may be at most {}", MAX_TIMEOUT_SECS));
+    }
+    // bash itself, the script, and whatever it opens have to fit.
+    if limits.max_open_files.map(|n| n < 16).unwrap_or(false) {
+        return Err("max_open_files must be at least 16".to_string());
+    }
+    Ok(())
+}
+
+/// The limits stored for a bundle, or none but the default timeout if it has
+/// no record — an administrator can run a bundle that exists only on disk.
+pub async fn for_bundle(bundle: &str) -> ShellLimits {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    match collection.find_one(doc! { "name": bundle, "deleted_at": null }).await {
+        Ok(Some(record)) => record.limits,
+        Ok(None) => ShellLimits::default(),
+        Err(error) => {
+            log::error!("{:?}", error);
+            ShellLimits::default()
+        }
+    }
+}
+
+/// Have the spawned bash set its own rlimits before exec.
+pub fn apply(command: &mut Command, limits: &ShellLimits) {
+    let cpu = limits.cpu_secs;
+    let files = limits.max_open_files;
+    if cpu.is_none() && files.is_none() {
+        return;
+    }
+
+    // SAFETY: runs in the forked child before exec, where only
+    // async-signal-safe calls are allowed; setrlimit(2) is one, and nothing
+    // here allocates.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(secs) = cpu {
+                let limit = rlimit(secs, secs + CPU_HARD_MARGIN);
+                if libc::setrlimit(libc::RLIMIT_CPU, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(n) = files {
+                let limit = rlimit(n, n);
+                if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+fn rlimit(soft: u64, hard: u64) -> libc::rlimit {
+    libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    }
+}
+
+/// A limit `watch` stopped a job for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Breach {
+    Timeout,
+    Output,
+}
+
+impl Breach {
+    pub fn status(&self) -> &'static str {
+        match self {
+            Breach::Timeout => "timed_out",
+            Breach::Output => "limit_exceeded",
+        }
+    }
+}
+
+/// Wait for the child, stopping its process group if the job it belongs to,
+/// which began at `started`, outlives the wall clock or outgrows the output
+/// limit. Returns how it exited and, if a limit ended it, which one.
+pub async fn watch(
+    child: &mut Child,
+    id: &str,
+    pgid: i32,
+    limits: &ShellLimits,
+    started: Instant,
+) -> (io::Result<ExitStatus>, Option<Breach>) {
+    let timeout = Duration::from_secs(limits.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+
+    let mut breach: Option<(Breach, Instant)> = None;
+    let mut killed = false;
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return (Ok(status), breach.map(|(b, _)| b)),
+            Ok(None) => {}
+            Err(e) => return (Err(e), breach.map(|(b, _)| b)),
+        }
+
+        match breach {
+            None => {
+                let output = std::fs::metadata(log_path(id)).map(|m| m.len()).unwrap_or(0);
+                if let Some((found, note)) = check(started.elapsed(), timeout, output, limits) {
+                    append_log(id, &format!("{}; sending SIGTERM.", note));
+                    signal_group(pgid, libc::SIGTERM);
+                    breach = Some((found, Instant::now()));
+                }
+            }
+            Some((_, since)) if !killed && since.elapsed() >= KILL_GRACE => {
+                append_log(id, "Still running after the grace period; sending SIGKILL.");
+                signal_group(pgid, libc::SIGKILL);
+                killed = true;
+            }
+            Some(_) => {}
+        }
+
+        tokio::time::sleep(WATCH_POLL).await;
+    }
+}
+
+/// Which limit, if any, a run is past — and the log note saying so.
+fn check(
+    elapsed: Duration,
+    timeout: Duration,
+    output: u64,
+    limits: &ShellLimits,
+) -> Option<(Breach, String)> {
+    if elapsed >= timeout {
+        return Some((
+            Breach::Timeout,
+            format!("Timed out after {}s", timeout.as_secs()),
+        ));
+    }
+    if let Some(max) = limits.max_output_bytes {
+        if output > max {
+            return Some((
+                Breach::Output,
+                format!("Output passed the {} byte limit", max),
+            ));
+        }
+    }
+    None
+}
+
+/// Whether an exit is the CPU rlimit's doing. See the note at the top.
+pub fn hit_cpu_limit(status: &ExitStatus, limits: &ShellLimits) -> bool {
+    limits.cpu_secs.is_some()
+        && (status.signal() == Some(libc::SIGXCPU) || status.code() == Some(128 + libc::SIGXCPU))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_and_oversized_limits_are_refused() {
+        assert!(validate(&ShellLimits::default()).is_ok());
+
+        let limits = ShellLimits { timeout_secs: Some(0), ..ShellLimits::default() };
+        assert!(validate(&limits).is_err());
+
+        let limits = ShellLimits { timeout_secs: Some(MAX_TIMEOUT_SECS + 1), ..ShellLimits::default() };
+        assert!(validate(&limits).is_err());
+
+        let limits = ShellLimits { max_open_files: Some(4), ..ShellLimits::default() };
+        assert!(validate(&limits).is_err());
+    }
+
+    #[test]
+    fn the_wall_clock_is_checked_before_output() {
+        let limits = ShellLimits { max_output_bytes: Some(10), ..ShellLimits::default() };
+        let timeout = Duration::from_secs(60);
+
+        assert!(check(Duration::from_secs(1), timeout, 5, &limits).is_none());
+        assert_eq!(
+            check(Duration::from_secs(1), timeout, 11, &limits).map(|(b, _)| b),
+            Some(Breach::Output)
+        );
+        assert_eq!(
+            check(Duration::from_secs(60), timeout, 11, &limits).map(|(b, _)| b),
+            Some(Breach::Timeout)
+        );
+        // No output limit set: any size is fine.
+        assert!(check(Duration::from_secs(1), timeout, u64::MAX, &ShellLimits::default()).is_none());
+    }
+
+    #[test]
+    fn a_command_killed_for_cpu_counts_only_with_a_cpu_limit() {
+        let limits = ShellLimits { cpu_secs: Some(5), ..ShellLimits::default() };
+        let by_signal = ExitStatus::from_raw(libc::SIGXCPU);
+        let passed_on = ExitStatus::from_raw((128 + libc::SIGXCPU) << 8);
+
+        assert!(hit_cpu_limit(&by_signal, &limits));
+        assert!(hit_cpu_limit(&passed_on, &limits));
+        assert!(!hit_cpu_limit(&ExitStatus::from_raw(1 << 8), &limits));
+        assert!(!hit_cpu_limit(&by_signal, &ShellLimits::default()));
+    }
+}
@@ -1,7 +1,8 @@
 use futures_util::TryStreamExt;
 use mongodb::bson::doc;
+use serde::Deserialize;
 
-use actix_web::{Error, HttpRequest, HttpResponse};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
 
 use crate::BuiltIns::mongo::MongoDB;
 use crate::Middleware::Auth::{require_access, require_cli, AccessRequirement};
@@ -9,6 +10,16 @@ use crate::Model::Account::AccountRole;
 use crate::Model::Shell::ShellBundle;
 use crate::utils::response::Response;
 
+use super::is_set;
+
+#[derive(Debug, Deserialize)]
+pub struct Params {
+    /// `?trash=1`: the deleted bundles still waiting to be purged
+    /// (shell/trash.rs), instead of the live ones.
+    #[serde(default)]
+    pub trash: Option<String>,
+}
+
 /// The bundles this server can run, newest first.
 ///
 /// The one shell route that takes either credential: the dashboard lists them
@@ -16,7 +27,7 @@ use crate::utils::response::Response;
 /// terminal. Reading names and descriptions executes nothing, so it does not
 /// warrant the stricter gate the run routes use — the session is tried first
 /// because a browser call carries fetch metadata that require_cli refuses.
-pub async fn task(req: HttpRequest) -> Result<HttpResponse, Error> {
+pub async fn task(req: HttpRequest, query: web::Query<Params>) -> Result<HttpResponse, Error> {
     if require_access(&req, AccessRequirement::Role(AccountRole::Administrator)).is_err() {
         require_cli(&req, AccessRequirement::Role(AccountRole::Administrator)).await?;
     }
@@ -24,8 +35,14 @@ pub async fn task(req: HttpRequest) -> Result<HttpResponse, Error> {
     let db = MongoDB.connect();
     let collection = db.collection::<ShellBundle>("shell_bundle");
 
+    let filter = if is_set(&query.trash) {
+        doc! { "deleted_at": { "$ne": null } }
+    } else {
+        doc! { "deleted_at": null }
+    };
+
     let cursor = collection
-        .find(doc! { "deleted_at": null })
+        .find(filter)
         .sort(doc! { "created_at": -1 })
         .await;
 
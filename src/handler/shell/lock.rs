@@ -0,0 +1,265 @@
+/*
+ * One run at a time per bundle — or, with SHELL_GLOBAL_LOCK=true in the
+ * environment, one at a time across all of them.
+ *
+ * Two vps-setup runs at once are two root processes editing the same apt
+ * state and sshd_config, and two writers of one /etc/<bundle>/vars.env. So a
+ * run takes its bundle's lock before anything is written, and a second caller
+ * gets a 409 naming the job that holds it — or, having asked with
+ * `queue: true`, a `queued` job that launches when the lock frees.
+ *
+ * The lock and the queue live in memory. That is enough because they only
+ * have to be right for as long as this process is: a restart interrupts every
+ * running and queued job (reconcile_interrupted), so there is nothing left to
+ * hold a lock for. Keeping the queue out of the database is also what keeps a
+ * queued run's variable values out of it — they may be passwords.
+ */
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::OnceLock;
+
+use chrono::Utc;
+use futures::future::BoxFuture;
+use mongodb::bson::doc;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Auth::User;
+use crate::Model::Shell::ShellJob;
+
+use super::integrity::Checked;
+use super::{approval, launch, record_job, Plan};
+
+/// What became of a run request.
+pub enum Admission {
+    /// It held the lock and main.sh is running.
+    Started(ShellJob),
+    /// The lock was held and the caller asked to wait.
+    Queued(ShellJob),
+    /// The lock was held by this job, and the caller didn't ask to wait.
+    Busy(String),
+    /// A target needs a second administrator first (shell/approval.rs).
+    Pending(ShellJob),
+}
+
+struct Queued {
+    job: ShellJob,
+    files: Checked,
+    vars: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct Runner {
+    /// Lock key -> the job holding it.
+    held: HashMap<String, String>,
+    /// Lock key -> the runs waiting on it, oldest first.
+    queued: HashMap<String, VecDeque<Queued>>,
+}
+
+/// An async mutex, because the critical section awaits: the job record is
+/// written while it is held, so two callers can't both see a free lock.
+fn runner() -> &'static Mutex<Runner> {
+    static RUNNER: OnceLock<Mutex<Runner>> = OnceLock::new();
+    RUNNER.get_or_init(|| Mutex::new(Runner::default()))
+}
+
+fn global() -> bool {
+    env::var("SHELL_GLOBAL_LOCK").map(|v| v == "true").unwrap_or(false)
+}
+
+/// Under the global lock every bundle shares one key.
+fn key_for(bundle: &str, global: bool) -> String {
+    if global {
+        "*".to_string()
+    } else {
+        bundle.to_string()
+    }
+}
+
+/// No run is admitted or approved while one of these is held. Taken by the
+/// handlers that swap or trash a bundle's files (shell/version.rs), so that
+/// their check for active jobs and the change they then make can't have a
+/// run recorded in between.
+pub struct Paused {
+    _runner: MutexGuard<'static, Runner>,
+}
+
+pub async fn pause() -> Paused {
+    Paused { _runner: runner().lock().await }
+}
+
+/// Start, queue or hold a run from `files`, which the caller has checked
+/// (shell/integrity.rs) before coming here: the hashing is too slow to do
+/// with every bundle's runs waiting.
+pub async fn admit(
+    bundle: &str,
+    files: Checked,
+    plan: &Plan,
+    user: &User,
+    vars: HashMap<String, String>,
+    secret_keys: Vec<String>,
+    queue: bool,
+) -> Result<Admission, String> {
+    // Taken before a run is held for approval as well: a pending_approval
+    // job counts as active to pause()'s callers too.
+    let mut runner = runner().lock().await;
+
+    if approval::required(bundle, &plan.targets).await? {
+        let job = approval::hold(bundle, files.dir, plan, user, vars, secret_keys).await?;
+        return Ok(Admission::Pending(job));
+    }
+
+    let key = key_for(bundle, global());
+
+    let mut var_keys: Vec<String> = vars.keys().cloned().collect();
+    var_keys.sort();
+
+    if let Some(holder) = runner.held.get(&key).cloned() {
+        if !queue {
+            return Ok(Admission::Busy(holder));
+        }
+
+        let job = record_job(bundle, plan, user, var_keys, secret_keys, "queued").await?;
+        runner.queued.entry(key).or_default().push_back(Queued {
+            job: job.clone(),
+            files,
+            vars,
+        });
+        return Ok(Admission::Queued(job));
+    }
+
+    let job = record_job(bundle, plan, user, var_keys, secret_keys, "running").await?;
+    runner.held.insert(key.clone(), job.uuid.clone());
+
+    match launch(job, &files, &vars).await {
+        Ok(job) => Ok(Admission::Started(job)),
+        Err(error) => {
+            runner.held.remove(&key);
+            Err(error)
+        }
+    }
+}
+
+/// Start a run a second administrator has just approved: its job is already
+/// recorded. It queues if the lock is held — whoever asked for it can't be
+/// asked again whether to wait. `files` checked first, as for `admit`.
+pub async fn admit_approved(
+    mut job: ShellJob,
+    files: Checked,
+    vars: HashMap<String, String>,
+) -> Result<ShellJob, String> {
+    let key = key_for(&job.bundle, global());
+    let mut runner = runner().lock().await;
+
+    if runner.held.contains_key(&key) {
+        let db = MongoDB.connect();
+        let collection = db.collection::<ShellJob>("shell_job");
+        let result = collection
+            .update_one(doc! { "uuid": &job.uuid }, doc! { "$set": { "status": "queued" } })
+            .await;
+        if let Err(error) = result {
+            log::error!("{:?}", error);
+            return Err(error.to_string());
+        }
+        job.status = "queued".to_string();
+        runner.queued.entry(key).or_default().push_back(Queued {
+            job: job.clone(),
+            files,
+            vars,
+        });
+        return Ok(job);
+    }
+
+    runner.held.insert(key.clone(), job.uuid.clone());
+    match launch(job, &files, &vars).await {
+        Ok(job) => Ok(job),
+        Err(error) => {
+            runner.held.remove(&key);
+            Err(error)
+        }
+    }
+}
+
+/// Give up the lock a finished job held, and launch whatever was queued
+/// behind it.
+///
+/// Boxed because it is called from the watcher `launch` spawns, and launching
+/// the next job spawns another watcher — as a plain async fn the future would
+/// contain itself.
+pub fn release(bundle: String, id: String) -> BoxFuture<'static, ()> {
+    Box::pin(async move {
+        let key = key_for(&bundle, global());
+        let mut runner = runner().lock().await;
+
+        if runner.held.get(&key) != Some(&id) {
+            return;
+        }
+        runner.held.remove(&key);
+
+        // A queued run that fails to launch (its vars file can't be written,
+        // bash can't be spawned) is recorded failed by launch itself; the
+        // lock then passes straight on to the one after it.
+        while let Some(next) = runner.queued.get_mut(&key).and_then(|q| q.pop_front()) {
+            let next_id = next.job.uuid.clone();
+            runner.held.insert(key.clone(), next_id.clone());
+
+            match launch(next.job, &next.files, &next.vars).await {
+                Ok(_) => return,
+                Err(error) => {
+                    log::error!("shell job {}: {}", next_id, error);
+                    runner.held.remove(&key);
+                }
+            }
+        }
+    })
+}
+
+/// Take a queued job out of the queue and record it cancelled. False if it
+/// wasn't waiting — it has started since, or was never queued.
+pub async fn dequeue(id: &str, cancelled_by: &str) -> bool {
+    let mut runner = runner().lock().await;
+
+    let mut found = false;
+    for queue in runner.queued.values_mut() {
+        let before = queue.len();
+        queue.retain(|q| q.job.uuid != id);
+        found |= queue.len() != before;
+    }
+    if !found {
+        return false;
+    }
+
+    let now = Utc::now().timestamp_millis();
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": id },
+            doc! { "$set": {
+                "status": "cancelled",
+                "cancelled_by": cancelled_by,
+                "cancelled_at": now,
+                "finished_at": now,
+            } },
+        )
+        .await;
+
+    if let Err(error) = result {
+        log::error!("shell job {}: {:?}", id, error);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_global_lock_is_one_key_for_every_bundle() {
+        assert_eq!(key_for("vps-setup", false), "vps-setup");
+        assert_ne!(key_for("vps-setup", false), key_for("deploy", false));
+        assert_eq!(key_for("vps-setup", true), key_for("deploy", true));
+    }
+}
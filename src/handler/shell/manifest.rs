@@ -0,0 +1,360 @@
+/*
+ * A bundle's optional bundle.json (Model::Shell::ShellManifest).
+ *
+ *   {
+ *     "targets": [
+ *       { "name": "sshd-config", "description": "Harden sshd",
+ *         "vars": ["SSH_PORT", "ADMIN_EMAIL"] }
+ *     ],
+ *     "vars": [
+ *       { "name": "SSH_PORT", "type": "int", "default": "22",
+ *         "pattern": "[0-9]{2,5}" },
+ *       { "name": "ADMIN_EMAIL", "type": "email" },
+ *       { "name": "MODE", "type": "enum", "options": ["strict", "lax"] }
+ *     ]
+ *   }
+ *
+ * Types are string, int, bool ("true"/"false"), enum, email and hostname.
+ * Values stay strings either way — they end up in a shell env file — the
+ * type only decides what is accepted.
+ *
+ * The manifest is read and checked once, on upload (create.rs, update.rs),
+ * and stored on the bundle record. A bundle without one behaves as before:
+ * `describe` asks main.sh, and `run` takes any well-formed name.
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use mongodb::bson::doc;
+use regex::Regex;
+use serde::Serialize;
+
+use actix_web::HttpResponse;
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Model::Shell::{ShellBundle, ShellManifest, ShellManifestTarget, ShellVar, ShellVarType};
+use crate::utils::response::Response;
+use crate::utils::validation::validate_email;
+
+use super::check_var;
+use super::profiles::Coverage;
+use super::redact::MASK;
+
+const MANIFEST_FILE: &str = "bundle.json";
+/// A manifest is a description, not a payload.
+const MAX_MANIFEST_BYTES: u64 = 256 * 1024;
+
+/// What `describe` answers for a bundle with a manifest. `vars` keeps the
+/// bare names the plain answer has, so a client that only reads those works
+/// against either kind of bundle.
+#[derive(Debug, Serialize)]
+pub struct Schema<'a> {
+    pub target: &'a str,
+    pub description: &'a str,
+    pub vars: Vec<&'a str>,
+    pub schema: Vec<ShellVar>,
+    /// Filled in by `describe` (shell/profiles.rs).
+    pub profiles: Vec<Coverage>,
+}
+
+/// Read and check `bundle.json` from an unpacked bundle. None if it has none.
+pub fn load(dir: &Path) -> Result<Option<ShellManifest>, String> {
+    let path = dir.join(MANIFEST_FILE);
+    let meta = match fs::metadata(&path) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(None),
+    };
+    if !meta.is_file() {
+        return Err(format!("{} is not a file", MANIFEST_FILE));
+    }
+    if meta.len() > MAX_MANIFEST_BYTES {
+        return Err(format!("{} is larger than {} bytes", MANIFEST_FILE, MAX_MANIFEST_BYTES));
+    }
+
+    let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", MANIFEST_FILE, e))?;
+    let manifest: ShellManifest =
+        serde_json::from_str(&text).map_err(|e| format!("{}: {}", MANIFEST_FILE, e))?;
+    check(&manifest)?;
+    Ok(Some(manifest))
+}
+
+/// Everything about a manifest that can be wrong before a value is ever
+/// checked against it.
+fn check(manifest: &ShellManifest) -> Result<(), String> {
+    let mut seen: Vec<&str> = Vec::new();
+    for var in &manifest.vars {
+        check_var(&var.name, "").map_err(|e| format!("{}: {}", MANIFEST_FILE, e))?;
+        if seen.contains(&var.name.as_str()) {
+            return Err(format!("{}: {} is declared twice", MANIFEST_FILE, var.name));
+        }
+        seen.push(&var.name);
+
+        if var.kind == ShellVarType::Enum && var.options.is_empty() {
+            return Err(format!("{}: enum {} has no options", MANIFEST_FILE, var.name));
+        }
+        if let Some(pattern) = &var.pattern {
+            anchored(pattern)
+                .map_err(|e| format!("{}: pattern for {}: {}", MANIFEST_FILE, var.name, e))?;
+        }
+        if let Some(default) = &var.default {
+            check_value(var, default)
+                .map_err(|e| format!("{}: default for {}", MANIFEST_FILE, e))?;
+        }
+    }
+
+    for target in &manifest.targets {
+        for name in &target.vars {
+            if !seen.contains(&name.as_str()) {
+                return Err(format!(
+                    "{}: target {} reads {}, which is not declared",
+                    MANIFEST_FILE, target.name, name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The manifest stored for a bundle, if its record has one. A bundle that
+/// exists only on disk has no record, and so no manifest.
+pub async fn for_bundle(bundle: &str) -> Result<Option<ShellManifest>, HttpResponse> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    match collection.find_one(doc! { "name": bundle, "deleted_at": null }).await {
+        Ok(record) => Ok(record.and_then(|r| r.manifest)),
+        Err(error) => {
+            log::error!("{:?}", error);
+            Err(Response::internal_server_error(&error.to_string()))
+        }
+    }
+}
+
+fn target<'a>(manifest: &'a ShellManifest, name: &str) -> Option<&'a ShellManifestTarget> {
+    manifest.targets.iter().find(|t| t.name == name)
+}
+
+fn var<'a>(manifest: &'a ShellManifest, name: &str) -> Option<&'a ShellVar> {
+    manifest.vars.iter().find(|v| v.name == name)
+}
+
+/// What a target reads. A target the manifest doesn't list — `--full`,
+/// `<step>-onwards` — is taken to read every declared variable, since it may
+/// run any step.
+fn reads<'a>(manifest: &'a ShellManifest, name: &str) -> Vec<&'a ShellVar> {
+    match target(manifest, name) {
+        Some(t) => t.vars.iter().filter_map(|v| var(manifest, v)).collect(),
+        None => manifest.vars.iter().collect(),
+    }
+}
+
+/// The typed answer to `describe`, with a secret's default masked: it is a
+/// value like any other, and this goes to whoever may run the bundle.
+pub fn schema<'a>(manifest: &'a ShellManifest, name: &'a str) -> Schema<'a> {
+    let description = target(manifest, name)
+        .map(|t| t.description.as_str())
+        .unwrap_or("");
+
+    let schema: Vec<ShellVar> = reads(manifest, name)
+        .into_iter()
+        .map(|v| {
+            let mut v = v.clone();
+            if v.secret && v.default.is_some() {
+                v.default = Some(MASK.to_string());
+            }
+            v
+        })
+        .collect();
+
+    Schema {
+        target: name,
+        description,
+        vars: reads(manifest, name).into_iter().map(|v| v.name.as_str()).collect(),
+        schema,
+        profiles: Vec::new(),
+    }
+}
+
+/// Every variable the manifest marks secret.
+pub fn secrets(manifest: &ShellManifest) -> Vec<String> {
+    manifest
+        .vars
+        .iter()
+        .filter(|v| v.secret)
+        .map(|v| v.name.clone())
+        .collect()
+}
+
+/// Check a run's variables against the manifest, and add the defaults for
+/// whatever the target reads that is neither supplied nor already in
+/// vars.env (`existing`).
+pub fn validate(
+    manifest: &ShellManifest,
+    name: &str,
+    vars: &mut HashMap<String, String>,
+    existing: &[String],
+) -> Result<(), String> {
+    for (key, value) in vars.iter() {
+        let declared = var(manifest, key)
+            .ok_or_else(|| format!("{} is not declared in {}", key, MANIFEST_FILE))?;
+        check_value(declared, value)?;
+    }
+
+    for declared in reads(manifest, name) {
+        if vars.contains_key(&declared.name) || existing.contains(&declared.name) {
+            continue;
+        }
+        if let Some(default) = &declared.default {
+            vars.insert(declared.name.clone(), default.clone());
+        }
+    }
+    Ok(())
+}
+
+fn check_value(var: &ShellVar, value: &str) -> Result<(), String> {
+    let ok = match var.kind {
+        ShellVarType::String => true,
+        ShellVarType::Int => value.parse::<i64>().is_ok(),
+        ShellVarType::Bool => value == "true" || value == "false",
+        ShellVarType::Enum => var.options.iter().any(|o| o == value),
+        ShellVarType::Email => validate_email(value).is_ok(),
+        ShellVarType::Hostname => is_hostname(value),
+    };
+    if !ok {
+        return Err(match var.kind {
+            ShellVarType::Enum => format!("{} must be one of: {}", var.name, var.options.join(", ")),
+            kind => format!("{} must be a valid {}", var.name, type_name(kind)),
+        });
+    }
+
+    if let Some(pattern) = &var.pattern {
+        let re = anchored(pattern).map_err(|e| e.to_string())?;
+        if !re.is_match(value) {
+            return Err(format!("{} does not match {}", var.name, pattern));
+        }
+    }
+    Ok(())
+}
+
+fn type_name(kind: ShellVarType) -> &'static str {
+    match kind {
+        ShellVarType::String => "string",
+        ShellVarType::Int => "integer",
+        ShellVarType::Bool => "boolean (true or false)",
+        ShellVarType::Enum => "option",
+        ShellVarType::Email => "email address",
+        ShellVarType::Hostname => "hostname",
+    }
+}
+
+/// A manifest pattern matches the whole value, not some part of it.
+fn anchored(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+/// RFC 1123: dot-separated labels of letters, digits and inner hyphens.
+fn is_hostname(value: &str) -> bool {
+    let value = value.strip_suffix('.').unwrap_or(value);
+    !value.is_empty()
+        && value.len() <= 253
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> ShellManifest {
+        serde_json::from_str(
+            r#"{
+                "targets": [{ "name": "ufw", "description": "Firewall", "vars": ["SSH_PORT"] }],
+                "vars": [
+                    { "name": "SSH_PORT", "type": "int", "default": "22", "pattern": "[0-9]{2,5}" },
+                    { "name": "MODE", "type": "enum", "options": ["strict", "lax"] },
+                    { "name": "HOST", "type": "hostname" },
+                    { "name": "NOTE" },
+                    { "name": "DB_PASS", "secret": true, "default": "changeme" }
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn a_well_formed_manifest_passes() {
+        let manifest = manifest();
+        assert!(check(&manifest).is_ok());
+        assert_eq!(manifest.vars[3].kind, ShellVarType::String);
+    }
+
+    #[test]
+    fn a_broken_manifest_is_refused() {
+        let mut bad = manifest();
+        bad.targets[0].vars.push("UNDECLARED".to_string());
+        assert!(check(&bad).is_err());
+
+        let mut bad = manifest();
+        bad.vars[1].options.clear();
+        assert!(check(&bad).is_err(), "an enum with nothing to choose");
+
+        let mut bad = manifest();
+        bad.vars[0].default = Some("not-a-number".to_string());
+        assert!(check(&bad).is_err(), "a default its own type refuses");
+
+        let mut bad = manifest();
+        bad.vars[0].pattern = Some("(".to_string());
+        assert!(check(&bad).is_err());
+    }
+
+    #[test]
+    fn values_are_checked_by_type_and_pattern() {
+        let manifest = manifest();
+        let check = |key: &str, value: &str| {
+            let mut vars = HashMap::from([(key.to_string(), value.to_string())]);
+            validate(&manifest, "ufw", &mut vars, &[])
+        };
+
+        assert!(check("SSH_PORT", "2222").is_ok());
+        assert!(check("SSH_PORT", "22a").is_err());
+        assert!(check("SSH_PORT", "1").is_err(), "the pattern wants two digits or more");
+        assert!(check("MODE", "strict").is_ok());
+        assert!(check("MODE", "other").is_err());
+        assert!(check("HOST", "db-1.example.com").is_ok());
+        assert!(check("HOST", "-bad.example.com").is_err());
+        assert!(check("UNKNOWN", "x").is_err(), "undeclared names are refused");
+    }
+
+    #[test]
+    fn defaults_fill_only_what_is_missing() {
+        let manifest = manifest();
+
+        let mut vars = HashMap::new();
+        validate(&manifest, "ufw", &mut vars, &[]).unwrap();
+        assert_eq!(vars.get("SSH_PORT").map(String::as_str), Some("22"));
+
+        let mut vars = HashMap::new();
+        validate(&manifest, "ufw", &mut vars, &["SSH_PORT".to_string()]).unwrap();
+        assert!(vars.is_empty(), "vars.env already has it");
+    }
+
+    #[test]
+    fn a_secret_default_is_not_described() {
+        let manifest = manifest();
+        let described = schema(&manifest, "--full");
+        let pass = described.schema.iter().find(|v| v.name == "DB_PASS").unwrap();
+        assert_eq!(pass.default.as_deref(), Some(MASK));
+        assert_eq!(secrets(&manifest), vec!["DB_PASS".to_string()]);
+
+        // The real default is still what a run gets.
+        let mut vars = HashMap::new();
+        validate(&manifest, "--full", &mut vars, &[]).unwrap();
+        assert_eq!(vars.get("DB_PASS").map(String::as_str), Some("changeme"));
+    }
+}
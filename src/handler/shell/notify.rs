@@ -0,0 +1,400 @@
+/*
+ * Telling someone a run finished, so nobody has to poll a half-hour
+ * vps-setup run to find out how it went.
+ *
+ *   PATCH /api/shell/{uuid}/notify   Model::Shell::ShellNotify —
+ *                                    administrator, from the dashboard
+ *
+ * When a job ends — however it ends — `finish_job` hands it to `finished`,
+ * which emails the account that ran it and/or every administrator, and POSTs
+ * the same facts as JSON to the bundle's webhook_url, if it has one. Each
+ * says what happened in words (`summary`) as well as the raw status, so a
+ * timeout, a run that stopped for missing variables and an ordinary non-zero
+ * exit read differently; the email subject leads with it.
+ *
+ * Both carry the last `log_lines` lines of the job log — which is already
+ * redacted (shell/redact.rs), so no secret leaves this way that the log
+ * itself doesn't hold.
+ *
+ * Delivery runs on a task of its own after the job record is final. A mail
+ * server that is down or a webhook that answers 500 is logged and that is
+ * all: the job's outcome doesn't depend on anyone hearing about it. Dry runs
+ * notify nobody.
+ */
+use std::env;
+use std::time::Duration;
+
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, to_bson};
+use serde::{Deserialize, Serialize};
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Integrations::Smtp;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Model::Account::{AccountCore, AccountRole};
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellBundle, ShellJob, ShellNotify};
+use crate::utils::response::Response;
+
+use super::retention;
+
+/// Log lines included when a bundle doesn't say.
+const DEFAULT_LOG_LINES: usize = 20;
+/// Past this the email is a log dump; `ct shell logs` has the rest.
+const MAX_LOG_LINES: usize = 200;
+/// A webhook that hasn't answered by then isn't going to.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    uuid: String,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+    form_data: web::Json<ShellNotify>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.notify", &path.uuid, gate).await?;
+
+    let mut settings = form_data.into_inner();
+    settings.webhook_url = settings
+        .webhook_url
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty());
+    if let Err(error) = validate(&settings) {
+        return Ok(Response::bad_request(&error));
+    }
+
+    let settings_bson = match to_bson(&settings) {
+        Ok(b) => b,
+        Err(error) => return Ok(Response::bad_request(&error.to_string())),
+    };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &path.uuid, "deleted_at": null },
+            doc! { "$set": { "notify": settings_bson } },
+        )
+        .await;
+
+    let update_result = match result {
+        Ok(r) => r,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    if update_result.matched_count == 0 {
+        return Ok(Response::not_found("Bundle not found"));
+    }
+
+    let detail = settings.webhook_url.clone().map(|url| format!("webhook {}", url));
+    Audit::record(&req, Some(&user), "shell.notify", &path.uuid, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(
+        Response { message: "Updated".to_string() }
+    ))
+}
+
+fn validate(settings: &ShellNotify) -> Result<(), String> {
+    if let Some(n) = settings.log_lines {
+        if n == 0 || n > MAX_LOG_LINES {
+            return Err(format!("log_lines must be between 1 and {}", MAX_LOG_LINES));
+        }
+    }
+    if let Some(url) = &settings.webhook_url {
+        let parsed = reqwest::Url::parse(url).map_err(|e| format!("webhook_url: {}", e))?;
+        if parsed.scheme() != "https" && parsed.scheme() != "http" {
+            return Err("webhook_url must be an http or https URL".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// What the webhook receives.
+#[derive(Debug, Serialize)]
+struct Finished<'a> {
+    event: &'static str,
+    job: &'a str,
+    bundle: &'a str,
+    target: &'a str,
+    status: &'a str,
+    summary: String,
+    exit_code: Option<i32>,
+    missing_vars: Option<&'a [String]>,
+    user_id: &'a str,
+    token_label: &'a str,
+    started_at: i64,
+    finished_at: Option<i64>,
+    duration_secs: Option<i64>,
+    log_tail: &'a [String],
+}
+
+/// Tell whoever the job's bundle says should hear about it. Spawned by
+/// `finish_job` once the record is final; nothing it does reaches the job.
+pub async fn finished(id: String) {
+    let db = MongoDB.connect();
+
+    let job = match db.collection::<ShellJob>("shell_job").find_one(doc! { "uuid": &id }).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return,
+        Err(error) => {
+            log::error!("shell job {}: {:?}", id, error);
+            return;
+        }
+    };
+    if job.kind == "dry_run" {
+        return;
+    }
+
+    let bundle = db
+        .collection::<ShellBundle>("shell_bundle")
+        .find_one(doc! { "name": &job.bundle, "deleted_at": null })
+        .await;
+    let settings = match bundle {
+        Ok(Some(bundle)) => bundle.notify,
+        Ok(None) => return,
+        Err(error) => {
+            log::error!("shell job {}: {:?}", id, error);
+            return;
+        }
+    };
+    if settings.failures_only && job.status == "success" {
+        return;
+    }
+    if !settings.email_runner && !settings.email_admins && settings.webhook_url.is_none() {
+        return;
+    }
+
+    let log = retention::read(&job.uuid).unwrap_or_default();
+    let log_tail = tail(&log, settings.log_lines.unwrap_or(DEFAULT_LOG_LINES));
+
+    let payload = Finished {
+        event: "shell.job.finished",
+        job: &job.uuid,
+        bundle: &job.bundle,
+        target: &job.target,
+        status: &job.status,
+        summary: summary(&job),
+        exit_code: job.exit_code,
+        missing_vars: job.missing_vars.as_deref(),
+        user_id: &job.user_id,
+        token_label: &job.token_label,
+        started_at: job.started_at,
+        finished_at: job.finished_at,
+        duration_secs: job.finished_at.map(|end| (end - job.started_at) / 1000),
+        log_tail: &log_tail,
+    };
+
+    if let Some(url) = &settings.webhook_url {
+        post(url, &payload).await;
+    }
+    if settings.email_runner || settings.email_admins {
+        email(&settings, &payload).await;
+    }
+}
+
+async fn post(url: &str, payload: &Finished<'_>) {
+    let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("shell job {}: webhook client: {}", payload.job, error);
+            return;
+        }
+    };
+
+    match client.post(url).json(payload).send().await {
+        Ok(res) if res.status().is_success() => {}
+        Ok(res) => log::warn!("shell job {}: webhook {} answered {}", payload.job, url, res.status()),
+        Err(error) => log::warn!("shell job {}: webhook {}: {}", payload.job, url, error),
+    }
+}
+
+async fn email(settings: &ShellNotify, payload: &Finished<'_>) {
+    if env::var("SMTP_EMAIL").is_err() || env::var("SMTP_PROJECT_NAME").is_err() {
+        log::warn!("shell job {}: SMTP isn't configured; no notification emails sent", payload.job);
+        return;
+    }
+
+    let mut filter = Vec::new();
+    if settings.email_runner {
+        filter.push(doc! { "uuid": payload.user_id });
+    }
+    if settings.email_admins {
+        match to_bson(&AccountRole::Administrator) {
+            Ok(role) => filter.push(doc! { "role": role }),
+            Err(error) => log::error!("{:?}", error),
+        }
+    }
+    if filter.is_empty() {
+        return;
+    }
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<AccountCore>("account_core");
+    let accounts: Result<Vec<AccountCore>, _> = match collection.find(doc! { "$or": filter }).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(error) => Err(error),
+    };
+    let accounts = match accounts {
+        Ok(accounts) => accounts,
+        Err(error) => {
+            log::error!("shell job {}: {:?}", payload.job, error);
+            return;
+        }
+    };
+
+    let subject = format!("[{}] {}: {}", payload.bundle, payload.target, payload.summary);
+    let mut details = vec![
+        ("Job", payload.job.to_string()),
+        ("Status", payload.status.to_string()),
+        ("Run by", match payload.token_label {
+            "" => payload.user_id.to_string(),
+            label => format!("{} ({})", payload.user_id, label),
+        }),
+    ];
+    if let Some(secs) = payload.duration_secs {
+        details.push(("Duration", duration(secs)));
+    }
+    if let Some(code) = payload.exit_code {
+        details.push(("Exit code", code.to_string()));
+    }
+
+    for account in accounts {
+        let message = Smtp::shell_job_finished_template(
+            &account.email_address,
+            &subject,
+            &payload.summary,
+            &details,
+            payload.log_tail,
+        );
+        let id = payload.job.to_string();
+        // lettre's SmtpTransport blocks.
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = Smtp::send_email(message) {
+                log::error!("shell job {}: notification to {}: {}", id, account.email_address, error);
+            }
+        });
+    }
+}
+
+/// How a job ended, in words — the part that tells a timeout from a missing
+/// variable from a plain failure.
+fn summary(job: &ShellJob) -> String {
+    match job.status.as_str() {
+        "success" => "succeeded".to_string(),
+        "failed_missing_vars" => match &job.missing_vars {
+            Some(vars) if !vars.is_empty() => format!("stopped for missing variables: {}", vars.join(", ")),
+            _ => "stopped for missing variables".to_string(),
+        },
+        "timed_out" => "timed out".to_string(),
+        "limit_exceeded" => "stopped at a resource limit".to_string(),
+        "cancelled" => "cancelled".to_string(),
+        "interrupted" => "interrupted by a server restart".to_string(),
+        "failed" => match job.exit_code {
+            Some(code) => format!("failed with exit code {}", code),
+            None => "failed before it could finish".to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
+fn tail(log: &str, n: usize) -> Vec<String> {
+    let lines: Vec<&str> = log.lines().collect();
+    let start = lines.len().saturating_sub(n.min(MAX_LOG_LINES));
+    lines[start..].iter().map(|l| l.to_string()).collect()
+}
+
+fn duration(secs: i64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{}h {}m", s / 3600, (s % 3600) / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(status: &str, exit_code: Option<i32>, missing: Option<Vec<&str>>) -> ShellJob {
+        ShellJob {
+            uuid: "j".to_string(),
+            bundle: "vps-setup".to_string(),
+            target: "--full".to_string(),
+            user_id: "u".to_string(),
+            token_label: String::new(),
+            var_keys: Vec::new(),
+            secret_keys: Vec::new(),
+            kind: "run".to_string(),
+            status: status.to_string(),
+            exit_code,
+            missing_vars: missing.map(|m| m.iter().map(|v| v.to_string()).collect()),
+            log_path: String::new(),
+            run_as: None,
+            pid: None,
+            cancelled_by: None,
+            cancelled_at: None,
+            approval_expires_at: None,
+            approved_by: None,
+            approved_at: None,
+            rejected_by: None,
+            rejected_at: None,
+            started_at: 0,
+            finished_at: None,
+            steps: Vec::new(),
+            continue_on_failure: false,
+            artifacts: Vec::new(),
+            log_pruned_at: None,
+        }
+    }
+
+    #[test]
+    fn each_way_of_failing_reads_differently() {
+        let failed = summary(&job("failed", Some(1), None));
+        let missing = summary(&job("failed_missing_vars", Some(3), Some(vec!["domain_name"])));
+        let timed_out = summary(&job("timed_out", None, None));
+
+        assert_eq!(failed, "failed with exit code 1");
+        assert_eq!(missing, "stopped for missing variables: domain_name");
+        assert_eq!(timed_out, "timed out");
+        assert_eq!(summary(&job("success", Some(0), None)), "succeeded");
+    }
+
+    #[test]
+    fn the_tail_is_the_last_lines() {
+        assert_eq!(tail("a\nb\nc\n", 2), vec!["b", "c"]);
+        assert_eq!(tail("a\n", 5), vec!["a"]);
+        assert!(tail("", 5).is_empty());
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        assert!(validate(&ShellNotify::default()).is_ok());
+
+        let bad_url = ShellNotify { webhook_url: Some("ftp://example.com/x".to_string()), ..ShellNotify::default() };
+        assert!(validate(&bad_url).is_err());
+
+        let good_url = ShellNotify { webhook_url: Some("https://example.com/x".to_string()), ..ShellNotify::default() };
+        assert!(validate(&good_url).is_ok());
+
+        let too_many = ShellNotify { log_lines: Some(MAX_LOG_LINES + 1), ..ShellNotify::default() };
+        assert!(validate(&too_many).is_err());
+    }
+
+    #[test]
+    fn durations_are_readable() {
+        assert_eq!(duration(42), "42s");
+        assert_eq!(duration(125), "2m 5s");
+        assert_eq!(duration(7260), "2h 1m");
+    }
+}
@@ -0,0 +1,317 @@
+/*
+ * `POST /api/shell/{name}/pipeline` — several targets of one bundle, in
+ * order, as one job.
+ *
+ *   { targets: ["ufw", "certbot", "nginx"], continue_on_failure: false,
+ *     vars: { KEY: "value" }, secret: [], profile, queue: false }
+ *
+ * answered like `run` (handler/shell.rs), ?dry_run=1 included. The caller
+ * needs leave to run every one of the targets, and a pipeline reaching a
+ * step marked for approval waits for it as a whole (shell/approval.rs).
+ *
+ * The job takes the bundle's lock once and keeps it until its last step
+ * ends, so no other run gets in between two of them; the variables are
+ * written once, before the first. Each step is its own `main.sh <target>`,
+ * spawned when the one before it has exited, and recorded on the job's
+ * `steps` — status, exit code, when it started and finished, and where its
+ * output begins and ends in the job's one log, after a "==> target" line.
+ *
+ * A step that fails skips the rest, unless the caller asked to continue past
+ * failures. A cancel, or running out of time or log, stops the step that is
+ * running and skips the rest either way. The job ends with the status of the
+ * first step that didn't succeed, or `success`.
+ *
+ * The bundle's time limit is on the job, so on all the steps together: each
+ * gets what the ones before it left. So is the output limit, which is on the
+ * log (shell/limits.rs).
+ */
+use std::io::Write;
+
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson};
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellJob, ShellJobStep};
+use crate::utils::response::Response;
+
+use super::{
+    append_log, authorize, bundle_dir, integrity, is_set, is_valid_target, prepare_vars, profiles,
+    stop_group, submit, was_cancelled, Outcome, Plan, Prepared, RunBody, RunQuery,
+};
+
+/// More than a bundle has steps; a cap on what one request can queue up.
+const MAX_STEPS: usize = 32;
+
+#[derive(Debug, Deserialize)]
+pub struct PipelineBody {
+    pub targets: Vec<String>,
+    /// Run the remaining steps after one fails, rather than skipping them.
+    #[serde(default)]
+    pub continue_on_failure: bool,
+    #[serde(flatten)]
+    pub run: RunBody,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<RunQuery>,
+    body: web::Json<PipelineBody>,
+) -> Result<HttpResponse, Error> {
+    let bundle = path.into_inner();
+    let PipelineBody { targets, continue_on_failure, run: mut body } = body.into_inner();
+    let action = if is_set(&query.dry_run) { "shell.dry_run" } else { "shell.run" };
+    let subject = format!("{}/{}", bundle, targets.join(" "));
+
+    if let Err(error) = check_targets(&targets) {
+        return Ok(Response::bad_request(&error));
+    }
+
+    let mut user = None;
+    for target in &targets {
+        match authorize(&req, &bundle, Some(target)).await {
+            Ok(found) => user = Some(found),
+            Err(res) => {
+                let detail = Some(format!("{}: {}", target, res.status()));
+                Audit::record(&req, None, action, &subject, AuditOutcome::Denied, detail).await;
+                return Ok(res);
+            }
+        }
+    }
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(Response::bad_request("A pipeline needs at least one target")),
+    };
+
+    let dir = match bundle_dir(&bundle) {
+        Ok(dir) => dir,
+        Err(res) => return Ok(res),
+    };
+    let files = match integrity::check(&bundle, &dir).await {
+        Ok(files) => files,
+        Err(res) => {
+            let detail = Some(format!("integrity: {}", res.status()));
+            Audit::record(&req, Some(&user), action, &subject, AuditOutcome::Failed, detail).await;
+            return Ok(res);
+        }
+    };
+
+    if let Err(res) = profiles::apply(&bundle, &mut body).await {
+        return Ok(res);
+    }
+
+    // Each target against the manifest: what one needs, the run has to
+    // supply before the first starts.
+    for target in &targets {
+        if let Err(res) = prepare_vars(&bundle, target, &mut body.vars, &mut body.secret).await {
+            return Ok(res);
+        }
+    }
+
+    let plan = Plan::pipeline(targets, continue_on_failure);
+    Ok(submit(&req, &user, &bundle, files, &plan, body, is_set(&query.dry_run)).await)
+}
+
+/// A pipeline's steps as first recorded, none of them started.
+pub fn steps(targets: &[String]) -> Vec<ShellJobStep> {
+    targets
+        .iter()
+        .map(|target| ShellJobStep {
+            target: target.clone(),
+            status: "pending".to_string(),
+            exit_code: None,
+            started_at: None,
+            finished_at: None,
+            duration_ms: None,
+            log_start: None,
+            log_end: None,
+        })
+        .collect()
+}
+
+/// Run a pipeline's steps in order, keeping the job's record of each current,
+/// and say how the job as a whole ended. Called from the watcher `launch`
+/// spawns, with the lock held.
+pub(super) async fn run_steps(
+    run: &Prepared,
+    mut steps: Vec<ShellJobStep>,
+    continue_on_failure: bool,
+) -> Outcome {
+    let total = steps.len();
+    let mut first_failure: Option<Outcome> = None;
+    let mut last_code = None;
+
+    for at in 0..total {
+        // Cancelled between two steps: there was no process to signal.
+        if was_cancelled(&run.id).await {
+            skip(&mut steps[at..]);
+            save(&run.id, &steps, None).await;
+            return ("cancelled", None, None);
+        }
+
+        let target = steps[at].target.clone();
+        let _ = writeln!(&run.log, "==> {} ({}/{})", target, at + 1, total);
+
+        let log_start = run.log_len();
+        let started_at = Utc::now().timestamp_millis();
+        steps[at].status = "running".to_string();
+        steps[at].started_at = Some(started_at);
+        steps[at].log_start = Some(log_start);
+
+        let (outcome, breach) = match run.spawn(&target) {
+            Ok(spawned) => {
+                save(&run.id, &steps, Some(spawned.pid)).await;
+                // Cancelled between the check above and the pid being on the
+                // record: the cancel had nothing to signal, so it falls to
+                // this.
+                if was_cancelled(&run.id).await {
+                    append_log(&run.id, "Cancelled as this step started; sending SIGTERM.");
+                    tokio::spawn(stop_group(spawned.pid));
+                }
+                run.wait(spawned, log_start).await
+            }
+            Err(error) => {
+                append_log(&run.id, &format!("Failed to spawn: {}", error));
+                (("failed", None, None), None)
+            }
+        };
+
+        let finished_at = Utc::now().timestamp_millis();
+        let step = &mut steps[at];
+        step.status = outcome.0.to_string();
+        step.exit_code = outcome.1;
+        step.finished_at = Some(finished_at);
+        step.duration_ms = Some(finished_at - started_at);
+        step.log_end = Some(run.log_len());
+        last_code = outcome.1;
+
+        let go_on = settle(&mut steps, at, continue_on_failure, breach.is_some());
+        save(&run.id, &steps, None).await;
+
+        if outcome.0 == "cancelled" {
+            return outcome;
+        }
+        if outcome.0 != "success" && first_failure.is_none() {
+            first_failure = Some(outcome);
+        }
+        if !go_on {
+            break;
+        }
+    }
+
+    first_failure.unwrap_or(("success", last_code, None))
+}
+
+/// Whether to go on after the step at `at` has ended — `job_limit` if one of
+/// the job's own limits ended it. When not, the steps after it are marked
+/// skipped.
+fn settle(steps: &mut [ShellJobStep], at: usize, continue_on_failure: bool, job_limit: bool) -> bool {
+    let go_on = match steps[at].status.as_str() {
+        "success" => true,
+        "cancelled" => false,
+        // Its time or its log, and either way the whole job's: whatever came
+        // next would be stopped as soon as it started. A CPU limit is on each
+        // command, so the next step has its own.
+        _ if job_limit => false,
+        _ => continue_on_failure,
+    };
+    if !go_on {
+        skip(&mut steps[at + 1..]);
+    }
+    go_on
+}
+
+fn skip(steps: &mut [ShellJobStep]) {
+    for step in steps {
+        step.status = "skipped".to_string();
+    }
+}
+
+fn check_targets(targets: &[String]) -> Result<(), String> {
+    if targets.is_empty() {
+        return Err("A pipeline needs at least one target".to_string());
+    }
+    if targets.len() > MAX_STEPS {
+        return Err(format!("A pipeline runs at most {} targets", MAX_STEPS));
+    }
+    match targets.iter().find(|target| !is_valid_target(target)) {
+        Some(target) => Err(format!("Invalid target name: {}", target)),
+        None => Ok(()),
+    }
+}
+
+/// Write the steps back to the job, with the pid of the one running for a
+/// cancel to signal — None between steps, so a cancel then doesn't signal a
+/// group that has ended.
+async fn save(id: &str, steps: &[ShellJobStep], pid: Option<i32>) {
+    let steps = match to_bson(steps) {
+        Ok(steps) => steps,
+        Err(error) => {
+            log::error!("shell job {}: {:?}", id, error);
+            return;
+        }
+    };
+    let set = doc! { "steps": steps, "pid": pid };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+    if let Err(error) = collection.update_one(doc! { "uuid": id }, doc! { "$set": set }).await {
+        log::error!("shell job {}: {:?}", id, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ended(statuses: &[&str]) -> Vec<ShellJobStep> {
+        let mut out = steps(&statuses.iter().map(|_| "ufw".to_string()).collect::<Vec<_>>());
+        for (step, status) in out.iter_mut().zip(statuses) {
+            step.status = status.to_string();
+        }
+        out
+    }
+
+    #[test]
+    fn a_failure_skips_the_rest_unless_asked_to_continue() {
+        let mut stop = ended(&["success", "failed", "pending", "pending"]);
+        assert!(settle(&mut stop, 0, false, false));
+        assert!(!settle(&mut stop, 1, false, false));
+        assert_eq!(stop[2].status, "skipped");
+        assert_eq!(stop[3].status, "skipped");
+
+        let mut go_on = ended(&["failed_missing_vars", "pending"]);
+        assert!(settle(&mut go_on, 0, true, false));
+        assert_eq!(go_on[1].status, "pending");
+    }
+
+    #[test]
+    fn a_cancel_or_a_job_limit_skips_the_rest_either_way() {
+        for (status, job_limit) in [("cancelled", false), ("timed_out", true), ("limit_exceeded", true)] {
+            let mut steps = ended(&[status, "pending"]);
+            assert!(!settle(&mut steps, 0, true, job_limit));
+            assert_eq!(steps[1].status, "skipped");
+        }
+    }
+
+    #[test]
+    fn a_cpu_limit_is_the_step_s_own() {
+        let mut steps = ended(&["limit_exceeded", "pending"]);
+        assert!(settle(&mut steps, 0, true, false));
+        assert_eq!(steps[1].status, "pending");
+    }
+
+    #[test]
+    fn targets_are_checked_before_anything_runs() {
+        let targets = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(check_targets(&targets(&["ufw", "certbot", "nginx"])).is_ok());
+        assert!(check_targets(&targets(&[])).is_err());
+        assert!(check_targets(&targets(&["ufw", "../etc"])).unwrap_err().contains("../etc"));
+        assert!(check_targets(&vec!["ufw".to_string(); MAX_STEPS + 1]).is_err());
+    }
+}
@@ -0,0 +1,516 @@
+/*
+ * Named sets of variables for a bundle's runs — `staging`, `prod`.
+ *
+ *   GET    /api/shell/{name}/profiles             the bundle's profiles
+ *   PUT    /api/shell/{name}/profiles/{profile}   { vars: { KEY: "value" },
+ *                                                   secret: [], unset: [] }
+ *   DELETE /api/shell/{name}/profiles/{profile}
+ *
+ * Administrator-only, with either credential, like schedules. A PUT creates
+ * the profile or edits it in place: the variables it names are set, the
+ * ones in `unset` removed, and the rest left as they were — so changing one
+ * value doesn't mean sending every secret again.
+ *
+ * `run` and `pipeline` then take `profile: "prod"`. Its variables go in
+ * under the ones the call supplies, so a per-call KEY=VALUE still wins, and
+ * the lot is checked against the manifest as if it had all been sent.
+ * `describe` says which of a target's variables each profile already covers.
+ *
+ * A value named in `secret`, or one the bundle's manifest marks secret, is
+ * stored sealed with AES-256-GCM under SHELL_PROFILE_KEY (32 bytes, base64:
+ * `openssl rand -base64 32`), bound to its bundle, profile and name so a
+ * sealed value can't be moved to another. Without the key a secret can be
+ * neither stored nor used. Responses show it masked, and a run treats it as
+ * secret like any other: root's alone in vars.env, or shared read-only with
+ * the account the run is for (shell/run_as.rs), and redacted from the log.
+ */
+use std::collections::HashMap;
+use std::env;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::ShellVarProfile;
+use crate::utils::response::Response;
+
+use super::redact::MASK;
+use super::{admin, bundle_dir, check_var, manifest, RunBody};
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    name: String,
+    profile: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestBody {
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    /// Which of `vars` to store sealed, on top of those the manifest marks.
+    #[serde(default)]
+    secret: Vec<String>,
+    #[serde(default)]
+    unset: Vec<String>,
+}
+
+/// How far a profile goes towards what a target reads (`describe`).
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Coverage {
+    pub profile: String,
+    pub satisfied: Vec<String>,
+    /// Read by the target, not in the profile, and with no manifest default.
+    pub missing: Vec<String>,
+}
+
+pub async fn task(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+    admin(&req).await?;
+    let bundle = path.into_inner();
+
+    match for_bundle(&bundle).await {
+        Ok(profiles) => Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(profiles.into_iter().map(masked).collect::<Vec<_>>())),
+        Err(res) => Ok(res),
+    }
+}
+
+pub async fn set(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+    form_data: web::Json<RequestBody>,
+) -> Result<HttpResponse, Error> {
+    let subject = format!("{}/{}", path.name, path.profile);
+    let user = Audit::checked(&req, "shell.profile.set", &subject, admin(&req).await).await?;
+    let body = form_data.into_inner();
+
+    if let Err(res) = bundle_dir(&path.name) {
+        return Ok(res);
+    }
+    if !is_valid_profile(&path.profile) {
+        return Ok(Response::bad_request(
+            "A profile name may only contain lowercase letters, digits, hyphens and underscores",
+        ));
+    }
+    for (key, value) in &body.vars {
+        if let Err(error) = check_var(key, value) {
+            return Ok(Response::bad_request(&error));
+        }
+    }
+
+    // Every declared variable is fair game, since a profile isn't tied to
+    // one target; "--full" is the target that reads them all.
+    let mut secret = body.secret;
+    match manifest::for_bundle(&path.name).await {
+        Ok(Some(declared)) => {
+            if let Err(error) = manifest::validate(&declared, "--full", &mut body.vars.clone(), &[]) {
+                return Ok(Response::bad_request(&error));
+            }
+            secret.extend(manifest::secrets(&declared));
+        }
+        Ok(None) => {}
+        Err(res) => return Ok(res),
+    }
+
+    let existing = match find(&path.name, &path.profile).await {
+        Ok(existing) => existing,
+        Err(res) => return Ok(res),
+    };
+    let now = Utc::now().timestamp_millis();
+    let mut profile = existing.unwrap_or_else(|| ShellVarProfile {
+        bundle: path.name.clone(),
+        name: path.profile.clone(),
+        vars: HashMap::new(),
+        secrets: HashMap::new(),
+        created_at: now,
+        created_by: user.user_id.clone(),
+        updated_at: now,
+        updated_by: user.user_id.clone(),
+    });
+
+    let key = if body.vars.keys().any(|k| secret.contains(k)) {
+        match key() {
+            Ok(key) => Some(key),
+            Err(error) => return Ok(Response::bad_request(&error)),
+        }
+    } else {
+        None
+    };
+    for key_name in &body.unset {
+        profile.vars.remove(key_name);
+        profile.secrets.remove(key_name);
+    }
+    for (name, value) in &body.vars {
+        match &key {
+            Some(key) if secret.contains(name) => {
+                let sealed = match seal(key, &aad(&profile, name), value) {
+                    Ok(sealed) => sealed,
+                    Err(error) => return Ok(Response::internal_server_error(&error)),
+                };
+                profile.vars.remove(name);
+                profile.secrets.insert(name.clone(), sealed);
+            }
+            _ => {
+                profile.secrets.remove(name);
+                profile.vars.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    profile.updated_at = now;
+    profile.updated_by = user.user_id.clone();
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellVarProfile>("shell_var_profile");
+    let result = collection
+        .replace_one(doc! { "bundle": &profile.bundle, "name": &profile.name }, &profile)
+        .upsert(true)
+        .await;
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let mut names: Vec<&String> = body.vars.keys().chain(body.unset.iter()).collect();
+    names.sort();
+    let detail = Some(names.into_iter().cloned().collect::<Vec<_>>().join(","));
+    Audit::record(&req, Some(&user), "shell.profile.set", &subject, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(masked(profile)))
+}
+
+pub async fn delete(req: HttpRequest, path: web::Path<PathVariables>) -> Result<HttpResponse, Error> {
+    let subject = format!("{}/{}", path.name, path.profile);
+    let user = Audit::checked(&req, "shell.profile.delete", &subject, admin(&req).await).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellVarProfile>("shell_var_profile");
+
+    match collection.delete_one(doc! { "bundle": &path.name, "name": &path.profile }).await {
+        Ok(result) if result.deleted_count == 0 => Ok(Response::not_found("No such profile")),
+        Ok(_) => {
+            Audit::record(&req, Some(&user), "shell.profile.delete", &subject, AuditOutcome::Success, None).await;
+            Ok(HttpResponse::Ok().content_type("application/json").json(
+                Response { message: "Deleted".to_string() }
+            ))
+        }
+        Err(error) => {
+            log::error!("{:?}", error);
+            Ok(Response::internal_server_error(&error.to_string()))
+        }
+    }
+}
+
+/// Fill a run's variables in from the profile it names, under the ones it
+/// supplies itself, and mark the profile's secrets secret. Nothing to do for
+/// a run without one.
+pub async fn apply(bundle: &str, body: &mut RunBody) -> Result<(), HttpResponse> {
+    let name = match body.profile.as_deref().map(str::trim).filter(|n| !n.is_empty()) {
+        Some(name) => name.to_string(),
+        None => return Ok(()),
+    };
+    let profile = match find(bundle, &name).await? {
+        Some(profile) => profile,
+        None => return Err(Response::bad_request(&format!("No profile named {}", name))),
+    };
+
+    let secrets = if profile.secrets.is_empty() {
+        HashMap::new()
+    } else {
+        let key = key().map_err(|e| Response::internal_server_error(&e))?;
+        opened(&key, &profile).map_err(|e| {
+            log::error!("shell profile {}/{}: {}", bundle, name, e);
+            Response::internal_server_error(&e)
+        })?
+    };
+    merge(body, profile.vars, secrets);
+    Ok(())
+}
+
+/// What each of a bundle's profiles covers of `needed`, the variables a
+/// target reads; `defaulted` are those the manifest supplies anyway.
+pub async fn coverage(bundle: &str, needed: &[&str], defaulted: &[&str]) -> Result<Vec<Coverage>, HttpResponse> {
+    Ok(for_bundle(bundle)
+        .await?
+        .iter()
+        .map(|profile| covers(profile, needed, defaulted))
+        .collect())
+}
+
+fn covers(profile: &ShellVarProfile, needed: &[&str], defaulted: &[&str]) -> Coverage {
+    let has = |name: &str| profile.vars.contains_key(name) || profile.secrets.contains_key(name);
+    Coverage {
+        profile: profile.name.clone(),
+        satisfied: needed.iter().filter(|n| has(n)).map(|n| n.to_string()).collect(),
+        missing: needed
+            .iter()
+            .filter(|n| !has(n) && !defaulted.contains(n))
+            .map(|n| n.to_string())
+            .collect(),
+    }
+}
+
+/// The profile's values under the call's own; every secret of the profile
+/// stays secret even where the call overrides it.
+fn merge(body: &mut RunBody, vars: HashMap<String, String>, secrets: HashMap<String, String>) {
+    for (name, value) in vars {
+        body.vars.entry(name).or_insert(value);
+    }
+    for (name, value) in secrets {
+        body.vars.entry(name.clone()).or_insert(value);
+        if !body.secret.contains(&name) {
+            body.secret.push(name);
+        }
+    }
+}
+
+async fn for_bundle(bundle: &str) -> Result<Vec<ShellVarProfile>, HttpResponse> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellVarProfile>("shell_var_profile");
+
+    let cursor = collection.find(doc! { "bundle": bundle }).sort(doc! { "name": 1 }).await;
+    let result = match cursor {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(error) => Err(error),
+    };
+    result.map_err(|error| {
+        log::error!("{:?}", error);
+        Response::internal_server_error(&error.to_string())
+    })
+}
+
+async fn find(bundle: &str, name: &str) -> Result<Option<ShellVarProfile>, HttpResponse> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellVarProfile>("shell_var_profile");
+
+    collection
+        .find_one(doc! { "bundle": bundle, "name": name })
+        .await
+        .map_err(|error| {
+            log::error!("{:?}", error);
+            Response::internal_server_error(&error.to_string())
+        })
+}
+
+/// A profile as it goes out in a response: secrets masked, among the rest.
+fn masked(mut profile: ShellVarProfile) -> ShellVarProfile {
+    for value in profile.secrets.values_mut() {
+        *value = MASK.to_string();
+    }
+    profile
+}
+
+/// SHELL_PROFILE_KEY, for whoever has something to seal or open.
+pub fn key() -> Result<LessSafeKey, String> {
+    parse_key(&env::var("SHELL_PROFILE_KEY").unwrap_or_default())
+}
+
+fn parse_key(text: &str) -> Result<LessSafeKey, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("SHELL_PROFILE_KEY isn't set on this server, so it can't keep secrets in a profile".to_string());
+    }
+    let bytes = STANDARD
+        .decode(text)
+        .map_err(|_| "SHELL_PROFILE_KEY isn't base64".to_string())?;
+    let key = UnboundKey::new(&AES_256_GCM, &bytes)
+        .map_err(|_| "SHELL_PROFILE_KEY must be 32 bytes".to_string())?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// What a sealed value is bound to: its bundle, profile and name.
+fn aad(profile: &ShellVarProfile, name: &str) -> String {
+    format!("{}/{}/{}", profile.bundle, profile.name, name)
+}
+
+/// Base64 of a fresh nonce followed by the ciphertext and its tag.
+fn seal(key: &LessSafeKey, aad: &str, value: &str) -> Result<String, String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| "no randomness for a nonce".to_string())?;
+
+    let mut sealed = value.as_bytes().to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad.as_bytes()),
+        &mut sealed,
+    )
+    .map_err(|_| "couldn't seal the value".to_string())?;
+
+    Ok(STANDARD.encode([&nonce[..], &sealed].concat()))
+}
+
+fn open(key: &LessSafeKey, aad: &str, sealed: &str) -> Result<String, String> {
+    let bytes = STANDARD.decode(sealed).map_err(|_| "a sealed value isn't base64".to_string())?;
+    if bytes.len() < NONCE_LEN {
+        return Err("a sealed value is truncated".to_string());
+    }
+    let (nonce, rest) = bytes.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "bad nonce".to_string())?;
+
+    let mut rest = rest.to_vec();
+    let plain = key
+        .open_in_place(nonce, Aad::from(aad.as_bytes()), &mut rest)
+        .map_err(|_| "a sealed value doesn't open under SHELL_PROFILE_KEY".to_string())?;
+    String::from_utf8(plain.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Every secret of `profile`, opened.
+fn opened(key: &LessSafeKey, profile: &ShellVarProfile) -> Result<HashMap<String, String>, String> {
+    profile
+        .secrets
+        .iter()
+        .map(|(name, sealed)| open(key, &aad(profile, name), sealed).map(|value| (name.clone(), value)))
+        .collect()
+}
+
+/// Take the values named in `secret` out of `vars`, sealed, for a record
+/// that keeps a run's variables the way a profile does — a schedule or a
+/// hook. `scope` names the record; it has a colon in it, which no bundle
+/// name can, so nothing sealed for one can be opened as a profile's.
+pub fn seal_secrets(
+    key: &LessSafeKey,
+    scope: &str,
+    vars: &mut HashMap<String, String>,
+    secret: &[String],
+) -> Result<HashMap<String, String>, String> {
+    let names: Vec<String> = vars.keys().filter(|k| secret.contains(k)).cloned().collect();
+    let mut sealed = HashMap::new();
+    for name in names {
+        if let Some(value) = vars.remove(&name) {
+            let aad = format!("{}/{}", scope, name);
+            sealed.insert(name, seal(key, &aad, &value)?);
+        }
+    }
+    Ok(sealed)
+}
+
+/// What `seal_secrets` sealed under `scope`, opened.
+pub fn open_secrets(
+    key: &LessSafeKey,
+    scope: &str,
+    sealed: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    sealed
+        .iter()
+        .map(|(name, value)| {
+            let aad = format!("{}/{}", scope, name);
+            open(key, &aad, value).map(|value| (name.clone(), value))
+        })
+        .collect()
+}
+
+fn is_valid_profile(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> LessSafeKey {
+        parse_key(&STANDARD.encode([7u8; 32])).unwrap()
+    }
+
+    fn profile(vars: &[(&str, &str)], secrets: &[(&str, &str)]) -> ShellVarProfile {
+        let pairs = |list: &[(&str, &str)]| list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        ShellVarProfile {
+            bundle: "vps-setup".to_string(),
+            name: "prod".to_string(),
+            vars: pairs(vars),
+            secrets: pairs(secrets),
+            created_at: 0,
+            created_by: "admin".to_string(),
+            updated_at: 0,
+            updated_by: "admin".to_string(),
+        }
+    }
+
+    #[test]
+    fn a_sealed_value_opens_only_where_it_was_sealed() {
+        let key = test_key();
+        let sealed = seal(&key, "vps-setup/prod/DB_PASSWORD", "hunter2").unwrap();
+        assert!(!sealed.contains("hunter2"));
+        assert_eq!(open(&key, "vps-setup/prod/DB_PASSWORD", &sealed).unwrap(), "hunter2");
+
+        assert!(open(&key, "vps-setup/staging/DB_PASSWORD", &sealed).is_err());
+        let other = parse_key(&STANDARD.encode([8u8; 32])).unwrap();
+        assert!(open(&other, "vps-setup/prod/DB_PASSWORD", &sealed).is_err());
+    }
+
+    #[test]
+    fn a_schedule_keeps_its_secrets_sealed() {
+        let key = test_key();
+        let mut vars = HashMap::from([
+            ("BUCKET".to_string(), "nightly".to_string()),
+            ("S3_SECRET".to_string(), "hunter2".to_string()),
+        ]);
+
+        let sealed = seal_secrets(&key, "schedule:s1", &mut vars, &["S3_SECRET".to_string()]).unwrap();
+        assert_eq!(vars.keys().collect::<Vec<_>>(), ["BUCKET"]);
+        assert!(!sealed["S3_SECRET"].contains("hunter2"));
+
+        assert_eq!(open_secrets(&key, "schedule:s1", &sealed).unwrap()["S3_SECRET"], "hunter2");
+        assert!(open_secrets(&key, "schedule:s2", &sealed).is_err());
+    }
+
+    #[test]
+    fn the_key_must_be_32_base64_bytes() {
+        assert!(parse_key("").is_err());
+        assert!(parse_key("not base64!").is_err());
+        assert!(parse_key(&STANDARD.encode([1u8; 16])).is_err());
+    }
+
+    #[test]
+    fn the_call_wins_and_secrets_stay_secret() {
+        let mut body = RunBody {
+            vars: HashMap::from([
+                ("DOMAIN".to_string(), "example.org".to_string()),
+                ("DB_PASSWORD".to_string(), "override".to_string()),
+            ]),
+            ..RunBody::default()
+        };
+        merge(
+            &mut body,
+            HashMap::from([
+                ("DOMAIN".to_string(), "prod.example.org".to_string()),
+                ("SSH_PORT".to_string(), "2222".to_string()),
+            ]),
+            HashMap::from([("DB_PASSWORD".to_string(), "hunter2".to_string())]),
+        );
+
+        assert_eq!(body.vars["DOMAIN"], "example.org");
+        assert_eq!(body.vars["SSH_PORT"], "2222");
+        assert_eq!(body.vars["DB_PASSWORD"], "override");
+        assert_eq!(body.secret, ["DB_PASSWORD"]);
+    }
+
+    #[test]
+    fn coverage_leaves_defaults_out_of_missing() {
+        let prod = profile(&[("DOMAIN", "example.org")], &[("DB_PASSWORD", "sealed")]);
+        let found = covers(&prod, &["DOMAIN", "DB_PASSWORD", "SSH_PORT", "ADMIN_EMAIL"], &["SSH_PORT"]);
+        assert_eq!(found.satisfied, ["DOMAIN", "DB_PASSWORD"]);
+        assert_eq!(found.missing, ["ADMIN_EMAIL"]);
+    }
+
+    #[test]
+    fn responses_never_carry_a_sealed_value() {
+        let shown = masked(profile(&[("DOMAIN", "example.org")], &[("DB_PASSWORD", "sealed")]));
+        assert_eq!(shown.secrets["DB_PASSWORD"], MASK);
+        assert_eq!(shown.vars["DOMAIN"], "example.org");
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * Secret values out of job logs.
+ *
+ * A run with secret variables — declared `secret` in bundle.json, or sent in
+ * `secret: [...]` with the run — doesn't get its log file as stdout and
+ * stderr. It gets pipes, and a thread per pipe copies each line into the log
+ * with every secret value replaced by `***`. What reaches the disk is already
+ * redacted, so the plain log route, ?follow=1 and anything reading LOG_DIR
+ * directly all see the same thing, and there is nothing to undo later.
+ *
+ * Line by line, because a value can't straddle a line (check_var refuses
+ * newlines in one), so a whole line always holds a whole value. A run
+ * without secrets keeps writing straight to the file, as before.
+ */
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+pub const MASK: &str = "***";
+
+#[derive(Clone)]
+pub struct Redactor {
+    /// Longest first, so a secret that contains another is masked whole.
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new(values: impl IntoIterator<Item = String>) -> Self {
+        let mut secrets: Vec<String> = values.into_iter().filter(|v| !v.is_empty()).collect();
+        secrets.sort_by_key(|v| std::cmp::Reverse(v.len()));
+        secrets.dedup();
+        Redactor { secrets }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    pub fn line(&self, line: &str) -> String {
+        let mut out = line.to_string();
+        for secret in &self.secrets {
+            if out.contains(secret.as_str()) {
+                out = out.replace(secret.as_str(), MASK);
+            }
+        }
+        out
+    }
+}
+
+/// Copy a child's output into the log, redacted. Join the handles before
+/// reading the log as final: the pipes can still hold output when the child
+/// has already exited.
+pub fn copy<R: Read + Send + 'static>(
+    from: R,
+    log: Arc<Mutex<File>>,
+    redactor: Redactor,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(from);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let text = redactor.line(&String::from_utf8_lossy(&buf));
+            if let Ok(mut file) = log.lock() {
+                let _ = file.write_all(text.as_bytes());
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_occurrence_is_masked() {
+        let redactor = Redactor::new(["hunter2".to_string()]);
+        assert_eq!(
+            redactor.line("pass=hunter2 again hunter2\n"),
+            "pass=*** again ***\n"
+        );
+        assert_eq!(redactor.line("nothing here\n"), "nothing here\n");
+    }
+
+    #[test]
+    fn the_longer_of_two_overlapping_secrets_goes_whole() {
+        let redactor = Redactor::new(["abc".to_string(), "abcdef".to_string()]);
+        assert_eq!(redactor.line("x abcdef y"), "x *** y");
+    }
+
+    #[test]
+    fn empty_values_mask_nothing() {
+        let redactor = Redactor::new([String::new()]);
+        assert!(redactor.is_empty());
+        assert_eq!(redactor.line("a"), "a");
+    }
+}
@@ -0,0 +1,128 @@
+/*
+ * `POST /api/shell/{uuid}/restore` — bring a deleted bundle back out of the
+ * trash (shell/trash.rs), at the version that was active when it was deleted.
+ *
+ * Administrator-only, from the dashboard. Refused if its name has since been
+ * taken by another upload: the name is the directory and the URL, so only
+ * one bundle can have it at a time. Its schedules, hooks and profiles come
+ * back with it, but the schedules stay disabled and the hooks revoked until
+ * an administrator sets them up again.
+ */
+use std::fs;
+
+use mongodb::bson::doc;
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::ShellBundle;
+use crate::utils::response::Response;
+
+use super::shell_root;
+use super::trash::{trash_dir, trash_root, unpark};
+use super::version::{activate, versions_dir};
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    uuid: String,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.restore", &path.uuid, gate).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let bundle = match collection
+        .find_one(doc! { "uuid": &path.uuid, "deleted_at": { "$ne": null } })
+        .await
+    {
+        Ok(Some(bundle)) => bundle,
+        Ok(None) => return Ok(Response::not_found("No such bundle in the trash")),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let (root, trash) = match (shell_root(), trash_root()) {
+        (Ok(root), Ok(trash)) => (root, trash),
+        (Err(error), _) | (_, Err(error)) => {
+            log::error!("{}", error);
+            return Ok(Response::internal_server_error(&error));
+        }
+    };
+
+    let taken = match collection.find_one(doc! { "name": &bundle.name, "deleted_at": null }).await {
+        Ok(found) => found.is_some(),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+    let versions = versions_dir(&root, &bundle.name);
+    let link = root.join(&bundle.name);
+
+    if taken || fs::symlink_metadata(&link).is_ok() || versions.exists() {
+        return Ok(HttpResponse::Conflict().content_type("application/json").json(Response {
+            message: format!("Another bundle is already named {}", bundle.name),
+        }));
+    }
+
+    let source = trash_dir(&trash, &bundle.uuid);
+    if !source.is_dir() {
+        return Ok(Response::not_found("This bundle's files are no longer in the trash"));
+    }
+
+    if let Some(parent) = versions.parent() {
+        if let Err(error) = fs::create_dir_all(parent) {
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    }
+    if let Err(error) = fs::rename(&source, &versions) {
+        log::error!("{}: {}", source.display(), error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+    if let Err(error) = activate(&root, &bundle.name, bundle.version) {
+        log::error!("{}", error);
+        let _ = fs::rename(&versions, &source);
+        return Ok(Response::internal_server_error(&error));
+    }
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &bundle.uuid },
+            doc! { "$set": { "deleted_at": null, "deleted_by": null } },
+        )
+        .await;
+
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        let _ = fs::remove_file(&link);
+        let _ = fs::rename(&versions, &source);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    // The bundle is back either way; what fails to follow it stays filed
+    // under its uuid, where nothing runs it.
+    if let Err(error) = unpark(&bundle.uuid, &bundle.name).await {
+        log::error!("shell bundle {}: {}", bundle.uuid, error);
+    }
+
+    Audit::record(&req, Some(&user), "shell.restore", &bundle.name, AuditOutcome::Success, None).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(ShellBundle {
+        deleted_at: None,
+        deleted_by: None,
+        ..bundle
+    }))
+}
@@ -0,0 +1,288 @@
+/*
+ * Keeping LOG_DIR from growing forever.
+ *
+ * `sweep`, which main.rs runs hourly, does two things to the logs of
+ * finished jobs:
+ *
+ *   - gzips every one that finished more than COMPRESS_AFTER ago, to
+ *     <uuid>.log.gz, removing the plain file. The wait leaves a follower time
+ *     to read the tail end, and a cancel's SIGKILL note time to land.
+ *   - deletes those past the retention policy, with the job's artifacts
+ *     (artifacts.rs), and stamps `log_pruned_at` on the job record so an
+ *     empty log reads as pruned, not lost:
+ *
+ *       SHELL_LOG_KEEP_PER_BUNDLE   only the newest N of each bundle (200)
+ *       SHELL_LOG_MAX_DAYS          nothing that finished longer ago (90)
+ *       SHELL_LOG_MAX_MB            the newest logs that fit, compressed
+ *                                   sizes counted (1024)
+ *
+ *     "0" turns a limit off. A log that breaks any of them goes.
+ *
+ * `read` is how everything else gets at a log, plain or compressed, so
+ * `job_logs` and `ct shell logs -f` don't need to know which it is. The job
+ * record itself is kept whatever happens to its log: it is the history.
+ */
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Model::Shell::ShellJob;
+
+use super::{artifacts, log_path, LOG_DIR};
+
+/// How long after a job finishes its log is left as plain text.
+const COMPRESS_AFTER: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_KEEP_PER_BUNDLE: u64 = 200;
+const DEFAULT_MAX_DAYS: u64 = 90;
+const DEFAULT_MAX_MB: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Policy {
+    keep_per_bundle: Option<usize>,
+    max_age_ms: Option<i64>,
+    max_total_bytes: Option<u64>,
+}
+
+/// A setting from the environment: the default when unset or unreadable,
+/// None ("no limit") for 0.
+pub(super) fn limit(name: &str, default: u64) -> Option<u64> {
+    parse_limit(env::var(name).ok().as_deref(), default)
+}
+
+fn parse_limit(value: Option<&str>, default: u64) -> Option<u64> {
+    let value = value
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(default);
+    Some(value).filter(|v| *v > 0)
+}
+
+fn policy() -> Policy {
+    Policy {
+        keep_per_bundle: limit("SHELL_LOG_KEEP_PER_BUNDLE", DEFAULT_KEEP_PER_BUNDLE).map(|n| n as usize),
+        max_age_ms: limit("SHELL_LOG_MAX_DAYS", DEFAULT_MAX_DAYS).map(|d| d as i64 * 24 * 60 * 60 * 1000),
+        max_total_bytes: limit("SHELL_LOG_MAX_MB", DEFAULT_MAX_MB).map(|mb| mb * 1024 * 1024),
+    }
+}
+
+pub fn gz_path(id: &str) -> PathBuf {
+    PathBuf::from(LOG_DIR).join(format!("{}.log.gz", id))
+}
+
+/// A job's log as it was written, whether or not it has been compressed since.
+/// None if there is neither file.
+pub fn read_bytes(id: &str) -> Option<Vec<u8>> {
+    if let Ok(bytes) = fs::read(log_path(id)) {
+        return Some(bytes);
+    }
+    let packed = fs::read(gz_path(id)).ok()?;
+    let mut bytes = Vec::new();
+    match GzDecoder::new(&packed[..]).read_to_end(&mut bytes) {
+        Ok(_) => Some(bytes),
+        Err(error) => {
+            log::error!("shell job {}: compressed log: {}", id, error);
+            None
+        }
+    }
+}
+
+pub fn read(id: &str) -> Option<String> {
+    read_bytes(id).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Compress what has finished, then prune what is past keeping.
+pub async fn sweep() {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    let cursor = collection
+        .find(doc! { "finished_at": { "$ne": null }, "log_pruned_at": null })
+        .sort(doc! { "finished_at": -1 })
+        .await;
+    let jobs: Vec<ShellJob> = match cursor {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(v) => v,
+            Err(error) => {
+                log::error!("{:?}", error);
+                return;
+            }
+        },
+        Err(error) => {
+            log::error!("{:?}", error);
+            return;
+        }
+    };
+
+    let now = Utc::now().timestamp_millis();
+    let compress_before = now - COMPRESS_AFTER.as_millis() as i64;
+
+    let ids: Vec<(String, i64)> = jobs
+        .iter()
+        .map(|job| (job.uuid.clone(), job.finished_at.unwrap_or_default()))
+        .collect();
+    let sized = tokio::task::spawn_blocking(move || {
+        ids.into_iter()
+            .map(|(id, finished_at)| {
+                if finished_at < compress_before {
+                    if let Err(error) = compress(&id) {
+                        log::error!("shell job {}: compressing log: {}", id, error);
+                    }
+                }
+                let size = fs::metadata(log_path(&id))
+                    .or_else(|_| fs::metadata(gz_path(&id)))
+                    .map(|m| m.len())
+                    .unwrap_or(0);
+                (id, size)
+            })
+            .collect::<HashMap<String, u64>>()
+    })
+    .await
+    .unwrap_or_default();
+
+    let entries: Vec<Entry> = jobs
+        .iter()
+        .map(|job| Entry {
+            id: &job.uuid,
+            bundle: &job.bundle,
+            finished_at: job.finished_at.unwrap_or_default(),
+            size: sized.get(&job.uuid).copied().unwrap_or(0),
+        })
+        .collect();
+
+    let doomed = select(&entries, now, &policy());
+    for id in &doomed {
+        let _ = fs::remove_file(log_path(id));
+        let _ = fs::remove_file(gz_path(id));
+        let _ = fs::remove_dir_all(artifacts::stored(id));
+
+        let result = collection
+            .update_one(doc! { "uuid": id }, doc! { "$set": { "log_pruned_at": now } })
+            .await;
+        if let Err(error) = result {
+            log::error!("shell job {}: {:?}", id, error);
+        }
+    }
+
+    if !doomed.is_empty() {
+        log::info!("Pruned {} shell job log(s)", doomed.len());
+    }
+}
+
+/// Replace a plain log with its gzip. Written to a temporary name first, so
+/// a crash midway leaves the plain file rather than half a compressed one.
+fn compress(id: &str) -> Result<(), String> {
+    let plain = log_path(id);
+    let bytes = match fs::read(&plain) {
+        Ok(bytes) => bytes,
+        // Already compressed, or a job that never wrote a log.
+        Err(_) => return Ok(()),
+    };
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&bytes).map_err(|e| e.to_string())?;
+    let packed = encoder.finish().map_err(|e| e.to_string())?;
+
+    let gz = gz_path(id);
+    let partial = gz.with_extension("gz.part");
+    fs::write(&partial, packed).map_err(|e| format!("{}: {}", partial.display(), e))?;
+    fs::rename(&partial, &gz).map_err(|e| format!("{}: {}", gz.display(), e))?;
+    fs::remove_file(&plain).map_err(|e| format!("{}: {}", plain.display(), e))
+}
+
+struct Entry<'a> {
+    id: &'a str,
+    bundle: &'a str,
+    finished_at: i64,
+    size: u64,
+}
+
+/// The logs to delete. `entries` are newest first, so the newest are the ones
+/// kept under each limit.
+fn select(entries: &[Entry], now: i64, policy: &Policy) -> Vec<String> {
+    let mut per_bundle: HashMap<&str, usize> = HashMap::new();
+    let mut total: u64 = 0;
+    let mut doomed = Vec::new();
+
+    for entry in entries {
+        let seen = per_bundle.entry(entry.bundle).or_default();
+        *seen += 1;
+
+        let too_many = policy.keep_per_bundle.map(|n| *seen > n).unwrap_or(false);
+        let too_old = policy.max_age_ms.map(|age| now - entry.finished_at > age).unwrap_or(false);
+        let too_big = policy.max_total_bytes.map(|max| total + entry.size > max).unwrap_or(false);
+
+        if too_many || too_old || too_big {
+            doomed.push(entry.id.to_string());
+        } else {
+            total += entry.size;
+        }
+    }
+    doomed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    fn entry<'a>(id: &'a str, bundle: &'a str, finished_at: i64, size: u64) -> Entry<'a> {
+        Entry { id, bundle, finished_at, size }
+    }
+
+    fn off() -> Policy {
+        Policy { keep_per_bundle: None, max_age_ms: None, max_total_bytes: None }
+    }
+
+    #[test]
+    fn only_the_newest_of_each_bundle_are_kept() {
+        let entries = [
+            entry("a3", "a", 30, 1),
+            entry("b2", "b", 25, 1),
+            entry("a2", "a", 20, 1),
+            entry("a1", "a", 10, 1),
+        ];
+        let policy = Policy { keep_per_bundle: Some(2), ..off() };
+        assert_eq!(select(&entries, 40, &policy), vec!["a1"]);
+    }
+
+    #[test]
+    fn old_logs_go() {
+        let now = 100 * DAY;
+        let entries = [entry("new", "a", now - DAY, 1), entry("old", "a", now - 31 * DAY, 1)];
+        let policy = Policy { max_age_ms: Some(30 * DAY), ..off() };
+        assert_eq!(select(&entries, now, &policy), vec!["old"]);
+    }
+
+    #[test]
+    fn the_size_budget_goes_to_the_newest() {
+        let entries = [entry("n", "a", 3, 60), entry("m", "b", 2, 50), entry("o", "a", 1, 30)];
+        let policy = Policy { max_total_bytes: Some(100), ..off() };
+        // m doesn't fit after n; o, smaller, still does.
+        assert_eq!(select(&entries, 4, &policy), vec!["m"]);
+    }
+
+    #[test]
+    fn no_limits_keep_everything() {
+        let entries = [entry("a", "a", 0, u64::MAX / 2), entry("b", "a", 0, u64::MAX / 2)];
+        assert!(select(&entries, i64::MAX, &off()).is_empty());
+    }
+
+    #[test]
+    fn a_zero_setting_means_no_limit() {
+        assert_eq!(parse_limit(Some("0"), 5), None);
+        assert_eq!(parse_limit(Some(" 7 "), 5), Some(7));
+        assert_eq!(parse_limit(Some("lots"), 5), Some(5));
+        assert_eq!(parse_limit(None, 5), Some(5));
+    }
+}
@@ -0,0 +1,352 @@
+/*
+ * Which account a bundle's jobs run as.
+ *
+ *   PATCH /api/shell/{uuid}/run-as   { run_as: "deploy" | "root" | null }
+ *
+ * By default a job runs as SHELL_RUN_AS (`nobody` if that isn't set), not as
+ * whatever the server runs as — which in practice is root. A bundle that
+ * really needs root, like vps-setup, has to be switched to it by an
+ * administrator from the dashboard; `null` puts it back on the default.
+ *
+ * The switch happens in the forked child just before exec, through std's
+ * CommandExt::uid/gid: setgid, then — when the server is root — setgroups to
+ * drop its supplementary groups, then setuid. HOME, USER and LOGNAME are
+ * those of the account. The job record says who it ran as.
+ *
+ * /etc/<bundle>/ and its vars.env stay root's. Before a run the account is
+ * given read access through the group — root:<its group>, 0750 and 0640 — so
+ * the script can source its variables; it can't save new ones (common.sh's
+ * `save_var`), which is a job for a bundle that runs as root. Handing it the
+ * files instead would let anything else running as the same account read the
+ * secrets, or swap vars.env for a link the next root write follows.
+ *
+ * main.sh runs as the same account when it is only asked about itself —
+ * `targets`, `describe`, and the --list on upload, which for a new bundle is
+ * the default account. Those run the bundle's code too.
+ */
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{chown, fchown, OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use mongodb::bson::doc;
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::ShellBundle;
+use crate::utils::response::Response;
+
+/// When SHELL_RUN_AS isn't set. Exists on every Linux host, owns nothing.
+const FALLBACK_USER: &str = "nobody";
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    uuid: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestBody {
+    run_as: Option<String>,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+    form_data: web::Json<RequestBody>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.run_as", &path.uuid, gate).await?;
+
+    let run_as = form_data.run_as.as_deref().map(str::trim).filter(|n| !n.is_empty());
+    if let Some(name) = run_as {
+        if !is_valid_user(name) {
+            return Ok(Response::bad_request("Invalid user name"));
+        }
+        // Caught now rather than as a failed job later.
+        if let Err(error) = lookup(name) {
+            return Ok(Response::bad_request(&error));
+        }
+    }
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &path.uuid, "deleted_at": null },
+            doc! { "$set": { "run_as": run_as } },
+        )
+        .await;
+
+    let update_result = match result {
+        Ok(r) => r,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    if update_result.matched_count == 0 {
+        return Ok(Response::not_found("Bundle not found"));
+    }
+
+    let effective = run_as.map(str::to_string).unwrap_or_else(default_user);
+    let detail = Some(format!("run_as={}", effective));
+    Audit::record(&req, Some(&user), "shell.run_as", &path.uuid, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(
+        Response { message: format!("Jobs will run as {}", effective) }
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+pub fn default_user() -> String {
+    std::env::var("SHELL_RUN_AS")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| FALLBACK_USER.to_string())
+}
+
+/// The account a bundle's jobs run as: its own setting, or the default. A
+/// bundle with no record — one that exists only on disk — gets the default.
+pub async fn for_bundle(bundle: &str) -> Result<Account, String> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    match collection.find_one(doc! { "name": bundle, "deleted_at": null }).await {
+        Ok(Some(record)) => resolve(record.run_as),
+        Ok(None) => resolve(None),
+        Err(error) => {
+            log::error!("{:?}", error);
+            Err(error.to_string())
+        }
+    }
+}
+
+/// The account a bundle whose record says `run_as` runs as.
+pub fn resolve(run_as: Option<String>) -> Result<Account, String> {
+    lookup(&run_as.unwrap_or_else(default_user))
+}
+
+/// Have the spawned bash become `account` before exec.
+pub fn apply(command: &mut Command, account: &Account) {
+    command
+        .uid(account.uid)
+        .gid(account.gid)
+        .env("HOME", &account.home)
+        .env("USER", &account.name)
+        .env("LOGNAME", &account.name);
+}
+
+/// Whether this process can start something as `account` at all: only root
+/// can become someone else. Said plainly here, rather than as the EPERM the
+/// spawn would fail with.
+pub fn check(account: &Account) -> Result<(), String> {
+    // SAFETY: geteuid(2) can't fail and touches no memory.
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 && euid != account.uid {
+        return Err(format!(
+            "the server isn't running as root, so it can't run main.sh as {}",
+            account.name
+        ));
+    }
+    Ok(())
+}
+
+/// Let `account` read the bundle's /etc directory and its vars.env, and no
+/// more: both owned by root, in the account's group. For root that is
+/// root:root — which also takes back a directory an earlier version of this
+/// server handed to the account. Nothing to do when the server isn't root
+/// and so couldn't have switched anyway.
+pub fn share(vars_file: &Path, account: &Account) -> Result<(), String> {
+    // SAFETY: as in `check`.
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    if let Some(dir) = vars_file.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        chown(dir, Some(0), Some(account.gid))
+            .and_then(|_| fs::set_permissions(dir, fs::Permissions::from_mode(0o750)))
+            .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+
+    // Through a handle opened without following a link, so a vars.env that
+    // isn't a plain file is refused rather than its target re-owned.
+    let file = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(vars_file)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{}: {}", vars_file.display(), e)),
+    };
+    // Root needs no group to read through.
+    let mode = if account.uid == 0 { 0o600 } else { 0o640 };
+    fchown(&file, Some(0), Some(account.gid))
+        .and_then(|_| file.set_permissions(fs::Permissions::from_mode(mode)))
+        .map_err(|e| format!("{}: {}", vars_file.display(), e))
+}
+
+pub fn lookup(name: &str) -> Result<Account, String> {
+    let c_name = CString::new(name).map_err(|_| format!("invalid user name: {}", name))?;
+    // SAFETY: every pointer is to memory `passwd` owns for the whole call.
+    let found = passwd(|pwd, buf, len, result| unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), pwd, buf, len, result)
+    })?;
+    found.ok_or_else(|| format!("no such user on this host: {}", name))
+}
+
+/// The account this process runs as.
+#[cfg(test)]
+pub fn current() -> Result<Account, String> {
+    // SAFETY: as in `check`.
+    let euid = unsafe { libc::geteuid() };
+    // SAFETY: as in `lookup`.
+    let found = passwd(|pwd, buf, len, result| unsafe {
+        libc::getpwuid_r(euid, pwd, buf, len, result)
+    })?;
+    found.ok_or_else(|| format!("uid {} has no passwd entry", euid))
+}
+
+/// Run a getpw*_r lookup, growing the buffer until the entry fits.
+fn passwd<F>(call: F) -> Result<Option<Account>, String>
+where
+    F: Fn(*mut libc::passwd, *mut libc::c_char, libc::size_t, *mut *mut libc::passwd) -> libc::c_int,
+{
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: passwd is plain C data; all-zero is a valid (empty) value,
+        // and it is only read below once the call has filled it in.
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+        let rc = call(&mut pwd, buf.as_mut_ptr(), buf.len(), &mut result);
+        if rc == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc).to_string());
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+
+        // SAFETY: on success the strings point into `buf`, NUL-terminated.
+        let text = |p: *const libc::c_char| unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned();
+        return Ok(Some(Account {
+            name: text(pwd.pw_name),
+            uid: pwd.pw_uid,
+            gid: pwd.pw_gid,
+            home: text(pwd.pw_dir),
+        }));
+    }
+}
+
+/// The shape useradd accepts by default, less the trailing `$` of machine
+/// accounts.
+fn is_valid_user(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= 32
+        && chars
+            .next()
+            .map(|c| c.is_ascii_lowercase() || c == '_')
+            .unwrap_or(false)
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_resolve_through_the_passwd_database() {
+        let root = lookup("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(lookup("no-such-user-here").is_err());
+    }
+
+    #[test]
+    fn a_job_runs_as_the_account_it_is_given() {
+        // Becoming yourself needs no privilege, so this exercises the same
+        // setgid/setuid path without root.
+        let me = current().unwrap();
+        assert_eq!(check(&me), Ok(()));
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("id -u; id -g; echo $HOME; echo $USER");
+        apply(&mut command, &me);
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{}\n{}\n{}\n{}\n", me.uid, me.gid, me.home, me.name)
+        );
+    }
+
+    #[test]
+    fn only_root_can_become_someone_else() {
+        let me = current().unwrap();
+        let other = Account { name: "other".to_string(), uid: me.uid + 1, ..me.clone() };
+        assert_eq!(check(&other).is_ok(), me.uid == 0);
+    }
+
+    #[test]
+    fn vars_stay_root_s_and_a_link_is_refused() {
+        use std::os::unix::fs::{symlink, MetadataExt};
+
+        let me = current().unwrap();
+        let root = std::env::temp_dir().join(format!("shell-run-as-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("demo");
+        fs::create_dir_all(&dir).unwrap();
+        let vars = dir.join("vars.env");
+        fs::write(&vars, "A=\"1\"\n").unwrap();
+
+        let account = Account { name: "nobody".to_string(), uid: 65534, gid: 65534, home: "/".to_string() };
+        share(&vars, &account).unwrap();
+        if me.uid == 0 {
+            let meta = fs::metadata(&vars).unwrap();
+            assert_eq!((meta.uid(), meta.gid(), meta.mode() & 0o777), (0, 65534, 0o640));
+            assert_eq!(fs::metadata(&dir).unwrap().uid(), 0);
+        }
+
+        let elsewhere = root.join("elsewhere");
+        fs::write(&elsewhere, "").unwrap();
+        fs::remove_file(&vars).unwrap();
+        symlink(&elsewhere, &vars).unwrap();
+        assert_eq!(share(&vars, &account).is_err(), me.uid == 0);
+        assert_eq!(fs::metadata(&elsewhere).unwrap().gid(), me.gid);
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn user_names_are_checked() {
+        for good in ["deploy", "_svc", "ci-runner2"] {
+            assert!(is_valid_user(good), "{}", good);
+        }
+        for bad in ["", "Deploy", "1abc", "a b", "../etc", "x$"] {
+            assert!(!is_valid_user(bad), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,465 @@
+/*
+ * Bundle targets on a timetable — a nightly certbot renewal, a backup.
+ *
+ *   GET    /api/shell/{name}/schedules        the bundle's schedules
+ *   POST   /api/shell/{name}/schedules        { target, cron, vars, secret }
+ *   PATCH  /api/shell/{name}/schedules/{id}   { enabled } — pause, resume
+ *   DELETE /api/shell/{name}/schedules/{id}
+ *
+ * Administrator-only, with either credential like the cross-bundle job list:
+ * a schedule runs root scripts unattended, long after whoever set it up has
+ * gone. Making one, or resuming one, takes a CLI token and not the session
+ * cookie — it sets up runs the way `run` starts one (see the top of
+ * shell.rs). Listing, pausing and deleting take either.
+ *
+ * `fire_due`, which main.rs calls every FIRE_POLL, starts each schedule whose
+ * next_run_at has passed the same way `run` would with `queue: true` — through
+ * the bundle's lock (shell/lock.rs) and `launch`, so the job is an ordinary
+ * `shell_job` record, its token_label naming the schedule. A firing is skipped
+ * if the job the last one started is still queued or running. The job runs as
+ * the administrator who made the schedule; once that account is gone,
+ * suspended or no longer an administrator, the schedule is disabled instead. A schedule
+ * whose time passed while the server was down fires once on start-up, not
+ * once per missed slot.
+ *
+ * A secret variable's value is kept sealed, under the same SHELL_PROFILE_KEY
+ * as a profile's (shell/profiles.rs), and opened only when the schedule fires.
+ */
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::cron::Schedule;
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_cli, AccessRequirement, User};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellJob, ShellSchedule};
+use crate::utils::response::Response;
+
+use super::lock::{self, Admission};
+use super::redact::MASK;
+use super::{admin, bundle_dir, integrity, is_admin, is_valid_target, prepare_vars, profiles, Plan};
+
+/// Cron has minute resolution; checking twice a minute keeps a firing within
+/// half a minute of its time.
+pub const FIRE_POLL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+pub struct RequestBody {
+    target: String,
+    cron: String,
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    #[serde(default)]
+    secret: Vec<String>,
+}
+
+pub async fn task(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+    admin(&req).await?;
+    let bundle = path.into_inner();
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSchedule>("shell_schedule");
+
+    let cursor = match collection
+        .find(doc! { "bundle": &bundle })
+        .sort(doc! { "created_at": -1 })
+        .await
+    {
+        Ok(c) => c,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let schedules: Vec<ShellSchedule> = match cursor.try_collect().await {
+        Ok(v) => v,
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let schedules: Vec<ShellSchedule> = schedules.into_iter().map(masked).collect();
+    Ok(HttpResponse::Ok().content_type("application/json").json(schedules))
+}
+
+pub async fn create(
+    req: HttpRequest,
+    path: web::Path<String>,
+    form_data: web::Json<RequestBody>,
+) -> Result<HttpResponse, Error> {
+    let bundle = path.into_inner();
+    let gate = require_cli(&req, AccessRequirement::Role(AccountRole::Administrator)).await;
+    let user = Audit::checked(&req, "shell.schedule.create", &bundle, gate).await?;
+    let body = form_data.into_inner();
+
+    if let Err(res) = bundle_dir(&bundle) {
+        return Ok(res);
+    }
+    if !is_valid_target(&body.target) {
+        return Ok(Response::bad_request("Invalid target name"));
+    }
+
+    let cron = body.cron.trim().to_string();
+    let next = match Schedule::parse(&cron) {
+        Ok(schedule) => schedule.next_after(Utc::now()),
+        Err(error) => return Ok(Response::bad_request(&error)),
+    };
+    let next = match next {
+        Some(next) => next.timestamp_millis(),
+        None => return Ok(Response::bad_request("That expression never fires")),
+    };
+
+    // Checked now so a typo is a 400 here rather than a failed job at 3am.
+    // Defaults are left to each firing, which sees vars.env as it is then.
+    let mut vars = body.vars;
+    let mut secret = body.secret;
+    if let Err(res) = prepare_vars(&bundle, &body.target, &mut vars.clone(), &mut secret).await {
+        return Ok(res);
+    }
+
+    let uuid = Uuid::now_v7().to_string();
+    // The key is only needed, and so only asked for, when there is
+    // something to seal.
+    let secrets = if vars.keys().any(|k| secret.contains(k)) {
+        let sealed = profiles::key()
+            .and_then(|key| profiles::seal_secrets(&key, &scope(&uuid), &mut vars, &secret));
+        match sealed {
+            Ok(secrets) => secrets,
+            Err(error) => return Ok(Response::bad_request(&error)),
+        }
+    } else {
+        HashMap::new()
+    };
+
+    let schedule = ShellSchedule {
+        uuid,
+        bundle,
+        target: body.target,
+        cron,
+        vars,
+        secret_keys: secret,
+        secrets,
+        enabled: true,
+        next_run_at: Some(next),
+        last_run_at: None,
+        last_job: None,
+        last_skipped_at: None,
+        created_at: Utc::now().timestamp_millis(),
+        created_by: user.user_id.clone(),
+    };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSchedule>("shell_schedule");
+    if let Err(error) = collection.insert_one(schedule.clone()).await {
+        log::error!("{:?}", error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let subject = format!("{}/{}", schedule.bundle, schedule.target);
+    let detail = Some(format!("{} {}", schedule.uuid, schedule.cron));
+    Audit::record(&req, Some(&user), "shell.schedule.create", &subject, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(masked(schedule)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ToggleBody {
+    enabled: bool,
+}
+
+pub async fn toggle(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    form_data: web::Json<ToggleBody>,
+) -> Result<HttpResponse, Error> {
+    let (bundle, id) = path.into_inner();
+    let gate = if form_data.enabled {
+        require_cli(&req, AccessRequirement::Role(AccountRole::Administrator)).await
+    } else {
+        admin(&req).await
+    };
+    let user = Audit::checked(&req, "shell.schedule.toggle", &bundle, gate).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSchedule>("shell_schedule");
+
+    let schedule = match collection.find_one(doc! { "uuid": &id, "bundle": &bundle }).await {
+        Ok(Some(schedule)) => schedule,
+        Ok(None) => return Ok(Response::not_found("No such schedule")),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    // Resuming counts from now: the slots that passed while it was paused
+    // are not owed a run.
+    let next = Schedule::parse(&schedule.cron)
+        .ok()
+        .and_then(|cron| cron.next_after(Utc::now()))
+        .map(|t| t.timestamp_millis());
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &id },
+            doc! { "$set": { "enabled": form_data.enabled, "next_run_at": next } },
+        )
+        .await;
+
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let detail = Some(format!("{} enabled={}", id, form_data.enabled));
+    Audit::record(&req, Some(&user), "shell.schedule.toggle", &bundle, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(masked(ShellSchedule {
+        enabled: form_data.enabled,
+        next_run_at: next,
+        ..schedule
+    })))
+}
+
+pub async fn delete(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (bundle, id) = path.into_inner();
+    let user = Audit::checked(&req, "shell.schedule.delete", &bundle, admin(&req).await).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSchedule>("shell_schedule");
+
+    match collection.delete_one(doc! { "uuid": &id, "bundle": &bundle }).await {
+        Ok(result) if result.deleted_count == 0 => Ok(Response::not_found("No such schedule")),
+        Ok(_) => {
+            Audit::record(&req, Some(&user), "shell.schedule.delete", &bundle, AuditOutcome::Success, Some(id)).await;
+            Ok(HttpResponse::Ok().content_type("application/json").json(
+                Response { message: "Deleted".to_string() }
+            ))
+        }
+        Err(error) => {
+            log::error!("{:?}", error);
+            Ok(Response::internal_server_error(&error.to_string()))
+        }
+    }
+}
+
+/// A schedule as it goes out in a response: secret values masked.
+fn masked(mut schedule: ShellSchedule) -> ShellSchedule {
+    for value in schedule.secrets.values_mut() {
+        *value = MASK.to_string();
+    }
+    schedule
+}
+
+/// What a schedule's secrets are sealed under (profiles::seal_secrets).
+fn scope(uuid: &str) -> String {
+    format!("schedule:{}", uuid)
+}
+
+/// Start every enabled schedule that is due.
+pub async fn fire_due() {
+    let now = Utc::now().timestamp_millis();
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSchedule>("shell_schedule");
+
+    let cursor = collection
+        .find(doc! { "enabled": true, "next_run_at": { "$ne": null, "$lte": now } })
+        .await;
+
+    let due: Vec<ShellSchedule> = match cursor {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(v) => v,
+            Err(error) => {
+                log::error!("{:?}", error);
+                return;
+            }
+        },
+        Err(error) => {
+            log::error!("{:?}", error);
+            return;
+        }
+    };
+
+    for schedule in due {
+        fire(schedule).await;
+    }
+}
+
+async fn fire(schedule: ShellSchedule) {
+    let now = Utc::now();
+    let next = Schedule::parse(&schedule.cron)
+        .ok()
+        .and_then(|cron| cron.next_after(now))
+        .map(|t| t.timestamp_millis());
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSchedule>("shell_schedule");
+
+    // Advanced before anything else: whatever happens below, this slot has
+    // been dealt with and the next poll mustn't try it again.
+    let advanced = collection
+        .update_one(
+            doc! { "uuid": &schedule.uuid },
+            doc! { "$set": { "next_run_at": next } },
+        )
+        .await;
+    if let Err(error) = advanced {
+        log::error!("schedule {}: {:?}", schedule.uuid, error);
+        return;
+    }
+
+    if let Some(last) = &schedule.last_job {
+        if still_going(last).await {
+            log::warn!("schedule {}: job {} is still going; skipped", schedule.uuid, last);
+            let _ = collection
+                .update_one(
+                    doc! { "uuid": &schedule.uuid },
+                    doc! { "$set": { "last_skipped_at": now.timestamp_millis() } },
+                )
+                .await;
+            return;
+        }
+    }
+
+    let dir = match bundle_dir(&schedule.bundle) {
+        Ok(dir) => dir,
+        Err(res) => {
+            log::warn!("schedule {}: bundle {} unavailable ({})", schedule.uuid, schedule.bundle, res.status());
+            return;
+        }
+    };
+
+    let mut vars = schedule.vars.clone();
+    if !schedule.secrets.is_empty() {
+        let opened = profiles::key()
+            .and_then(|key| profiles::open_secrets(&key, &scope(&schedule.uuid), &schedule.secrets));
+        match opened {
+            Ok(opened) => vars.extend(opened),
+            Err(error) => {
+                log::error!("schedule {}: {}", schedule.uuid, error);
+                return;
+            }
+        }
+    }
+    let mut secret = schedule.secret_keys.clone();
+    if let Err(res) = prepare_vars(&schedule.bundle, &schedule.target, &mut vars, &mut secret).await {
+        log::warn!("schedule {}: variables refused ({})", schedule.uuid, res.status());
+        return;
+    }
+
+    let user = User {
+        user_id: schedule.created_by.clone(),
+        role: AccountRole::Administrator,
+        token_label: Some(format!("schedule {}", schedule.uuid)),
+    };
+
+    let subject = format!("{}/{}", schedule.bundle, schedule.target);
+
+    // Runs as whoever made it, so only while they could still make it.
+    match is_admin(&schedule.created_by).await {
+        Ok(true) => {}
+        Ok(false) => {
+            log::warn!(
+                "schedule {}: {} is no longer an administrator; disabled",
+                schedule.uuid,
+                schedule.created_by
+            );
+            let _ = collection
+                .update_one(doc! { "uuid": &schedule.uuid }, doc! { "$set": { "enabled": false } })
+                .await;
+            let detail = Some(format!("schedule {}: its owner is no longer an administrator", schedule.uuid));
+            Audit::record_system(&user, "shell.run", &subject, AuditOutcome::Denied, detail).await;
+            return;
+        }
+        Err(error) => {
+            log::error!("schedule {}: {}", schedule.uuid, error);
+            return;
+        }
+    }
+
+    let files = match integrity::verify(&schedule.bundle, &dir).await {
+        Ok(files) => files,
+        Err(error) => {
+            log::error!("schedule {}: {}", schedule.uuid, error);
+            Audit::record_system(&user, "shell.run", &subject, AuditOutcome::Failed, Some(error)).await;
+            return;
+        }
+    };
+
+    let job = match lock::admit(&schedule.bundle, files, &Plan::single(&schedule.target), &user, vars, secret, true).await {
+        Ok(Admission::Started(job)) | Ok(Admission::Queued(job)) | Ok(Admission::Pending(job)) => job,
+        Ok(Admission::Busy(_)) => return,
+        Err(error) => {
+            log::error!("schedule {}: {}", schedule.uuid, error);
+            Audit::record_system(&user, "shell.run", &subject, AuditOutcome::Failed, Some(error)).await;
+            return;
+        }
+    };
+    Audit::record_system(&user, "shell.run", &subject, AuditOutcome::Success, Some(job.uuid.clone())).await;
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &schedule.uuid },
+            doc! { "$set": { "last_run_at": now.timestamp_millis(), "last_job": &job.uuid } },
+        )
+        .await;
+    if let Err(error) = result {
+        log::error!("schedule {}: {:?}", schedule.uuid, error);
+    }
+}
+
+async fn still_going(job: &str) -> bool {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    matches!(
+        collection
+            .count_documents(doc! { "uuid": job, "status": { "$in": ["pending_approval", "queued", "running"] } })
+            .await,
+        Ok(n) if n > 0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_values_never_leave_masked() {
+        let schedule = ShellSchedule {
+            uuid: "s".to_string(),
+            bundle: "vps-setup".to_string(),
+            target: "backup".to_string(),
+            cron: "@daily".to_string(),
+            vars: HashMap::from([("BUCKET".to_string(), "nightly".to_string())]),
+            secret_keys: vec!["S3_KEY".to_string()],
+            secrets: HashMap::from([("S3_KEY".to_string(), "c2VhbGVk".to_string())]),
+            enabled: true,
+            next_run_at: None,
+            last_run_at: None,
+            last_job: None,
+            last_skipped_at: None,
+            created_at: 0,
+            created_by: "admin".to_string(),
+        };
+
+        let out = masked(schedule);
+        assert_eq!(out.vars["BUCKET"], "nightly");
+        assert_eq!(out.secrets["S3_KEY"], MASK);
+    }
+}
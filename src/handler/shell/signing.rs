@@ -0,0 +1,284 @@
+/*
+ * Signed bundle uploads.
+ *
+ *   GET    /api/shell/keys           the registered public keys
+ *   POST   /api/shell/keys           { name, public_key } — register one
+ *   DELETE /api/shell/keys/{name}
+ *
+ * All administrator-only, from the dashboard. A key is Ed25519: the raw 32
+ * bytes in base64, or the PEM that `openssl pkey -pubout` writes.
+ *
+ * An upload (create.rs, update.rs) may then carry a `signature` field: a
+ * detached Ed25519 signature over the archive file exactly as uploaded, in
+ * base64 or hex —
+ *
+ *   openssl pkeyutl -sign -inkey key.pem -rawin -in bundle.tar.gz | base64 -w0
+ *
+ * It is checked against every registered key before anything is unpacked. A
+ * signature that matches none refuses the upload; the key that matched is
+ * recorded on the version as `signed_by`. Unsigned uploads are accepted
+ * unless SHELL_REQUIRE_SIGNATURE=true.
+ *
+ * Removing a key doesn't change what existing versions say signed them.
+ */
+use std::env;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::ShellSigningKey;
+use crate::utils::{archive, response::Response};
+
+/// What an Ed25519 SubjectPublicKeyInfo has in front of the 32 key bytes.
+const SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+#[derive(Debug, Deserialize)]
+pub struct KeyBody {
+    name: String,
+    public_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeyPath {
+    name: String,
+}
+
+pub async fn keys(req: HttpRequest) -> Result<HttpResponse, Error> {
+    require_access(&req, AccessRequirement::Role(AccountRole::Administrator))?;
+
+    match registered().await {
+        Ok(keys) => Ok(HttpResponse::Ok().content_type("application/json").json(keys)),
+        Err(res) => Ok(res),
+    }
+}
+
+pub async fn add_key(req: HttpRequest, form_data: web::Json<KeyBody>) -> Result<HttpResponse, Error> {
+    let name = form_data.name.trim().to_lowercase();
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.key.add", &name, gate).await?;
+
+    if !is_valid_key_name(&name) {
+        return Ok(Response::bad_request(
+            "Name may only contain lowercase letters, digits, hyphens and underscores",
+        ));
+    }
+    let key = match parse_public_key(&form_data.public_key) {
+        Ok(key) => key,
+        Err(error) => return Ok(Response::bad_request(&error)),
+    };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSigningKey>("shell_signing_key");
+
+    match collection.find_one(doc! { "name": &name }).await {
+        Ok(Some(_)) => return Ok(Response::bad_request("A key with that name already exists")),
+        Ok(None) => {}
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    }
+
+    let record = ShellSigningKey {
+        name: name.clone(),
+        public_key: STANDARD.encode(key),
+        created_at: Utc::now().timestamp_millis(),
+        created_by: user.user_id.clone(),
+    };
+    if let Err(error) = collection.insert_one(record.clone()).await {
+        log::error!("{:?}", error);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    Audit::record(&req, Some(&user), "shell.key.add", &name, AuditOutcome::Success, None).await;
+    Ok(HttpResponse::Ok().content_type("application/json").json(record))
+}
+
+pub async fn remove_key(req: HttpRequest, path: web::Path<KeyPath>) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.key.remove", &path.name, gate).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSigningKey>("shell_signing_key");
+
+    match collection.delete_one(doc! { "name": &path.name }).await {
+        Ok(r) if r.deleted_count == 0 => Ok(Response::not_found("No such key")),
+        Ok(_) => {
+            Audit::record(&req, Some(&user), "shell.key.remove", &path.name, AuditOutcome::Success, None).await;
+            Ok(HttpResponse::Ok().content_type("application/json").json(
+                Response { message: "Removed".to_string() }
+            ))
+        }
+        Err(error) => {
+            log::error!("{:?}", error);
+            Ok(Response::internal_server_error(&error.to_string()))
+        }
+    }
+}
+
+/// The name of the key `signature` verifies `source` under; None for an
+/// unsigned upload the server accepts. The error is the response to send.
+pub async fn check(source: &archive::Source<'_>, signature: Option<&str>) -> Result<Option<String>, HttpResponse> {
+    let signature = match signature.map(str::trim).filter(|s| !s.is_empty()) {
+        Some(signature) => signature,
+        None if required() => {
+            return Err(Response::bad_request("This server only accepts signed bundles"));
+        }
+        None => return Ok(None),
+    };
+
+    let signature = decode_signature(signature).map_err(|e| Response::bad_request(&e))?;
+    let keys = registered().await?;
+    let archive = source.read().map_err(|e| {
+        log::error!("{}", e);
+        Response::internal_server_error(&e)
+    })?;
+
+    match signer(&keys, &archive, &signature) {
+        Some(name) => Ok(Some(name)),
+        None => Err(Response::bad_request(
+            "The signature doesn't match the archive under any registered key",
+        )),
+    }
+}
+
+fn required() -> bool {
+    env::var("SHELL_REQUIRE_SIGNATURE").map(|v| v == "true").unwrap_or(false)
+}
+
+async fn registered() -> Result<Vec<ShellSigningKey>, HttpResponse> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellSigningKey>("shell_signing_key");
+
+    let cursor = collection.find(doc! {}).sort(doc! { "name": 1 }).await;
+    let result = match cursor {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(error) => Err(error),
+    };
+    result.map_err(|error| {
+        log::error!("{:?}", error);
+        Response::internal_server_error(&error.to_string())
+    })
+}
+
+/// The first of `keys` that `signature` over `message` verifies under.
+fn signer(keys: &[ShellSigningKey], message: &[u8], signature: &[u8]) -> Option<String> {
+    keys.iter()
+        .find(|key| match STANDARD.decode(&key.public_key) {
+            Ok(bytes) => UnparsedPublicKey::new(&ED25519, bytes).verify(message, signature).is_ok(),
+            Err(_) => false,
+        })
+        .map(|key| key.name.clone())
+}
+
+/// The 32 key bytes from raw base64 or a PEM public key.
+fn parse_public_key(text: &str) -> Result<[u8; 32], String> {
+    let body: String = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let bytes = STANDARD
+        .decode(body)
+        .map_err(|_| "The public key is neither base64 nor PEM".to_string())?;
+
+    let raw = match bytes.len() {
+        32 => &bytes[..],
+        44 if bytes.starts_with(&SPKI_PREFIX) => &bytes[SPKI_PREFIX.len()..],
+        _ => return Err("The public key is not an Ed25519 key".to_string()),
+    };
+    let mut key = [0u8; 32];
+    key.copy_from_slice(raw);
+    Ok(key)
+}
+
+/// A 64-byte signature, given in hex or base64.
+fn decode_signature(text: &str) -> Result<Vec<u8>, String> {
+    let bytes = match hex::decode(text) {
+        Ok(bytes) => bytes,
+        Err(_) => STANDARD
+            .decode(text)
+            .map_err(|_| "The signature is neither hex nor base64".to_string())?,
+    };
+    if bytes.len() != 64 {
+        return Err("An Ed25519 signature is 64 bytes".to_string());
+    }
+    Ok(bytes)
+}
+
+fn is_valid_key_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn registered(name: &str, pair: &Ed25519KeyPair) -> ShellSigningKey {
+        ShellSigningKey {
+            name: name.to_string(),
+            public_key: STANDARD.encode(pair.public_key().as_ref()),
+            created_at: 0,
+            created_by: "admin".to_string(),
+        }
+    }
+
+    #[test]
+    fn the_key_that_signed_is_the_one_named() {
+        let (ours, theirs) = (pair(), pair());
+        let keys = [registered("ci", &theirs), registered("release", &ours)];
+        let signature = ours.sign(b"bundle.tar.gz");
+
+        assert_eq!(signer(&keys, b"bundle.tar.gz", signature.as_ref()), Some("release".to_string()));
+        assert_eq!(signer(&keys, b"tampered", signature.as_ref()), None);
+        assert_eq!(signer(&keys[..1], b"bundle.tar.gz", signature.as_ref()), None);
+    }
+
+    #[test]
+    fn public_keys_come_raw_or_as_pem() {
+        let key = pair();
+        let raw = key.public_key().as_ref().to_vec();
+        assert_eq!(parse_public_key(&STANDARD.encode(&raw)).unwrap().to_vec(), raw);
+
+        let der = [&SPKI_PREFIX[..], &raw].concat();
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode(der)
+        );
+        assert_eq!(parse_public_key(&pem).unwrap().to_vec(), raw);
+
+        assert!(parse_public_key(&STANDARD.encode([0u8; 16])).is_err());
+        assert!(parse_public_key("not a key").is_err());
+    }
+
+    #[test]
+    fn signatures_come_as_hex_or_base64() {
+        let signature = [7u8; 64];
+        assert_eq!(decode_signature(&hex::encode(signature)).unwrap(), signature);
+        assert_eq!(decode_signature(&STANDARD.encode(signature)).unwrap(), signature);
+        assert!(decode_signature(&hex::encode([7u8; 32])).is_err());
+    }
+}
@@ -2,7 +2,9 @@ use serde::{Deserialize, Serialize};
 use mongodb::bson::doc;
 use crate::Model::Shell::ShellBundle;
 use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
 use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
 use crate::Middleware::Auth::{require_access, AccessRequirement};
 use crate::utils::response::Response;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
@@ -29,7 +31,8 @@ pub async fn task(
     path: web::Path<PathVariables>,
     form_data: web::Json<RequestBody>,
 ) -> Result<HttpResponse, Error> {
-    require_access(&req, AccessRequirement::Role(AccountRole::Administrator))?;
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.public_run", &path.uuid, gate).await?;
 
     let db = MongoDB.connect();
     let collection = db.collection::<ShellBundle>("shell_bundle");
@@ -51,6 +54,9 @@ pub async fn task(
         return Ok(Response::not_found("Bundle not found"));
     }
 
+    let detail = Some(format!("public_run={}", form_data.public_run));
+    Audit::record(&req, Some(&user), "shell.public_run", &path.uuid, AuditOutcome::Success, detail).await;
+
     Ok(HttpResponse::Ok().content_type("application/json").json(
         Response { message: "Updated".to_string() }
     ))
@@ -0,0 +1,216 @@
+/*
+ * Where a deleted bundle's files wait until they are restored or purged.
+ *
+ * Deleting a bundle (shell/delete.rs) moves its version directories out of
+ * SHELL_ROOT to TRASH_DIR/<uuid>/ and removes the SHELL_ROOT/<name> link, so
+ * bundle_dir no longer finds it and every execution route answers 404. Keyed
+ * by uuid rather than name, because once a bundle is deleted its name is free
+ * for a new upload, and that one may be deleted too.
+ *
+ * Its schedules, hooks and profiles are keyed by name too, so they are
+ * moved out of the way with it (`park`): filed under the bundle's uuid,
+ * schedules disabled and hooks revoked. Left where they were, they would
+ * start targets on whatever bundle is uploaded under the name next. A
+ * restore puts them back, still disabled and revoked.
+ *
+ * After SHELL_TRASH_DAYS (30 by default) `purge_expired`, which main.rs runs
+ * hourly, removes the files, those records and the bundle's own for good.
+ * Job records and logs are left alone — they are history, and expire on
+ * their own terms.
+ */
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use futures_util::TryStreamExt;
+use mongodb::bson::doc;
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Model::Shell::{ShellBundle, ShellHook, ShellSchedule, ShellVarProfile};
+
+const TRASH_DIR: &str = "./trash/shell";
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+/// TRASH_DIR, created if it isn't there yet.
+pub fn trash_root() -> Result<PathBuf, String> {
+    let root = Path::new(TRASH_DIR);
+    fs::create_dir_all(root).map_err(|e| format!("{}: {}", TRASH_DIR, e))?;
+    root.canonicalize()
+        .map_err(|e| format!("{}: {}", TRASH_DIR, e))
+}
+
+pub fn trash_dir(root: &Path, uuid: &str) -> PathBuf {
+    root.join(uuid)
+}
+
+fn retention_days() -> i64 {
+    env::var("SHELL_TRASH_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|d| *d >= 0)
+        .unwrap_or(DEFAULT_RETENTION_DAYS)
+}
+
+/// The deleted_at before which a bundle is past keeping.
+fn cutoff(now: i64, days: i64) -> i64 {
+    now - days * 24 * 60 * 60 * 1000
+}
+
+/// The name a deleted bundle's schedules, hooks and profiles are filed
+/// under. No bundle can have it: is_valid_bundle refuses a leading dot.
+fn parked(uuid: &str) -> String {
+    format!(".trash-{}", uuid)
+}
+
+/// File the schedules, hooks and profiles of the bundle `name` under its
+/// uuid, disabling the schedules and revoking the hooks on the way.
+pub async fn park(name: &str, uuid: &str) -> Result<(), String> {
+    let db = MongoDB.connect();
+    let parked = parked(uuid);
+    let now = Utc::now().timestamp_millis();
+
+    let schedules = db.collection::<ShellSchedule>("shell_schedule");
+    let hooks = db.collection::<ShellHook>("shell_hook");
+    let profiles = db.collection::<ShellVarProfile>("shell_var_profile");
+
+    schedules
+        .update_many(
+            doc! { "bundle": name },
+            doc! { "$set": { "bundle": &parked, "enabled": false } },
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    hooks
+        .update_many(
+            doc! { "bundle": name, "revoked_at": null },
+            doc! { "$set": { "revoked_at": now } },
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    hooks
+        .update_many(doc! { "bundle": name }, doc! { "$set": { "bundle": &parked } })
+        .await
+        .map_err(|e| e.to_string())?;
+    profiles
+        .update_many(doc! { "bundle": name }, doc! { "$set": { "bundle": &parked } })
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Give a restored bundle back what `park` filed away.
+pub async fn unpark(uuid: &str, name: &str) -> Result<(), String> {
+    let db = MongoDB.connect();
+    let parked = parked(uuid);
+    let back = doc! { "$set": { "bundle": name } };
+
+    db.collection::<ShellSchedule>("shell_schedule")
+        .update_many(doc! { "bundle": &parked }, back.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    db.collection::<ShellHook>("shell_hook")
+        .update_many(doc! { "bundle": &parked }, back.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    db.collection::<ShellVarProfile>("shell_var_profile")
+        .update_many(doc! { "bundle": &parked }, back)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Remove what `park` filed away, for good.
+async fn remove_parked(uuid: &str) -> Result<(), String> {
+    let db = MongoDB.connect();
+    let parked = parked(uuid);
+
+    db.collection::<ShellSchedule>("shell_schedule")
+        .delete_many(doc! { "bundle": &parked })
+        .await
+        .map_err(|e| e.to_string())?;
+    db.collection::<ShellHook>("shell_hook")
+        .delete_many(doc! { "bundle": &parked })
+        .await
+        .map_err(|e| e.to_string())?;
+    db.collection::<ShellVarProfile>("shell_var_profile")
+        .delete_many(doc! { "bundle": &parked })
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Remove the files and records of bundles deleted longer ago than the
+/// retention period.
+pub async fn purge_expired() {
+    let root = match trash_root() {
+        Ok(root) => root,
+        Err(error) => {
+            log::error!("{}", error);
+            return;
+        }
+    };
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let before = cutoff(Utc::now().timestamp_millis(), retention_days());
+    let cursor = collection
+        .find(doc! { "deleted_at": { "$ne": null, "$lt": before } })
+        .await;
+
+    let expired: Vec<ShellBundle> = match cursor {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(v) => v,
+            Err(error) => {
+                log::error!("{:?}", error);
+                return;
+            }
+        },
+        Err(error) => {
+            log::error!("{:?}", error);
+            return;
+        }
+    };
+
+    for bundle in expired {
+        let dir = trash_dir(&root, &bundle.uuid);
+        if dir.exists() {
+            if let Err(error) = fs::remove_dir_all(&dir) {
+                // Keep the record, so the next sweep tries again.
+                log::error!("{}: {}", dir.display(), error);
+                continue;
+            }
+        }
+
+        if let Err(error) = remove_parked(&bundle.uuid).await {
+            log::error!("shell bundle {}: {}", bundle.uuid, error);
+            continue;
+        }
+
+        match collection.delete_one(doc! { "uuid": &bundle.uuid }).await {
+            Ok(_) => log::info!("Purged shell bundle {} ({})", bundle.name, bundle.uuid),
+            Err(error) => log::error!("{:?}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::shell::is_valid_bundle;
+
+    #[test]
+    fn parked_records_are_out_of_every_bundle_s_way() {
+        let name = parked("0190b8a2-0000-7000-8000-000000000000");
+        assert!(!is_valid_bundle(&name));
+        assert_ne!(parked("a"), parked("b"));
+    }
+
+    #[test]
+    fn the_cutoff_is_whole_days_back() {
+        let day = 24 * 60 * 60 * 1000;
+        assert_eq!(cutoff(10 * day, 3), 7 * day);
+        assert_eq!(cutoff(10 * day, 0), 10 * day);
+    }
+}
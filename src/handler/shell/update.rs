@@ -0,0 +1,298 @@
+/*
+ * Upload a new version of an existing shell bundle.
+ *
+ * Same body shape and archive limits as create.rs, multipart with a
+ * deprecated JSON fallback, without the name: the bundle is the one in the
+ * URL. The archive is unpacked beside the versions already on disk, checked
+ * for a main.sh and a sound bundle.json (manifest.rs), its targets read, and
+ * only then numbered and made the active one (version.rs) — a bad upload
+ * leaves the bundle as it was.
+ */
+use std::fs;
+
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson};
+use serde::{Deserialize, Serialize};
+
+use actix_multipart::Multipart;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement, User};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellBundle, ShellBundleVersion};
+use crate::utils::{archive, response::Response, upload};
+
+use super::create::{LIMITS, MAX_UPLOAD_BYTES};
+use super::version::{
+    activate, adopt_unversioned, busy, has_active_jobs, known_versions, prune, staging_dir,
+    version_dir, versions_dir, MAX_VERSIONS,
+};
+use super::{integrity, list_targets, lock, manifest, run_as, shell_root, signing};
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    uuid: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestBody {
+    /// Replaces the bundle's description when given; left as it was if not.
+    description: Option<String>,
+    file: Vec<u8>,
+    /// Detached Ed25519 signature over `file` (signing.rs).
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+    payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.upload_version", &path.uuid, gate).await?;
+
+    let upload = match upload::receive(payload, "file", MAX_UPLOAD_BYTES).await {
+        Ok(upload) => upload,
+        Err(res) => return Ok(res),
+    };
+    let file = match &upload.file {
+        Some(file) if file.size > 0 => file,
+        _ => return Ok(Response::bad_request("An archive (.zip, .tar or .tar.gz) is required")),
+    };
+
+    let description = upload.field("description");
+    let signature = upload.fields.get("signature").map(String::as_str);
+    let source = archive::Source::File(&file.path);
+    update(&req, &user, &path.uuid, Some(description), source, signature).await
+}
+
+/// The JSON body, deprecated in favour of `task`.
+pub async fn task_json(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+    form_data: web::Json<RequestBody>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.upload_version", &path.uuid, gate).await?;
+    log::warn!("shell.upload_version: deprecated JSON upload from {}", user.user_id);
+
+    if form_data.file.is_empty() {
+        return Ok(upload::deprecated(Response::bad_request(
+            "An archive (.zip, .tar or .tar.gz) is required",
+        )));
+    }
+
+    let description = form_data.description.clone();
+    let source = archive::Source::Bytes(&form_data.file);
+    let res = update(&req, &user, &path.uuid, description, source, form_data.signature.as_deref()).await?;
+    Ok(upload::deprecated(res))
+}
+
+async fn update(
+    req: &HttpRequest,
+    user: &User,
+    uuid: &str,
+    description: Option<String>,
+    source: archive::Source<'_>,
+    signature: Option<&str>,
+) -> Result<HttpResponse, Error> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let bundle = match collection.find_one(doc! { "uuid": uuid, "deleted_at": null }).await {
+        Ok(Some(bundle)) => bundle,
+        Ok(None) => return Ok(Response::not_found("Bundle not found")),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    // Before anything is unpacked: a bad signature leaves nothing on disk.
+    let signed_by = match signing::check(&source, signature).await {
+        Ok(signed_by) => signed_by,
+        Err(res) => return Ok(res),
+    };
+
+    let root = match shell_root() {
+        Ok(root) => root,
+        Err(error) => {
+            log::error!("{}", error);
+            return Ok(Response::internal_server_error(&error));
+        }
+    };
+
+    // Unpacked, checked, hashed and listed in a directory of its own, with no
+    // lock held: runs on every bundle wait on lock::pause, and a slow upload
+    // shouldn't hold them all up. It only gets its number under the pause.
+    let staged = staging_dir(&root, &bundle.name);
+    if let Err(error) = fs::create_dir_all(versions_dir(&root, &bundle.name)) {
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    if let Err(error) = archive::extract(source, &staged, &LIMITS) {
+        let _ = fs::remove_dir_all(&staged);
+        return Ok(Response::bad_request(&error));
+    }
+
+    if !staged.join("main.sh").is_file() {
+        let _ = fs::remove_dir_all(&staged);
+        return Ok(Response::bad_request(
+            "The archive has no main.sh at its root",
+        ));
+    }
+
+    // Optional, but a bundle.json that is there and wrong is refused here,
+    // not discovered on the first run.
+    let manifest = match manifest::load(&staged) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            let _ = fs::remove_dir_all(&staged);
+            return Ok(Response::bad_request(&error));
+        }
+    };
+
+    // Hashed before --list runs, as create.rs does.
+    let files = match integrity::hash_tree(&staged) {
+        Ok(files) => files,
+        Err(error) => {
+            log::error!("{}", error);
+            let _ = fs::remove_dir_all(&staged);
+            return Ok(Response::internal_server_error(&error));
+        }
+    };
+
+    let targets = run_as::resolve(bundle.run_as.clone())
+        .and_then(|account| list_targets(&staged, &account))
+        .unwrap_or_default();
+
+    let _paused = lock::pause().await;
+    match has_active_jobs(&bundle.name).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let _ = fs::remove_dir_all(&staged);
+            return Ok(busy());
+        }
+        Err(res) => {
+            let _ = fs::remove_dir_all(&staged);
+            return Ok(res);
+        }
+    }
+
+    // Read again: another upload may have been numbered while this one was
+    // being unpacked.
+    let bundle = match collection.find_one(doc! { "uuid": uuid, "deleted_at": null }).await {
+        Ok(Some(bundle)) => bundle,
+        Ok(None) => {
+            let _ = fs::remove_dir_all(&staged);
+            return Ok(Response::not_found("Bundle not found"));
+        }
+        Err(error) => {
+            log::error!("{:?}", error);
+            let _ = fs::remove_dir_all(&staged);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    if let Err(error) = adopt_unversioned(&root, &bundle.name) {
+        log::error!("{}", error);
+        let _ = fs::remove_dir_all(&staged);
+        return Ok(Response::internal_server_error(&error));
+    }
+
+    let mut versions = known_versions(&bundle);
+    let next = versions.iter().map(|v| v.version).max().unwrap_or(0) + 1;
+    let target_dir = version_dir(&root, &bundle.name, next);
+
+    // Left over from an upload that died between numbering and recording.
+    let _ = fs::remove_dir_all(&target_dir);
+    if let Err(error) = fs::rename(&staged, &target_dir) {
+        log::error!("{}: {}", target_dir.display(), error);
+        let _ = fs::remove_dir_all(&staged);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    if let Err(error) = activate(&root, &bundle.name, next) {
+        log::error!("{}", error);
+        let _ = fs::remove_dir_all(&target_dir);
+        return Ok(Response::internal_server_error(&error));
+    }
+
+    versions.push(ShellBundleVersion {
+        version: next,
+        targets: targets.clone(),
+        manifest: manifest.clone(),
+        files: files.clone(),
+        signed_by: signed_by.clone(),
+        created_at: Utc::now().timestamp_millis(),
+        created_by: user.user_id.clone(),
+    });
+    // Oldest go first; the one just activated is always the newest.
+    if versions.len() > MAX_VERSIONS {
+        versions.drain(..versions.len() - MAX_VERSIONS);
+    }
+
+    let description = description
+        .as_deref()
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(str::to_string)
+        .unwrap_or(bundle.description.clone());
+
+    let bsons = (to_bson(&versions), to_bson(&manifest), to_bson(&files));
+    let (versions_bson, manifest_bson, files_bson) = match bsons {
+        (Ok(v), Ok(m), Ok(f)) => (v, m, f),
+        (Err(error), _, _) | (_, Err(error), _) | (_, _, Err(error)) => {
+            let _ = activate(&root, &bundle.name, bundle.version);
+            let _ = fs::remove_dir_all(&target_dir);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &bundle.uuid },
+            doc! { "$set": {
+                "version": next,
+                "targets": &targets,
+                "versions": versions_bson,
+                "manifest": manifest_bson,
+                "files": files_bson,
+                "signed_by": &signed_by,
+                "description": &description,
+            } },
+        )
+        .await;
+
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        // Back to what the record still says is live.
+        let _ = activate(&root, &bundle.name, bundle.version);
+        let _ = fs::remove_dir_all(&target_dir);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let keep: Vec<u32> = versions.iter().map(|v| v.version).collect();
+    prune(&root, &bundle.name, &keep);
+
+    let detail = Some(match &signed_by {
+        Some(key) => format!("version {}, signed by {}", next, key),
+        None => format!("version {}", next),
+    });
+    Audit::record(req, Some(user), "shell.upload_version", &bundle.name, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(ShellBundle {
+        version: next,
+        targets,
+        versions,
+        manifest,
+        files,
+        signed_by,
+        description,
+        ..bundle
+    }))
+}
@@ -0,0 +1,319 @@
+/*
+ * Versioned bundle directories.
+ *
+ * A bundle's files live in SHELL_ROOT/.versions/<name>/<n>/, one directory per
+ * upload, and SHELL_ROOT/<name> is a symlink to whichever is active. Nothing
+ * that runs a bundle needs to know: bundle_dir canonicalizes, so a job
+ * resolves the link once, at launch, and keeps running in the version it
+ * started in even if another is activated underneath it.
+ *
+ * Switching makes a new link beside the old one and renames it over the top,
+ * which is atomic — there is no moment at which <name> is missing or points
+ * at a half-unpacked upload. `.versions` can't collide with a bundle, because
+ * is_valid_bundle refuses a leading dot.
+ *
+ *   PUT   /api/shell/{uuid}           upload a new version (shell/update.rs)
+ *   PATCH /api/shell/{uuid}/version   { version: n } — make an older one
+ *                                     active again
+ *
+ * Both are administrator-only and refused while a job on the bundle is queued,
+ * running or awaiting approval: a run already going is safe in its own
+ * directory, but its record would then name a target list that is no longer
+ * the live one, and a held one would be approved against files nobody
+ * approved. The check and the swap are made under lock::pause, so no run is
+ * admitted between them.
+ */
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use mongodb::bson::{doc, to_bson};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Audit;
+use crate::Middleware::Auth::{require_access, AccessRequirement};
+use crate::Model::Account::AccountRole;
+use crate::Model::Audit::AuditOutcome;
+use crate::Model::Shell::{ShellBundle, ShellBundleVersion, ShellJob};
+use crate::utils::response::Response;
+
+use super::{lock, shell_root};
+
+const VERSIONS_DIR: &str = ".versions";
+/// Older versions past this many are removed from disk when a new one is
+/// uploaded. The active one is always kept.
+pub const MAX_VERSIONS: usize = 5;
+
+#[derive(Debug, Deserialize)]
+pub struct PathVariables {
+    uuid: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestBody {
+    version: u32,
+}
+
+pub async fn task(
+    req: HttpRequest,
+    path: web::Path<PathVariables>,
+    form_data: web::Json<RequestBody>,
+) -> Result<HttpResponse, Error> {
+    let gate = require_access(&req, AccessRequirement::Role(AccountRole::Administrator));
+    let user = Audit::checked(&req, "shell.version", &path.uuid, gate).await?;
+
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellBundle>("shell_bundle");
+
+    let bundle = match collection.find_one(doc! { "uuid": &path.uuid, "deleted_at": null }).await {
+        Ok(Some(bundle)) => bundle,
+        Ok(None) => return Ok(Response::not_found("Bundle not found")),
+        Err(error) => {
+            log::error!("{:?}", error);
+            return Ok(Response::internal_server_error(&error.to_string()));
+        }
+    };
+
+    let wanted = match known_versions(&bundle)
+        .into_iter()
+        .find(|v| v.version == form_data.version)
+    {
+        Some(v) => v,
+        None => return Ok(Response::not_found("No such version of this bundle")),
+    };
+
+    let root = match shell_root() {
+        Ok(root) => root,
+        Err(error) => return Ok(Response::internal_server_error(&error)),
+    };
+    let (manifest, files) = match (to_bson(&wanted.manifest), to_bson(&wanted.files)) {
+        (Ok(m), Ok(f)) => (m, f),
+        (Err(error), _) | (_, Err(error)) => {
+            return Ok(Response::internal_server_error(&error.to_string()))
+        }
+    };
+
+    // Held for the check, the swap and the record, and nothing slower.
+    let _paused = lock::pause().await;
+    match has_active_jobs(&bundle.name).await {
+        Ok(false) => {}
+        Ok(true) => return Ok(busy()),
+        Err(res) => return Ok(res),
+    }
+
+    if let Err(error) = adopt_unversioned(&root, &bundle.name) {
+        log::error!("{}", error);
+        return Ok(Response::internal_server_error(&error));
+    }
+    if !version_dir(&root, &bundle.name, wanted.version).join("main.sh").is_file() {
+        return Ok(Response::not_found("That version is no longer on disk"));
+    }
+
+    if let Err(error) = activate(&root, &bundle.name, wanted.version) {
+        log::error!("{}", error);
+        return Ok(Response::internal_server_error(&error));
+    }
+
+    let result = collection
+        .update_one(
+            doc! { "uuid": &bundle.uuid },
+            doc! { "$set": {
+                "version": wanted.version,
+                "targets": &wanted.targets,
+                "manifest": manifest,
+                // What integrity.rs checks runs against from now on.
+                "files": files,
+                "signed_by": &wanted.signed_by,
+            } },
+        )
+        .await;
+
+    if let Err(error) = result {
+        log::error!("{:?}", error);
+        // Put the link back where the record says it is.
+        let _ = activate(&root, &bundle.name, bundle.version);
+        return Ok(Response::internal_server_error(&error.to_string()));
+    }
+
+    let detail = Some(format!("version {}", wanted.version));
+    Audit::record(&req, Some(&user), "shell.version", &bundle.name, AuditOutcome::Success, detail).await;
+
+    Ok(HttpResponse::Ok().content_type("application/json").json(
+        Response { message: format!("Version {} is active", wanted.version) }
+    ))
+}
+
+pub fn versions_dir(root: &Path, name: &str) -> PathBuf {
+    root.join(VERSIONS_DIR).join(name)
+}
+
+pub fn version_dir(root: &Path, name: &str, version: u32) -> PathBuf {
+    versions_dir(root, name).join(version.to_string())
+}
+
+/// Where an upload is unpacked before it has a number (shell/update.rs):
+/// beside the versions, under a name `prune` never takes for one.
+pub fn staging_dir(root: &Path, name: &str) -> PathBuf {
+    versions_dir(root, name).join(format!(".upload-{}", Uuid::now_v7()))
+}
+
+/// The versions a record knows about. A bundle that predates versioning has
+/// an empty list but is, implicitly, version 1.
+pub fn known_versions(bundle: &ShellBundle) -> Vec<ShellBundleVersion> {
+    if !bundle.versions.is_empty() {
+        return bundle.versions.clone();
+    }
+    vec![ShellBundleVersion {
+        version: bundle.version,
+        targets: bundle.targets.clone(),
+        manifest: bundle.manifest.clone(),
+        files: bundle.files.clone(),
+        signed_by: bundle.signed_by.clone(),
+        created_at: bundle.created_at,
+        created_by: bundle.created_by.clone(),
+    }]
+}
+
+/// Point SHELL_ROOT/<name> at `version`, atomically.
+pub fn activate(root: &Path, name: &str, version: u32) -> Result<(), String> {
+    let link = root.join(name);
+    let staged = root.join(format!(".{}.next", name));
+    // Relative, so the tree can be moved or mounted elsewhere whole.
+    let target = Path::new(VERSIONS_DIR).join(name).join(version.to_string());
+
+    let _ = fs::remove_file(&staged);
+    symlink(&target, &staged).map_err(|e| format!("{}: {}", staged.display(), e))?;
+    fs::rename(&staged, &link).map_err(|e| {
+        let _ = fs::remove_file(&staged);
+        format!("{}: {}", link.display(), e)
+    })
+}
+
+/// A bundle uploaded before versioning is a plain directory at
+/// SHELL_ROOT/<name>. Move it to be version 1 and link to it, so it can be
+/// switched like any other. A no-op for a bundle that is already a link.
+pub fn adopt_unversioned(root: &Path, name: &str) -> Result<(), String> {
+    let dir = root.join(name);
+    let meta = match fs::symlink_metadata(&dir) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(()),
+    };
+    if meta.file_type().is_symlink() || !meta.is_dir() {
+        return Ok(());
+    }
+
+    let first = version_dir(root, name, 1);
+    fs::create_dir_all(versions_dir(root, name)).map_err(|e| e.to_string())?;
+    fs::rename(&dir, &first).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    activate(root, name, 1)
+}
+
+/// Remove every version directory of `name` that isn't in `keep`.
+pub fn prune(root: &Path, name: &str, keep: &[u32]) {
+    let entries = match fs::read_dir(versions_dir(root, name)) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let version = entry.file_name().to_string_lossy().parse::<u32>().ok();
+        if let Some(version) = version {
+            if !keep.contains(&version) {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+}
+
+/// Whether any job on this bundle is queued, running or awaiting approval.
+/// Only an answer to act on while lock::pause is held.
+pub async fn has_active_jobs(name: &str) -> Result<bool, HttpResponse> {
+    let db = MongoDB.connect();
+    let collection = db.collection::<ShellJob>("shell_job");
+
+    let active = doc! { "$in": ["queued", "running", "pending_approval"] };
+    collection
+        .count_documents(doc! { "bundle": name, "status": active })
+        .await
+        .map(|n| n > 0)
+        .map_err(|error| {
+            log::error!("{:?}", error);
+            Response::internal_server_error(&error.to_string())
+        })
+}
+
+pub fn busy() -> HttpResponse {
+    HttpResponse::Conflict().content_type("application/json").json(Response {
+        message: "A job on this bundle is queued, running or awaiting approval; \
+                  try again once it has finished"
+            .to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(label: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("shell-version-{}-{}", label, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn activating_swaps_the_link_between_versions() {
+        let root = scratch("swap");
+        for v in [1, 2] {
+            let dir = version_dir(&root, "demo", v);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("main.sh"), format!("# v{}\n", v)).unwrap();
+        }
+
+        activate(&root, "demo", 1).unwrap();
+        assert_eq!(fs::read_to_string(root.join("demo/main.sh")).unwrap(), "# v1\n");
+
+        activate(&root, "demo", 2).unwrap();
+        assert_eq!(fs::read_to_string(root.join("demo/main.sh")).unwrap(), "# v2\n");
+        assert!(!root.join(".demo.next").exists(), "the staging link was left behind");
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn an_unversioned_bundle_becomes_version_one() {
+        let root = scratch("adopt");
+        fs::create_dir_all(root.join("demo")).unwrap();
+        fs::write(root.join("demo/main.sh"), "# old\n").unwrap();
+
+        adopt_unversioned(&root, "demo").unwrap();
+        assert!(fs::symlink_metadata(root.join("demo")).unwrap().file_type().is_symlink());
+        assert!(version_dir(&root, "demo", 1).join("main.sh").is_file());
+        assert_eq!(fs::read_to_string(root.join("demo/main.sh")).unwrap(), "# old\n");
+
+        // Already a link: nothing to do.
+        adopt_unversioned(&root, "demo").unwrap();
+        assert!(version_dir(&root, "demo", 1).join("main.sh").is_file());
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn pruning_keeps_what_it_is_told_to() {
+        let root = scratch("prune");
+        for v in 1..=3 {
+            fs::create_dir_all(version_dir(&root, "demo", v)).unwrap();
+        }
+
+        prune(&root, "demo", &[2, 3]);
+        assert!(!version_dir(&root, "demo", 1).exists());
+        assert!(version_dir(&root, "demo", 2).exists());
+        assert!(version_dir(&root, "demo", 3).exists());
+
+        fs::remove_dir_all(&root).ok();
+    }
+}
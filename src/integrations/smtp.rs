@@ -213,6 +213,97 @@ pub fn contact_message_template(name: &str, from_email: &str, subject: &str, mes
         .unwrap()
 }
 
+pub fn shell_approval_request_template(email: &str, requester: &str, bundle: &str, target: &str, job_id: &str, expires_at: &str) -> Message {
+    let smtp_email = env::var("SMTP_EMAIL")
+        .expect("SMTP_EMAIL must be set on .env file");
+
+    let smtp_project_name = env::var("SMTP_PROJECT_NAME")
+        .expect("SMTP_PROJECT_NAME must be set on .env file");
+
+    let from = format!("{} <{}>", smtp_project_name, smtp_email);
+    let approve = format!("ct shell approve {} {}", bundle, job_id);
+    let reject = format!("ct shell reject {} {}", bundle, job_id);
+
+    Message::builder()
+        .from(from.parse().unwrap())
+        .to(email.parse().unwrap())
+        .subject(format!("[Approval] {} {}", bundle, target))
+        .multipart(
+            MultiPart::alternative()
+                .singlepart(SinglePart::plain(format!(
+                    "{} wants to run {} on {}. It needs a second administrator.\n\nJob: {}\nWaits until: {}\n\nApprove: {}\nReject:  {}",
+                    requester, target, bundle, job_id, expires_at, approve, reject
+                )))
+                .singlepart(
+                    SinglePart::builder()
+                        .header(header::ContentType::TEXT_HTML)
+                        .body(format!(
+                            r#"<html><body style="font-family:Arial,sans-serif;background:#f4f4f4;padding:24px">
+                            <div style="background:#fff;border-radius:8px;padding:32px;max-width:600px;margin:auto">
+                                <h2 style="margin-top:0">Run Waiting for Approval</h2>
+                                <p><strong>{requester}</strong> wants to run <strong>{target}</strong> on <strong>{bundle}</strong>. It needs a second administrator.</p>
+                                <p><strong>Job:</strong> {job_id}</p>
+                                <p><strong>Waits until:</strong> {expires_at}</p>
+                                <hr style="border:none;border-top:1px solid #eee;margin:16px 0">
+                                <p><code>{approve}</code></p>
+                                <p><code>{reject}</code></p>
+                            </div></body></html>"#
+                        )),
+                ),
+        )
+        .unwrap()
+}
+
+pub fn shell_job_finished_template(email: &str, subject: &str, summary: &str, details: &[(&str, String)], log_tail: &[String]) -> Message {
+    let smtp_email = env::var("SMTP_EMAIL")
+        .expect("SMTP_EMAIL must be set on .env file");
+
+    let smtp_project_name = env::var("SMTP_PROJECT_NAME")
+        .expect("SMTP_PROJECT_NAME must be set on .env file");
+
+    let from = format!("{} <{}>", smtp_project_name, smtp_email);
+
+    let plain_details: String = details.iter().map(|(k, v)| format!("{}: {}\n", k, v)).collect();
+    let html_details: String = details
+        .iter()
+        .map(|(k, v)| format!("<p><strong>{}:</strong> {}</p>", k, escape_html(v)))
+        .collect();
+    let log = log_tail.join("\n");
+    let html_log = escape_html(&log);
+    let html_summary = escape_html(summary);
+
+    Message::builder()
+        .from(from.parse().unwrap())
+        .to(email.parse().unwrap())
+        .subject(subject)
+        .multipart(
+            MultiPart::alternative()
+                .singlepart(SinglePart::plain(format!(
+                    "{}\n\n{}\n{}",
+                    summary, plain_details, log
+                )))
+                .singlepart(
+                    SinglePart::builder()
+                        .header(header::ContentType::TEXT_HTML)
+                        .body(format!(
+                            r#"<html><body style="font-family:Arial,sans-serif;background:#f4f4f4;padding:24px">
+                            <div style="background:#fff;border-radius:8px;padding:32px;max-width:760px;margin:auto">
+                                <h2 style="margin-top:0">{html_summary}</h2>
+                                {html_details}
+                                <hr style="border:none;border-top:1px solid #eee;margin:16px 0">
+                                <pre style="white-space:pre-wrap;font-size:12px;background:#f8f8f8;padding:12px">{html_log}</pre>
+                            </div></body></html>"#
+                        )),
+                ),
+        )
+        .unwrap()
+}
+
+/// Job output goes into the HTML part as text, not markup.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
 pub fn send_email(message: Message) -> Result<(),String>{
     let smtp_email = env::var("SMTP_EMAIL")
     .expect("SMTP_EMAIL must be set on .env file");
@@ -48,29 +48,59 @@ async fn main() -> io::Result<()> {
     let _logger = BuiltIns::logger::init();
 
     /*
-    RUNS CORN JOB FOR YOUR PROJECT
-    Remove the following code block if you are not using this feature.
+        Sqlite Database Initialization
+        Remove the following code block if you are not using this feature.
+    */ 
+    log::info!("\nExecuting Sqlite3 Prerequisites...");
+    BuiltIns::sqlite::create_initial_tables().expect("Failed to initiate!\n");
+
+    /*
+        Shell jobs the previous process was still watching can't be finished
+        by this one — mark them interrupted before anything new starts.
+    */
+    Handler::Shell::reconcile_interrupted().await;
+
+    /*
+        Shell bundle schedules: start whatever is due. This has to come
+        after the reconcile above, or a job fired on the first tick could be
+        mistaken for one left over from the last process and marked
+        interrupted. The cron expressions themselves are parsed in
+        builtins/cron.rs.
     */
-    /*  tokio::spawn(async move {
-            use tokio::time::{self, Duration};
-            let mut interval = time::interval(Duration::from_secs(60));   
-            loop {
+    tokio::spawn(async move {
+        use tokio::time;
+        let mut interval = time::interval(Handler::Shell::Schedules::FIRE_POLL);
+        loop {
             interval.tick().await;
+            Handler::Shell::Schedules::fire_due().await;
+        }
+    });
 
-                /*
-                Execute your desired CORN routines here.
-                */ 
-                BuiltIn::cron::greet().await;
-            }
-        });
+    /*
+        Deleted shell bundles sit in the trash for SHELL_TRASH_DAYS, then are
+        purged for good. Checked hourly.
     */
+    tokio::spawn(async move {
+        use tokio::time::{self, Duration};
+        let mut interval = time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            Handler::Shell::Trash::purge_expired().await;
+        }
+    });
 
     /*
-        Sqlite Database Initialization
-        Remove the following code block if you are not using this feature.
-    */ 
-    log::info!("\nExecuting Sqlite3 Prerequisites...");
-    BuiltIns::sqlite::create_initial_tables().expect("Failed to initiate!\n");
+        Shell job logs: gzip the finished ones and prune past the retention
+        policy (SHELL_LOG_* in .env). Hourly too.
+    */
+    tokio::spawn(async move {
+        use tokio::time::{self, Duration};
+        let mut interval = time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            Handler::Shell::Retention::sweep().await;
+        }
+    });
 
     let mut listenfd = ListenFd::from_env();
 
@@ -269,6 +299,7 @@ async fn main() -> io::Result<()> {
         .configure(Routes::Project::router)
         .configure(Routes::Shell::router)
         .configure(Routes::Cli::router)
+        .configure(Routes::Audit::router)
         .configure(Routes::Auth::router)
         .configure(Routes::Pages::router)
     });
@@ -1,2 +1,5 @@
 pub mod auth;
-pub use auth as Auth;
\ No newline at end of file
+pub use auth as Auth;
+
+pub mod audit;
+pub use audit as Audit;
@@ -0,0 +1,139 @@
+/*
+ * The audit trail: one `audit_event` row (Model::Audit::AuditEvent) per
+ * privileged action — shell uploads, runs and cancels, CLI logins and
+ * logouts, sign-ins, content changes — with who, from where, and how it went.
+ *
+ * Like auth.rs, plain functions a handler calls rather than a .wrap() layer:
+ * only the handler knows what the action was and whether it happened. The
+ * usual shape is to pass the gate's result through `checked`, which records a
+ * refusal, and `record` the outcome once the handler knows it:
+ *
+ *   let user = Audit::checked(&req, "shell.upload", "", require_access(..)).await?;
+ *   ...
+ *   Audit::record(&req, Some(&user), "shell.upload", &name, AuditOutcome::Success, None).await;
+ *
+ * The write is spawned, not awaited, and a failed one is logged and otherwise
+ * ignored. Losing a row is bad; holding up or refusing a deploy because the
+ * audit insert was slow or hiccuped would be worse.
+ *
+ * `ip` is what actix reports as the real remote address — the first
+ * Forwarded/X-Forwarded-For hop when the TLS terminator sets one, the socket
+ * peer otherwise. A caller that reaches the server directly can write that
+ * header itself, so treat it as the proxy's word, not proof.
+ */
+use chrono::Utc;
+use uuid::Uuid;
+
+use actix_web::{Error, HttpRequest};
+
+use crate::BuiltIns::mongo::MongoDB;
+use crate::Middleware::Auth::User;
+use crate::Model::Audit::{AuditEvent, AuditOutcome};
+
+/// Longer than this and a user agent is cut — it is a header the caller
+/// chooses, and the row shouldn't be theirs to bloat.
+const MAX_USER_AGENT: usize = 256;
+
+pub async fn record(
+    req: &HttpRequest,
+    actor: Option<&User>,
+    action: &str,
+    subject: &str,
+    outcome: AuditOutcome,
+    detail: Option<String>,
+) {
+    let mut event = event(actor, action, subject, outcome, detail);
+    event.ip = req.connection_info().realip_remote_addr().map(str::to_string);
+    event.user_agent = req
+        .headers()
+        .get("User-Agent")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.chars().take(MAX_USER_AGENT).collect());
+    insert(event);
+}
+
+/// For what the server does on someone's behalf with no request in hand — a
+/// schedule firing. `actor.token_label` says on whose.
+pub async fn record_system(
+    actor: &User,
+    action: &str,
+    subject: &str,
+    outcome: AuditOutcome,
+    detail: Option<String>,
+) {
+    insert(event(Some(actor), action, subject, outcome, detail));
+}
+
+/// Hand an auth gate's result back unchanged, recording it as a denial first
+/// if it refused.
+pub async fn checked(
+    req: &HttpRequest,
+    action: &str,
+    subject: &str,
+    gate: Result<User, Error>,
+) -> Result<User, Error> {
+    if let Err(error) = &gate {
+        let status = error.as_response_error().status_code();
+        record(req, None, action, subject, AuditOutcome::Denied, Some(status.to_string())).await;
+    }
+    gate
+}
+
+fn event(
+    actor: Option<&User>,
+    action: &str,
+    subject: &str,
+    outcome: AuditOutcome,
+    detail: Option<String>,
+) -> AuditEvent {
+    AuditEvent {
+        uuid: Uuid::now_v7().to_string(),
+        at: Utc::now().timestamp_millis(),
+        action: action.to_string(),
+        subject: subject.to_string(),
+        outcome,
+        actor: actor.map(|u| u.user_id.clone()),
+        role: actor.map(|u| u.role.clone()),
+        token_label: actor.and_then(|u| u.token_label.clone()),
+        ip: None,
+        user_agent: None,
+        detail,
+    }
+}
+
+fn insert(event: AuditEvent) {
+    tokio::spawn(async move {
+        let db = MongoDB.connect();
+        let collection = db.collection::<AuditEvent>("audit_event");
+        if let Err(error) = collection.insert_one(&event).await {
+            log::error!("audit {} {}: {:?}", event.action, event.subject, error);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Model::Account::AccountRole;
+
+    #[test]
+    fn an_event_carries_its_actor() {
+        let user = User {
+            user_id: "u1".to_string(),
+            role: AccountRole::Administrator,
+            token_label: Some("laptop".to_string()),
+        };
+        let e = event(Some(&user), "shell.run", "site/deploy", AuditOutcome::Success, None);
+        assert_eq!(e.actor.as_deref(), Some("u1"));
+        assert_eq!(e.role, Some(AccountRole::Administrator));
+        assert_eq!(e.token_label.as_deref(), Some("laptop"));
+
+        let e = event(None, "auth.sign_in", "someone", AuditOutcome::Failed, None);
+        assert!(e.actor.is_none() && e.role.is_none() && e.token_label.is_none());
+    }
+
+    #[test]
+    fn outcomes_are_stored_lowercase() {
+        assert_eq!(serde_json::to_value(AuditOutcome::Denied).unwrap(), "denied");
+    }
+}
@@ -26,6 +26,9 @@ pub enum AccessRequirement {
 pub struct User {
     pub user_id: String,
     pub role: AccountRole,
+    /// The label of the CLI token that authenticated this call — usually the
+    /// hostname `ct login` ran on. None for the dashboard's session cookie.
+    pub token_label: Option<String>,
 }
 
 /// Pull the access token off the request. API clients send it as a Bearer
@@ -82,6 +85,7 @@ pub fn require_access(
     Ok(User {
         user_id: claims.sub,
         role: claims.role,
+        token_label: None,
     })
 }
 
@@ -204,6 +208,7 @@ pub async fn require_cli(
     Ok(User {
         user_id: record.user_id,
         role: record.role,
+        token_label: Some(record.label),
     })
 }
 
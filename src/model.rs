@@ -21,6 +21,9 @@ pub use shell as Shell;
 pub mod cli_token;
 pub use cli_token as CliToken;
 
+pub mod audit;
+pub use audit as Audit;
+
 // Ported from velora_backend's src/model.rs — image metadata now lives here
 // in Mongo (ImageStruct below) instead of in sqlite columns, which is what
 // let builtins/image.rs go away: sqlite only holds the original/webp blobs.
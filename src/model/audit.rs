@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Model::Account::AccountRole;
+
+/// One privileged action, or an attempt at one, in the `audit_event`
+/// collection (middleware/audit.rs).
+///
+/// Append-only: nothing in the application updates or deletes a row, so the
+/// collection answers "who ran what, from where" for as long as it is kept.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuditEvent {
+    pub uuid: String,
+    pub at: i64,
+    /// Dotted, area first — `shell.run`, `cli.login`, `auth.sign_in`.
+    pub action: String,
+    /// What was acted on: a bundle, "bundle/target", a project uuid, the
+    /// name a sign-in was attempted with. Empty when there is nothing to name.
+    pub subject: String,
+    pub outcome: AuditOutcome,
+    /// None when nobody was authenticated — a refused call, a failed sign-in.
+    pub actor: Option<String>,
+    pub role: Option<AccountRole>,
+    /// The CLI token's label, or what started the action on someone's
+    /// behalf ("schedule <uuid>", "hook <uuid>"). None for the dashboard.
+    pub token_label: Option<String>,
+    /// The socket peer. None for actions the server starts itself.
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Free text: a job id, a status code, why something failed.
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    /// The auth gate refused the caller.
+    Denied,
+    /// The caller got through but the action didn't happen.
+    Failed,
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 /// An uploaded shell bundle: a directory of scripts under SHELL_ROOT with a
@@ -6,6 +8,9 @@ use serde::{Deserialize, Serialize};
 /// `name` is both the display name and the directory, which is why it is
 /// validated against handler/shell.rs's `is_valid_bundle` and has to be unique
 /// — the URL /api/shell/{name}/run/... is derived from it.
+///
+/// Each upload is kept as a numbered version; SHELL_ROOT/<name> points at the
+/// active one (see handler/shell/version.rs).
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ShellBundle {
     pub uuid: String,
@@ -16,6 +21,25 @@ pub struct ShellBundle {
     /// on every page load.
     #[serde(default)]
     pub targets: Vec<String>,
+    /// The active version. Bundles uploaded before versioning are version 1.
+    #[serde(default = "first_version")]
+    pub version: u32,
+    /// Every version still on disk, oldest first. Empty for a bundle that has
+    /// only ever had its first upload from before versioning.
+    #[serde(default)]
+    pub versions: Vec<ShellBundleVersion>,
+    /// The active version's bundle.json, if it shipped one.
+    #[serde(default)]
+    pub manifest: Option<ShellManifest>,
+    /// Every file of the active version as it was unpacked, checked before
+    /// each run (handler/shell/integrity.rs). Empty for a bundle uploaded
+    /// before these were recorded.
+    #[serde(default)]
+    pub files: Vec<ShellFileHash>,
+    /// The ShellSigningKey the active version's archive was signed with, if
+    /// it was (handler/shell/signing.rs).
+    #[serde(default)]
+    pub signed_by: Option<String>,
     /// Whether an ordinary signed-in User may run this bundle's targets, not
     /// just an Administrator.
     ///
@@ -25,8 +49,367 @@ pub struct ShellBundle {
     /// bundle rather than a side effect of uploading it.
     #[serde(default)]
     pub public_run: bool,
+    /// Finer than public_run: particular accounts or groups, particular
+    /// targets, for a while (handler/shell/grants.rs).
+    #[serde(default)]
+    pub grants: Vec<ShellGrant>,
+    /// Steps a run may only reach once a second administrator approves it
+    /// (handler/shell/approval.rs).
+    #[serde(default)]
+    pub requires_approval: Vec<String>,
+    #[serde(default)]
+    pub limits: ShellLimits,
+    #[serde(default)]
+    pub notify: ShellNotify,
+    /// The account its jobs run as; None for the server's default
+    /// (handler/shell/run_as.rs). "root" only if an administrator chose it.
+    #[serde(default)]
+    pub run_as: Option<String>,
     pub created_at: i64,
     pub created_by: String,
     pub deleted_at: Option<i64>,
     pub deleted_by: Option<String>,
 }
+
+/// Permission for one account, or every member of one group, to run a
+/// bundle's targets. Exactly one of `user_id` and `group` is set.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellGrant {
+    pub uuid: String,
+    pub user_id: Option<String>,
+    /// A ShellGroup name.
+    pub group: Option<String>,
+    /// Empty for every target, `--full` included.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// Epoch millis. A grant past it is ignored, not removed.
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+    pub created_by: String,
+}
+
+/// A named set of accounts a grant can be given to, in `shell_group`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellGroup {
+    pub name: String,
+    #[serde(default)]
+    pub members: Vec<String>,
+    pub created_at: i64,
+    pub created_by: String,
+}
+
+/// An Ed25519 public key an administrator registered to sign bundles with,
+/// in `shell_signing_key`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellSigningKey {
+    pub name: String,
+    /// The raw 32-byte key, base64.
+    pub public_key: String,
+    pub created_at: i64,
+    pub created_by: String,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+fn run_kind() -> String {
+    "run".to_string()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellBundleVersion {
+    pub version: u32,
+    /// What this version's main.sh --list said, so rolling back restores the
+    /// dashboard's view of it without shelling out.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub manifest: Option<ShellManifest>,
+    #[serde(default)]
+    pub files: Vec<ShellFileHash>,
+    #[serde(default)]
+    pub signed_by: Option<String>,
+    pub created_at: i64,
+    pub created_by: String,
+}
+
+/// One file of a bundle version, by its path under the bundle directory.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ShellFileHash {
+    pub path: String,
+    /// Hex SHA-256 of the contents — of where it points, for a symlink.
+    pub sha256: String,
+    #[serde(default)]
+    pub symlink: bool,
+}
+
+/// A bundle's optional `bundle.json`: what its targets are for and what each
+/// variable is, so `describe` can say more than a name and `run` can refuse a
+/// bad value before it reaches /etc/<bundle>/vars.env. Checked on upload by
+/// handler/shell/manifest.rs.
+///
+/// main.sh --list stays the source of the target list and its order; a
+/// target here only adds a description and the variables it reads.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ShellManifest {
+    #[serde(default)]
+    pub targets: Vec<ShellManifestTarget>,
+    #[serde(default)]
+    pub vars: Vec<ShellVar>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellManifestTarget {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Names from ShellManifest.vars.
+    #[serde(default)]
+    pub vars: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellVar {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: ShellVarType,
+    #[serde(default)]
+    pub description: String,
+    /// Written for a target that reads this variable when the caller didn't
+    /// supply it and vars.env doesn't have it yet.
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub secret: bool,
+    /// A regex the whole value has to match, on top of the type's own check.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// The allowed values of an `enum`.
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ShellVarType {
+    #[default]
+    String,
+    Int,
+    Bool,
+    Enum,
+    Email,
+    Hostname,
+}
+
+/// Bounds on a single run of a bundle, set from the dashboard. Anything left
+/// unset is unlimited, except the wall clock, which falls back to
+/// DEFAULT_TIMEOUT_SECS in handler/shell/limits.rs — a hung main.sh should
+/// not be able to hold its bundle's lock forever.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ShellLimits {
+    pub timeout_secs: Option<u64>,
+    /// RLIMIT_CPU, per process: each command a step runs gets this much.
+    pub cpu_secs: Option<u64>,
+    /// RLIMIT_NOFILE.
+    pub max_open_files: Option<u64>,
+    /// Size of the job log, checked as it grows.
+    pub max_output_bytes: Option<u64>,
+}
+
+/// Who hears when a run of a bundle finishes, set from the dashboard
+/// (handler/shell/notify.rs). Everything off by default.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ShellNotify {
+    /// Email the account that started the run.
+    #[serde(default)]
+    pub email_runner: bool,
+    /// Email every administrator.
+    #[serde(default)]
+    pub email_admins: bool,
+    /// POST a JSON summary here.
+    pub webhook_url: Option<String>,
+    /// Only for runs that didn't succeed.
+    #[serde(default)]
+    pub failures_only: bool,
+    /// How much of the end of the log to include; DEFAULT_LOG_LINES if unset.
+    pub log_lines: Option<usize>,
+}
+
+/// One run of a bundle target, kept in `shell_job` so a job's status and
+/// history outlive the process that started it. The output itself stays on
+/// disk under LOG_DIR; this is the index to it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellJob {
+    pub uuid: String,
+    /// Which bundle under SHELL_ROOT this ran, so one bundle's job ids can't
+    /// be used to read another's logs.
+    pub bundle: String,
+    /// For a pipeline, its targets in order, space-separated.
+    pub target: String,
+    /// The account behind the CLI token that started the run, and that
+    /// token's label — so "who ran this" has an answer beyond a uuid.
+    pub user_id: String,
+    pub token_label: String,
+    /// Names only. The values went to /etc/<bundle>/vars.env and may well be
+    /// passwords; the record says what was supplied, not what it was.
+    #[serde(default)]
+    pub var_keys: Vec<String>,
+    /// Which variables are secret for this run — names again, never values.
+    /// Whatever they hold is redacted from the log as it is written
+    /// (handler/shell/redact.rs).
+    #[serde(default)]
+    pub secret_keys: Vec<String>,
+    /// run | dry_run — the latter with DRY_RUN=1 and its variables in the
+    /// environment rather than vars.env (see handler/shell.rs).
+    #[serde(default = "run_kind")]
+    pub kind: String,
+    /// pending_approval | queued | running | success | failed |
+    /// failed_missing_vars | interrupted | cancelled | timed_out |
+    /// limit_exceeded | rejected | approval_expired
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub missing_vars: Option<Vec<String>>,
+    pub log_path: String,
+    /// The account main.sh ran as, once it was started. None for a job from
+    /// before runs dropped privileges — those all ran as the server.
+    #[serde(default)]
+    pub run_as: Option<String>,
+    /// The bash process, which `launch` makes the leader of its own process
+    /// group — so this is also the group a cancel signals.
+    pub pid: Option<i32>,
+    /// Set by /cancel. The watcher reads it when the process exits, so the
+    /// job ends up `cancelled` rather than an ordinary signal-killed `failed`.
+    pub cancelled_by: Option<String>,
+    pub cancelled_at: Option<i64>,
+    /// For a run that needed a second administrator: until when it waits for
+    /// one, and who decided. Never the account that asked for the run.
+    #[serde(default)]
+    pub approval_expires_at: Option<i64>,
+    #[serde(default)]
+    pub approved_by: Option<String>,
+    #[serde(default)]
+    pub approved_at: Option<i64>,
+    #[serde(default)]
+    pub rejected_by: Option<String>,
+    #[serde(default)]
+    pub rejected_at: Option<i64>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    /// A pipeline's targets, each with how it went, in the order they run
+    /// (handler/shell/pipeline.rs). Empty for a job of one target.
+    #[serde(default)]
+    pub steps: Vec<ShellJobStep>,
+    /// Go on to the next step after one fails, rather than skipping the rest.
+    #[serde(default)]
+    pub continue_on_failure: bool,
+    /// What the job left in its ARTIFACT_DIR and the server kept
+    /// (handler/shell/artifacts.rs).
+    #[serde(default)]
+    pub artifacts: Vec<ShellArtifact>,
+    /// When the retention sweep deleted this job's log
+    /// (handler/shell/retention.rs). The record outlives it.
+    #[serde(default)]
+    pub log_pruned_at: Option<i64>,
+}
+
+/// One target of a pipeline job. Its output is the stretch of the job's log
+/// from `log_start` up to `log_end`, byte offsets into the uncompressed text.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ShellJobStep {
+    pub target: String,
+    /// pending | running | success | failed | failed_missing_vars |
+    /// cancelled | timed_out | limit_exceeded | skipped | interrupted
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub log_start: Option<u64>,
+    pub log_end: Option<u64>,
+}
+
+/// A file a job wrote to its ARTIFACT_DIR, kept under ARTIFACT_ROOT/<job>/.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ShellArtifact {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Named variables for a bundle's runs (handler/shell/profiles.rs), one
+/// record per bundle and name.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellVarProfile {
+    pub bundle: String,
+    pub name: String,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    /// Sealed with SHELL_PROFILE_KEY: base64 of the nonce, then the
+    /// ciphertext. Never a value as it was given.
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+    pub created_at: i64,
+    pub created_by: String,
+    pub updated_at: i64,
+    pub updated_by: String,
+}
+
+/// A target run on a timetable (handler/shell/schedules.rs). The variables
+/// are stored because there is nobody to supply them when it fires; a
+/// secret one is sealed the way a profile's is, masked in every response,
+/// and redacted from the job log like any other.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellSchedule {
+    pub uuid: String,
+    pub bundle: String,
+    pub target: String,
+    /// Five-field cron, in UTC (builtins/cron.rs).
+    pub cron: String,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    #[serde(default)]
+    pub secret_keys: Vec<String>,
+    /// The secret ones among them, sealed with SHELL_PROFILE_KEY
+    /// (handler/shell/profiles.rs).
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+    pub enabled: bool,
+    /// None once the expression can't fire again.
+    pub next_run_at: Option<i64>,
+    pub last_run_at: Option<i64>,
+    /// The job the last firing started, in `shell_job`.
+    pub last_job: Option<String>,
+    /// A firing passed over because the last job was still going.
+    pub last_skipped_at: Option<i64>,
+    pub created_at: i64,
+    pub created_by: String,
+}
+
+/// A webhook that starts one target of one bundle (handler/shell/hooks.rs).
+/// `uuid` is the id in its URL.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShellHook {
+    pub uuid: String,
+    pub bundle: String,
+    pub target: String,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    #[serde(default)]
+    pub secret_keys: Vec<String>,
+    /// The secret ones among them, sealed with SHELL_PROFILE_KEY
+    /// (handler/shell/profiles.rs).
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+    /// The HMAC key, hex. Kept as is, not hashed — verifying a signature needs
+    /// the key itself. Only ever returned once, by the route that creates the
+    /// hook; every other response blanks it.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    pub created_at: i64,
+    pub created_by: String,
+    pub revoked_at: Option<i64>,
+    pub last_delivery_at: Option<i64>,
+    /// The job the last accepted delivery started, in `shell_job`.
+    pub last_job: Option<String>,
+}
@@ -27,3 +27,6 @@ pub use shell as Shell;
 
 pub mod cli;
 pub use cli as Cli;
+
+pub mod audit;
+pub use audit as Audit;
@@ -0,0 +1,10 @@
+use actix_web::web;
+use crate::Handler;
+
+pub fn router(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/audit")
+        // Administrator-only, with either credential (handler/audit/list.rs).
+        .route("", web::get().to(Handler::Audit::List::task))
+    );
+}
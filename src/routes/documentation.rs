@@ -1,10 +1,11 @@
-use actix_web::web;
+use actix_web::{guard, web};
 use crate::Handler;
+use crate::utils::upload;
 
-// The zip goes over the wire as a JSON array of bytes (matches
-// RequestBody.file: Vec<u8> in handler/documentation/create.rs), which runs
-// several times larger than the raw file — actix's 2MB JSON default would
-// reject anything but a trivial zip, so this scope gets a roomier limit.
+// Only for the deprecated JSON form of the upload, where the zip goes over the
+// wire as an array of bytes (RequestBody.file: Vec<u8> in
+// handler/documentation/create.rs) several times larger than the raw file.
+// The multipart form is capped by the handler as it streams.
 const CREATE_DOC_JSON_LIMIT: usize = 64 * 1024 * 1024;
 
 pub fn router(cfg: &mut web::ServiceConfig) {
@@ -17,7 +18,9 @@ pub fn router(cfg: &mut web::ServiceConfig) {
         // which registers two competing Resources for the same path).
         .service(
             web::resource("")
-            .route(web::post().to(Handler::Documentation::Create::task))
+            // Multipart first; any other body is the deprecated JSON form.
+            .route(web::post().guard(guard::fn_guard(upload::is_multipart)).to(Handler::Documentation::Create::task))
+            .route(web::post().to(Handler::Documentation::Create::task_json))
             .route(web::get().to(Handler::Documentation::List::task))
         )
         .route(
@@ -1,10 +1,11 @@
-use actix_web::web;
+use actix_web::{guard, web};
 use crate::Handler;
+use crate::utils::upload;
 
-// The zip goes over the wire as a JSON array of bytes (RequestBody.file:
-// Vec<u8> in handler/shell/create.rs), which runs several times larger than
-// the raw file — actix's 2MB JSON default would reject anything but a trivial
-// bundle.
+// Uploads come as multipart (handler/shell/create.rs, utils/upload.rs), which
+// no JSON limit touches. This is for the deprecated JSON form, where the zip
+// is an array of bytes several times larger than the raw file — actix's 2MB
+// default would reject anything but a trivial bundle.
 const CREATE_JSON_LIMIT: usize = 32 * 1024 * 1024;
 
 pub fn router(cfg: &mut web::ServiceConfig) {
@@ -15,10 +16,13 @@ pub fn router(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api/shell")
         .app_data(web::JsonConfig::default().limit(CREATE_JSON_LIMIT))
-        // Upload a bundle, and list the ones installed.
+        // Upload a bundle, and list the ones installed. A multipart body
+        // takes the first route; anything else falls to the deprecated JSON
+        // one.
         .service(
             web::resource("")
-            .route(web::post().to(Handler::Shell::Create::task))
+            .route(web::post().guard(guard::fn_guard(upload::is_multipart)).to(Handler::Shell::Create::task))
+            .route(web::post().to(Handler::Shell::Create::task_json))
             .route(web::get().to(Handler::Shell::List::task))
         )
         // Administrator-only, from the dashboard: opens a bundle to ordinary
@@ -28,6 +32,101 @@ pub fn router(cfg: &mut web::ServiceConfig) {
             "/{uuid}/public-run",
             web::patch().to(Handler::Shell::TogglePublic::task)
         )
+        // Administrator-only, from the dashboard: timeout and rlimits for a
+        // bundle's runs (handler/shell/limits.rs).
+        .route(
+            "/{uuid}/limits",
+            web::patch().to(Handler::Shell::Limits::task)
+        )
+        // Administrator-only, from the dashboard: who hears when a run
+        // finishes (handler/shell/notify.rs).
+        .route(
+            "/{uuid}/notify",
+            web::patch().to(Handler::Shell::Notify::task)
+        )
+        // Administrator-only, from the dashboard: which account a bundle's
+        // jobs run as, root included (handler/shell/run_as.rs).
+        .route(
+            "/{uuid}/run-as",
+            web::patch().to(Handler::Shell::RunAs::task)
+        )
+        // Administrator-only, from the dashboard: who besides an
+        // administrator may run a bundle, and the groups a grant can name
+        // (handler/shell/grants.rs). Plain routes rather than a resource, so
+        // that a bundle named "groups" still reaches its own GET
+        // /groups/targets and the like below.
+        .route(
+            "/groups",
+            web::get().to(Handler::Shell::Grants::groups)
+        )
+        .route(
+            "/groups/{group}",
+            web::put().to(Handler::Shell::Grants::set_group)
+        )
+        .route(
+            "/groups/{group}",
+            web::delete().to(Handler::Shell::Grants::delete_group)
+        )
+        .route(
+            "/{uuid}/grants",
+            web::post().to(Handler::Shell::Grants::add)
+        )
+        .route(
+            "/{uuid}/grants/{grant}",
+            web::delete().to(Handler::Shell::Grants::remove)
+        )
+        // Administrator-only, from the dashboard: the public keys an upload
+        // may be signed with (handler/shell/signing.rs). Plain routes, like
+        // the groups above.
+        .route(
+            "/keys",
+            web::get().to(Handler::Shell::Signing::keys)
+        )
+        .route(
+            "/keys",
+            web::post().to(Handler::Shell::Signing::add_key)
+        )
+        .route(
+            "/keys/{name}",
+            web::delete().to(Handler::Shell::Signing::remove_key)
+        )
+        // Administrator-only, from the dashboard: the steps a run needs a
+        // second administrator's approval to reach
+        // (handler/shell/approval.rs).
+        .route(
+            "/{uuid}/approval",
+            web::patch().to(Handler::Shell::Approval::task)
+        )
+        // Administrator-only, from the dashboard: upload a new version of a
+        // bundle, or switch back to an earlier one (handler/shell/version.rs).
+        .route(
+            "/{uuid}",
+            web::put().guard(guard::fn_guard(upload::is_multipart)).to(Handler::Shell::Update::task)
+        )
+        .route(
+            "/{uuid}",
+            web::put().to(Handler::Shell::Update::task_json)
+        )
+        .route(
+            "/{uuid}/version",
+            web::patch().to(Handler::Shell::Version::task)
+        )
+        // Administrator-only, from the dashboard: move a bundle to the trash,
+        // or bring it back (handler/shell/trash.rs).
+        .route(
+            "/{uuid}",
+            web::delete().to(Handler::Shell::Delete::task)
+        )
+        .route(
+            "/{uuid}/restore",
+            web::post().to(Handler::Shell::Restore::task)
+        )
+        // Administrator-only: every bundle's run history, for the dashboard.
+        // One segment, so it can't collide with the {name}/... routes below.
+        .route(
+            "/jobs",
+            web::get().to(Handler::Shell::Jobs::all)
+        )
         // {name} is an uploaded bundle directory under SHELL_ROOT. These run
         // root shell scripts on the host, so unlike the two routes above they
         // take a CLI token only — no session cookie, and nothing carrying
@@ -44,15 +143,86 @@ pub fn router(cfg: &mut web::ServiceConfig) {
             "/{name}/run/{target}",
             web::post().to(Handler::Shell::run)
         )
-        // The more specific path has to come before /jobs/{id}, otherwise
+        .route(
+            "/{name}/pipeline",
+            web::post().to(Handler::Shell::Pipeline::task)
+        )
+        // The more specific paths have to come before /jobs/{id}, otherwise
         // "{id}" swallows "some-id/logs".
         .route(
             "/{name}/jobs/{id}/logs",
             web::get().to(Handler::Shell::job_logs)
         )
+        .route(
+            "/{name}/jobs/{id}/artifacts",
+            web::get().to(Handler::Shell::Artifacts::list)
+        )
+        .route(
+            "/{name}/jobs/{id}/artifacts/{file}",
+            web::get().to(Handler::Shell::Artifacts::download)
+        )
+        .route(
+            "/{name}/jobs/{id}/cancel",
+            web::post().to(Handler::Shell::Cancel::task)
+        )
+        // Administrator-only, with either credential, and not the one who
+        // asked for the run (handler/shell/approval.rs).
+        .route(
+            "/{name}/jobs/{id}/approve",
+            web::post().to(Handler::Shell::Approval::approve)
+        )
+        .route(
+            "/{name}/jobs/{id}/reject",
+            web::post().to(Handler::Shell::Approval::reject)
+        )
         .route(
             "/{name}/jobs/{id}",
             web::get().to(Handler::Shell::job)
         )
+        .route(
+            "/{name}/jobs",
+            web::get().to(Handler::Shell::Jobs::task)
+        )
+        // Administrator-only, with either credential: targets on a timetable
+        // (handler/shell/schedules.rs).
+        .service(
+            web::resource("/{name}/schedules")
+            .route(web::get().to(Handler::Shell::Schedules::task))
+            .route(web::post().to(Handler::Shell::Schedules::create))
+        )
+        .service(
+            web::resource("/{name}/schedules/{id}")
+            .route(web::patch().to(Handler::Shell::Schedules::toggle))
+            .route(web::delete().to(Handler::Shell::Schedules::delete))
+        )
+        // Administrator-only, with either credential: named variables a run
+        // can take (handler/shell/profiles.rs).
+        .route(
+            "/{name}/profiles",
+            web::get().to(Handler::Shell::Profiles::task)
+        )
+        .service(
+            web::resource("/{name}/profiles/{profile}")
+            .route(web::put().to(Handler::Shell::Profiles::set))
+            .route(web::delete().to(Handler::Shell::Profiles::delete))
+        )
+        // Administrator-only, with either credential: webhooks bound to a
+        // target (handler/shell/hooks.rs).
+        .service(
+            web::resource("/{name}/hooks")
+            .route(web::get().to(Handler::Shell::Hooks::task))
+            .route(web::post().to(Handler::Shell::Hooks::create))
+        )
+        .route(
+            "/{name}/hooks/{id}",
+            web::delete().to(Handler::Shell::Hooks::revoke)
+        )
+        // The hook itself: no session and no CLI token, only the signature
+        // over its body. Last, so that a bundle named "hooks" still reaches
+        // its own /hooks routes above.
+        .route(
+            "/hooks/{id}",
+            web::post().to(Handler::Shell::Hooks::deliver)
+        )
     );
 }
@@ -9,3 +9,5 @@ pub mod youtube;
 pub mod mkdocs;
 
 pub mod archive;
+
+pub mod upload;
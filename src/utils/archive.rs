@@ -1,9 +1,10 @@
 /*
- * Safe zip extraction, shared by the two things that accept an upload of one:
- * documentation sites (utils/mkdocs.rs) and shell bundles
- * (handler/shell/create.rs).
+ * Safe archive extraction, shared by the two things that accept an upload of
+ * one: documentation sites (utils/mkdocs.rs) and shell bundles
+ * (handler/shell/create.rs). A zip, a .tar or a .tar.gz — told apart by their
+ * first bytes, not by a file name the JSON upload doesn't have.
  *
- * "Safe" here means three specific things, each of which is a way a malicious
+ * "Safe" here means four specific things, each of which is a way a malicious
  * or careless archive breaks a naive extractor:
  *
  *   • entries that would land outside the target — absolute paths, `..`
@@ -11,23 +12,139 @@
  *     written where it wasn't meant to go;
  *   • the entry count and the *uncompressed* total are capped, because a zip
  *     bomb is a few KB on the wire;
- *   • a single shared top-level directory is stripped, since archives made by
- *     zipping a folder carry it and callers want the contents at the root.
+ *   • symlinks follow the caller's `Symlinks` policy instead of turning into
+ *     regular files holding their target's name. Allowed ones are made only
+ *     after every file is written, so nothing is ever written through one,
+ *     and the whole archive is refused if any of them resolves outside the
+ *     target or not at all. Hard links and device files are refused/skipped;
+ *   • what survives of an entry's mode is its execute bits, so a bundle's
+ *     helper scripts stay runnable. Files are otherwise 0644: no setuid or
+ *     setgid, nothing group- or world-writable in scripts that may run as
+ *     root, and always readable by the unprivileged account a job runs as.
+ *
+ * A single shared top-level directory is stripped, since archives made from
+ * a folder carry it and callers want the contents at the root.
  */
+use std::borrow::Cow;
 use std::fs;
-use std::io::{Cursor, Read};
-use std::path::{Path, PathBuf};
+use std::io::{self, Cursor, Read, Seek};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use tar::EntryType;
 use zip::ZipArchive;
 
 pub struct Limits {
     pub max_entries: usize,
     /// Uncompressed, summed across every file in the archive.
     pub max_total_bytes: u64,
+    pub symlinks: Symlinks,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Symlinks {
+    /// An archive with a symlink in it is refused.
+    Reject,
+    /// Relative symlinks that resolve to something inside the target.
+    Inside,
+}
+
+/// Where an archive is: in memory, or — for a multipart upload
+/// (utils/upload.rs) — in a file it was streamed to, so a large one is never
+/// held whole.
+pub enum Source<'a> {
+    Bytes(&'a [u8]),
+    File(&'a Path),
+}
+
+impl<'a> Source<'a> {
+    /// The archive whole, as a signature over it is checked
+    /// (handler/shell/signing.rs).
+    pub fn read(&self) -> Result<Cow<'a, [u8]>, String> {
+        match self {
+            Source::Bytes(bytes) => Ok(Cow::Borrowed(*bytes)),
+            Source::File(path) => fs::read(path)
+                .map(Cow::Owned)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// Mode bits kept from the archive, on top of FILE_MODE.
+const KEPT_MODE: u32 = 0o111;
+const FILE_MODE: u32 = 0o644;
+/// S_IFMT / S_IFLNK, as zip stores them in the high half of external_attr.
+const TYPE_MASK: u32 = 0o170000;
+const TYPE_SYMLINK: u32 = 0o120000;
+/// A link target is a path, not file content.
+const MAX_LINK_BYTES: u64 = 4096;
+
+/// Extract `source` into `target_dir`, creating it if needed.
+pub fn extract(source: Source, target_dir: &Path, limits: &Limits) -> Result<(), String> {
+    let format = match &source {
+        Source::Bytes(bytes) => sniff(bytes),
+        Source::File(path) => {
+            let mut head = Vec::with_capacity(512);
+            open(&source)?
+                .take(512)
+                .read_to_end(&mut head)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            sniff(&head)
+        }
+    };
+
+    match format {
+        Some(Format::Zip) => match source {
+            Source::Bytes(bytes) => extract_zip(Cursor::new(bytes), target_dir, limits),
+            Source::File(path) => {
+                let file = fs::File::open(path).map_err(|e| e.to_string())?;
+                extract_zip(file, target_dir, limits)
+            }
+        },
+        Some(format) => extract_tar(&source, format, target_dir, limits),
+        None => Err("Not a zip, tar or tar.gz archive".to_string()),
+    }
+}
+
+/// Which kind of archive `head`, the first 512 bytes or so, starts.
+fn sniff(head: &[u8]) -> Option<Format> {
+    if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+        Some(Format::Zip)
+    } else if head.starts_with(&[0x1f, 0x8b]) {
+        Some(Format::TarGz)
+    } else if head.len() >= 262 && &head[257..262] == b"ustar" {
+        Some(Format::Tar)
+    } else {
+        None
+    }
+}
+
+fn open<'a>(source: &Source<'a>) -> Result<Box<dyn Read + 'a>, String> {
+    Ok(match source {
+        Source::Bytes(bytes) => Box::new(Cursor::new(*bytes)),
+        Source::File(path) => Box::new(fs::File::open(path).map_err(|e| e.to_string())?),
+    })
 }
 
-/// Extract `zip_bytes` into `target_dir`, creating it if needed.
-pub fn unzip(zip_bytes: &[u8], target_dir: &Path, limits: &Limits) -> Result<(), String> {
-    let mut archive = ZipArchive::new(Cursor::new(zip_bytes))
+fn open_tar<'a>(source: &Source<'a>, format: Format) -> Result<tar::Archive<Box<dyn Read + 'a>>, String> {
+    let reader = open(source)?;
+    let reader: Box<dyn Read + 'a> = match format {
+        Format::TarGz => Box::new(GzDecoder::new(reader)),
+        _ => reader,
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+fn extract_zip<R: Read + Seek>(reader: R, target_dir: &Path, limits: &Limits) -> Result<(), String> {
+    let mut archive = ZipArchive::new(reader)
         .map_err(|e| format!("Not a readable zip file: {}", e))?;
 
     if archive.len() > limits.max_entries {
@@ -37,12 +154,8 @@ pub fn unzip(zip_bytes: &[u8], target_dir: &Path, limits: &Limits) -> Result<(),
         ));
     }
 
-    let strip = common_root(&archive);
-
-    fs::create_dir_all(target_dir).map_err(|e| e.to_string())?;
-
-    let mut total: u64 = 0;
-    let mut wrote_any = false;
+    let strip = common_root(archive.file_names());
+    let mut out = Writer::new(target_dir, strip, limits)?;
 
     for i in 0..archive.len() {
         let mut entry = archive.by_index(i).map_err(|e| e.to_string())?;
@@ -53,53 +166,240 @@ pub fn unzip(zip_bytes: &[u8], target_dir: &Path, limits: &Limits) -> Result<(),
             None => continue,
         };
 
-        let rel = match &strip {
-            Some(root) => match name.strip_prefix(root) {
-                Ok(r) => r.to_path_buf(),
-                Err(_) => continue,
-            },
+        if entry.is_dir() {
+            out.dir(&name)?;
+            continue;
+        }
+
+        let mode = entry.unix_mode();
+        if mode.map(|m| m & TYPE_MASK == TYPE_SYMLINK).unwrap_or(false) {
+            let target = read_link_target(&mut entry)?;
+            out.symlink(&name, &target)?;
+            continue;
+        }
+
+        let size = entry.size();
+        out.file(&name, mode, size, &mut entry)?;
+    }
+
+    out.finish()
+}
+
+fn extract_tar(source: &Source, format: Format, target_dir: &Path, limits: &Limits) -> Result<(), String> {
+    let unreadable = |e: io::Error| format!("Not a readable tar archive: {}", e);
+
+    // Reading a tar is one pass from the front, and the shared top directory
+    // has to be known before the first entry is written, so the names are
+    // read once on their own. Cheap: the data in between is skipped.
+    let mut names = Vec::new();
+    let mut archive = open_tar(source, format)?;
+    for entry in archive.entries().map_err(unreadable)? {
+        let entry = entry.map_err(unreadable)?;
+        names.push(tar_name(&entry.path().map_err(unreadable)?, entry.header().entry_type()));
+        if names.len() > limits.max_entries {
+            return Err(format!(
+                "Archive has too many files (max {})",
+                limits.max_entries
+            ));
+        }
+    }
+
+    let strip = common_root(names.iter().map(String::as_str));
+    let mut out = Writer::new(target_dir, strip, limits)?;
+
+    let mut archive = open_tar(source, format)?;
+    for entry in archive.entries().map_err(unreadable)? {
+        let mut entry = entry.map_err(unreadable)?;
+        let path = entry.path().map_err(unreadable)?.into_owned();
+
+        let name = match enclosed(&path) {
+            Some(p) => p,
+            None => continue,
+        };
+
+        match entry.header().entry_type() {
+            EntryType::Directory => out.dir(&name)?,
+            EntryType::Regular | EntryType::Continuous => {
+                let mode = entry.header().mode().ok();
+                let size = entry.size();
+                out.file(&name, mode, size, &mut entry)?;
+            }
+            EntryType::Symlink => {
+                let target = match entry.link_name().map_err(unreadable)? {
+                    Some(target) => target.into_owned(),
+                    None => return Err(format!("{}: a symlink with no target", path.display())),
+                };
+                out.symlink(&name, &target)?;
+            }
+            EntryType::Link => {
+                return Err(format!("{}: hard links are not accepted", path.display()));
+            }
+            // Devices, fifos and the like have no business in a bundle or
+            // a doc site.
+            _ => continue,
+        }
+    }
+
+    out.finish()
+}
+
+/// A tar entry's name as common_root reads it: no leading `./`, which
+/// `tar -C dir .` puts on every entry, and a trailing `/` on directories, as
+/// zip has.
+fn tar_name(path: &Path, kind: EntryType) -> String {
+    let name = path.to_string_lossy();
+    let mut name = name.trim_start_matches("./").trim_end_matches('/').to_string();
+    if kind == EntryType::Directory && !name.is_empty() {
+        name.push('/');
+    }
+    name
+}
+
+/// `path` with only plain components, or None if it has anything that could
+/// reach outside the target — the tar counterpart of zip's `enclosed_name`.
+fn enclosed(path: &Path) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+fn read_link_target(entry: &mut impl Read) -> Result<PathBuf, String> {
+    let mut buf = Vec::new();
+    entry
+        .take(MAX_LINK_BYTES)
+        .read_to_end(&mut buf)
+        .map_err(|e| e.to_string())?;
+    Ok(PathBuf::from(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+/// Where extracted entries go, whatever the archive format: the limits, the
+/// stripped top directory and the symlink policy, in one place.
+struct Writer<'a> {
+    target_dir: &'a Path,
+    strip: Option<PathBuf>,
+    limits: &'a Limits,
+    total: u64,
+    wrote_any: bool,
+    /// (where, pointing at), made last.
+    links: Vec<(PathBuf, PathBuf)>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(target_dir: &'a Path, strip: Option<PathBuf>, limits: &'a Limits) -> Result<Self, String> {
+        fs::create_dir_all(target_dir).map_err(|e| e.to_string())?;
+        Ok(Writer { target_dir, strip, limits, total: 0, wrote_any: false, links: Vec::new() })
+    }
+
+    /// Where `name` goes under the target, or None to skip it.
+    fn place(&self, name: &Path) -> Option<PathBuf> {
+        let rel = match &self.strip {
+            Some(root) => name.strip_prefix(root).ok()?,
             None => name,
         };
         if rel.as_os_str().is_empty() {
-            continue;
+            return None;
         }
+        Some(self.target_dir.join(rel))
+    }
 
-        let out_path = target_dir.join(&rel);
-
-        if entry.is_dir() {
-            fs::create_dir_all(&out_path).map_err(|e| e.to_string())?;
-            continue;
+    fn dir(&mut self, name: &Path) -> Result<(), String> {
+        if let Some(path) = self.place(name) {
+            fs::create_dir_all(&path).map_err(|e| e.to_string())?;
         }
+        Ok(())
+    }
+
+    fn file(&mut self, name: &Path, mode: Option<u32>, size: u64, reader: &mut impl Read) -> Result<(), String> {
+        let path = match self.place(name) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
 
-        total += entry.size();
-        if total > limits.max_total_bytes {
+        self.total += size;
+        if self.total > self.limits.max_total_bytes {
             return Err("Archive is too large once unpacked".to_string());
         }
 
-        if let Some(parent) = out_path.parent() {
+        if let Some(parent) = path.parent() {
             fs::create_dir_all(parent).map_err(|e| e.to_string())?;
         }
 
-        let mut buf = Vec::with_capacity(entry.size() as usize);
-        entry.read_to_end(&mut buf).map_err(|e| e.to_string())?;
-        fs::write(&out_path, &buf).map_err(|e| e.to_string())?;
-        wrote_any = true;
+        let mut file = fs::File::create(&path).map_err(|e| e.to_string())?;
+        // Held to the declared size, which is what was counted above.
+        io::copy(&mut reader.take(size), &mut file).map_err(|e| e.to_string())?;
+
+        let mode = FILE_MODE | (mode.unwrap_or(0) & KEPT_MODE);
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).map_err(|e| e.to_string())?;
+
+        self.wrote_any = true;
+        Ok(())
     }
 
-    if !wrote_any {
-        return Err("Archive contained no files".to_string());
+    fn symlink(&mut self, name: &Path, target: &Path) -> Result<(), String> {
+        let path = match self.place(name) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.limits.symlinks == Symlinks::Reject {
+            return Err(format!("{}: symlinks are not accepted", name.display()));
+        }
+        if target.as_os_str().is_empty() || target.is_absolute() {
+            return Err(format!(
+                "{}: a symlink must point at a relative path inside the archive",
+                name.display()
+            ));
+        }
+        self.links.push((path, target.to_path_buf()));
+        Ok(())
     }
 
-    Ok(())
+    /// Make the symlinks, then check where every one of them lands. Checked
+    /// on the real tree rather than by reading the targets, because one link
+    /// can lead through another.
+    fn finish(self) -> Result<(), String> {
+        for (path, target) in &self.links {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            symlink(target, path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+
+        if !self.links.is_empty() {
+            let root = fs::canonicalize(self.target_dir).map_err(|e| e.to_string())?;
+            for (path, _) in &self.links {
+                let shown = path.strip_prefix(self.target_dir).unwrap_or(path).display();
+                match fs::canonicalize(path) {
+                    Ok(resolved) if resolved.starts_with(&root) => {}
+                    Ok(_) => return Err(format!("{}: a symlink that leads outside the archive", shown)),
+                    Err(_) => return Err(format!("{}: a symlink that leads nowhere", shown)),
+                }
+            }
+        }
+
+        if !self.wrote_any {
+            return Err("Archive contained no files".to_string());
+        }
+        Ok(())
+    }
 }
 
-/// The single directory every entry sits under, if there is one — zipping a
+/// The single directory every entry sits under, if there is one — archiving a
 /// `site/` or `vps-setup/` folder whole gives that. None when entries already
 /// sit at the archive root, so an archive made from inside the folder works too.
-fn common_root(archive: &ZipArchive<Cursor<&[u8]>>) -> Option<PathBuf> {
+fn common_root<'a>(names: impl Iterator<Item = &'a str>) -> Option<PathBuf> {
     let mut root: Option<String> = None;
 
-    for name in archive.file_names() {
+    for name in names {
+        // The `./` entry `tar -C dir .` starts with.
+        if name.is_empty() {
+            continue;
+        }
         let first = name.split('/').next().unwrap_or("");
         if first.is_empty() {
             return None;
@@ -118,3 +418,124 @@ fn common_root(archive: &ZipArchive<Cursor<&[u8]>>) -> Option<PathBuf> {
 
     root.map(PathBuf::from)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const LIMITS: Limits = Limits { max_entries: 50, max_total_bytes: 1024 * 1024, symlinks: Symlinks::Inside };
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("archive-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    /// A tar of `bundle/` with main.sh, an executable helper, a setuid file
+    /// and whatever links are asked for.
+    fn tarball(links: &[(&str, &str)], gzip: bool) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut add = |path: &str, mode: u32, body: &[u8]| {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(body.len() as u64);
+            header.set_mode(mode);
+            header.set_entry_type(EntryType::Regular);
+            builder.append_data(&mut header, path, body).unwrap();
+        };
+        add("./bundle/main.sh", 0o644, b"echo main");
+        add("./bundle/lib/helper.sh", 0o777, b"echo helper");
+        add("./bundle/suid", 0o6755, b"x");
+        for (name, target) in links {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(0);
+            header.set_entry_type(EntryType::Symlink);
+            builder.append_link(&mut header, name, target).unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        if !gzip {
+            return tar;
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&tar).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn formats_are_told_apart_by_their_first_bytes() {
+        assert_eq!(sniff(b"PK\x03\x04rest"), Some(Format::Zip));
+        assert_eq!(sniff(&[0x1f, 0x8b, 8, 0]), Some(Format::TarGz));
+        assert_eq!(sniff(&tarball(&[], false)), Some(Format::Tar));
+        assert_eq!(sniff(b"{\"name\": \"not an archive\"}"), None);
+    }
+
+    #[test]
+    fn a_tar_gz_unpacks_with_its_execute_bits_and_nothing_more() {
+        let dir = scratch("modes");
+        extract(Source::Bytes(&tarball(&[], true)), &dir, &LIMITS).unwrap();
+
+        assert_eq!(fs::read(dir.join("main.sh")).unwrap(), b"echo main");
+        assert_eq!(mode(&dir.join("main.sh")), 0o644);
+        assert_eq!(mode(&dir.join("lib/helper.sh")), 0o755);
+        assert_eq!(mode(&dir.join("suid")), 0o755);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_zip_keeps_execute_bits_too() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default().unix_permissions(0o755);
+        writer.start_file("run.sh", options).unwrap();
+        writer.write_all(b"echo run").unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let dir = scratch("zip");
+        extract(Source::Bytes(&bytes), &dir, &LIMITS).unwrap();
+        assert_eq!(mode(&dir.join("run.sh")), 0o755);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn symlinks_inside_the_archive_are_kept() {
+        let dir = scratch("inside");
+        let tar = tarball(&[("bundle/run", "lib/helper.sh"), ("bundle/lib/main", "../main.sh")], false);
+        extract(Source::Bytes(&tar), &dir, &LIMITS).unwrap();
+
+        assert!(fs::symlink_metadata(dir.join("run")).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(dir.join("lib/main")).unwrap(), b"echo main");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn symlinks_that_leave_the_archive_are_refused() {
+        for target in ["/etc/passwd", "../../outside", "missing"] {
+            let dir = scratch("outside");
+            let tar = tarball(&[("bundle/link", target)], false);
+            assert!(extract(Source::Bytes(&tar), &dir, &LIMITS).is_err(), "{}", target);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn a_chain_of_links_is_judged_by_where_it_ends() {
+        // Each target reads as inside; followed, `b` is the target's parent.
+        let dir = scratch("chain");
+        let tar = tarball(&[("bundle/lib/up", ".."), ("bundle/b", "lib/up/..")], false);
+        assert!(extract(Source::Bytes(&tar), &dir, &LIMITS).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn symlinks_can_be_refused_outright() {
+        let dir = scratch("reject");
+        let limits = Limits { symlinks: Symlinks::Reject, ..LIMITS };
+        let tar = tarball(&[("bundle/run", "lib/helper.sh")], false);
+        assert!(extract(Source::Bytes(&tar), &dir, &limits).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -45,17 +45,19 @@ const FAVICON_LINKS: &str = concat!(
     "<link rel=\"shortcut icon\" href=\"/assets/favicon/favicon.ico\">",
 );
 
-/// Unpack `zip_bytes` into `target_dir` (which must not already exist) and make
-/// the result servable at `/documentation/{uuid}/`.
-pub fn unpack(zip_bytes: &[u8], target_dir: &Path, uuid: &str) -> Result<(), String> {
-    archive::unzip(
-        zip_bytes,
+/// Unpack `source`, a zip or tarball, into `target_dir` (which must not
+/// already exist) and make the result servable at `/documentation/{uuid}/`.
+pub fn unpack(source: archive::Source, target_dir: &Path, uuid: &str) -> Result<(), String> {
+    archive::extract(
+        source,
         target_dir,
         &archive::Limits {
             // A doc site is a few hundred files; well past that and something
             // is wrong.
             max_entries: 5_000,
             max_total_bytes: 250 * 1024 * 1024,
+            // mkdocs never writes one, and these files are served as-is.
+            symlinks: archive::Symlinks::Reject,
         },
     )?;
 
@@ -428,7 +430,7 @@ mod tests {
         let out = std::env::temp_dir().join(format!("mkdocs-unpack-{}", uuid));
         let _ = fs::remove_dir_all(&out);
 
-        unpack(&bytes, &out, uuid).expect("unpack should succeed");
+        unpack(archive::Source::Bytes(&bytes), &out, uuid).expect("unpack should succeed");
 
         // 1. the `site/` wrapper is gone — assets sit at the root
         assert!(out.join("assets/stylesheets").is_dir(), "assets/ not at root");
@@ -502,6 +504,6 @@ fn unpack_fixture_for_inspection() {
     let bytes = fs::read("site.zip").expect("site.zip fixture required");
     let out = PathBuf::from("./documentation/preview");
     let _ = fs::remove_dir_all(&out);
-    unpack(&bytes, &out, "preview").unwrap();
+    unpack(archive::Source::Bytes(&bytes), &out, "preview").unwrap();
     println!("unpacked to {}", out.display());
 }
@@ -0,0 +1,250 @@
+/*
+ * Receiving an archive as multipart/form-data.
+ *
+ * The bundle and documentation uploads used to take the zip as a JSON array
+ * of bytes, which costs about four times the file in request body and all of
+ * it in memory before a byte is checked. `receive` instead streams the one
+ * file part to a temporary file, counting as it goes, and answers 413 the
+ * moment the count passes the caller's cap — nothing past it is read. The
+ * text parts beside it come back as a map.
+ *
+ * The file is removed when the `TempFile` is dropped, so a handler that
+ * returns early on a bad field leaves nothing behind in the temp directory.
+ *
+ * The JSON bodies are still accepted, for scripts written against them, and
+ * answered with a `Deprecation` header (`deprecated`).
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use actix_multipart::Multipart;
+use actix_web::guard::GuardContext;
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::HttpResponse;
+use futures_util::StreamExt as _;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::utils::response::Response;
+
+/// All the text parts of one request together. A name and a description,
+/// not a place to send a second file.
+const MAX_FIELD_BYTES: usize = 64 * 1024;
+
+/// The file part of an upload, on disk.
+#[derive(Debug)]
+pub struct TempFile {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Upload {
+    pub fields: HashMap<String, String>,
+    /// None when the request had no part named as the file.
+    pub file: Option<TempFile>,
+}
+
+impl Upload {
+    /// A text part, trimmed; empty when it wasn't sent.
+    pub fn field(&self, name: &str) -> String {
+        self.fields.get(name).map(|v| v.trim().to_string()).unwrap_or_default()
+    }
+}
+
+/// Read a multipart body whose file part is `file_field`, holding that part
+/// to `max_file_bytes`. The error is the response to send.
+pub async fn receive(
+    mut payload: Multipart,
+    file_field: &str,
+    max_file_bytes: u64,
+) -> Result<Upload, HttpResponse> {
+    let mut upload = Upload::default();
+    let mut field_bytes: usize = 0;
+
+    while let Some(item) = payload.next().await {
+        let mut field = item.map_err(|e| Response::bad_request(&e.to_string()))?;
+
+        let (name, is_file) = match field.content_disposition() {
+            Some(cd) => match cd.get_name() {
+                Some(n) => (n.to_string(), cd.get_filename().is_some()),
+                None => return Err(Response::bad_request("Missing field name")),
+            },
+            None => return Err(Response::bad_request("Missing content disposition")),
+        };
+
+        if name == file_field {
+            if upload.file.is_some() {
+                return Err(Response::bad_request("Only one file may be uploaded"));
+            }
+
+            let path = std::env::temp_dir().join(format!("upload-{}", Uuid::now_v7()));
+            let mut out = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .await
+                .map_err(|e| {
+                    log::error!("{}: {}", path.display(), e);
+                    Response::internal_server_error(&e.to_string())
+                })?;
+            // Owned from here on, so every early return below removes it.
+            let mut file = TempFile { path, size: 0 };
+
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk.map_err(|e| Response::bad_request(&e.to_string()))?;
+                file.size += chunk.len() as u64;
+                if file.size > max_file_bytes {
+                    return Err(too_large(&format!(
+                        "The file is too large (max {}MB)",
+                        max_file_bytes / (1024 * 1024)
+                    )));
+                }
+                out.write_all(&chunk).await.map_err(|e| {
+                    log::error!("{}: {}", file.path.display(), e);
+                    Response::internal_server_error(&e.to_string())
+                })?;
+            }
+            out.flush()
+                .await
+                .map_err(|e| Response::internal_server_error(&e.to_string()))?;
+
+            upload.file = Some(file);
+        } else if is_file {
+            return Err(Response::bad_request(&format!("Unexpected file in field {}", name)));
+        } else {
+            let mut bytes: Vec<u8> = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk.map_err(|e| Response::bad_request(&e.to_string()))?;
+                field_bytes += chunk.len();
+                if field_bytes > MAX_FIELD_BYTES {
+                    return Err(too_large("The form fields are too large"));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            upload.fields.insert(name, String::from_utf8_lossy(&bytes).to_string());
+        }
+    }
+
+    Ok(upload)
+}
+
+fn too_large(message: &str) -> HttpResponse {
+    HttpResponse::PayloadTooLarge()
+        .content_type("application/json")
+        .json(Response { message: message.to_string() })
+}
+
+/// Route guard for the multipart form of an upload route; the JSON form is
+/// registered after it, without one.
+pub fn is_multipart(ctx: &GuardContext) -> bool {
+    ctx.head()
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start().to_ascii_lowercase().starts_with("multipart/form-data"))
+        .unwrap_or(false)
+}
+
+/// Mark a response to the old JSON form of an upload (RFC 9745).
+pub fn deprecated(mut res: HttpResponse) -> HttpResponse {
+    res.headers_mut().insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_static("true"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderMap;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+
+    const BOUNDARY: &str = "xXxBOUNDARYxXx";
+
+    fn body(file: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(
+            format!(
+                "--{b}\r\nContent-Disposition: form-data; name=\"name\"\r\n\r\n  tools \r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.zip\"\r\n\
+                 Content-Type: application/zip\r\n\r\n",
+                b = BOUNDARY
+            )
+            .as_bytes(),
+        );
+        out.extend_from_slice(file);
+        out.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        out
+    }
+
+    fn multipart(body: Vec<u8>) -> Multipart {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/form-data; boundary={}", BOUNDARY)).unwrap(),
+        );
+        let stream = futures_util::stream::iter(
+            body.chunks(7)
+                .map(|c| Ok(Bytes::copy_from_slice(c)))
+                .collect::<Vec<_>>(),
+        );
+        Multipart::new(&headers, stream)
+    }
+
+    #[actix_web::test]
+    async fn the_file_lands_on_disk_and_goes_with_the_upload() {
+        let upload = receive(multipart(body(b"PK-not-really")), "file", 1024)
+            .await
+            .unwrap();
+        assert_eq!(upload.field("name"), "tools");
+        assert_eq!(upload.field("description"), "");
+
+        let path = {
+            let file = upload.file.as_ref().unwrap();
+            assert_eq!(file.size, 13);
+            assert_eq!(fs::read(&file.path).unwrap(), b"PK-not-really");
+            file.path.clone()
+        };
+        drop(upload);
+        assert!(!path.exists());
+    }
+
+    #[actix_web::test]
+    async fn an_oversized_file_is_refused_while_streaming() {
+        let res = receive(multipart(body(&[0u8; 64])), "file", 32).await.unwrap_err();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_web::test]
+    async fn a_file_under_another_name_is_refused() {
+        let res = receive(multipart(body(b"zip")), "archive", 1024).await.unwrap_err();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn only_multipart_bodies_pass_the_guard() {
+        let req = TestRequest::default()
+            .insert_header((CONTENT_TYPE, "multipart/form-data; boundary=x"))
+            .to_srv_request();
+        assert!(is_multipart(&req.guard_ctx()));
+
+        let req = TestRequest::default()
+            .insert_header((CONTENT_TYPE, "application/json"))
+            .to_srv_request();
+        assert!(!is_multipart(&req.guard_ctx()));
+
+        assert!(!is_multipart(&TestRequest::default().to_srv_request().guard_ctx()));
+    }
+}